@@ -0,0 +1,2 @@
+-- path inside the app container where the project volume is mounted, NULL means no volume
+ALTER TABLE projects ADD COLUMN volume_path TEXT;
@@ -0,0 +1,3 @@
+-- newline separated CIDRs checked by the proxy against the client address
+ALTER TABLE projects ADD COLUMN ip_allowlist TEXT NOT NULL DEFAULT '';
+ALTER TABLE projects ADD COLUMN ip_denylist TEXT NOT NULL DEFAULT '';
@@ -0,0 +1,2 @@
+-- JSON array with the sidecar containers started next to every deployment of the project
+ALTER TABLE projects ADD COLUMN sidecars TEXT NOT NULL DEFAULT '[]';
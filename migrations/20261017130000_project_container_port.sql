@@ -0,0 +1,2 @@
+-- port the app listens on inside the container, NULL means the default one
+ALTER TABLE projects ADD COLUMN container_port INTEGER;
@@ -0,0 +1,4 @@
+-- when the project was deleted, NULL for active projects. Deleted projects are purged after a grace period
+ALTER TABLE projects ADD COLUMN deleted INTEGER;
+-- whether the volume should also be removed when the project is purged
+ALTER TABLE projects ADD COLUMN delete_volume INTEGER NOT NULL DEFAULT 0;
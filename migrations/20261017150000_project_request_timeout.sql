@@ -0,0 +1,2 @@
+-- seconds the proxy waits for the app to respond, NULL means the server default
+ALTER TABLE projects ADD COLUMN request_timeout_secs INTEGER;
@@ -0,0 +1,3 @@
+-- candidate deployment receiving a share of the production traffic
+ALTER TABLE projects ADD COLUMN canary_deployment_id INTEGER;
+ALTER TABLE projects ADD COLUMN canary_percent INTEGER NOT NULL DEFAULT 0;
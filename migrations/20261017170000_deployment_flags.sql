@@ -0,0 +1,2 @@
+-- runtime only env vars of a single deployment, same format as env
+ALTER TABLE deployments ADD COLUMN flags TEXT NOT NULL DEFAULT '';
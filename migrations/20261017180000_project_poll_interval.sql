@@ -0,0 +1,2 @@
+-- seconds between checks for new commits, NULL means the server default
+ALTER TABLE projects ADD COLUMN poll_interval_secs INTEGER;
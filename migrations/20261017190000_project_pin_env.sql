@@ -0,0 +1,2 @@
+-- whether deployments keep the env they were created with when the project env changes
+ALTER TABLE projects ADD COLUMN pin_env INTEGER NOT NULL DEFAULT 0;
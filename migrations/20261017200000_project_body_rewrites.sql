@@ -0,0 +1,2 @@
+-- JSON array with the find/replace rules the proxy applies to text responses
+ALTER TABLE projects ADD COLUMN body_rewrites TEXT NOT NULL DEFAULT '[]';
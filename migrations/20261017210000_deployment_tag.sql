@@ -0,0 +1,2 @@
+-- git tag the deployment was created for, these are releases with a hostname derived from the tag
+ALTER TABLE deployments ADD COLUMN tag TEXT;
@@ -0,0 +1,2 @@
+-- concurrent requests the proxy sends to each deployment, NULL means unlimited
+ALTER TABLE projects ADD COLUMN max_connections INTEGER;
@@ -0,0 +1,2 @@
+-- JSON array with the redirect rules the proxy answers before reaching the deployment
+ALTER TABLE projects ADD COLUMN redirects TEXT NOT NULL DEFAULT '[]';
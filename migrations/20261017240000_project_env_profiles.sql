@@ -0,0 +1,2 @@
+-- JSON object from profile name to the env applied on top of the project env
+ALTER TABLE projects ADD COLUMN env_profiles TEXT NOT NULL DEFAULT '{}';
@@ -0,0 +1,2 @@
+-- folder inside the image served by the proxy, no container runs for the project if set
+ALTER TABLE projects ADD COLUMN static_dir TEXT;
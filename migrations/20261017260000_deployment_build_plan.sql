@@ -0,0 +1,2 @@
+-- JSON with the providers and commands nixpacks resolved for the last build
+ALTER TABLE deployments ADD COLUMN build_plan TEXT;
@@ -0,0 +1,3 @@
+-- domains are stored lowercased, so the primary key also catches the same domain with a different case
+DELETE FROM domains WHERE rowid NOT IN (SELECT min(rowid) FROM domains GROUP BY lower(domain));
+UPDATE domains SET domain = lower(domain);
@@ -0,0 +1,3 @@
+-- human readable label used in the deployment hostname instead of the url id
+ALTER TABLE deployments ADD COLUMN slug TEXT;
+CREATE UNIQUE INDEX deployments_project_slug ON deployments (project, slug);
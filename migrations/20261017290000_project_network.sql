@@ -0,0 +1,4 @@
+-- extra docker network the app containers join, besides the prezel one
+ALTER TABLE projects ADD COLUMN docker_network TEXT;
+-- newline separated DNS servers for the app containers
+ALTER TABLE projects ADD COLUMN dns_servers TEXT NOT NULL DEFAULT '';
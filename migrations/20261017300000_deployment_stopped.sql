@@ -0,0 +1,2 @@
+-- stopped deployments are not started again until they are explicitly started
+ALTER TABLE deployments ADD COLUMN stopped INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1 @@
+ALTER TABLE deployments ADD COLUMN base_images TEXT;
@@ -0,0 +1,2 @@
+-- extra seconds idle containers keep running before going to standby, NULL means none
+ALTER TABLE projects ADD COLUMN standby_grace_secs INTEGER;
@@ -0,0 +1,2 @@
+-- label used in the hostnames of the project instead of its name
+ALTER TABLE projects ADD COLUMN hostname_prefix TEXT;
@@ -0,0 +1,2 @@
+-- JSON array with the methods and paths the proxy forwards, any request if empty
+ALTER TABLE projects ADD COLUMN route_allowlist TEXT NOT NULL DEFAULT '[]';
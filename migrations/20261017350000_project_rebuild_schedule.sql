@@ -0,0 +1,2 @@
+-- cron expression for periodic rebuilds of the production deployment
+ALTER TABLE projects ADD COLUMN rebuild_schedule TEXT;
@@ -0,0 +1,7 @@
+-- box wide read only mode for maintenance, the box is writable while there is no row
+CREATE TABLE read_only (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    message TEXT,
+    pause_deployments BOOLEAN NOT NULL,
+    since INTEGER NOT NULL
+);
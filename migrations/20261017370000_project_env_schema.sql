@@ -0,0 +1,2 @@
+-- JSON array with the env rules checked before the app container starts
+ALTER TABLE projects ADD COLUMN env_schema TEXT NOT NULL DEFAULT '[]';
@@ -0,0 +1,2 @@
+-- JSON object with the overrides of the environment the app is built in
+ALTER TABLE projects ADD COLUMN build_environment TEXT NOT NULL DEFAULT '{}';
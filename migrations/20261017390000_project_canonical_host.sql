@@ -0,0 +1,2 @@
+-- off, www or apex, the custom domain the other one of the pair redirects to
+ALTER TABLE projects ADD COLUMN canonical_host TEXT NOT NULL DEFAULT 'off';
@@ -0,0 +1,2 @@
+-- bytes of the image the deployment was last built into, null until built
+ALTER TABLE deployments ADD COLUMN image_size INTEGER;
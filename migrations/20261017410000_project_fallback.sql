@@ -0,0 +1,2 @@
+-- deployment production requests fail over to when the production one is down or answers with a 5xx
+ALTER TABLE projects ADD COLUMN fallback_deployment_id INTEGER;
@@ -0,0 +1,2 @@
+-- set for the markers of the build phase starting at that line, null for the regular output
+ALTER TABLE build ADD COLUMN phase TEXT;
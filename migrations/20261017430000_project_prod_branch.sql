@@ -0,0 +1,2 @@
+-- branch production deployments come from, the default branch of the repo if null
+ALTER TABLE projects ADD COLUMN prod_branch TEXT;
@@ -0,0 +1,2 @@
+-- incremented on every update, so concurrent updates based on an old version are rejected
+ALTER TABLE projects ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
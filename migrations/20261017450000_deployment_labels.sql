@@ -0,0 +1,2 @@
+-- key/value metadata set by the user, one NAME=value per line like the flags
+ALTER TABLE deployments ADD COLUMN labels TEXT NOT NULL DEFAULT '';
@@ -0,0 +1,2 @@
+-- days the build logs of the project are kept, NULL means the server default
+ALTER TABLE projects ADD COLUMN log_retention_days INTEGER;
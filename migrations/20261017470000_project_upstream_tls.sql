@@ -0,0 +1,2 @@
+-- off, on or skip_verify, whether the proxy talks HTTPS to the app container
+ALTER TABLE projects ADD COLUMN upstream_tls TEXT NOT NULL DEFAULT 'off';
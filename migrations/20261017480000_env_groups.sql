@@ -0,0 +1,9 @@
+-- named env shared by several projects, attached by name in projects.env_groups
+CREATE TABLE env_groups (
+    id INTEGER PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL UNIQUE,
+    env TEXT NOT NULL DEFAULT '',
+    created INTEGER NOT NULL
+);
+-- names of the attached groups one per line, the later ones override the earlier ones
+ALTER TABLE projects ADD COLUMN env_groups TEXT NOT NULL DEFAULT '';
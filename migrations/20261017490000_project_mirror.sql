@@ -0,0 +1,4 @@
+-- shadow deployment getting a copy of a share of the production requests, its responses are discarded
+ALTER TABLE projects ADD COLUMN mirror_deployment_id INTEGER;
+ALTER TABLE projects ADD COLUMN mirror_percent INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE projects ADD COLUMN mirror_all_methods INTEGER NOT NULL DEFAULT 0;
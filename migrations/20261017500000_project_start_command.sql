@@ -0,0 +1,2 @@
+-- replaces the start command detected at build time, passed to the app container when it starts
+ALTER TABLE projects ADD COLUMN start_command TEXT;
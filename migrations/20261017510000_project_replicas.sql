@@ -0,0 +1,2 @@
+-- JSON object with the bounds the app containers of the deployments are scaled between
+ALTER TABLE projects ADD COLUMN replicas TEXT NOT NULL DEFAULT '{}';
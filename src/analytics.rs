@@ -0,0 +1,260 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rand::RngCore;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+
+use crate::logging::RequestLog;
+
+/// analytics are kept in buckets of this size, windows are rounded up to whole buckets
+pub(crate) const ANALYTICS_BUCKET: Duration = Duration::from_secs(60 * 60);
+/// how far back analytics can be read
+pub(crate) const ANALYTICS_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+/// visitors over this are not counted as unique anymore, to bound the memory of every bucket
+const MAX_VISITORS_PER_BUCKET: usize = 5000;
+/// requests to other paths over this are counted under `OTHER_PATHS`
+const MAX_PATHS_PER_BUCKET: usize = 200;
+const OTHER_PATHS: &str = "(other)";
+const TOP_PATHS: usize = 10;
+
+#[derive(Default)]
+struct Bucket {
+    start: i64,
+    requests: u64,
+    status_2xx: u64,
+    status_3xx: u64,
+    status_4xx: u64,
+    status_5xx: u64,
+    visitors: HashSet<u64>,
+    paths: HashMap<String, u64>,
+}
+
+#[derive(Serialize, ToSchema, Debug, PartialEq)]
+pub(crate) struct PathCount {
+    pub(crate) path: String,
+    pub(crate) requests: u64,
+}
+
+#[derive(Serialize, ToSchema, Debug, PartialEq)]
+pub(crate) struct DeploymentAnalytics {
+    /// The requested window rounded up to whole hours
+    pub(crate) window_secs: u64,
+    pub(crate) requests: u64,
+    /// Distinct client addresses, counted up to a few thousands per hour
+    pub(crate) unique_visitors: u64,
+    pub(crate) status_2xx: u64,
+    pub(crate) status_3xx: u64,
+    pub(crate) status_4xx: u64,
+    pub(crate) status_5xx: u64,
+    /// Most requested paths, from the most to the least requested
+    pub(crate) top_paths: Vec<PathCount>,
+}
+
+/// Rolling per deployment traffic summary fed by the request logger. Client addresses
+/// are only kept as hashes salted with a secret that changes on every restart
+#[derive(Clone)]
+pub(crate) struct Analytics {
+    salt: Arc<[u8; 32]>,
+    /// buckets by deployment, from oldest to latest
+    deployments: Arc<Mutex<HashMap<i64, VecDeque<Bucket>>>>,
+}
+
+impl Analytics {
+    pub(crate) fn new() -> Self {
+        let mut salt = [0; 32];
+        rand::thread_rng().fill_bytes(&mut salt);
+        Self {
+            salt: salt.into(),
+            deployments: Default::default(),
+        }
+    }
+
+    pub(crate) fn record(&self, event: &RequestLog, client_ip: Option<IpAddr>) {
+        let visitor = client_ip.map(|ip| self.hash_ip(ip));
+        let start = bucket_start(event.time);
+        let mut deployments = self.deployments.lock().unwrap();
+        let buckets = deployments.entry(event.deployment).or_default();
+        if buckets.back().map_or(true, |bucket| bucket.start < start) {
+            buckets.push_back(Bucket {
+                start,
+                ..Default::default()
+            });
+            // a new bucket is a good time to drop the expired ones everywhere
+            let oldest = start - ANALYTICS_RETENTION.as_millis() as i64;
+            deployments.retain(|_, buckets| {
+                while buckets.front().is_some_and(|bucket| bucket.start <= oldest) {
+                    buckets.pop_front();
+                }
+                !buckets.is_empty()
+            });
+        }
+        let Some(bucket) = deployments.get_mut(&event.deployment).and_then(|buckets| {
+            buckets
+                .iter_mut()
+                .rev()
+                .find(|bucket| bucket.start <= start)
+        }) else {
+            return;
+        };
+
+        bucket.requests += 1;
+        match event.status / 100 {
+            2 => bucket.status_2xx += 1,
+            3 => bucket.status_3xx += 1,
+            4 => bucket.status_4xx += 1,
+            5 => bucket.status_5xx += 1,
+            _ => {}
+        }
+        if let Some(visitor) = visitor {
+            if bucket.visitors.len() < MAX_VISITORS_PER_BUCKET {
+                bucket.visitors.insert(visitor);
+            }
+        }
+        let path = if bucket.paths.len() < MAX_PATHS_PER_BUCKET
+            || bucket.paths.contains_key(&event.path)
+        {
+            event.path.as_str()
+        } else {
+            OTHER_PATHS
+        };
+        *bucket.paths.entry(path.to_owned()).or_default() += 1;
+    }
+
+    /// Summary of the buckets of the deployment in the `window` ending at `now`
+    pub(crate) fn report(
+        &self,
+        deployment: i64,
+        window: Duration,
+        now: i64,
+    ) -> DeploymentAnalytics {
+        let bucket_millis = ANALYTICS_BUCKET.as_millis() as i64;
+        let count = window
+            .as_millis()
+            .div_ceil(ANALYTICS_BUCKET.as_millis())
+            .max(1) as i64;
+        let first_start = bucket_start(now) - (count - 1) * bucket_millis;
+
+        let mut report = DeploymentAnalytics {
+            window_secs: count as u64 * ANALYTICS_BUCKET.as_secs(),
+            requests: 0,
+            unique_visitors: 0,
+            status_2xx: 0,
+            status_3xx: 0,
+            status_4xx: 0,
+            status_5xx: 0,
+            top_paths: vec![],
+        };
+        let mut visitors: HashSet<u64> = HashSet::new();
+        let mut paths: HashMap<&str, u64> = HashMap::new();
+        let deployments = self.deployments.lock().unwrap();
+        let buckets = deployments.get(&deployment).into_iter().flatten();
+        for bucket in buckets.filter(|bucket| bucket.start >= first_start) {
+            report.requests += bucket.requests;
+            report.status_2xx += bucket.status_2xx;
+            report.status_3xx += bucket.status_3xx;
+            report.status_4xx += bucket.status_4xx;
+            report.status_5xx += bucket.status_5xx;
+            visitors.extend(&bucket.visitors);
+            for (path, requests) in &bucket.paths {
+                *paths.entry(path).or_default() += requests;
+            }
+        }
+        report.unique_visitors = visitors.len() as u64;
+
+        let mut paths: Vec<_> = paths.into_iter().collect();
+        // ties sorted by path so the order is stable
+        paths.sort_by(|(a_path, a), (b_path, b)| b.cmp(a).then(a_path.cmp(b_path)));
+        report.top_paths = paths
+            .into_iter()
+            .take(TOP_PATHS)
+            .map(|(path, requests)| PathCount {
+                path: path.to_owned(),
+                requests,
+            })
+            .collect();
+        report
+    }
+
+    fn hash_ip(&self, ip: IpAddr) -> u64 {
+        let mut hasher = Sha256::new();
+        hasher.update(self.salt.as_slice());
+        hasher.update(ip.to_string());
+        let hash = hasher.finalize();
+        u64::from_le_bytes(hash[..8].try_into().unwrap())
+    }
+}
+
+fn bucket_start(time: i64) -> i64 {
+    let bucket_millis = ANALYTICS_BUCKET.as_millis() as i64;
+    time - time.rem_euclid(bucket_millis)
+}
+
+#[cfg(test)]
+mod analytics_tests {
+    use std::{net::IpAddr, time::Duration};
+
+    use super::{Analytics, PathCount, ANALYTICS_BUCKET};
+    use crate::logging::{Level, RequestLog};
+
+    const HOUR: i64 = ANALYTICS_BUCKET.as_millis() as i64;
+
+    fn event(deployment: i64, time: i64, path: &str, status: u16) -> RequestLog {
+        RequestLog {
+            time,
+            level: Level::INFO,
+            deployment,
+            host: "example.com".to_owned(),
+            method: "GET".to_owned(),
+            path: path.to_owned(),
+            status,
+            request_id: String::new(),
+            duration_ms: 0,
+        }
+    }
+
+    #[test]
+    fn test_analytics_report() {
+        let analytics = Analytics::new();
+        let ip = |ip: &str| Some(ip.parse::<IpAddr>().unwrap());
+        let now = 30 * HOUR + 10;
+        analytics.record(&event(1, 28 * HOUR, "/old", 200), ip("10.0.0.9"));
+        analytics.record(&event(1, 29 * HOUR, "/", 200), ip("10.0.0.1"));
+        analytics.record(&event(1, 29 * HOUR + 5, "/about", 404), ip("10.0.0.2"));
+        analytics.record(&event(1, 30 * HOUR, "/", 503), ip("10.0.0.1"));
+        analytics.record(&event(1, 30 * HOUR + 1, "/", 301), None);
+        analytics.record(&event(2, 30 * HOUR, "/", 200), ip("10.0.0.3"));
+
+        let report = analytics.report(1, Duration::from_secs(90 * 60), now);
+        assert_eq!(report.window_secs, 2 * 60 * 60);
+        assert_eq!(report.requests, 4);
+        assert_eq!(report.unique_visitors, 2);
+        assert_eq!(
+            (
+                report.status_2xx,
+                report.status_3xx,
+                report.status_4xx,
+                report.status_5xx
+            ),
+            (1, 1, 1, 1)
+        );
+        let path = |path: &str, requests| PathCount {
+            path: path.to_owned(),
+            requests,
+        };
+        assert_eq!(report.top_paths, vec![path("/", 3), path("/about", 1)]);
+    }
+
+    #[test]
+    fn test_expired_buckets_are_dropped() {
+        let analytics = Analytics::new();
+        analytics.record(&event(1, 0, "/", 200), None);
+        analytics.record(&event(2, 25 * HOUR, "/", 200), None);
+        assert!(!analytics.deployments.lock().unwrap().contains_key(&1));
+    }
+}
@@ -1,21 +1,52 @@
 use actix_web::{
-    delete, get, patch, post,
-    web::{Data, Json, Path},
+    delete, get, patch, post, put,
+    web::{Data, Json, Path, Query},
     HttpResponse, Responder,
 };
+use std::net::IpAddr;
+
 use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use tokio::net::lookup_host;
+use utoipa::ToSchema;
 
 use crate::{
     api::{
+        env_groups::check_env_groups,
         security::RequireApiKey,
         utils::{get_all_deployments, get_prod_deployment, get_prod_deployment_id},
-        AppState, ErrorResponse, FullProjectInfo, ProjectInfo,
+        AppState, CloneProject, ErrorCode, ErrorResponse, FullProjectInfo, ProjectInfo,
+    },
+    container::{
+        build_plan::BuildEnvironment,
+        network::{validate_dns_servers, validate_network},
+        replicas::Replicas,
+        sidecar::Sidecar,
+    },
+    db::{InsertDeployment, InsertProject, Project, UpdateConflict, UpdateProject},
+    deployments::{cron::CronSchedule, label::validate_hostname_prefix},
+    docker::NamedVolume,
+    env::{set_var, EnvRule, EnvVars},
+    ip_filter::{parse_cidrs, IpFilter},
+    proxy::{
+        redirect::{CanonicalHost, RedirectRule},
+        rewrite::BodyRewrite,
+        routes::AllowedRoute,
+        static_files::validate_static_dir,
+        upstream_tls::UpstreamTls,
     },
-    db::{InsertProject, UpdateProject},
 };
 
+#[derive(Deserialize)]
+struct GetProjectsParams {
+    include_deleted: Option<bool>,
+}
+
 /// Get projects
 #[utoipa::path(
+    params(
+        ("include_deleted" = Option<bool>, Query, description = "Also return the deleted projects that can still be restored")
+    ),
     responses(
         (status = 200, description = "Hello world", body = [ProjectInfo])
     ),
@@ -24,39 +55,88 @@ use crate::{
     )
 )]
 #[get("/apps", wrap = "RequireApiKey")]
-async fn get_projects(state: Data<AppState>) -> impl Responder {
-    let projects = state.db.get_projects().await;
-    let projects_with_deployments = projects.into_iter().map(|project| {
-        let state = state.clone();
-        async move {
-            let prod_deployment = get_prod_deployment(&state, project.id).await;
-            let prod_deployment_id = get_prod_deployment_id(&state.db, &project).await;
+async fn get_projects(state: Data<AppState>, params: Query<GetProjectsParams>) -> impl Responder {
+    let mut projects = state.db.get_projects().await;
+    if params.include_deleted == Some(true) {
+        projects.extend(state.db.get_deleted_projects().await);
+    }
+    let projects_with_deployments = projects
+        .into_iter()
+        .map(|project| get_project_info(&state, project));
+    HttpResponse::Ok().json(join_all(projects_with_deployments).await)
+}
 
-            // TODO: if the repo is not available, simply don't return that info
-            let repo = state
-                .github
-                .get_repo(&project.repo_id)
-                .await
-                .unwrap()
-                .unwrap();
-            ProjectInfo {
-                name: project.name.clone(),
-                id: project.id,
-                repo: repo.into(),
-                created: project.created,
-                env: project.env.clone(),
-                custom_domains: project.custom_domains,
-                prod_deployment_id,
-                prod_deployment,
-            }
-        }
-    });
+async fn get_project_info(state: &AppState, project: Project) -> ProjectInfo {
+    let prod_deployment = get_prod_deployment(state, project.id).await;
+    let prod_deployment_id = get_prod_deployment_id(&state.db, &project).await;
 
-    HttpResponse::Ok().json(join_all(projects_with_deployments).await)
+    // TODO: if the repo is not available, simply don't return that info
+    let repo = state
+        .github
+        .get_repo(&project.repo_id)
+        .await
+        .unwrap()
+        .unwrap();
+    let secret_env = project.secret_env();
+    ProjectInfo {
+        name: project.name,
+        id: project.id,
+        version: project.version,
+        repo: repo.into(),
+        created: project.created,
+        secret_env,
+        env: project.env,
+        custom_domains: project.custom_domains,
+        volume_path: project.volume_path,
+        ip_allowlist: project.ip_allowlist,
+        ip_denylist: project.ip_denylist,
+        sidecars: project.sidecars,
+        container_port: project.container_port,
+        request_timeout_secs: project.request_timeout_secs,
+        canary_deployment_id: project.canary_deployment_id,
+        canary_percent: project.canary_percent,
+        fallback_deployment_id: project.fallback_deployment_id,
+        mirror_deployment_id: project.mirror_deployment_id,
+        mirror_percent: project.mirror_percent,
+        mirror_all_methods: project.mirror_all_methods,
+        poll_interval_secs: project.poll_interval_secs,
+        pin_env: project.pin_env,
+        body_rewrites: project.body_rewrites,
+        max_connections: project.max_connections,
+        redirects: project.redirects,
+        route_allowlist: project.route_allowlist,
+        env_profiles: project.env_profiles,
+        env_schema: project.env_schema,
+        build_environment: project.build_environment,
+        canonical_host: project.canonical_host,
+        static_dir: project.static_dir,
+        docker_network: project.docker_network,
+        dns_servers: project.dns_servers,
+        standby_grace_secs: project.standby_grace_secs,
+        hostname_prefix: project.hostname_prefix,
+        rebuild_schedule: project.rebuild_schedule,
+        prod_branch: project.prod_branch,
+        log_retention_days: project.log_retention_days,
+        upstream_tls: project.upstream_tls,
+        start_command: project.start_command,
+        replicas: project.replicas,
+        env_groups: project.env_groups,
+        deleted: project.deleted,
+        prod_deployment_id,
+        prod_deployment,
+    }
+}
+
+#[derive(Deserialize)]
+struct GetProjectParams {
+    label: Option<String>,
 }
 
 /// Get project by name
 #[utoipa::path(
+    params(
+        ("label" = Option<String>, Query, description = "Only return the deployments with this label, as `key=value`, or just `key` for any value")
+    ),
     responses(
         (status = 200, description = "Hello world", body = FullProjectInfo),
         (status = 404, description = "Project not found", body = ErrorResponse)
@@ -66,7 +146,11 @@ async fn get_projects(state: Data<AppState>) -> impl Responder {
     )
 )]
 #[get("/apps/{name}", wrap = "RequireApiKey")]
-async fn get_project(state: Data<AppState>, name: Path<String>) -> impl Responder {
+async fn get_project(
+    state: Data<AppState>,
+    name: Path<String>,
+    params: Query<GetProjectParams>,
+) -> impl Responder {
     let name = name.into_inner();
     let project = state.db.get_project_by_name(&name).await;
     match project {
@@ -80,44 +164,410 @@ async fn get_project(state: Data<AppState>, name: Path<String>) -> impl Responde
 
             let prod_deployment_id = get_prod_deployment_id(&state.db, &project).await;
             let prod_deployment = get_prod_deployment(&state, project.id).await;
-            let deployments = get_all_deployments(&state, project.id).await;
+            let mut deployments = get_all_deployments(&state, project.id).await;
+            if let Some(label) = &params.label {
+                let (key, value) = match label.split_once('=') {
+                    Some((key, value)) => (key, Some(value)),
+                    None => (label.as_str(), None),
+                };
+                deployments.retain(|deployment| match deployment.labels.get(key) {
+                    Some(found) => value.map_or(true, |value| found == value),
+                    None => false,
+                });
+            }
+            let secret_env = project.secret_env();
 
             HttpResponse::Ok().json(FullProjectInfo {
                 name: project.name,
                 id: project.id,
+                version: project.version,
                 repo: repo.into(),
                 created: project.created,
+                secret_env,
                 env: project.env,
                 custom_domains: project.custom_domains,
+                volume_path: project.volume_path,
+                ip_allowlist: project.ip_allowlist,
+                ip_denylist: project.ip_denylist,
+                sidecars: project.sidecars,
+                container_port: project.container_port,
+                request_timeout_secs: project.request_timeout_secs,
+                canary_deployment_id: project.canary_deployment_id,
+                canary_percent: project.canary_percent,
+                fallback_deployment_id: project.fallback_deployment_id,
+                mirror_deployment_id: project.mirror_deployment_id,
+                mirror_percent: project.mirror_percent,
+                mirror_all_methods: project.mirror_all_methods,
+                poll_interval_secs: project.poll_interval_secs,
+                pin_env: project.pin_env,
+                body_rewrites: project.body_rewrites,
+                max_connections: project.max_connections,
+                redirects: project.redirects,
+                route_allowlist: project.route_allowlist,
+                env_profiles: project.env_profiles,
+                env_schema: project.env_schema,
+                build_environment: project.build_environment,
+                canonical_host: project.canonical_host,
+                static_dir: project.static_dir,
+                docker_network: project.docker_network,
+                dns_servers: project.dns_servers,
+                standby_grace_secs: project.standby_grace_secs,
+                hostname_prefix: project.hostname_prefix,
+                rebuild_schedule: project.rebuild_schedule,
+                prod_branch: project.prod_branch,
+                log_retention_days: project.log_retention_days,
+                upstream_tls: project.upstream_tls,
+                start_command: project.start_command,
+                replicas: project.replicas,
+                env_groups: project.env_groups,
                 prod_deployment_id,
                 prod_deployment,
                 deployments,
             })
         }
-        None => HttpResponse::NotFound().json(ErrorResponse::NotFound(format!("name = {name}"))),
+        None => ErrorResponse::new(ErrorCode::ProjectNotFound, format!("name = {name}")).into(),
     }
 }
 
+#[derive(Serialize, ToSchema)]
+pub(crate) struct CreatedProject {
+    project: ProjectInfo,
+    /// Id of the deployment created from `deploy_ref`, if any
+    deployment_id: Option<i64>,
+}
+
 /// Create project
+///
+/// If `deploy_ref` is set, the branch or commit is deployed to production right away.
+/// The production branch is still polled as usual afterwards
 #[utoipa::path(
     request_body = InsertProject,
     responses(
-        (status = 201, description = "Project created successfully"),
-        (status = 400, description = "'api' is not a valid app name"),
+        (status = 201, description = "Project created successfully", body = CreatedProject),
+        (status = 400, description = "'api' is not a valid app name, some CIDR, sidecar, port or the volume path is invalid, or `deploy_ref` is not found in the repository", body = ErrorResponse),
+        (status = 403, description = "The repository is not accessible by the Github app", body = ErrorResponse),
+        (status = 409, description = "The project name or some custom domain is already in use", body = ErrorResponse),
+        (status = 502, description = "Github could not be reached", body = ErrorResponse),
     ),
     security(
         ("api_key" = [])
     )
 )]
 #[post("/apps", wrap = "RequireApiKey")] // TODO: return project when successfully inserted
-async fn create_project(project: Json<InsertProject>, state: Data<AppState>) -> impl Responder {
-    if &project.name != "api" {
-        state.db.insert_project(project.0).await;
-        state.manager.full_sync_with_github().await;
-        HttpResponse::Ok()
-    } else {
-        HttpResponse::BadRequest()
+async fn create_project(mut project: Json<InsertProject>, state: Data<AppState>) -> impl Responder {
+    if &project.name == "api" {
+        return ErrorResponse::new(ErrorCode::InvalidProjectName, "'api' is a reserved name")
+            .into();
+    }
+    project.custom_domains =
+        match normalize_custom_domains(&project.custom_domains, &state.manager.box_domain) {
+            Ok(domains) => domains,
+            Err(message) => return ErrorResponse::new(ErrorCode::InvalidDomain, message).into(),
+        };
+    if let Some(conflict) = find_domain_conflict(&state, &project.custom_domains, None).await {
+        return conflict.into();
+    }
+    if let Err(response) = check_canonical_host(project.canonical_host, &project.custom_domains) {
+        return response;
+    }
+    if let Some(Err(error)) = project
+        .hostname_prefix
+        .as_deref()
+        .map(validate_hostname_prefix)
+    {
+        return ErrorResponse::new(ErrorCode::InvalidHostnamePrefix, error.to_string()).into();
+    }
+    let label = project.hostname_prefix.as_deref().unwrap_or(&project.name);
+    if let Some(conflict) = find_hostname_conflict(&state, label, None).await {
+        return conflict.into();
+    }
+    if let Err(error) = IpFilter::new(&project.ip_allowlist, &project.ip_denylist) {
+        return ErrorResponse::new(ErrorCode::InvalidCidr, error.to_string()).into();
+    }
+    if let Err(error) = Sidecar::validate(&project.sidecars) {
+        return ErrorResponse::new(ErrorCode::InvalidSidecar, error.to_string()).into();
+    }
+    if let Err(error) = BodyRewrite::validate(&project.body_rewrites) {
+        return ErrorResponse::new(ErrorCode::InvalidBodyRewrite, error.to_string()).into();
+    }
+    if let Err(error) = RedirectRule::validate(&project.redirects) {
+        return ErrorResponse::new(ErrorCode::InvalidRedirect, error.to_string()).into();
+    }
+    if let Err(error) = AllowedRoute::validate(&project.route_allowlist) {
+        return ErrorResponse::new(ErrorCode::InvalidRoute, error.to_string()).into();
+    }
+    if let Err(error) = EnvRule::validate(&project.env_schema) {
+        return ErrorResponse::new(ErrorCode::InvalidEnvSchema, format!("{error:#}")).into();
+    }
+    if let Err(error) = project.build_environment.validate() {
+        return ErrorResponse::new(ErrorCode::InvalidBuildEnvironment, error.to_string()).into();
+    }
+    if let Err(error) = project.replicas.validate() {
+        return ErrorResponse::new(ErrorCode::InvalidReplicas, error.to_string()).into();
+    }
+    if let Some(Err(error)) = project.rebuild_schedule.as_deref().map(CronSchedule::parse) {
+        return ErrorResponse::new(ErrorCode::InvalidSchedule, error.to_string()).into();
+    }
+    if let Some(Err(error)) = project.static_dir.as_deref().map(validate_static_dir) {
+        return ErrorResponse::new(ErrorCode::InvalidStaticDir, error.to_string()).into();
+    }
+    if let Some(Err(error)) = project
+        .volume_path
+        .as_deref()
+        .map(NamedVolume::validate_path)
+    {
+        return ErrorResponse::new(ErrorCode::InvalidVolumePath, error.to_string()).into();
+    }
+    if let Err(response) = check_upstream_tls(project.upstream_tls, project.static_dir.is_some()) {
+        return response;
+    }
+    if let Err(response) = check_env_groups(&state, &project.env_groups).await {
+        return response;
+    }
+    if let Some(command) = &project.start_command {
+        if command.trim().is_empty() {
+            return ErrorResponse::new(
+                ErrorCode::InvalidStartCommand,
+                "the start command can not be empty, leave it out to use the detected one",
+            )
+            .into();
+        }
+    }
+    if let Some(Err(error)) = project.docker_network.as_deref().map(validate_network) {
+        return ErrorResponse::new(ErrorCode::InvalidNetwork, error.to_string()).into();
     }
+    if let Err(error) = validate_dns_servers(&project.dns_servers) {
+        return ErrorResponse::new(ErrorCode::InvalidNetwork, error.to_string()).into();
+    }
+    if project.container_port == Some(0) {
+        return ErrorResponse::new(
+            ErrorCode::InvalidPort,
+            "the port must be between 1 and 65535",
+        )
+        .into();
+    }
+    if project.request_timeout_secs == Some(0) {
+        return ErrorResponse::new(
+            ErrorCode::InvalidTimeout,
+            "the timeout must be at least 1 second",
+        )
+        .into();
+    }
+    if project.max_connections == Some(0) {
+        return ErrorResponse::new(
+            ErrorCode::InvalidConnectionLimit,
+            "the connection limit must be at least 1",
+        )
+        .into();
+    }
+    if project.poll_interval_secs == Some(0) {
+        return ErrorResponse::new(
+            ErrorCode::InvalidTimeout,
+            "the poll interval must be at least 1 second",
+        )
+        .into();
+    }
+    if project.log_retention_days == Some(0) {
+        return ErrorResponse::new(
+            ErrorCode::InvalidTimeout,
+            "the log retention must be at least 1 day",
+        )
+        .into();
+    }
+    if state
+        .db
+        .get_project_id_by_name(&project.name)
+        .await
+        .is_some()
+    {
+        return ErrorResponse::new(
+            ErrorCode::ProjectNameConflict,
+            "project name already in use",
+        )
+        .with_details(format!("name = {}", project.name))
+        .into();
+    }
+    match state.github.get_repo(&project.repo_id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return ErrorResponse::new(
+                ErrorCode::RepoUnauthorized,
+                "the repository is not accessible by the Github app",
+            )
+            .with_details(format!("repo_id = {}", project.repo_id))
+            .into()
+        }
+        Err(error) => {
+            return ErrorResponse::new(ErrorCode::GithubUnavailable, "failed to read from Github")
+                .with_details(error.to_string())
+                .into()
+        }
+    }
+    let commit = match project.deploy_ref.as_deref() {
+        Some(git_ref) => match state
+            .github
+            .get_latest_commit(&project.repo_id, git_ref)
+            .await
+        {
+            Ok(Some(commit)) => Some(commit),
+            Ok(None) => {
+                return ErrorResponse::new(
+                    ErrorCode::InvalidGitRef,
+                    "no branch or commit found with that name in the repository",
+                )
+                .with_details(format!("deploy_ref = {git_ref}"))
+                .into()
+            }
+            Err(error) => {
+                return ErrorResponse::new(ErrorCode::InternalError, "failed to read from Github")
+                    .with_details(error.to_string())
+                    .into()
+            }
+        },
+        None => None,
+    };
+    let id = match state.db.insert_project(project.0).await {
+        Ok(id) => id,
+        Err(conflict) => return domain_conflict(&state, &conflict.domain).await.into(),
+    };
+    let Some(project) = state.db.get_project(id).await else {
+        return ErrorResponse::new(ErrorCode::ProjectNotFound, format!("id = {id}")).into();
+    };
+    let mut deployment_id = None;
+    if let Some(commit) = commit {
+        // inserted before the sync, so the production branch is not deployed twice
+        // if the ref points to its latest commit
+        let deployment = InsertDeployment {
+            env: project.resolve_env(None),
+            sha: commit.sha,
+            timestamp: commit.timestamp,
+            branch: None,
+            tag: None,
+            project: id,
+        };
+        deployment_id = Some(state.db.insert_deployment(deployment).await);
+    }
+    state.manager.full_sync_with_github().await;
+    HttpResponse::Ok().json(CreatedProject {
+        project: get_project_info(&state, project).await,
+        deployment_id,
+    })
+}
+
+/// Clone project
+///
+/// Creates a new project with the same configuration as an existing one, pointed at
+/// another repository or at another root directory of the same one.
+/// Custom domains are not copied, as they can only belong to one project.
+#[utoipa::path(
+    request_body = CloneProject,
+    responses(
+        (status = 200, description = "Project cloned successfully", body = ProjectInfo),
+        (status = 400, description = "'api' is not a valid app name", body = ErrorResponse),
+        (status = 403, description = "The repository is not accessible by the Github app", body = ErrorResponse),
+        (status = 404, description = "Project not found", body = ErrorResponse),
+        (status = 409, description = "The project name is already in use", body = ErrorResponse),
+        (status = 502, description = "Github could not be reached", body = ErrorResponse),
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[post("/apps/{id}/clone", wrap = "RequireApiKey")]
+async fn clone_project(
+    clone: Json<CloneProject>,
+    state: Data<AppState>,
+    id: Path<i64>,
+) -> impl Responder {
+    let id = id.into_inner();
+    let Some(source) = state.db.get_project(id).await else {
+        return ErrorResponse::new(ErrorCode::ProjectNotFound, format!("id = {id}")).into();
+    };
+    let CloneProject {
+        name,
+        repo_id,
+        root,
+    } = clone.0;
+    if name == "api" {
+        return ErrorResponse::new(ErrorCode::InvalidProjectName, "'api' is a reserved name")
+            .into();
+    }
+    if state.db.get_project_id_by_name(&name).await.is_some() {
+        return ErrorResponse::new(
+            ErrorCode::ProjectNameConflict,
+            "project name already in use",
+        )
+        .with_details(format!("name = {name}"))
+        .into();
+    }
+    if let Some(conflict) = find_hostname_conflict(&state, &name, None).await {
+        return conflict.into();
+    }
+    let repo_id = repo_id.unwrap_or(source.repo_id);
+    match state.github.get_repo(&repo_id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return ErrorResponse::new(
+                ErrorCode::RepoUnauthorized,
+                "the repository is not accessible by the Github app",
+            )
+            .with_details(format!("repo_id = {repo_id}"))
+            .into()
+        }
+        Err(error) => {
+            return ErrorResponse::new(ErrorCode::GithubUnavailable, "failed to read from Github")
+                .with_details(error.to_string())
+                .into()
+        }
+    }
+
+    let new_id = state
+        .db
+        .insert_project(InsertProject {
+            name,
+            repo_id,
+            env: source.env,
+            root: root.unwrap_or(source.root),
+            custom_domains: vec![],
+            volume_path: source.volume_path,
+            ip_allowlist: source.ip_allowlist,
+            ip_denylist: source.ip_denylist,
+            sidecars: source.sidecars,
+            container_port: source.container_port.map(|port| port as u16),
+            request_timeout_secs: source.request_timeout_secs.map(|secs| secs as u32),
+            poll_interval_secs: source.poll_interval_secs.map(|secs| secs as u32),
+            pin_env: source.pin_env,
+            body_rewrites: source.body_rewrites,
+            max_connections: source.max_connections.map(|max| max as u32),
+            redirects: source.redirects,
+            route_allowlist: source.route_allowlist,
+            env_profiles: source.env_profiles,
+            env_schema: source.env_schema,
+            build_environment: source.build_environment,
+            canonical_host: source.canonical_host,
+            static_dir: source.static_dir,
+            docker_network: source.docker_network,
+            dns_servers: source.dns_servers,
+            standby_grace_secs: source.standby_grace_secs.map(|secs| secs as u32),
+            // prefixes are unique, the clone goes by its own name
+            hostname_prefix: None,
+            rebuild_schedule: source.rebuild_schedule,
+            prod_branch: source.prod_branch,
+            log_retention_days: source.log_retention_days.map(|days| days as u32),
+            upstream_tls: source.upstream_tls,
+            start_command: source.start_command,
+            replicas: source.replicas,
+            env_groups: source.env_groups,
+            deploy_ref: None,
+        })
+        .await
+        // no custom domains to conflict
+        .unwrap();
+    state.manager.full_sync_with_github().await;
+    let Some(project) = state.db.get_project(new_id).await else {
+        return ErrorResponse::new(ErrorCode::ProjectNotFound, format!("id = {new_id}")).into();
+    };
+    HttpResponse::Ok().json(get_project_info(&state, project).await)
 }
 
 /// Update project
@@ -125,7 +575,9 @@ async fn create_project(project: Json<InsertProject>, state: Data<AppState>) ->
     request_body = UpdateProject,
     responses(
         (status = 200, description = "Project updated successfully"),
-        // (status = 409, description = "Todo with id already exists", body = ErrorResponse, example = json!(ErrorResponse::Conflict(String::from("id = 1"))))
+        (status = 400, description = "Some CIDR, sidecar, the volume path, the canary, the fallback or the mirror config is invalid", body = ErrorResponse),
+        (status = 404, description = "Project not found", body = ErrorResponse),
+        (status = 409, description = "The project name or some custom domain is already in use, or the project changed since `version`", body = ErrorResponse),
     ),
     security(
         ("api_key" = [])
@@ -133,27 +585,545 @@ async fn create_project(project: Json<InsertProject>, state: Data<AppState>) ->
 )]
 #[patch("/apps/{id}", wrap = "RequireApiKey")]
 async fn update_project(
-    project: Json<UpdateProject>,
+    mut project: Json<UpdateProject>,
     state: Data<AppState>,
     id: Path<i64>,
 ) -> impl Responder {
-    state.db.update_project(id.into_inner(), project.0).await;
+    let id = id.into_inner();
+    let Some(current) = state.db.get_project(id).await else {
+        return ErrorResponse::new(ErrorCode::ProjectNotFound, format!("id = {id}")).into();
+    };
+    let cidrs = [&project.ip_allowlist, &project.ip_denylist];
+    for cidrs in cidrs.into_iter().flatten() {
+        if let Err(error) = parse_cidrs(cidrs) {
+            return ErrorResponse::new(ErrorCode::InvalidCidr, error.to_string()).into();
+        }
+    }
+    if let Some(Err(error)) = project.sidecars.as_deref().map(Sidecar::validate) {
+        return ErrorResponse::new(ErrorCode::InvalidSidecar, error.to_string()).into();
+    }
+    if let Some(Err(error)) = project.body_rewrites.as_deref().map(BodyRewrite::validate) {
+        return ErrorResponse::new(ErrorCode::InvalidBodyRewrite, error.to_string()).into();
+    }
+    if let Some(Err(error)) = project.redirects.as_deref().map(RedirectRule::validate) {
+        return ErrorResponse::new(ErrorCode::InvalidRedirect, error.to_string()).into();
+    }
+    if let Some(Err(error)) = project
+        .route_allowlist
+        .as_deref()
+        .map(AllowedRoute::validate)
+    {
+        return ErrorResponse::new(ErrorCode::InvalidRoute, error.to_string()).into();
+    }
+    if let Some(Err(error)) = project.env_schema.as_deref().map(EnvRule::validate) {
+        return ErrorResponse::new(ErrorCode::InvalidEnvSchema, format!("{error:#}")).into();
+    }
+    if let Some(Err(error)) = project
+        .build_environment
+        .as_ref()
+        .map(BuildEnvironment::validate)
+    {
+        return ErrorResponse::new(ErrorCode::InvalidBuildEnvironment, error.to_string()).into();
+    }
+    if let Some(Err(error)) = project.replicas.as_ref().map(Replicas::validate) {
+        return ErrorResponse::new(ErrorCode::InvalidReplicas, error.to_string()).into();
+    }
+    let rebuild_schedule = project
+        .rebuild_schedule
+        .as_deref()
+        .filter(|schedule| !schedule.is_empty());
+    if let Some(Err(error)) = rebuild_schedule.map(CronSchedule::parse) {
+        return ErrorResponse::new(ErrorCode::InvalidSchedule, error.to_string()).into();
+    }
+    let static_dir = project.static_dir.as_deref().filter(|dir| !dir.is_empty());
+    if let Some(Err(error)) = static_dir.map(validate_static_dir) {
+        return ErrorResponse::new(ErrorCode::InvalidStaticDir, error.to_string()).into();
+    }
+    // an empty path removes the volume
+    let volume_path = project
+        .volume_path
+        .as_deref()
+        .filter(|path| !path.is_empty());
+    if let Some(Err(error)) = volume_path.map(NamedVolume::validate_path) {
+        return ErrorResponse::new(ErrorCode::InvalidVolumePath, error.to_string()).into();
+    }
+    let docker_network = project
+        .docker_network
+        .as_deref()
+        .filter(|network| !network.is_empty());
+    if let Some(Err(error)) = docker_network.map(validate_network) {
+        return ErrorResponse::new(ErrorCode::InvalidNetwork, error.to_string()).into();
+    }
+    if let Some(Err(error)) = project.dns_servers.as_deref().map(validate_dns_servers) {
+        return ErrorResponse::new(ErrorCode::InvalidNetwork, error.to_string()).into();
+    }
+    if project.canary_percent.is_some_and(|percent| percent > 100) {
+        return ErrorResponse::new(
+            ErrorCode::InvalidCanary,
+            "the canary percentage must be between 0 and 100",
+        )
+        .into();
+    }
+    if let Some(deployment) = project.canary_deployment_id.filter(|id| *id != 0) {
+        let owner = state.db.get_deployment(deployment).await;
+        if !owner.is_some_and(|owner| owner.project == id) {
+            return ErrorResponse::new(
+                ErrorCode::InvalidCanary,
+                "the canary deployment does not belong to this project",
+            )
+            .with_details(format!("deployment = {deployment}"))
+            .into();
+        }
+    }
+    if let Some(deployment) = project.fallback_deployment_id.filter(|id| *id != 0) {
+        let owner = state.db.get_deployment(deployment).await;
+        if !owner.is_some_and(|owner| owner.project == id) {
+            return ErrorResponse::new(
+                ErrorCode::InvalidFallback,
+                "the fallback deployment does not belong to this project",
+            )
+            .with_details(format!("deployment = {deployment}"))
+            .into();
+        }
+    }
+    if project.mirror_percent.is_some_and(|percent| percent > 100) {
+        return ErrorResponse::new(
+            ErrorCode::InvalidMirror,
+            "the mirror percentage must be between 0 and 100",
+        )
+        .into();
+    }
+    if let Some(deployment) = project.mirror_deployment_id.filter(|id| *id != 0) {
+        let owner = state.db.get_deployment(deployment).await;
+        if !owner.is_some_and(|owner| owner.project == id) {
+            return ErrorResponse::new(
+                ErrorCode::InvalidMirror,
+                "the mirror deployment does not belong to this project",
+            )
+            .with_details(format!("deployment = {deployment}"))
+            .into();
+        }
+    }
+    if let Some(name) = &project.name {
+        let existing = state.db.get_project_id_by_name(name).await;
+        if existing.is_some_and(|existing| existing != id) {
+            return ErrorResponse::new(
+                ErrorCode::ProjectNameConflict,
+                "project name already in use",
+            )
+            .with_details(format!("name = {name}"))
+            .into();
+        }
+    }
+    let hostname_prefix = match project.hostname_prefix.as_deref() {
+        Some("") => None,
+        Some(prefix) => Some(prefix),
+        None => current.hostname_prefix.as_deref(),
+    };
+    if let Some(Err(error)) = hostname_prefix.map(validate_hostname_prefix) {
+        return ErrorResponse::new(ErrorCode::InvalidHostnamePrefix, error.to_string()).into();
+    }
+    let name = project.name.as_deref().unwrap_or(&current.name);
+    let label = hostname_prefix.unwrap_or(name);
+    if let Some(conflict) = find_hostname_conflict(&state, label, Some(id)).await {
+        return conflict.into();
+    }
+    if let Some(domains) = &project.custom_domains {
+        let domains = match normalize_custom_domains(domains, &state.manager.box_domain) {
+            Ok(domains) => domains,
+            Err(message) => return ErrorResponse::new(ErrorCode::InvalidDomain, message).into(),
+        };
+        if let Some(conflict) = find_domain_conflict(&state, &domains, Some(id)).await {
+            return conflict.into();
+        }
+        project.custom_domains = Some(domains);
+    }
+    if let Some(canonical_host) = project.canonical_host {
+        let domains = project
+            .custom_domains
+            .as_ref()
+            .unwrap_or(&current.custom_domains);
+        if let Err(response) = check_canonical_host(canonical_host, domains) {
+            return response;
+        }
+    }
+    if project.upstream_tls.is_some() || project.static_dir.is_some() {
+        let upstream_tls = project.upstream_tls.unwrap_or(current.upstream_tls);
+        let is_static = match &project.static_dir {
+            Some(static_dir) => !static_dir.is_empty(),
+            None => current.static_dir.is_some(),
+        };
+        if let Err(response) = check_upstream_tls(upstream_tls, is_static) {
+            return response;
+        }
+    }
+    if let Some(env_groups) = &project.env_groups {
+        if let Err(response) = check_env_groups(&state, env_groups).await {
+            return response;
+        }
+    }
+    // empty goes back to the detected command, but blank is most likely a mistake
+    if let Some(command) = &project.start_command {
+        if !command.is_empty() && command.trim().is_empty() {
+            return ErrorResponse::new(
+                ErrorCode::InvalidStartCommand,
+                "the start command can not be blank",
+            )
+            .into();
+        }
+    }
+    match state.db.update_project(id, project.0).await {
+        Ok(()) => {}
+        Err(UpdateConflict::Domain(conflict)) => {
+            return domain_conflict(&state, &conflict.domain).await.into()
+        }
+        Err(UpdateConflict::Version { current }) => {
+            return ErrorResponse::new(
+                ErrorCode::VersionConflict,
+                "the project was updated by someone else, fetch it again before updating it",
+            )
+            .with_details(format!("version = {current}"))
+            .into()
+        }
+    }
     state.manager.sync_with_db().await; // TODO: review if its fine not doing a full sync with github here
-    HttpResponse::Ok()
+    HttpResponse::Ok().finish()
+}
+
+/// The canonical host only makes sense with both the apex and the www domain registered
+fn check_canonical_host(
+    canonical_host: CanonicalHost,
+    domains: &[String],
+) -> Result<(), HttpResponse> {
+    if canonical_host != CanonicalHost::Off && canonical_host.redirects(domains).is_empty() {
+        return Err(ErrorResponse::new(
+            ErrorCode::InvalidCanonicalHost,
+            "the canonical host needs both a domain and its www subdomain as custom domains",
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Static sites are served by the proxy itself, there is no app container to connect to
+fn check_upstream_tls(upstream_tls: UpstreamTls, is_static: bool) -> Result<(), HttpResponse> {
+    if upstream_tls.is_enabled() && is_static {
+        return Err(ErrorResponse::new(
+            ErrorCode::InvalidUpstreamTls,
+            "static sites have no app container to connect to over TLS",
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Lowercases the domains and drops the repeated ones. The box hostname
+/// and its subdomains are routed by prezel itself, so they are rejected
+fn normalize_custom_domains(domains: &[String], box_domain: &str) -> Result<Vec<String>, String> {
+    let mut normalized: Vec<String> = vec![];
+    for domain in domains {
+        let domain = domain.trim().trim_end_matches('.').to_lowercase();
+        if domain.is_empty() {
+            return Err("custom domains can not be empty".to_owned());
+        }
+        if domain == box_domain || domain.ends_with(&format!(".{box_domain}")) {
+            return Err(format!("{domain} is already served by this instance"));
+        }
+        if !normalized.contains(&domain) {
+            normalized.push(domain);
+        }
+    }
+    Ok(normalized)
+}
+
+/// Error for the first domain owned by a project other than `project`
+async fn find_domain_conflict(
+    state: &AppState,
+    domains: &[String],
+    project: Option<i64>,
+) -> Option<ErrorResponse> {
+    for domain in domains {
+        let owner = state.db.get_domain_project(domain).await;
+        if owner.is_some_and(|(owner, _)| Some(owner) != project) {
+            return Some(domain_conflict(state, domain).await);
+        }
+    }
+    None
+}
+
+/// Hostnames are routed by their first label, so two projects can not share it
+async fn find_hostname_conflict(
+    state: &AppState,
+    label: &str,
+    project: Option<i64>,
+) -> Option<ErrorResponse> {
+    let owner = state.db.get_project_id_by_hostname_label(label).await;
+    owner.filter(|owner| Some(*owner) != project).map(|_| {
+        ErrorResponse::new(
+            ErrorCode::HostnamePrefixConflict,
+            "hostname prefix already used by another project",
+        )
+        .with_details(format!("prefix = {label}"))
+    })
+}
+
+async fn domain_conflict(state: &AppState, domain: &str) -> ErrorResponse {
+    let owner = state.db.get_domain_project(domain).await;
+    let message = match owner {
+        Some((_, name)) => format!("domain already used by project {name}"),
+        None => "domain already used by another project".to_owned(),
+    };
+    ErrorResponse::new(ErrorCode::DomainConflict, message)
+        .with_details(format!("domain = {domain}"))
+}
+
+#[derive(Deserialize)]
+struct DeleteProjectParams {
+    delete_volume: Option<bool>,
 }
 
 /// Delete project
+///
+/// The project stops being served right away, but it can be restored until it gets purged
+/// after the retention period. The project volume, if any, is kept unless `delete_volume=true`
+/// is passed, in which case it is removed together with all its data when the project is purged.
 #[utoipa::path(
+    params(
+        ("delete_volume" = Option<bool>, Query, description = "Also delete the project persistent volume and all its data when the project is purged")
+    ),
     responses(
         (status = 200, description = "Project deleted successfully"),
+        (status = 404, description = "Project not found", body = ErrorResponse),
     ),
     security(
         ("api_key" = [])
     )
 )]
 #[delete("/apps/{id}", wrap = "RequireApiKey")]
-async fn delete_project(state: Data<AppState>, id: Path<i64>) -> impl Responder {
-    state.db.delete_project(id.into_inner()).await;
+async fn delete_project(
+    state: Data<AppState>,
+    id: Path<i64>,
+    params: Query<DeleteProjectParams>,
+) -> impl Responder {
+    let id = id.into_inner();
+    if state.db.get_project(id).await.is_none() {
+        return ErrorResponse::new(ErrorCode::ProjectNotFound, format!("id = {id}")).into();
+    }
+    let delete_volume = params.delete_volume == Some(true);
+    state.db.soft_delete_project(id, delete_volume).await;
+    state.manager.sync_with_db().await;
+    HttpResponse::Ok().finish()
+}
+
+/// Restore a deleted project
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Project restored successfully"),
+        (status = 404, description = "No deleted project with this id, it might have been purged already", body = ErrorResponse),
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[post("/apps/{id}/restore", wrap = "RequireApiKey")]
+async fn restore_project(state: Data<AppState>, id: Path<i64>) -> impl Responder {
+    let id = id.into_inner();
+    if !state.db.restore_project(id).await {
+        return ErrorResponse::new(ErrorCode::ProjectNotFound, format!("id = {id}")).into();
+    }
     state.manager.sync_with_db().await;
+    HttpResponse::Ok().finish()
+}
+
+#[derive(Deserialize)]
+struct GetProjectEnvParams {
+    mask: Option<bool>,
+}
+
+/// Export project env
+///
+/// Returns the project env in dotenv format, one `NAME=value` line per variable.
+/// Values are replaced with `********` if `mask=true` is passed.
+#[utoipa::path(
+    params(
+        ("mask" = Option<bool>, Query, description = "Hide the values of the variables")
+    ),
+    responses(
+        (status = 200, description = "Project env in dotenv format", body = String, content_type = "text/plain"),
+        (status = 404, description = "Project not found", body = ErrorResponse),
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[get("/apps/{id}/env", wrap = "RequireApiKey")]
+async fn get_project_env(
+    state: Data<AppState>,
+    id: Path<i64>,
+    params: Query<GetProjectEnvParams>,
+) -> impl Responder {
+    let id = id.into_inner();
+    let Some(project) = state.db.get_project(id).await else {
+        return ErrorResponse::new(ErrorCode::ProjectNotFound, format!("id = {id}")).into();
+    };
+    let env = EnvVars::from(project.env.as_str());
     HttpResponse::Ok()
+        .content_type("text/plain; charset=utf-8")
+        .body(env.to_dotenv(params.mask.unwrap_or(false)))
+}
+
+/// Replace project env
+///
+/// Takes the whole env in dotenv format, variables missing from it are removed.
+/// Unless the env is pinned, existing deployments pick up the new env.
+#[utoipa::path(
+    request_body(content = String, content_type = "text/plain"),
+    responses(
+        (status = 200, description = "Project env replaced successfully"),
+        (status = 400, description = "Some line is not a valid assignment", body = ErrorResponse),
+        (status = 404, description = "Project not found", body = ErrorResponse),
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[put("/apps/{id}/env", wrap = "RequireApiKey")]
+async fn set_project_env(state: Data<AppState>, id: Path<i64>, body: String) -> impl Responder {
+    let id = id.into_inner();
+    if state.db.get_project(id).await.is_none() {
+        return ErrorResponse::new(ErrorCode::ProjectNotFound, format!("id = {id}")).into();
+    }
+    let env = match EnvVars::parse_dotenv(&body) {
+        Ok(env) => env,
+        Err(error) => return ErrorResponse::new(ErrorCode::InvalidEnv, error.to_string()).into(),
+    };
+    state.db.set_project_env(id, &env.to_dotenv(false)).await;
+    state.manager.sync_with_db().await;
+    HttpResponse::Ok().finish()
+}
+
+/// Set secret env variable
+///
+/// The value is encrypted before being stored and only decrypted for the containers,
+/// the API never returns it. Requires a secrets key in the server config.
+#[utoipa::path(
+    request_body(content = String, content_type = "text/plain"),
+    responses(
+        (status = 200, description = "Secret set successfully"),
+        (status = 400, description = "Invalid variable name", body = ErrorResponse),
+        (status = 404, description = "Project not found", body = ErrorResponse),
+        (status = 409, description = "No secrets key configured", body = ErrorResponse),
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[put("/apps/{id}/secrets/{name}", wrap = "RequireApiKey")]
+async fn set_project_secret(
+    state: Data<AppState>,
+    path: Path<(i64, String)>,
+    body: String,
+) -> impl Responder {
+    let (id, name) = path.into_inner();
+    let Some(project) = state.db.get_project(id).await else {
+        return ErrorResponse::new(ErrorCode::ProjectNotFound, format!("id = {id}")).into();
+    };
+    if name.is_empty() || name.contains(|char: char| char == '=' || char.is_whitespace()) {
+        return ErrorResponse::new(ErrorCode::InvalidEnv, "invalid variable name")
+            .with_details(format!("name = {name}"))
+            .into();
+    }
+    let encrypted = match state.manager.secrets.encrypt(&body) {
+        Ok(encrypted) => encrypted,
+        Err(error) => {
+            return ErrorResponse::new(ErrorCode::SecretsDisabled, error.to_string()).into()
+        }
+    };
+    let env = set_var(&project.env, &name, &encrypted);
+    state.db.set_project_env(id, &env).await;
+    state.manager.sync_with_db().await;
+    HttpResponse::Ok().finish()
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DomainStatus {
+    /// Every address of the domain belongs to this instance
+    Ok,
+    /// Some address of the domain belongs to another server
+    Misconfigured,
+    /// The domain has no A or AAAA records
+    NotResolving,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct DomainCheck {
+    domain: String,
+    status: DomainStatus,
+    /// Addresses the domain resolves to
+    resolved: Vec<String>,
+    /// Addresses of the instance hostname, the domain should point to them
+    expected: Vec<String>,
+}
+
+/// Check custom domain DNS
+///
+/// Resolves the domain and compares its addresses with the ones of the instance hostname.
+/// Only for diagnostics, routing and certificates don't depend on the result.
+#[utoipa::path(
+    responses(
+        (status = 200, description = "DNS records of the domain checked", body = DomainCheck),
+        (status = 404, description = "Project not found or the domain does not belong to it", body = ErrorResponse),
+        (status = 500, description = "The instance hostname could not be resolved", body = ErrorResponse),
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[get("/apps/{id}/domains/{domain}/check", wrap = "RequireApiKey")]
+async fn check_project_domain(state: Data<AppState>, path: Path<(i64, String)>) -> impl Responder {
+    let (id, domain) = path.into_inner();
+    let Some(project) = state.db.get_project(id).await else {
+        return ErrorResponse::new(ErrorCode::ProjectNotFound, format!("id = {id}")).into();
+    };
+    let domain = domain.trim().trim_end_matches('.').to_lowercase();
+    if !project.custom_domains.contains(&domain) {
+        return ErrorResponse::new(ErrorCode::DomainNotFound, "domain not set on this project")
+            .with_details(format!("domain = {domain}"))
+            .into();
+    }
+    let expected = resolve_domain(&state.manager.box_domain).await;
+    if expected.is_empty() {
+        return ErrorResponse::new(
+            ErrorCode::InternalError,
+            "could not resolve the instance hostname",
+        )
+        .with_details(format!("hostname = {}", state.manager.box_domain))
+        .into();
+    }
+    let resolved = resolve_domain(&domain).await;
+    let status = if resolved.is_empty() {
+        DomainStatus::NotResolving
+    } else if resolved.iter().all(|ip| expected.contains(ip)) {
+        DomainStatus::Ok
+    } else {
+        DomainStatus::Misconfigured
+    };
+    HttpResponse::Ok().json(DomainCheck {
+        domain,
+        status,
+        resolved: resolved.iter().map(IpAddr::to_string).collect(),
+        expected: expected.iter().map(IpAddr::to_string).collect(),
+    })
+}
+
+/// Sorted addresses of the domain, empty if it can not be resolved
+async fn resolve_domain(domain: &str) -> Vec<IpAddr> {
+    let mut ips: Vec<_> = match lookup_host((domain, 443)).await {
+        Ok(addrs) => addrs.map(|addr| addr.ip().to_canonical()).collect(),
+        Err(_) => vec![],
+    };
+    ips.sort();
+    ips.dedup();
+    ips
 }
@@ -1,20 +1,63 @@
+use std::{collections::HashMap, time::Duration};
+
 use actix_web::{
-    delete, get, post,
-    web::{Data, Json, Path},
+    delete, get,
+    http::header,
+    post, put,
+    web::{Data, Json, Path, Query},
     HttpResponse, Responder,
 };
+use flate2::{write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
 use crate::{
-    api::{security::RequireApiKey, utils::clone_deployment, AppState},
-    logging::{read_request_event_logs, Log},
+    analytics::ANALYTICS_RETENTION,
+    api::{
+        security::RequireApiKey,
+        utils::{clone_deployment, get_deployment, redeploy_active_deployments},
+        ApiDeployment, AppState, ErrorCode, ErrorResponse, Status,
+    },
+    conf::Conf,
+    db::BuildResult,
+    deployments::label::{tag_to_label, validate_slug},
+    env::EnvVars,
+    logging::{
+        aggregate_request_metrics, read_request_event_logs, read_request_logs_since, Log,
+        MetricsBucket, MAX_METRICS_BUCKETS, REQUEST_LOG_RETENTION,
+    },
+    proxy::share::{sign_share_token, SHARE_PARAM},
+    runtime::runtime,
+    time::now,
 };
 
+#[derive(Serialize, ToSchema)]
+pub(crate) struct Redeployment {
+    /// The same deployment if it was resumed, a new one otherwise
+    deployment_id: i64,
+    /// Whether the failed build is resumed, reusing the steps that completed,
+    /// instead of rebuilding from scratch
+    resumed: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct CommitDeployments {
+    project_id: i64,
+    project_name: String,
+    /// Newest first
+    deployments: Vec<ApiDeployment>,
+}
+
 // TODO: this should take the id from the PATH, should not be POST I guess
 /// Re-deploy based on an existing deployment
+///
+/// Failed deployments are built again in place if the env of the project didn't change since,
+/// so the build resumes from the step that failed. Otherwise a new deployment is created
 #[utoipa::path(
     request_body = i64,
     responses(
-        (status = 200, description = "Deployment redeployed successfully"),
+        (status = 200, description = "Deployment redeployed successfully", body = Redeployment),
+        (status = 404, description = "Deployment not found", body = ErrorResponse),
     ),
     security(
         ("api_key" = [])
@@ -22,15 +65,68 @@ use crate::{
 )]
 #[post("/deployments/redeploy", wrap = "RequireApiKey")]
 async fn redeploy(deployment: Json<i64>, state: Data<AppState>) -> impl Responder {
-    clone_deployment(&state.db, deployment.0).await;
+    let id = deployment.0;
+    let Some(deployment) = state.db.get_deployment(id).await else {
+        return ErrorResponse::new(ErrorCode::DeploymentNotFound, format!("id = {id}")).into();
+    };
+    // the env goes into the Dockerfile, a new one would invalidate the cached steps anyway
+    let env_unchanged = match state.db.get_project(deployment.project).await {
+        Some(project) => state.manager.secrets.same_env(
+            &deployment.env,
+            &project.resolve_env(deployment.branch.as_deref()),
+        ),
+        None => false,
+    };
+    if deployment.result == Some(BuildResult::Failed)
+        && env_unchanged
+        && state.manager.retry_failed_build(id).await
+    {
+        return HttpResponse::Ok().json(Redeployment {
+            deployment_id: id,
+            resumed: true,
+        });
+    }
+    let Some(new_id) = clone_deployment(&state.db, id).await else {
+        return ErrorResponse::new(ErrorCode::DeploymentNotFound, format!("id = {id}")).into();
+    };
     state.manager.sync_with_db().await;
-    HttpResponse::Ok()
+    HttpResponse::Ok().json(Redeployment {
+        deployment_id: new_id,
+        resumed: false,
+    })
+}
+
+/// Re-deploy all the active deployments of a project
+///
+/// Active deployments are the latest ones for the production branch, every open pull request
+/// and every tag. Deployments already queued or building are skipped, and the new ones go
+/// through the regular build queue.
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Ids of the newly queued deployments", body = [i64]),
+        (status = 404, description = "Project not found", body = ErrorResponse),
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[post("/apps/{id}/redeploy-all", wrap = "RequireApiKey")]
+async fn redeploy_all(state: Data<AppState>, id: Path<i64>) -> impl Responder {
+    let id = id.into_inner();
+    if state.db.get_project(id).await.is_none() {
+        return ErrorResponse::new(ErrorCode::ProjectNotFound, format!("id = {id}")).into();
+    }
+
+    let queued = redeploy_active_deployments(&state, id).await;
+    state.manager.sync_with_db().await;
+    HttpResponse::Ok().json(queued)
 }
 
 /// Delete deployment
 #[utoipa::path(
     responses(
         (status = 200, description = "Deployment deleted successfully"),
+        (status = 404, description = "Deployment not found", body = ErrorResponse),
     ),
     security(
         ("api_key" = [])
@@ -38,9 +134,13 @@ async fn redeploy(deployment: Json<i64>, state: Data<AppState>) -> impl Responde
 )]
 #[delete("/deployments/{id}", wrap = "RequireApiKey")]
 async fn delete_deployment(state: Data<AppState>, id: Path<i64>) -> impl Responder {
-    state.db.delete_deployment(id.into_inner()).await;
+    let id = id.into_inner();
+    if state.db.get_deployment(id).await.is_none() {
+        return ErrorResponse::new(ErrorCode::DeploymentNotFound, format!("id = {id}")).into();
+    }
+    state.db.delete_deployment(id).await;
     state.manager.sync_with_db().await;
-    HttpResponse::Ok()
+    HttpResponse::Ok().finish()
 }
 
 /// Sync deployments with github
@@ -62,8 +162,8 @@ async fn sync(state: Data<AppState>) -> impl Responder {
 #[utoipa::path(
     responses(
         (status = 200, description = "Fetched deployment execution logs", body = [Log]),
-        (status = 404, description = "Deployment not found", body = String),
-        (status = 500, description = "Internal error when fetching logs", body = String)
+        (status = 404, description = "Deployment not found", body = ErrorResponse),
+        (status = 500, description = "Internal error when fetching logs", body = ErrorResponse)
     ),
     security(
         ("api_key" = [])
@@ -74,7 +174,9 @@ async fn get_deployment_logs(state: Data<AppState>, id: Path<i64>) -> impl Respo
     let id = id.into_inner();
     let app_container = match state.manager.get_deployment(id).await {
         Some(deployment) => deployment.app_container.clone(),
-        None => return HttpResponse::NotFound().json("not found"),
+        None => {
+            return ErrorResponse::new(ErrorCode::DeploymentNotFound, format!("id = {id}")).into()
+        }
     };
 
     let container_logs = app_container
@@ -91,8 +193,169 @@ async fn get_deployment_logs(state: Data<AppState>, id: Path<i64>) -> impl Respo
             logs.sort_by_key(|log| -log.time); // from latest to oldest
             HttpResponse::Ok().json(logs)
         }
-        Err(error) => HttpResponse::InternalServerError().json(error.to_string()), // need a ErrorResponse variant for this
+        Err(error) => ErrorResponse::new(ErrorCode::InternalError, "failed to read logs")
+            .with_details(error.to_string())
+            .into(),
+    }
+}
+
+#[derive(Deserialize)]
+struct MetricsParams {
+    window: Option<String>,
+    bucket: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct RequestMetrics {
+    window_secs: u64,
+    bucket_secs: u64,
+    /// From oldest to latest
+    buckets: Vec<MetricsBucket>,
+}
+
+/// Get deployment request metrics
+///
+/// Request counts by status class, bucketed over a recent time window. The window
+/// defaults to `1h` and the bucket size to `1m`, both accept `s`, `m` and `h` units.
+/// Only the last hours of request logs are kept, so longer windows are rejected.
+#[utoipa::path(
+    params(
+        ("window" = Option<String>, Query, description = "How far back to look, e.g. `1h`"),
+        ("bucket" = Option<String>, Query, description = "Size of every bucket, e.g. `1m`")
+    ),
+    responses(
+        (status = 200, description = "Fetched deployment request metrics", body = RequestMetrics),
+        (status = 400, description = "Invalid window or bucket", body = ErrorResponse),
+        (status = 404, description = "Deployment not found", body = ErrorResponse),
+        (status = 500, description = "Internal error when reading the request logs", body = ErrorResponse)
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[get("/deployments/{id}/metrics", wrap = "RequireApiKey")]
+async fn get_deployment_metrics(
+    state: Data<AppState>,
+    id: Path<i64>,
+    params: Query<MetricsParams>,
+) -> impl Responder {
+    let id = id.into_inner();
+    if state.db.get_deployment(id).await.is_none() {
+        return ErrorResponse::new(ErrorCode::DeploymentNotFound, format!("id = {id}")).into();
+    }
+    let (window, bucket) = match parse_metrics_params(&params) {
+        Ok(params) => params,
+        Err(message) => return ErrorResponse::new(ErrorCode::InvalidMetricsWindow, message).into(),
+    };
+
+    let end = now();
+    let since = end - window.as_millis() as i64;
+    match read_request_logs_since(since) {
+        Ok(events) => HttpResponse::Ok().json(RequestMetrics {
+            window_secs: window.as_secs(),
+            bucket_secs: bucket.as_secs(),
+            buckets: aggregate_request_metrics(events, id, end, window, bucket),
+        }),
+        Err(error) => ErrorResponse::new(ErrorCode::InternalError, "failed to read logs")
+            .with_details(error.to_string())
+            .into(),
+    }
+}
+
+fn parse_metrics_params(params: &MetricsParams) -> Result<(Duration, Duration), String> {
+    let parse = |value: &Option<String>, default: Duration| match value {
+        Some(value) => parse_duration(value).ok_or_else(|| format!("invalid duration: {value}")),
+        None => Ok(default),
+    };
+    let window = parse(&params.window, Duration::from_secs(60 * 60))?;
+    let bucket = parse(&params.bucket, Duration::from_secs(60))?;
+    if window > REQUEST_LOG_RETENTION {
+        return Err(format!(
+            "the window can not be longer than {}h",
+            REQUEST_LOG_RETENTION.as_secs() / 3600
+        ));
+    }
+    if bucket > window {
+        return Err("the bucket can not be longer than the window".to_owned());
+    }
+    if window.as_secs() / bucket.as_secs() > MAX_METRICS_BUCKETS {
+        return Err(format!(
+            "the window can not contain more than {MAX_METRICS_BUCKETS} buckets"
+        ));
+    }
+    Ok((window, bucket))
+}
+
+#[derive(Deserialize)]
+struct AnalyticsParams {
+    window: Option<String>,
+}
+
+/// Get deployment analytics
+///
+/// Request totals, unique visitors, status classes and the most requested paths over a recent
+/// window. The window defaults to `24h`, which is also the longest one, and is rounded up to
+/// whole hours. Analytics are kept in memory, so they start over when the server restarts.
+#[utoipa::path(
+    params(
+        ("window" = Option<String>, Query, description = "How far back to look, e.g. `6h`")
+    ),
+    responses(
+        (status = 200, description = "Fetched deployment analytics", body = DeploymentAnalytics),
+        (status = 400, description = "Invalid window", body = ErrorResponse),
+        (status = 404, description = "Deployment not found", body = ErrorResponse),
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[get("/deployments/{id}/analytics", wrap = "RequireApiKey")]
+async fn get_deployment_analytics(
+    state: Data<AppState>,
+    id: Path<i64>,
+    params: Query<AnalyticsParams>,
+) -> impl Responder {
+    let id = id.into_inner();
+    if state.db.get_deployment(id).await.is_none() {
+        return ErrorResponse::new(ErrorCode::DeploymentNotFound, format!("id = {id}")).into();
     }
+    let window = match params.window.as_deref().map(parse_duration) {
+        Some(Some(window)) if window <= ANALYTICS_RETENTION => window,
+        Some(Some(_)) => {
+            return ErrorResponse::new(
+                ErrorCode::InvalidMetricsWindow,
+                format!(
+                    "the window can not be longer than {}h",
+                    ANALYTICS_RETENTION.as_secs() / 3600
+                ),
+            )
+            .into()
+        }
+        Some(None) => {
+            let window = params.window.as_deref().unwrap_or_default();
+            return ErrorResponse::new(
+                ErrorCode::InvalidMetricsWindow,
+                format!("invalid duration: {window}"),
+            )
+            .into();
+        }
+        None => ANALYTICS_RETENTION,
+    };
+    HttpResponse::Ok().json(state.analytics.report(id, window, now()))
+}
+
+/// Parses durations like `30s`, `5m` or `1h`, zero is not valid
+fn parse_duration(value: &str) -> Option<Duration> {
+    let split = value.len().checked_sub(1)?;
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = amount.parse().ok().filter(|amount| *amount > 0)?;
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        _ => return None,
+    };
+    Some(Duration::from_secs(amount.checked_mul(multiplier)?))
 }
 
 /// Get deployment build logs
@@ -118,3 +381,684 @@ async fn get_deployment_build_logs(state: Data<AppState>, id: Path<i64>) -> impl
         .collect();
     HttpResponse::Ok().json(logs)
 }
+
+const MAX_BUILD_LOG_PAGE: i64 = 1000;
+
+#[derive(Deserialize)]
+struct BuildLogTailParams {
+    offset: Option<i64>,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct BuildLogTail {
+    /// From oldest to latest
+    logs: Vec<Log>,
+    /// Offset to pass in the next request to only get the logs after these
+    next_offset: i64,
+    /// Whether more logs are already available after `next_offset`
+    has_more: bool,
+    /// Whether the build is still running, so more logs might show up
+    building: bool,
+}
+
+/// Tail deployment build logs
+///
+/// Returns the build logs after the given offset, starting from the beginning if there is none.
+/// Polling with the returned `next_offset` only fetches the new lines. Every response holds
+/// up to `limit` lines, 1000 at most, `has_more` is set if there are more to fetch right away.
+#[utoipa::path(
+    params(
+        ("offset" = Option<i64>, Query, description = "The `next_offset` of the previous response"),
+        ("limit" = Option<i64>, Query, description = "Max number of lines to return, 1000 by default")
+    ),
+    responses(
+        (status = 200, description = "Fetched deployment build logs", body = BuildLogTail),
+        (status = 404, description = "Deployment not found", body = ErrorResponse),
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[get("/deployments/{id}/build/tail", wrap = "RequireApiKey")]
+async fn tail_deployment_build_logs(
+    state: Data<AppState>,
+    id: Path<i64>,
+    params: Query<BuildLogTailParams>,
+) -> impl Responder {
+    let id = id.into_inner();
+    let Some(deployment) = state.db.get_deployment(id).await else {
+        return ErrorResponse::new(ErrorCode::DeploymentNotFound, format!("id = {id}")).into();
+    };
+    let offset = params.offset.unwrap_or(0);
+    let limit = params
+        .limit
+        .unwrap_or(MAX_BUILD_LOG_PAGE)
+        .clamp(1, MAX_BUILD_LOG_PAGE);
+
+    // one extra line tells if there is more to fetch
+    let mut logs = state
+        .db
+        .get_deployment_build_logs_after(id, offset, limit + 1)
+        .await;
+    let has_more = logs.len() as i64 > limit;
+    logs.truncate(limit as usize);
+    let next_offset = logs.last().map_or(offset, |log| log.id);
+    let building = deployment.build_started.is_some() && deployment.build_finished.is_none();
+    HttpResponse::Ok().json(BuildLogTail {
+        logs: logs.into_iter().map(|log| log.into()).collect(),
+        next_offset,
+        has_more,
+        building,
+    })
+}
+
+/// Get deployment build plan
+///
+/// The providers nixpacks detected for the last build of the deployment and the commands it resolved.
+/// The start command is the one of the project if it sets one, as it replaces the detected one
+/// the next time the container starts
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Fetched deployment build plan", body = BuildPlan),
+        (status = 404, description = "Deployment not found or not built yet", body = ErrorResponse),
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[get("/deployments/{id}/build-plan", wrap = "RequireApiKey")]
+async fn get_deployment_build_plan(state: Data<AppState>, id: Path<i64>) -> impl Responder {
+    let id = id.into_inner();
+    let Some(deployment) = state.db.get_deployment(id).await else {
+        return ErrorResponse::new(ErrorCode::DeploymentNotFound, format!("id = {id}")).into();
+    };
+    let project = state.db.get_project(deployment.project).await;
+    let start_command = project.and_then(|project| project.start_command);
+    match state.db.get_deployment_build_plan(id).await {
+        Some(mut plan) => {
+            if let Some(start_command) = start_command {
+                plan.detected_start_command = plan.start_command.replace(start_command);
+            }
+            HttpResponse::Ok().json(plan)
+        }
+        None => ErrorResponse::new(ErrorCode::BuildPlanNotFound, format!("id = {id}")).into(),
+    }
+}
+
+/// Set deployment feature flags
+///
+/// Flags are env vars applied on top of the project env of a single deployment, replacing
+/// the previous ones. The running container is restarted with them, without rebuilding.
+#[utoipa::path(
+    request_body = HashMap<String, String>,
+    responses(
+        (status = 200, description = "Flags updated successfully"),
+        (status = 400, description = "Some flag name or value is invalid", body = ErrorResponse),
+        (status = 404, description = "Deployment not found", body = ErrorResponse),
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[put("/deployments/{id}/flags", wrap = "RequireApiKey")]
+async fn update_deployment_flags(
+    flags: Json<HashMap<String, String>>,
+    state: Data<AppState>,
+    id: Path<i64>,
+) -> impl Responder {
+    let id = id.into_inner();
+    if state.db.get_deployment(id).await.is_none() {
+        return ErrorResponse::new(ErrorCode::DeploymentNotFound, format!("id = {id}")).into();
+    }
+    // env strings are stored one NAME=value per line, split at the first '='
+    let invalid = flags.iter().find(|(name, value)| {
+        name.is_empty() || name.contains(['=', '\n']) || value.contains('\n')
+    });
+    if let Some((name, _)) = invalid {
+        return ErrorResponse::new(
+            ErrorCode::InvalidFlag,
+            "flag names can't be empty or contain '=', names and values can't contain new lines",
+        )
+        .with_details(format!("flag = {name}"))
+        .into();
+    }
+    let flags: String = EnvVars::from(flags.0).into();
+    state.db.update_deployment_flags(id, &flags).await;
+    state.manager.sync_with_db().await;
+    HttpResponse::Ok().finish()
+}
+
+/// Set deployment labels
+///
+/// Labels are key/value metadata to organize deployments, replacing the previous ones.
+/// They don't affect routing and can be used to filter the deployments of a project.
+#[utoipa::path(
+    request_body = HashMap<String, String>,
+    responses(
+        (status = 200, description = "Labels updated successfully"),
+        (status = 400, description = "Some label key or value is invalid", body = ErrorResponse),
+        (status = 404, description = "Deployment not found", body = ErrorResponse),
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[put("/deployments/{id}/labels", wrap = "RequireApiKey")]
+async fn update_deployment_labels(
+    labels: Json<HashMap<String, String>>,
+    state: Data<AppState>,
+    id: Path<i64>,
+) -> impl Responder {
+    let id = id.into_inner();
+    if state.db.get_deployment(id).await.is_none() {
+        return ErrorResponse::new(ErrorCode::DeploymentNotFound, format!("id = {id}")).into();
+    }
+    // stored like the flags, and filtered with `key=value`
+    let invalid = labels.iter().find(|(key, value)| {
+        key.trim().is_empty() || key.contains(['=', '\n']) || value.contains('\n')
+    });
+    if let Some((key, _)) = invalid {
+        return ErrorResponse::new(
+            ErrorCode::InvalidLabel,
+            "label keys can't be empty or contain '=', keys and values can't contain new lines",
+        )
+        .with_details(format!("label = {key}"))
+        .into();
+    }
+    let labels: String = EnvVars::from(labels.0).into();
+    state.db.update_deployment_labels(id, &labels).await;
+    HttpResponse::Ok().finish()
+}
+
+/// Stop deployment
+///
+/// Removes the container of the deployment right away and keeps it from starting again,
+/// requests get a 503 page until the deployment is started again
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Deployment stopped successfully"),
+        (status = 404, description = "Deployment not found", body = ErrorResponse),
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[post("/deployments/{id}/stop", wrap = "RequireApiKey")]
+async fn stop_deployment(state: Data<AppState>, id: Path<i64>) -> impl Responder {
+    let id = id.into_inner();
+    if state.db.get_deployment(id).await.is_none() {
+        return ErrorResponse::new(ErrorCode::DeploymentNotFound, format!("id = {id}")).into();
+    }
+    state.db.update_deployment_stopped(id, true).await;
+    state.manager.sync_with_db().await;
+    state.manager.stop_deployment(id).await;
+    HttpResponse::Ok().finish()
+}
+
+/// Start deployment
+///
+/// Undoes a stop, the container starts again with the next request
+/// or right away for production deployments
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Deployment started successfully"),
+        (status = 404, description = "Deployment not found", body = ErrorResponse),
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[post("/deployments/{id}/start", wrap = "RequireApiKey")]
+async fn start_deployment(state: Data<AppState>, id: Path<i64>) -> impl Responder {
+    let id = id.into_inner();
+    if state.db.get_deployment(id).await.is_none() {
+        return ErrorResponse::new(ErrorCode::DeploymentNotFound, format!("id = {id}")).into();
+    }
+    state.db.update_deployment_stopped(id, false).await;
+    state.manager.sync_with_db().await;
+    HttpResponse::Ok().finish()
+}
+
+const MAX_WARM_UP_PIN_SECS: u64 = 7 * 24 * 60 * 60;
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct WarmUpDeployment {
+    /// Seconds the container keeps running even without traffic, 7 days at most
+    pin_secs: Option<u64>,
+}
+
+/// Warm up deployment
+///
+/// Starts the container ahead of traffic so the first requests don't hit a cold start,
+/// and responds with the resulting status once it is running.
+/// It goes back to standby once idle for the usual timeout plus the standby grace period,
+/// counting from the end of the pin if there is one
+#[utoipa::path(
+    request_body = WarmUpDeployment,
+    responses(
+        (status = 200, description = "Deployment warmed up successfully", body = Status),
+        (status = 400, description = "Invalid pin duration", body = ErrorResponse),
+        (status = 404, description = "Deployment not found", body = ErrorResponse),
+        (status = 409, description = "The deployment is stopped, not built yet or failed", body = ErrorResponse),
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[post("/deployments/{id}/warmup", wrap = "RequireApiKey")]
+async fn warm_up_deployment(
+    request: Json<WarmUpDeployment>,
+    state: Data<AppState>,
+    id: Path<i64>,
+) -> impl Responder {
+    let id = id.into_inner();
+    if request
+        .pin_secs
+        .is_some_and(|secs| secs > MAX_WARM_UP_PIN_SECS)
+    {
+        return ErrorResponse::new(ErrorCode::InvalidWarmUp, "the pin can't exceed 7 days").into();
+    }
+    let app_container = match state.manager.get_deployment(id).await {
+        Some(deployment) => deployment.app_container.clone(),
+        None => {
+            return ErrorResponse::new(ErrorCode::DeploymentNotFound, format!("id = {id}")).into()
+        }
+    };
+    let status = app_container.get_status().await;
+    if !matches!(status, Status::StandBy | Status::Ready | Status::Restarting) {
+        return ErrorResponse::new(
+            ErrorCode::DeploymentNotStartable,
+            format!(
+                "only built deployments can be warmed up, this one is {}",
+                status.to_string()
+            ),
+        )
+        .with_details(format!("id = {id}"))
+        .into();
+    }
+    let pin = request.pin_secs.map(Duration::from_secs);
+    if let Err(error) = app_container.warm_up(pin).await {
+        return ErrorResponse::new(ErrorCode::InternalError, "failed to start the container")
+            .with_details(error.to_string())
+            .into();
+    }
+    HttpResponse::Ok().json(app_container.get_status().await)
+}
+
+/// Get deployment by url id
+///
+/// Resolves the url id found in a deployment hostname, like `abc123` in `my-app-abc123.example.com`,
+/// back to the deployment
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Fetched deployment successfully", body = ApiDeployment),
+        (status = 404, description = "Deployment not found", body = ErrorResponse),
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[get("/deployments/by-url-id/{url_id}", wrap = "RequireApiKey")]
+async fn get_deployment_by_url_id(state: Data<AppState>, url_id: Path<String>) -> impl Responder {
+    let url_id = url_id.into_inner();
+    let deployment = match state.db.get_deployment_by_url_id(&url_id).await {
+        Some(deployment) => get_deployment(&state, deployment.id).await,
+        None => None,
+    };
+    match deployment {
+        Some(deployment) => HttpResponse::Ok().json(deployment),
+        None => {
+            ErrorResponse::new(ErrorCode::DeploymentNotFound, format!("url_id = {url_id}")).into()
+        }
+    }
+}
+
+/// Get deployments by commit
+///
+/// Groups by project the deployments created from the commit, across every project, e.g. the
+/// ones a push to a monorepo triggered. Abbreviated shas of at least 7 characters are accepted
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Fetched the deployments of the commit", body = [CommitDeployments]),
+        (status = 400, description = "The sha is not a hex string of 7 to 40 characters", body = ErrorResponse),
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[get("/deployments/by-sha/{sha}", wrap = "RequireApiKey")]
+async fn get_deployments_by_sha(state: Data<AppState>, sha: Path<String>) -> impl Responder {
+    let sha = sha.into_inner().to_ascii_lowercase();
+    let is_hex = sha.chars().all(|char| char.is_ascii_hexdigit());
+    if !is_hex || !(7..=40).contains(&sha.len()) {
+        return ErrorResponse::new(
+            ErrorCode::InvalidSha,
+            "shas are hex strings of 7 to 40 characters",
+        )
+        .with_details(format!("sha = {sha}"))
+        .into();
+    }
+    let mut groups: Vec<CommitDeployments> = vec![];
+    for deployment in state.db.get_deployments_by_sha(&sha).await {
+        // the deployment might be deleted in the meantime
+        let Some(api_deployment) = get_deployment(&state, deployment.id).await else {
+            continue;
+        };
+        match groups.last_mut() {
+            Some(group) if group.project_id == deployment.project => {
+                group.deployments.push(api_deployment)
+            }
+            _ => {
+                let Some(project) = state.db.get_project(deployment.project).await else {
+                    continue;
+                };
+                groups.push(CommitDeployments {
+                    project_id: project.id,
+                    project_name: project.name,
+                    deployments: vec![api_deployment],
+                });
+            }
+        }
+    }
+    HttpResponse::Ok().json(groups)
+}
+
+/// Set deployment slug
+///
+/// The slug replaces the url id in the deployment hostname, e.g. `my-app-pr-42.example.com`.
+/// The hostname based on the url id keeps working. Send `null` to remove the slug.
+#[utoipa::path(
+    request_body = Option<String>,
+    responses(
+        (status = 200, description = "Slug updated successfully"),
+        (status = 400, description = "The slug is not a valid DNS label", body = ErrorResponse),
+        (status = 404, description = "Deployment not found", body = ErrorResponse),
+        (status = 409, description = "The slug is already used in the project", body = ErrorResponse),
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[put("/deployments/{id}/slug", wrap = "RequireApiKey")]
+async fn update_deployment_slug(
+    slug: Json<Option<String>>,
+    state: Data<AppState>,
+    id: Path<i64>,
+) -> impl Responder {
+    let id = id.into_inner();
+    let Some(deployment) = state.db.get_deployment(id).await else {
+        return ErrorResponse::new(ErrorCode::DeploymentNotFound, format!("id = {id}")).into();
+    };
+    let Some(project) = state.db.get_project(deployment.project).await else {
+        return ErrorResponse::new(ErrorCode::ProjectNotFound, format!("id = {id}")).into();
+    };
+    if let Some(slug) = slug.as_deref() {
+        if let Err(error) = validate_slug(slug, project.hostname_label()) {
+            return ErrorResponse::new(ErrorCode::InvalidSlug, error.to_string()).into();
+        }
+        // any other way of reaching a deployment of the project counts as taken
+        let taken = state
+            .db
+            .get_deployments()
+            .await
+            .filter(|other| other.project == project.id && other.id != id)
+            .any(|other| {
+                other.slug.as_deref() == Some(slug)
+                    || other.url_id == slug
+                    || other.tag.as_deref().map(tag_to_label).as_deref() == Some(slug)
+            });
+        if taken {
+            return ErrorResponse::new(
+                ErrorCode::SlugConflict,
+                "slug already used by another deployment of the project",
+            )
+            .with_details(format!("slug = {slug}"))
+            .into();
+        }
+    }
+    state.db.update_deployment_slug(id, slug.as_deref()).await;
+    state.manager.sync_with_db().await;
+    HttpResponse::Ok().finish()
+}
+
+/// Get deployment resource usage
+///
+/// Live CPU, memory and network usage of the deployment app container.
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Fetched deployment stats", body = ContainerStats),
+        (status = 404, description = "Deployment not found", body = ErrorResponse),
+        (status = 409, description = "Deployment not running", body = ErrorResponse),
+        (status = 500, description = "Internal error when fetching stats", body = ErrorResponse)
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[get("/deployments/{id}/stats", wrap = "RequireApiKey")]
+async fn get_deployment_stats(state: Data<AppState>, id: Path<i64>) -> impl Responder {
+    let id = id.into_inner();
+    let app_container = match state.manager.get_deployment(id).await {
+        Some(deployment) => deployment.app_container.clone(),
+        None => {
+            return ErrorResponse::new(ErrorCode::DeploymentNotFound, format!("id = {id}")).into()
+        }
+    };
+    let Some(container) = app_container.get_container_id().await else {
+        return ErrorResponse::new(ErrorCode::DeploymentNotRunning, format!("id = {id}")).into();
+    };
+
+    match runtime().get_container_stats(&container).await {
+        Ok(stats) => HttpResponse::Ok().json(stats),
+        Err(error) => ErrorResponse::new(ErrorCode::InternalError, "failed to read stats")
+            .with_details(error.to_string())
+            .into(),
+    }
+}
+
+const DEFAULT_SHARE_SECS: u64 = 24 * 60 * 60;
+const MAX_SHARE_SECS: u64 = 30 * 24 * 60 * 60;
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct CreateShareLink {
+    /// Seconds the link stays valid, one day if not set and 30 days at most
+    expires_in_secs: Option<u64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ShareLink {
+    url: String,
+    /// Expiration of the link in milliseconds since the epoch
+    expires: i64,
+}
+
+/// Create deployment share link
+///
+/// Signed link giving access to a private deployment without logging in, until it expires.
+/// The token goes in the `prezel_share` query param and only works for this deployment.
+/// Changing the instance token invalidates every link.
+#[utoipa::path(
+    request_body = CreateShareLink,
+    responses(
+        (status = 200, description = "Share link created successfully", body = ShareLink),
+        (status = 400, description = "Invalid expiration", body = ErrorResponse),
+        (status = 404, description = "Deployment not found", body = ErrorResponse),
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[post("/deployments/{id}/share", wrap = "RequireApiKey")]
+async fn create_share_link(
+    request: Json<CreateShareLink>,
+    state: Data<AppState>,
+    id: Path<i64>,
+) -> impl Responder {
+    let id = id.into_inner();
+    let secs = request.expires_in_secs.unwrap_or(DEFAULT_SHARE_SECS);
+    if secs == 0 || secs > MAX_SHARE_SECS {
+        return ErrorResponse::new(
+            ErrorCode::InvalidShareExpiration,
+            "the expiration must be between 1 second and 30 days",
+        )
+        .into();
+    }
+    let Some(db_deployment) = state.db.get_deployment(id).await else {
+        return ErrorResponse::new(ErrorCode::DeploymentNotFound, format!("id = {id}")).into();
+    };
+    let Some(project) = state.db.get_project(db_deployment.project).await else {
+        return ErrorResponse::new(ErrorCode::ProjectNotFound, format!("id = {id}")).into();
+    };
+    let hostname = match state.manager.get_deployment(id).await {
+        Some(deployment) => {
+            deployment.get_app_hostname(&state.manager.box_domain, project.hostname_label())
+        }
+        None => {
+            return ErrorResponse::new(ErrorCode::DeploymentNotFound, format!("id = {id}")).into()
+        }
+    };
+
+    let expires = now() + secs as i64 * 1000;
+    let token = sign_share_token(&Conf::read().token, id, expires);
+    HttpResponse::Ok().json(ShareLink {
+        url: format!("https://{hostname}/?{SHARE_PARAM}={token}"),
+        expires,
+    })
+}
+
+/// Metadata stored as `manifest.json` in the log archive, env values are never included
+#[derive(Serialize)]
+struct LogArchiveManifest {
+    deployment: i64,
+    project: i64,
+    project_name: String,
+    sha: String,
+    branch: Option<String>,
+    tag: Option<String>,
+    slug: Option<String>,
+    url_id: String,
+    status: Option<Status>,
+    created: i64,
+    build_started: Option<i64>,
+    build_finished: Option<i64>,
+    env_names: Vec<String>,
+    flag_names: Vec<String>,
+    generated: i64,
+}
+
+/// Download deployment logs
+///
+/// Gzipped tar archive with `manifest.json` describing the deployment, `build.jsonl` with
+/// the build logs and `runtime.jsonl` with the container and request logs still available,
+/// one JSON log per line from oldest to latest. Only the env variable names are included.
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Log archive", content_type = "application/gzip"),
+        (status = 404, description = "Deployment not found", body = ErrorResponse),
+        (status = 500, description = "Internal error when reading the logs", body = ErrorResponse)
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[get("/deployments/{id}/logs/archive", wrap = "RequireApiKey")]
+async fn get_deployment_log_archive(state: Data<AppState>, id: Path<i64>) -> impl Responder {
+    let id = id.into_inner();
+    let Some(deployment) = state.db.get_deployment_with_project(id).await else {
+        return ErrorResponse::new(ErrorCode::DeploymentNotFound, format!("id = {id}")).into();
+    };
+
+    let build_logs: Vec<Log> = state
+        .db
+        .get_deployment_build_logs(id)
+        .await
+        .into_iter()
+        .map(|log| log.into())
+        .collect();
+    let mut runtime_logs = match read_request_event_logs() {
+        Ok(logs) => logs.filter(|log| log.deployment == id).collect::<Vec<_>>(),
+        Err(error) => {
+            return ErrorResponse::new(ErrorCode::InternalError, "failed to read logs")
+                .with_details(error.to_string())
+                .into()
+        }
+    };
+    let mut status = None;
+    if let Some(running) = state.manager.get_deployment(id).await {
+        status = Some(running.app_container.get_status().await);
+        let container_logs = running.app_container.get_logs().await;
+        runtime_logs.extend(container_logs.map(|log| Log::from_docker(log, id)));
+    }
+    runtime_logs.sort_by_key(|log| log.time);
+
+    let mut env_names: Vec<_> = HashMap::from(EnvVars::from(deployment.env.as_str()))
+        .into_keys()
+        .collect();
+    env_names.sort();
+    let mut flag_names: Vec<_> = HashMap::from(EnvVars::from(deployment.flags.as_str()))
+        .into_keys()
+        .collect();
+    flag_names.sort();
+    let manifest = LogArchiveManifest {
+        deployment: id,
+        project: deployment.project.id,
+        project_name: deployment.project.name.clone(),
+        sha: deployment.sha.clone(),
+        branch: deployment.branch.clone(),
+        tag: deployment.tag.clone(),
+        slug: deployment.slug.clone(),
+        url_id: deployment.url_id.clone(),
+        status,
+        created: deployment.created,
+        build_started: deployment.build_started,
+        build_finished: deployment.build_finished,
+        env_names,
+        flag_names,
+        generated: now(),
+    };
+
+    match build_log_archive(&manifest, &build_logs, &runtime_logs) {
+        Ok(archive) => HttpResponse::Ok()
+            .content_type("application/gzip")
+            .insert_header((
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"deployment-{id}-logs.tar.gz\""),
+            ))
+            .body(archive),
+        Err(error) => ErrorResponse::new(ErrorCode::InternalError, "failed to build the archive")
+            .with_details(error.to_string())
+            .into(),
+    }
+}
+
+fn build_log_archive(
+    manifest: &LogArchiveManifest,
+    build_logs: &[Log],
+    runtime_logs: &[Log],
+) -> anyhow::Result<Vec<u8>> {
+    let to_json_lines = |logs: &[Log]| -> anyhow::Result<Vec<u8>> {
+        let mut lines = vec![];
+        for log in logs {
+            serde_json::to_writer(&mut lines, log)?;
+            lines.push(b'\n');
+        }
+        Ok(lines)
+    };
+    let files = [
+        ("manifest.json", serde_json::to_vec_pretty(manifest)?),
+        ("build.jsonl", to_json_lines(build_logs)?),
+        ("runtime.jsonl", to_json_lines(runtime_logs)?),
+    ];
+
+    let mut archive = tar::Builder::new(GzEncoder::new(vec![], Compression::default()));
+    let mtime = (manifest.generated / 1000).max(0) as u64;
+    for (name, content) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+        archive.append_data(&mut header, name, content.as_slice())?;
+    }
+    Ok(archive.into_inner()?.finish()?)
+}
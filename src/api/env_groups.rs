@@ -0,0 +1,238 @@
+use actix_web::{
+    delete, get, post, put,
+    web::{Data, Json, Path},
+    HttpResponse, Responder,
+};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    api::{
+        security::RequireApiKey, utils::redeploy_active_deployments, AppState, ErrorCode,
+        ErrorResponse,
+    },
+    db::EnvGroup,
+    env::EnvVars,
+};
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct EnvGroupInfo {
+    name: String,
+    /// In dotenv format, secret values stay encrypted
+    env: String,
+    created: i64,
+    /// Ids of the projects the group is attached to
+    projects: Vec<i64>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct InsertEnvGroup {
+    /// Lowercase letters, digits and dashes, like `payments-staging`
+    name: String,
+    /// In dotenv format
+    #[serde(default)]
+    env: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct UpdateEnvGroup {
+    /// In dotenv format, variables missing from it are removed from the group
+    env: String,
+    /// Also redeploy the active deployments of the attached projects, so the builds get the new env
+    #[serde(default)]
+    redeploy: bool,
+}
+
+/// Get env groups
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Fetched the env groups", body = [EnvGroupInfo])
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[get("/env-groups", wrap = "RequireApiKey")]
+async fn get_env_groups(state: Data<AppState>) -> impl Responder {
+    let mut groups = vec![];
+    for group in state.db.get_env_groups().await {
+        groups.push(get_env_group_info(&state, group).await);
+    }
+    HttpResponse::Ok().json(groups)
+}
+
+/// Get env group by name
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Fetched the env group", body = EnvGroupInfo),
+        (status = 404, description = "Env group not found", body = ErrorResponse)
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[get("/env-groups/{name}", wrap = "RequireApiKey")]
+async fn get_env_group(state: Data<AppState>, name: Path<String>) -> impl Responder {
+    let name = name.into_inner();
+    match state.db.get_env_group(&name).await {
+        Some(group) => HttpResponse::Ok().json(get_env_group_info(&state, group).await),
+        None => ErrorResponse::new(ErrorCode::EnvGroupNotFound, format!("name = {name}")).into(),
+    }
+}
+
+/// Create env group
+#[utoipa::path(
+    request_body = InsertEnvGroup,
+    responses(
+        (status = 200, description = "Env group created successfully"),
+        (status = 400, description = "The name or some line of the env is invalid", body = ErrorResponse),
+        (status = 409, description = "The name is already in use", body = ErrorResponse)
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[post("/env-groups", wrap = "RequireApiKey")]
+async fn create_env_group(state: Data<AppState>, group: Json<InsertEnvGroup>) -> impl Responder {
+    if let Err(message) = validate_env_group_name(&group.name) {
+        return ErrorResponse::new(ErrorCode::InvalidEnvGroup, message)
+            .with_details(format!("name = {}", group.name))
+            .into();
+    }
+    let env = match EnvVars::parse_dotenv(&group.env) {
+        Ok(env) => env,
+        Err(error) => return ErrorResponse::new(ErrorCode::InvalidEnv, error.to_string()).into(),
+    };
+    if state.db.get_env_group(&group.name).await.is_some() {
+        return ErrorResponse::new(
+            ErrorCode::EnvGroupNameConflict,
+            "env group name already in use",
+        )
+        .with_details(format!("name = {}", group.name))
+        .into();
+    }
+    state
+        .db
+        .insert_env_group(&group.name, &env.to_dotenv(false))
+        .await;
+    HttpResponse::Ok().finish()
+}
+
+/// Replace env group env
+///
+/// Unless their env is pinned, existing deployments of the attached projects pick up the new env.
+/// Returns the ids of the new deployments if `redeploy` is set
+#[utoipa::path(
+    request_body = UpdateEnvGroup,
+    responses(
+        (status = 200, description = "Env group updated successfully", body = [i64]),
+        (status = 400, description = "Some line of the env is not a valid assignment", body = ErrorResponse),
+        (status = 404, description = "Env group not found", body = ErrorResponse)
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[put("/env-groups/{name}", wrap = "RequireApiKey")]
+async fn update_env_group(
+    state: Data<AppState>,
+    name: Path<String>,
+    group: Json<UpdateEnvGroup>,
+) -> impl Responder {
+    let name = name.into_inner();
+    if state.db.get_env_group(&name).await.is_none() {
+        return ErrorResponse::new(ErrorCode::EnvGroupNotFound, format!("name = {name}")).into();
+    }
+    let env = match EnvVars::parse_dotenv(&group.env) {
+        Ok(env) => env,
+        Err(error) => return ErrorResponse::new(ErrorCode::InvalidEnv, error.to_string()).into(),
+    };
+    state
+        .db
+        .set_env_group_env(&name, &env.to_dotenv(false))
+        .await;
+    let mut queued = vec![];
+    if group.redeploy {
+        for project in state.db.get_env_group_projects(&name).await {
+            queued.extend(redeploy_active_deployments(&state, project).await);
+        }
+    }
+    state.manager.sync_with_db().await;
+    HttpResponse::Ok().json(queued)
+}
+
+/// Delete env group
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Env group deleted successfully"),
+        (status = 404, description = "Env group not found", body = ErrorResponse),
+        (status = 409, description = "Some project, maybe a deleted one, still has the group attached", body = ErrorResponse)
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[delete("/env-groups/{name}", wrap = "RequireApiKey")]
+async fn delete_env_group(state: Data<AppState>, name: Path<String>) -> impl Responder {
+    let name = name.into_inner();
+    if state.db.get_env_group(&name).await.is_none() {
+        return ErrorResponse::new(ErrorCode::EnvGroupNotFound, format!("name = {name}")).into();
+    }
+    let projects = state.db.get_env_group_projects(&name).await;
+    if !projects.is_empty() {
+        let projects = projects.iter().map(i64::to_string).collect::<Vec<_>>();
+        return ErrorResponse::new(
+            ErrorCode::EnvGroupInUse,
+            "the env group is still attached to some project",
+        )
+        .with_details(format!("projects = {}", projects.join(",")))
+        .into();
+    }
+    state.db.delete_env_group(&name).await;
+    HttpResponse::Ok().finish()
+}
+
+/// Groups attached to a project need to exist, and attaching one twice is most likely a mistake
+pub(super) async fn check_env_groups(
+    state: &AppState,
+    groups: &[String],
+) -> Result<(), HttpResponse> {
+    for (index, name) in groups.iter().enumerate() {
+        if groups[..index].contains(name) {
+            return Err(
+                ErrorResponse::new(ErrorCode::InvalidEnvGroup, "env group attached twice")
+                    .with_details(format!("name = {name}"))
+                    .into(),
+            );
+        }
+        if state.db.get_env_group(name).await.is_none() {
+            return Err(
+                ErrorResponse::new(ErrorCode::InvalidEnvGroup, "env group not found")
+                    .with_details(format!("name = {name}"))
+                    .into(),
+            );
+        }
+    }
+    Ok(())
+}
+
+fn validate_env_group_name(name: &str) -> Result<(), &'static str> {
+    if name.is_empty() || name.len() > 63 {
+        return Err("env group names must be between 1 and 63 characters long");
+    }
+    let valid = |char: char| char.is_ascii_lowercase() || char.is_ascii_digit() || char == '-';
+    if !name.chars().all(valid) {
+        return Err("env group names can only contain lowercase letters, digits and dashes");
+    }
+    Ok(())
+}
+
+async fn get_env_group_info(state: &AppState, group: EnvGroup) -> EnvGroupInfo {
+    let projects = state.db.get_env_group_projects(&group.name).await;
+    EnvGroupInfo {
+        name: group.name,
+        env: group.env,
+        created: group.created,
+        projects,
+    }
+}
@@ -1,24 +1,59 @@
-use actix_web::web::{Data, ServiceConfig};
+use std::collections::HashMap;
+
+use actix_web::{
+    http::StatusCode,
+    web::{Data, ServiceConfig},
+    HttpResponse,
+};
 use octocrab::models::Repository as CrabRepository;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use utoipa::{OpenApi, ToSchema};
 
 use crate::{
+    analytics::{Analytics, DeploymentAnalytics, PathCount},
+    container::{
+        build_plan::{BuildEnvironment, BuildPlan},
+        replicas::Replicas,
+        sidecar::Sidecar,
+        StartTimings,
+    },
     db::{BuildResult, Db, DeploymentWithProject, InsertProject, UpdateProject},
-    deployments::{deployment::Deployment, manager::Manager},
-    github::Github,
-    logging::{Level, Log},
+    deployments::{
+        deployment::Deployment,
+        manager::{Manager, RouteKind},
+    },
+    env::{EnvRule, EnvType, EnvVars},
+    github::{Github, RateLimit, RateLimits},
+    logging::{Level, Log, LogLevel, MetricsBucket},
+    proxy::{
+        redirect::{CanonicalHost, RedirectRule},
+        rewrite::BodyRewrite,
+        routes::AllowedRoute,
+        upstream_tls::UpstreamTls,
+    },
+    runtime::ContainerStats,
+    secrets::Secrets,
+};
+use apps::{CreatedProject, DomainCheck, DomainStatus};
+use deployments::{
+    BuildLogTail, CommitDeployments, CreateShareLink, Redeployment, RequestMetrics, ShareLink,
+    WarmUpDeployment,
+};
+use env_groups::{EnvGroupInfo, InsertEnvGroup, UpdateEnvGroup};
+use system::{
+    BuildQueue, CanaryRoute, DependencyHealth, GithubSync, HealthReport, LogFilter, ProxyRoute,
+    PrunedImages, QueuedBuildInfo, ReadOnlyMode, SetReadOnlyMode, SyncedDeployment,
 };
 
 mod apps;
 mod deployments;
+mod env_groups;
+mod read_only;
 mod security;
 pub(crate) mod server;
 mod system;
 mod utils;
 
-pub(crate) const API_PORT: u16 = 5045;
-
 // TODO: move this to routes.rs so I don't forget updating them
 #[derive(OpenApi)]
 #[openapi(
@@ -26,18 +61,57 @@ pub(crate) const API_PORT: u16 = 5045;
         system::health,
         system::get_repos,
         system::get_system_logs,
+        system::get_system_log_filter,
+        system::set_system_log_filter,
+        system::get_github_rate_limit,
+        system::get_build_queue,
+        system::prune_images,
+        system::get_routes,
+        system::get_read_only,
+        system::set_read_only,
+        system::shutdown,
+        system::sync_github,
+        system::get_config_schema,
+        system::validate_config,
         apps::get_projects,
         apps::get_project,
         apps::create_project,
+        apps::clone_project,
         apps::update_project,
         apps::delete_project,
+        apps::restore_project,
+        apps::get_project_env,
+        apps::set_project_env,
+        apps::set_project_secret,
+        apps::check_project_domain,
+        env_groups::get_env_groups,
+        env_groups::get_env_group,
+        env_groups::create_env_group,
+        env_groups::update_env_group,
+        env_groups::delete_env_group,
         deployments::redeploy,
+        deployments::redeploy_all,
         deployments::delete_deployment,
         deployments::sync,
         deployments::get_deployment_logs,
-        deployments::get_deployment_build_logs
+        deployments::get_deployment_log_archive,
+        deployments::get_deployment_build_logs,
+        deployments::get_deployment_build_plan,
+        deployments::get_deployment_metrics,
+        deployments::get_deployment_analytics,
+        deployments::get_deployment_stats,
+        deployments::get_deployment_by_url_id,
+        deployments::get_deployments_by_sha,
+        deployments::update_deployment_flags,
+        deployments::update_deployment_labels,
+        deployments::update_deployment_slug,
+        deployments::stop_deployment,
+        deployments::start_deployment,
+        deployments::warm_up_deployment,
+        deployments::tail_deployment_build_logs,
+        deployments::create_share_link
     ),
-    components(schemas(ProjectInfo, FullProjectInfo, ErrorResponse, UpdateProject, Repository, ApiDeployment, Log, Level, Status, InsertProject)),
+    components(schemas(ProjectInfo, FullProjectInfo, ErrorResponse, ErrorCode, UpdateProject, CloneProject, Repository, ApiDeployment, DeploymentUrls, DeploymentUrl, DeploymentTimings, ApiSidecar, Sidecar, BodyRewrite, RedirectRule, AllowedRoute, EnvRule, EnvType, ContainerStats, RateLimits, RateLimit, BuildQueue, QueuedBuildInfo, PrunedImages, ProxyRoute, CanaryRoute, RouteKind, ReadOnlyMode, SetReadOnlyMode, GithubSync, SyncedDeployment, HealthReport, DependencyHealth, RequestMetrics, MetricsBucket, DeploymentAnalytics, PathCount, BuildLogTail, CreateShareLink, ShareLink, WarmUpDeployment, Redeployment, CommitDeployments, CreatedProject, DomainCheck, DomainStatus, EnvGroupInfo, InsertEnvGroup, UpdateEnvGroup, BuildPlan, BuildEnvironment, Replicas, CanonicalHost, UpstreamTls, Log, Level, LogLevel, LogFilter, Status, InsertProject)),
     tags(
         (name = "prezel", description = "Prezel management endpoints.")
     ),
@@ -51,16 +125,55 @@ fn configure_service(store: Data<AppState>) -> impl FnOnce(&mut ServiceConfig) {
             .service(system::health)
             .service(system::get_repos)
             .service(system::get_system_logs)
+            .service(system::get_system_log_filter)
+            .service(system::set_system_log_filter)
+            .service(system::get_github_rate_limit)
+            .service(system::get_build_queue)
+            .service(system::prune_images)
+            .service(system::get_routes)
+            .service(system::get_read_only)
+            .service(system::set_read_only)
+            .service(system::shutdown)
+            .service(system::sync_github)
+            .service(system::get_config_schema)
+            .service(system::validate_config)
             .service(apps::get_projects)
             .service(apps::get_project)
             .service(apps::create_project)
+            .service(apps::clone_project)
             .service(apps::update_project)
             .service(apps::delete_project)
+            .service(apps::restore_project)
+            .service(apps::get_project_env)
+            .service(apps::set_project_env)
+            .service(apps::set_project_secret)
+            .service(apps::check_project_domain)
+            .service(env_groups::get_env_groups)
+            .service(env_groups::get_env_group)
+            .service(env_groups::create_env_group)
+            .service(env_groups::update_env_group)
+            .service(env_groups::delete_env_group)
             .service(deployments::redeploy)
+            .service(deployments::redeploy_all)
             .service(deployments::delete_deployment)
             .service(deployments::sync)
+            .service(deployments::get_deployment_log_archive)
             .service(deployments::get_deployment_logs)
-            .service(deployments::get_deployment_build_logs);
+            .service(deployments::get_deployment_build_logs)
+            .service(deployments::get_deployment_build_plan)
+            .service(deployments::get_deployment_metrics)
+            .service(deployments::get_deployment_analytics)
+            .service(deployments::get_deployment_stats)
+            .service(deployments::get_deployment_by_url_id)
+            .service(deployments::get_deployments_by_sha)
+            .service(deployments::update_deployment_flags)
+            .service(deployments::update_deployment_labels)
+            .service(deployments::update_deployment_slug)
+            .service(deployments::stop_deployment)
+            .service(deployments::start_deployment)
+            .service(deployments::warm_up_deployment)
+            .service(deployments::tail_deployment_build_logs)
+            .service(deployments::create_share_link);
         // If I add anything here also need to add it in api/mod.rs
     }
 }
@@ -71,16 +184,164 @@ pub(crate) struct AppState {
     pub(crate) db: Db,
     pub(crate) manager: Manager,
     pub(crate) github: Github,
+    pub(crate) analytics: Analytics,
 }
 
-#[derive(Serialize, ToSchema)]
-enum ErrorResponse {
-    /// When Todo is not found by search term.
-    NotFound(String),
-    /// When there is a conflict storing a new todo.
-    Conflict(String),
-    /// When todo endpoint was called without correct credentials
-    Unauthorized(String),
+/// Stable identifiers clients can branch on, the message might change between versions
+#[derive(Serialize, ToSchema, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+enum ErrorCode {
+    ProjectNotFound,
+    DeploymentNotFound,
+    BuildPlanNotFound,
+    ProjectNameConflict,
+    DomainConflict,
+    VersionConflict,
+    HostnamePrefixConflict,
+    DomainNotFound,
+    EnvGroupNotFound,
+    EnvGroupNameConflict,
+    EnvGroupInUse,
+    SlugConflict,
+    InvalidProjectName,
+    InvalidDomain,
+    InvalidCidr,
+    InvalidSidecar,
+    InvalidPort,
+    InvalidTimeout,
+    InvalidCanary,
+    InvalidFallback,
+    InvalidMirror,
+    InvalidFlag,
+    InvalidLabel,
+    InvalidBodyRewrite,
+    InvalidConnectionLimit,
+    InvalidRedirect,
+    InvalidRoute,
+    InvalidSchedule,
+    InvalidMetricsWindow,
+    InvalidStaticDir,
+    InvalidSlug,
+    InvalidNetwork,
+    InvalidShareExpiration,
+    InvalidLogFilter,
+    InvalidConfig,
+    InvalidWarmUp,
+    InvalidEnv,
+    InvalidEnvSchema,
+    InvalidBuildEnvironment,
+    InvalidCanonicalHost,
+    InvalidUpstreamTls,
+    InvalidStartCommand,
+    InvalidHostnamePrefix,
+    InvalidEnvGroup,
+    InvalidSha,
+    InvalidGitRef,
+    InvalidReplicas,
+    InvalidVolumePath,
+    DeploymentNotRunning,
+    DeploymentNotStartable,
+    SecretsDisabled,
+    ReadOnly,
+    LocalOnly,
+    RepoUnauthorized,
+    GithubUnavailable,
+    MissingApiKey,
+    InvalidApiKey,
+    InternalError,
+}
+
+impl ErrorCode {
+    fn status(&self) -> StatusCode {
+        match self {
+            Self::ProjectNotFound
+            | Self::DeploymentNotFound
+            | Self::BuildPlanNotFound
+            | Self::DomainNotFound
+            | Self::EnvGroupNotFound => StatusCode::NOT_FOUND,
+            Self::ProjectNameConflict
+            | Self::DomainConflict
+            | Self::VersionConflict
+            | Self::HostnamePrefixConflict
+            | Self::SlugConflict
+            | Self::EnvGroupNameConflict
+            | Self::EnvGroupInUse
+            | Self::DeploymentNotRunning
+            | Self::DeploymentNotStartable
+            | Self::SecretsDisabled => StatusCode::CONFLICT,
+            Self::InvalidProjectName
+            | Self::InvalidDomain
+            | Self::InvalidCidr
+            | Self::InvalidSidecar
+            | Self::InvalidPort
+            | Self::InvalidTimeout
+            | Self::InvalidCanary
+            | Self::InvalidFallback
+            | Self::InvalidMirror
+            | Self::InvalidFlag
+            | Self::InvalidLabel
+            | Self::InvalidBodyRewrite
+            | Self::InvalidConnectionLimit
+            | Self::InvalidRedirect
+            | Self::InvalidRoute
+            | Self::InvalidSchedule
+            | Self::InvalidMetricsWindow
+            | Self::InvalidStaticDir
+            | Self::InvalidSlug
+            | Self::InvalidNetwork
+            | Self::InvalidShareExpiration
+            | Self::InvalidLogFilter
+            | Self::InvalidConfig
+            | Self::InvalidWarmUp
+            | Self::InvalidEnv
+            | Self::InvalidEnvSchema
+            | Self::InvalidBuildEnvironment
+            | Self::InvalidCanonicalHost
+            | Self::InvalidUpstreamTls
+            | Self::InvalidStartCommand
+            | Self::InvalidHostnamePrefix
+            | Self::InvalidEnvGroup
+            | Self::InvalidSha
+            | Self::InvalidGitRef
+            | Self::InvalidReplicas
+            | Self::InvalidVolumePath => StatusCode::BAD_REQUEST,
+            Self::RepoUnauthorized | Self::LocalOnly => StatusCode::FORBIDDEN,
+            Self::MissingApiKey | Self::InvalidApiKey => StatusCode::UNAUTHORIZED,
+            Self::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::GithubUnavailable => StatusCode::BAD_GATEWAY,
+            Self::ReadOnly => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema, Debug)]
+struct ErrorResponse {
+    code: ErrorCode,
+    /// Human readable description of the error
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<String>,
+}
+
+impl ErrorResponse {
+    fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    fn with_details(mut self, details: impl Into<String>) -> Self {
+        self.details = Some(details.into());
+        self
+    }
+}
+
+impl From<ErrorResponse> for HttpResponse {
+    fn from(error: ErrorResponse) -> Self {
+        HttpResponse::build(error.code.status()).json(error)
+    }
 }
 
 // #[derive(Serialize, ToSchema)]
@@ -104,13 +365,16 @@ enum ErrorResponse {
 //     }
 // }
 
-#[derive(Debug, PartialEq, Clone, Copy, ToSchema, Serialize)]
+#[derive(Debug, PartialEq, Clone, Copy, ToSchema, Serialize, Deserialize)]
 pub(crate) enum Status {
     Built,
     StandBy,
     Queued,
     Building,
     Ready,
+    Restarting,
+    /// Stopped through the api, it is not started until it is started again
+    Stopped,
     Failed,
 }
 
@@ -122,12 +386,49 @@ impl ToString for Status {
             Self::Building => "building",
             Self::StandBy => "stand by",
             Self::Ready => "ready",
+            Self::Restarting => "restarting",
+            Self::Stopped => "stopped",
             Self::Failed => "failed",
         };
         string.to_owned()
     }
 }
 
+#[derive(Serialize, ToSchema)]
+#[schema(title = "Sidecar")]
+struct ApiSidecar {
+    name: String,
+    /// docker container id, only present while the deployment is running
+    container: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct DeploymentUrl {
+    url: String,
+    /// Whether the container behind is running, otherwise the first request waits for it to start
+    ready: bool,
+}
+
+impl DeploymentUrl {
+    fn new(hostname: String, status: &Status) -> Self {
+        Self {
+            url: format!("https://{hostname}"),
+            ready: matches!(status, Status::Ready | Status::Restarting),
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+struct DeploymentUrls {
+    /// Main address of the deployment, based on the slug if set or the tag for release deployments
+    app: DeploymentUrl,
+    /// Address based on the url id, the same as `app` unless there is a slug or a tag
+    preview: DeploymentUrl,
+    /// Production address of the project, only set for the production deployment
+    production: Option<DeploymentUrl>,
+    database: DeploymentUrl,
+}
+
 #[derive(Serialize, ToSchema)]
 #[schema(title = "Deployment")]
 struct ApiDeployment {
@@ -136,16 +437,64 @@ struct ApiDeployment {
     // project: Project, // TODO: review why I needed this
     sha: String,
     gitref: String,
+    /// Set for release deployments created from a git tag
+    tag: Option<String>,
+    /// Used in the hostname instead of the url id if set
+    slug: Option<String>,
+    /// Whether the deployment is the one served on the production address of the project
+    is_production: bool,
     // port: u16,
+    /// Only set once the deployment is known to the manager
+    urls: Option<DeploymentUrls>,
+    /// Same as `urls.app.url`, kept for older clients
     url: Option<String>,
+    /// Same as `urls.production.url`, kept for older clients
     target_url: Option<String>,
+    /// Same as `urls.database.url`, kept for older clients
     db_url: Option<String>,
     status: Status,
     app_container: Option<String>,
+    sidecars: Vec<ApiSidecar>,
+    /// Env the deployment was created with, including the profile, values are masked
+    env: HashMap<String, String>,
+    /// Env profile of the project applied on top of the project env, if any
+    env_profile: Option<String>,
+    /// Whether the project env changed since the deployment was created
+    env_outdated: bool,
+    /// Feature flags set as env vars on top of the project env
+    flags: HashMap<String, String>,
+    /// Key/value metadata to organize deployments, doesn't affect routing
+    labels: HashMap<String, String>,
     // execution_logs: Vec<DockerLog>,
     created: i64,
     build_started: Option<i64>,
     build_finished: Option<i64>,
+    /// Size in bytes of the built image, null until the deployment is built
+    image_size: Option<i64>,
+    timings: DeploymentTimings,
+    /// App containers taking requests, more than one if the project scales its deployments
+    replicas: usize,
+}
+
+/// Duration of each phase of the deployment in ms, null until the phase is over
+#[derive(Serialize, ToSchema)]
+struct DeploymentTimings {
+    /// From the deployment creation to the start of the build
+    queued: Option<i64>,
+    building: Option<i64>,
+    /// From the container start to the app being ready, for the last start
+    starting: Option<i64>,
+}
+
+impl DeploymentTimings {
+    fn new(db_deployment: &DeploymentWithProject, start: StartTimings) -> Self {
+        let duration = |from: Option<i64>, to: Option<i64>| Some(to? - from?).filter(|ms| *ms >= 0);
+        Self {
+            queued: duration(Some(db_deployment.created), db_deployment.build_started),
+            building: duration(db_deployment.build_started, db_deployment.build_finished),
+            starting: duration(start.started, start.ready),
+        }
+    }
 }
 
 // TODO: move this somewhere else
@@ -157,34 +506,77 @@ impl ApiDeployment {
         is_prod: bool,
         box_domain: &str,
         github: &Github,
+        secrets: &Secrets,
     ) -> Self {
-        let (status, url, prod_url, db_url, app_container) = if let Some(deployment) = deployment {
-            let status = deployment.app_container.status.read().await.to_status();
+        let mut running_sidecars = vec![];
+        let mut start_timings = StartTimings::default();
+        let mut replicas = 0;
+        let (status, urls, app_container) = if let Some(deployment) = deployment {
+            let status = deployment.app_container.get_status().await;
+            let db_status = deployment.prisma_container.get_status().await;
 
-            let project_name = &db_deployment.project.name;
-            let url = Some(deployment.get_app_hostname(box_domain, project_name)).plus_https();
-            let db_url = Some(deployment.get_db_hostname(box_domain, project_name)).plus_https();
-            let prod_url = is_prod
-                .then_some(deployment.get_prod_hostname(box_domain, project_name))
-                .plus_https();
+            let project_name = db_deployment.project.hostname_label();
+            let urls = DeploymentUrls {
+                app: DeploymentUrl::new(
+                    deployment.get_app_hostname(box_domain, project_name),
+                    &status,
+                ),
+                preview: DeploymentUrl::new(
+                    deployment.get_commit_hostname(box_domain, project_name),
+                    &status,
+                ),
+                production: is_prod.then(|| {
+                    DeploymentUrl::new(
+                        deployment.get_prod_hostname(box_domain, project_name),
+                        &status,
+                    )
+                }),
+                database: DeploymentUrl::new(
+                    deployment.get_db_hostname(box_domain, project_name),
+                    &db_status,
+                ),
+            };
 
             let app_container = deployment.app_container.get_container_id().await;
-            (status, url, prod_url, db_url, app_container)
+            running_sidecars = deployment.app_container.get_running_sidecars().await;
+            start_timings = deployment.app_container.start_timings();
+            replicas = deployment.app_container.get_replica_count().await;
+            (status, Some(urls), app_container)
         } else {
             let status = match db_deployment.result {
+                _ if db_deployment.stopped != 0 => Status::Stopped,
                 Some(BuildResult::Failed) => Status::Failed,
                 Some(BuildResult::Built) => Status::Built,
                 None => Status::Queued,
             };
-            (status, None, None, None, None)
+            (status, None, None)
         };
 
-        let repo_id = db_deployment.project.repo_id.clone();
-        let gitref = match &db_deployment.branch {
-            Some(branch) => branch.clone(),
-            None => github.get_default_branch(&repo_id).await.unwrap(),
+        let gitref = match (&db_deployment.branch, &db_deployment.tag) {
+            (Some(branch), _) => branch.clone(),
+            (None, Some(tag)) => tag.clone(),
+            (None, None) => github
+                .get_prod_branch(&db_deployment.project)
+                .await
+                .unwrap(),
         };
 
+        let sidecars = db_deployment
+            .project
+            .sidecars
+            .iter()
+            .map(|sidecar| ApiSidecar {
+                name: sidecar.name.clone(),
+                container: running_sidecars
+                    .iter()
+                    .find(|running| running.name == sidecar.name)
+                    .map(|running| running.container.clone()),
+            })
+            .collect();
+
+        let project = &db_deployment.project;
+        let branch = db_deployment.branch.as_deref();
+
         // TODO: I should have a nested struct for the container related
         // info so it can be an option as a whole
         Self {
@@ -193,28 +585,33 @@ impl ApiDeployment {
             // project: value.deployment.project.clone(),// TODO: review why I needed this
             sha: db_deployment.sha.clone(),
             gitref,
-            url, // TODO: add method to get the http version from the same object !!!
-            target_url: prod_url,
-            db_url,
+            tag: db_deployment.tag.clone(),
+            slug: db_deployment.slug.clone(),
+            is_production: is_prod,
+            url: urls.as_ref().map(|urls| urls.app.url.clone()),
+            target_url: urls
+                .as_ref()
+                .and_then(|urls| Some(urls.production.as_ref()?.url.clone())),
+            db_url: urls.as_ref().map(|urls| urls.database.url.clone()),
+            urls,
             status,
             app_container,
+            sidecars,
+            env: EnvVars::from(db_deployment.env.as_str()).masked(),
+            env_profile: project.env_profile(branch).map(str::to_owned),
+            env_outdated: !secrets.same_env(&db_deployment.env, &project.resolve_env(branch)),
+            flags: EnvVars::from(db_deployment.flags.as_str()).into(),
+            labels: EnvVars::from(db_deployment.labels.as_str()).into(),
             created: db_deployment.created,
             build_started: db_deployment.build_started,
             build_finished: db_deployment.build_finished,
+            image_size: db_deployment.image_size,
+            timings: DeploymentTimings::new(db_deployment, start_timings),
+            replicas,
         }
     }
 }
 
-trait PlusHttps {
-    fn plus_https(&self) -> Option<String>;
-}
-
-impl PlusHttps for Option<String> {
-    fn plus_https(&self) -> Option<String> {
-        self.as_ref().map(|hostname| format!("https://{hostname}"))
-    }
-}
-
 #[derive(Serialize, ToSchema)]
 struct Repository {
     id: String,
@@ -240,22 +637,115 @@ impl From<CrabRepository> for Repository {
 struct ProjectInfo {
     name: String,
     id: i64,
+    /// Pass it when updating the project so the update fails if someone else changed it since
+    version: i64,
     repo: Repository,
     created: i64,
     env: String,
+    /// Variables holding encrypted secrets, in the env or the profiles. Their values stay encrypted
+    secret_env: Vec<String>,
     custom_domains: Vec<String>,
+    volume_path: Option<String>,
+    ip_allowlist: Vec<String>,
+    ip_denylist: Vec<String>,
+    sidecars: Vec<Sidecar>,
+    container_port: Option<i64>,
+    request_timeout_secs: Option<i64>,
+    canary_deployment_id: Option<i64>,
+    canary_percent: i64,
+    fallback_deployment_id: Option<i64>,
+    mirror_deployment_id: Option<i64>,
+    mirror_percent: i64,
+    mirror_all_methods: bool,
+    poll_interval_secs: Option<i64>,
+    pin_env: bool,
+    body_rewrites: Vec<BodyRewrite>,
+    max_connections: Option<i64>,
+    redirects: Vec<RedirectRule>,
+    route_allowlist: Vec<AllowedRoute>,
+    env_profiles: HashMap<String, String>,
+    env_schema: Vec<EnvRule>,
+    build_environment: BuildEnvironment,
+    canonical_host: CanonicalHost,
+    static_dir: Option<String>,
+    docker_network: Option<String>,
+    dns_servers: Vec<String>,
+    standby_grace_secs: Option<i64>,
+    hostname_prefix: Option<String>,
+    rebuild_schedule: Option<String>,
+    prod_branch: Option<String>,
+    /// Days the build logs are kept, the server default if not set
+    log_retention_days: Option<i64>,
+    upstream_tls: UpstreamTls,
+    /// Command the app container runs instead of the detected one
+    start_command: Option<String>,
+    replicas: Replicas,
+    /// Shared env groups merged under the project env, later groups override earlier ones
+    env_groups: Vec<String>,
+    /// set if the project was deleted and is waiting to be purged
+    deleted: Option<i64>,
     prod_deployment_id: Option<i64>,
     prod_deployment: Option<ApiDeployment>,
 }
 
+#[derive(Deserialize, ToSchema)]
+struct CloneProject {
+    name: String,
+    /// Repository for the new project, the same one as the source if not set
+    repo_id: Option<String>,
+    /// Root directory inside the repository, the same one as the source if not set
+    root: Option<String>,
+}
+
 #[derive(Serialize, ToSchema)]
 struct FullProjectInfo {
     name: String,
     id: i64,
+    /// Pass it when updating the project so the update fails if someone else changed it since
+    version: i64,
     repo: Repository,
     created: i64,
     env: String,
+    /// Variables holding encrypted secrets, in the env or the profiles. Their values stay encrypted
+    secret_env: Vec<String>,
     custom_domains: Vec<String>,
+    volume_path: Option<String>,
+    ip_allowlist: Vec<String>,
+    ip_denylist: Vec<String>,
+    sidecars: Vec<Sidecar>,
+    container_port: Option<i64>,
+    request_timeout_secs: Option<i64>,
+    canary_deployment_id: Option<i64>,
+    canary_percent: i64,
+    fallback_deployment_id: Option<i64>,
+    mirror_deployment_id: Option<i64>,
+    mirror_percent: i64,
+    mirror_all_methods: bool,
+    poll_interval_secs: Option<i64>,
+    pin_env: bool,
+    body_rewrites: Vec<BodyRewrite>,
+    max_connections: Option<i64>,
+    redirects: Vec<RedirectRule>,
+    route_allowlist: Vec<AllowedRoute>,
+    env_profiles: HashMap<String, String>,
+    env_schema: Vec<EnvRule>,
+    build_environment: BuildEnvironment,
+    canonical_host: CanonicalHost,
+    static_dir: Option<String>,
+    docker_network: Option<String>,
+    dns_servers: Vec<String>,
+    standby_grace_secs: Option<i64>,
+    hostname_prefix: Option<String>,
+    rebuild_schedule: Option<String>,
+    prod_branch: Option<String>,
+    /// Days the build logs are kept, the server default if not set
+    log_retention_days: Option<i64>,
+    upstream_tls: UpstreamTls,
+    /// Command the app container runs instead of the detected one
+    start_command: Option<String>,
+    replicas: Replicas,
+    /// Shared env groups merged under the project env, later groups override earlier ones
+    env_groups: Vec<String>,
     prod_deployment_id: Option<i64>,
     prod_deployment: Option<ApiDeployment>,
     /// All project deployments sorted by created datetime descending
@@ -0,0 +1,103 @@
+use std::{
+    future::{self, Ready},
+    rc::Rc,
+};
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::Method,
+    web::Data,
+    HttpResponse,
+};
+use futures::future::LocalBoxFuture;
+
+use crate::conf::Conf;
+
+use super::{security::API_KEY_NAME, AppState, ErrorCode, ErrorResponse};
+
+/// Rejects the requests changing anything while the box is read only. Reads and
+/// the system endpoints, including the one turning the read only mode off, are let through.
+/// So are the requests without the api key, the endpoints answer them with 401 as usual
+pub(super) struct RejectWhenReadOnly;
+
+impl<S> Transform<S, ServiceRequest> for RejectWhenReadOnly
+where
+    S: Service<
+            ServiceRequest,
+            Response = ServiceResponse<actix_web::body::BoxBody>,
+            Error = actix_web::Error,
+        > + 'static,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse<actix_web::body::BoxBody>;
+    type Error = actix_web::Error;
+    type Transform = ReadOnlyMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        let Conf { token, .. } = Conf::read();
+        future::ready(Ok(ReadOnlyMiddleware {
+            service: Rc::new(service),
+            api_key: token,
+        }))
+    }
+}
+
+pub(super) struct ReadOnlyMiddleware<S> {
+    service: Rc<S>,
+    api_key: String,
+}
+
+impl<S> Service<ServiceRequest> for ReadOnlyMiddleware<S>
+where
+    S: Service<
+            ServiceRequest,
+            Response = ServiceResponse<actix_web::body::BoxBody>,
+            Error = actix_web::Error,
+        > + 'static,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse<actix_web::body::BoxBody>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, actix_web::Error>>;
+
+    fn poll_ready(
+        &self,
+        ctx: &mut core::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(ctx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let is_authenticated = req
+            .headers()
+            .get(API_KEY_NAME)
+            .is_some_and(|key| key == &self.api_key);
+        Box::pin(async move {
+            let is_read = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+            if is_read || !is_authenticated || req.path().starts_with("/system/") {
+                return service.call(req).await;
+            }
+            let read_only = match req.app_data::<Data<AppState>>() {
+                Some(state) => state.db.get_read_only().await,
+                None => None,
+            };
+            match read_only {
+                Some(read_only) => {
+                    let mut error = ErrorResponse::new(
+                        ErrorCode::ReadOnly,
+                        "prezel is in read only mode for maintenance, changes are rejected until it is turned off",
+                    );
+                    if let Some(message) = read_only.message {
+                        error = error.with_details(message);
+                    }
+                    let response: HttpResponse = error.into();
+                    Ok(req.into_response(response))
+                }
+                None => service.call(req).await,
+            }
+        })
+    }
+}
@@ -2,18 +2,30 @@ use std::future::{self, Ready};
 
 use actix_web::{
     dev::{Service, ServiceRequest, ServiceResponse, Transform},
-    HttpResponse,
+    http::header,
+    HttpRequest, HttpResponse,
 };
 use futures::future::LocalBoxFuture;
 
 use crate::conf::Conf;
 
-use super::ErrorResponse;
+use super::{ErrorCode, ErrorResponse};
 
 pub(super) const API_KEY_NAME: &str = "X-API-Key";
 
 pub(super) struct RequireApiKey;
 
+/// Requests from outside reach the API through the proxy, which only forwards the API hostname
+pub(super) fn is_local_request(req: &HttpRequest, conf: &Conf) -> bool {
+    let host = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or_default();
+    let hostname = host.split(':').next().unwrap_or_default();
+    !hostname.eq_ignore_ascii_case(&conf.api_hostname())
+}
+
 impl<S> Transform<S, ServiceRequest> for RequireApiKey
 where
     S: Service<
@@ -73,16 +85,13 @@ where
                 // TODO: avoid these early returns, make the Box::ping generic at the bottom
                 return response(
                     req,
-                    HttpResponse::Unauthorized().json(ErrorResponse::Unauthorized(String::from(
-                        "incorrect api key",
-                    ))),
+                    ErrorResponse::new(ErrorCode::InvalidApiKey, "incorrect api key").into(),
                 );
             }
             None => {
                 return response(
                     req,
-                    HttpResponse::Unauthorized()
-                        .json(ErrorResponse::Unauthorized(String::from("missing api key"))),
+                    ErrorResponse::new(ErrorCode::MissingApiKey, "missing api key").into(),
                 );
             }
             _ => (), // just passthrough
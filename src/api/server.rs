@@ -1,4 +1,4 @@
-use std::{error::Error, net::Ipv4Addr};
+use std::{error::Error, fs, net::Ipv4Addr};
 
 use actix_cors::Cors;
 use actix_web::{middleware::Logger, web::Data, App, HttpServer};
@@ -13,7 +13,9 @@ use utoipa::{
 use utoipa_rapidoc::RapiDoc;
 
 use crate::{
-    api::{configure_service, security::API_KEY_NAME, AppState, API_PORT},
+    analytics::Analytics,
+    api::{configure_service, read_only::RejectWhenReadOnly, security::API_KEY_NAME, AppState},
+    conf::ApiConf,
     db::Db,
     deployments::manager::Manager,
     github::Github,
@@ -34,32 +36,40 @@ pub(crate) async fn run_api_server(
     manager: Manager,
     db: Db,
     github: Github,
+    analytics: Analytics,
     api_hostname: &str,
     coordinator_hostname: String,
+    api_conf: ApiConf,
 ) -> Result<(), impl Error> {
     let state = AppState {
         db,
         manager: manager.clone(),
         github,
+        analytics,
     };
 
     let base_url = format!("https://{api_hostname}");
-    let localhost = "http://127.0.0.1:5045";
+    let localhost = format!("http://127.0.0.1:{}", api_conf.port);
 
     let mut openapi = get_open_api();
-    openapi.servers = Some(vec![Server::new(&base_url), Server::new(localhost)]);
+    openapi.servers = Some(if api_conf.socket.is_some() {
+        vec![Server::new(&base_url)]
+    } else {
+        vec![Server::new(&base_url), Server::new(&localhost)]
+    });
 
     info!("Prezel API service listening at {base_url}");
     info!("Docs available at {base_url}/docs");
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         let cors = Cors::default()
             .allowed_origin(&coordinator_hostname)
-            .allowed_origin(localhost)
+            .allowed_origin(&localhost)
             .allow_any_method()
             .allow_any_header()
             .max_age(3600);
         // This factory closure is called on each worker thread independently.
         App::new()
+            .wrap(RejectWhenReadOnly)
             .wrap(Logger::default())
             .wrap(cors)
             .configure(configure_service(Data::new(state.clone())))
@@ -67,7 +77,17 @@ pub(crate) async fn run_api_server(
             .service(RapiDoc::with_openapi("/openapi.json", openapi.clone()).path("/docs"))
     })
     .workers(1)
-    .bind((Ipv4Addr::LOCALHOST, API_PORT))?
-    .run()
-    .await
+    // the proxy handles the signals, draining its connections before exiting the process
+    .disable_signals();
+    let server = match &api_conf.socket {
+        Some(socket) => {
+            // left behind if the previous process didn't shut down cleanly
+            if socket.exists() {
+                fs::remove_file(socket)?;
+            }
+            server.bind_uds(socket)?
+        }
+        None => server.bind((Ipv4Addr::LOCALHOST, api_conf.port))?,
+    };
+    server.run().await
 }
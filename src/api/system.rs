@@ -1,23 +1,352 @@
-use actix_web::{get, web::Data, HttpResponse, Responder};
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    get, post, put,
+    web::{Data, Json, Query},
+    HttpRequest, HttpResponse, Responder,
+};
+use log::info;
+use nix::{
+    sys::signal::{kill, Signal},
+    unistd::Pid,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{join, time::timeout};
+use utoipa::ToSchema;
 
 use crate::{
-    api::{security::RequireApiKey, AppState, Repository},
+    api::{
+        security::{is_local_request, RequireApiKey},
+        AppState, ErrorCode, ErrorResponse, Repository, Status,
+    },
+    conf::Conf,
+    container::repo_config::{RepoConfig, REPO_CONFIG_FILE},
+    deployments::manager::RouteKind,
     docker::get_container_execution_logs,
+    logging::{filter_system_logs, get_log_filter, set_log_filter, LogLevel},
+    runtime::runtime,
+    time::now,
 };
 
+/// for each dependency of the deep health check
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+/// the build worker builds one container at a time
+const BUILD_SLOTS: usize = 1;
+/// number of recent builds the wait estimation is based on
+const AVERAGE_BUILD_SAMPLE: i64 = 20;
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct BuildQueue {
+    slots: usize,
+    /// Average duration of the recent builds
+    average_build_secs: Option<i64>,
+    building: Vec<QueuedBuildInfo>,
+    queued: Vec<QueuedBuildInfo>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct QueuedBuildInfo {
+    deployment_id: i64,
+    project_id: i64,
+    /// Whether this is the build of the deployment database container instead of the app
+    db: bool,
+    /// Requested through the deployment URL, these are built first
+    prioritized: bool,
+    queued_at: i64,
+    build_started: Option<i64>,
+    /// Position in the queue, starting at 1, for queued builds
+    position: Option<usize>,
+    /// Build slot in use, starting at 1, for running builds
+    slot: Option<usize>,
+    /// Rough estimation based on the average build duration
+    estimated_wait_secs: Option<i64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct PrunedImages {
+    /// Ids of the removed images
+    images: Vec<String>,
+    reclaimed_bytes: u64,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ProxyRoute {
+    hostname: String,
+    kind: RouteKind,
+    project_id: Option<i64>,
+    /// Deployment the hostname resolves to, the production one for production and custom domain hostnames
+    deployment_id: Option<i64>,
+    canary: Option<CanaryRoute>,
+    status: Option<Status>,
+    /// Address the requests are forwarded to, missing while the container is not running
+    upstream: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct CanaryRoute {
+    deployment_id: i64,
+    /// Percentage of the traffic going to the canary instead of the deployment
+    percent: u8,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ReadOnlyMode {
+    enabled: bool,
+    message: Option<String>,
+    /// Whether new deployments are paused as well
+    pause_deployments: bool,
+    /// When the read only mode was turned on, in milliseconds since the epoch
+    since: Option<i64>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct SetReadOnlyMode {
+    enabled: bool,
+    /// Returned along with the rejected requests, like the expected end of the maintenance
+    message: Option<String>,
+    /// Also stop creating deployments for new commits, tags, schedules and base image updates,
+    /// true by default
+    pause_deployments: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct HealthParams {
+    deep: Option<bool>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct HealthReport {
+    /// `Healthy`, or `Degraded` if some dependency failed its check
+    status: &'static str,
+    read_only: bool,
+    docker: DependencyHealth,
+    database: DependencyHealth,
+    github: DependencyHealth,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct DependencyHealth {
+    healthy: bool,
+    /// Why the check failed
+    error: Option<String>,
+    duration_ms: u128,
+}
+
+impl DependencyHealth {
+    async fn check(check: impl Future<Output = anyhow::Result<()>>) -> Self {
+        let start = Instant::now();
+        let error = match timeout(HEALTH_CHECK_TIMEOUT, check).await {
+            Ok(Ok(())) => None,
+            Ok(Err(error)) => Some(format!("{error:#}")),
+            Err(_) => Some(format!(
+                "no answer after {}s",
+                HEALTH_CHECK_TIMEOUT.as_secs()
+            )),
+        };
+        Self {
+            healthy: error.is_none(),
+            error,
+            duration_ms: start.elapsed().as_millis(),
+        }
+    }
+}
+
 /// Hello world
+///
+/// Returns `ReadOnly` instead of `Healthy` while the box is in read only mode.
+/// With `deep=true` it also checks the container runtime, the database and the Github app,
+/// answering with a 503 and the failing checks if any of them is down
 #[utoipa::path(
+    params(
+        ("deep" = Option<bool>, Query, description = "Also check the dependencies, each one with a short timeout")
+    ),
     responses(
-        (status = 200, description = "Said hi to the world", body = &str)
+        (status = 200, description = "Said hi to the world, or a `HealthReport` for deep checks", body = &str),
+        (status = 503, description = "Some dependency failed its check", body = HealthReport)
     )
 )]
 #[get("/health")]
-async fn health() -> impl Responder {
-    HttpResponse::Ok().json("Healthy")
+async fn health(state: Data<AppState>, params: Query<HealthParams>) -> impl Responder {
+    let read_only = state.db.get_read_only().await.is_some();
+    if params.deep != Some(true) {
+        return match read_only {
+            true => HttpResponse::Ok().json("ReadOnly"),
+            false => HttpResponse::Ok().json("Healthy"),
+        };
+    }
+    let (docker, database, github) = join!(
+        DependencyHealth::check(runtime().ping()),
+        DependencyHealth::check(state.db.check_writable()),
+        DependencyHealth::check(state.github.check_auth()),
+    );
+    let healthy = docker.healthy && database.healthy && github.healthy;
+    let report = HealthReport {
+        status: if healthy { "Healthy" } else { "Degraded" },
+        read_only,
+        docker,
+        database,
+        github,
+    };
+    if healthy {
+        HttpResponse::Ok().json(report)
+    } else {
+        HttpResponse::ServiceUnavailable().json(report)
+    }
+}
+
+/// Get the read only mode
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Fetched the read only mode", body = ReadOnlyMode)
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[get("/system/read-only", wrap = "RequireApiKey")]
+async fn get_read_only(state: Data<AppState>) -> impl Responder {
+    let mode = match state.db.get_read_only().await {
+        Some(read_only) => ReadOnlyMode {
+            enabled: true,
+            message: read_only.message,
+            pause_deployments: read_only.pause_deployments,
+            since: Some(read_only.since),
+        },
+        None => ReadOnlyMode {
+            enabled: false,
+            message: None,
+            pause_deployments: false,
+            since: None,
+        },
+    };
+    HttpResponse::Ok().json(mode)
+}
+
+/// Set the read only mode
+///
+/// While the box is read only, the requests creating, updating or deleting anything are rejected
+/// with a 503, except for the system endpoints. Reads keep working and the deployments keep
+/// being served. The mode is kept across restarts until it is turned off
+#[utoipa::path(
+    request_body = SetReadOnlyMode,
+    responses(
+        (status = 200, description = "Read only mode updated successfully")
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[put("/system/read-only", wrap = "RequireApiKey")]
+async fn set_read_only(state: Data<AppState>, mode: Json<SetReadOnlyMode>) -> impl Responder {
+    if mode.enabled {
+        let message = mode
+            .message
+            .as_deref()
+            .filter(|message| !message.is_empty());
+        let pause_deployments = mode.pause_deployments.unwrap_or(true);
+        state.db.set_read_only(message, pause_deployments).await;
+    } else {
+        state.db.clear_read_only().await;
+    }
+    HttpResponse::Ok().finish()
+}
+
+/// Shut down the box gracefully
+///
+/// The proxy stops accepting connections and keeps serving the open ones for
+/// `drain_timeout_secs`, then the process exits. Same as sending SIGTERM to the process.
+/// The API keeps answering until then.
+/// App containers and their replicas are not stopped, nothing references them after a
+/// restart, so the docker garbage collector removes them once the box is back up
+#[utoipa::path(
+    responses(
+        (status = 202, description = "Graceful shutdown started"),
+        (status = 500, description = "The process could not be signaled", body = ErrorResponse)
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[post("/system/shutdown", wrap = "RequireApiKey")]
+async fn shutdown() -> HttpResponse {
+    info!("Graceful shutdown requested through the API");
+    if let Err(error) = kill(Pid::this(), Signal::SIGTERM) {
+        return ErrorResponse::new(ErrorCode::InternalError, "failed to signal the process")
+            .with_details(error.to_string())
+            .into();
+    }
+    HttpResponse::Accepted().finish()
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct GithubSync {
+    /// Deployments are paused so Github was not polled
+    paused: bool,
+    /// Deployments created by this sweep for new commits, pull requests and tags
+    deployments: Vec<SyncedDeployment>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct SyncedDeployment {
+    id: i64,
+    project_id: i64,
+    sha: String,
+    branch: Option<String>,
+    tag: Option<String>,
+}
+
+/// Sweep Github right away
+///
+/// Polls every project regardless of its poll interval. Waits for any sweep already in
+/// progress instead of running alongside it. Does nothing while deployments are paused
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Github swept successfully", body = GithubSync)
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[post("/system/sync-github", wrap = "RequireApiKey")]
+async fn sync_github(state: Data<AppState>) -> impl Responder {
+    let paused = state.db.are_deployments_paused().await;
+    let deployments = state
+        .manager
+        .full_sync_with_github()
+        .await
+        .into_iter()
+        .map(|deployment| SyncedDeployment {
+            id: deployment.id,
+            project_id: deployment.project,
+            sha: deployment.sha,
+            branch: deployment.branch,
+            tag: deployment.tag,
+        })
+        .collect();
+    HttpResponse::Ok().json(GithubSync {
+        paused,
+        deployments,
+    })
+}
+
+#[derive(Deserialize)]
+struct SystemLogsParams {
+    min_level: Option<LogLevel>,
+    since: Option<i64>,
 }
 
 /// Get system logs
+///
+/// Lines with no level, like the continuation lines of an event, are filtered
+/// by the level of the line before them
 #[utoipa::path(
+    params(
+        ("min_level" = Option<LogLevel>, Query, description = "Only return lines at this level or above"),
+        ("since" = Option<i64>, Query, description = "Only return lines logged after this time, in milliseconds since the epoch")
+    ),
     responses(
         (status = 200, description = "Fetched system logs", body = [Log])
     ),
@@ -26,9 +355,94 @@ async fn health() -> impl Responder {
     )
 )]
 #[get("/system/logs", wrap = "RequireApiKey")]
-async fn get_system_logs() -> impl Responder {
+async fn get_system_logs(params: Query<SystemLogsParams>) -> impl Responder {
     let logs = get_container_execution_logs("prezel").await;
-    HttpResponse::Ok().json(logs.collect::<Vec<_>>())
+    HttpResponse::Ok().json(filter_system_logs(logs, params.min_level, params.since))
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub(crate) struct LogFilter {
+    /// Same syntax as `RUST_LOG`, like `debug` or `info,prezel::proxy=trace`
+    filter: String,
+}
+
+/// Get system log filter
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Fetched the current log filter", body = LogFilter)
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[get("/system/log-filter", wrap = "RequireApiKey")]
+async fn get_system_log_filter() -> impl Responder {
+    match get_log_filter() {
+        Some(filter) => HttpResponse::Ok().json(LogFilter { filter }),
+        None => ErrorResponse::new(ErrorCode::InternalError, "the log filter is not set").into(),
+    }
+}
+
+/// Set system log filter
+///
+/// Changes the verbosity of the server logs right away, without a restart.
+/// The change is lost when the server restarts
+#[utoipa::path(
+    request_body = LogFilter,
+    responses(
+        (status = 200, description = "Log filter updated successfully"),
+        (status = 400, description = "Invalid filter", body = ErrorResponse)
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[put("/system/log-filter", wrap = "RequireApiKey")]
+async fn set_system_log_filter(filter: Json<LogFilter>) -> impl Responder {
+    match set_log_filter(&filter.filter) {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(error) => ErrorResponse::new(ErrorCode::InvalidLogFilter, format!("{error:#}")).into(),
+    }
+}
+
+/// Get config file schema
+///
+/// JSON schema of the `prezel.json` file in the repositories, for editors and CI to validate it.
+/// It is public, as editors fetch it without credentials
+#[utoipa::path(
+    responses(
+        (status = 200, description = "JSON schema of the config file", body = Object)
+    )
+)]
+#[get("/system/config-schema")]
+async fn get_config_schema() -> impl Responder {
+    HttpResponse::Ok().json(RepoConfig::json_schema())
+}
+
+/// Validate config file
+///
+/// Takes the content of a `prezel.json` file and parses it the same way the builds do
+#[utoipa::path(
+    request_body(content = String, content_type = "application/json"),
+    responses(
+        (status = 200, description = "The config is valid"),
+        (status = 400, description = "The config is invalid", body = ErrorResponse)
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[post("/system/config/validate", wrap = "RequireApiKey")]
+async fn validate_config(body: String) -> impl Responder {
+    match RepoConfig::parse(&body) {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(error) => ErrorResponse::new(
+            ErrorCode::InvalidConfig,
+            format!("invalid {REPO_CONFIG_FILE}"),
+        )
+        .with_details(error.to_string())
+        .into(),
+    }
 }
 
 /// Get repositories
@@ -49,3 +463,169 @@ async fn get_repos(state: Data<AppState>) -> impl Responder {
         .collect::<Vec<Repository>>();
     HttpResponse::Ok().json(repos)
 }
+
+/// Get the remaining Github API rate limits
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Fetched Github rate limits", body = RateLimits),
+        (status = 500, description = "Failed to read the limits from Github", body = ErrorResponse)
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[get("/system/github-rate-limit", wrap = "RequireApiKey")]
+async fn get_github_rate_limit(state: Data<AppState>) -> impl Responder {
+    match state.github.get_rate_limits().await {
+        Ok(limits) => HttpResponse::Ok().json(limits),
+        Err(error) => ErrorResponse::new(ErrorCode::InternalError, "failed to read rate limits")
+            .with_details(error.to_string())
+            .into(),
+    }
+}
+
+/// Get the build queue
+///
+/// Deployments are built one at a time. Queued deployments that were requested through
+/// their URL are built first, the order of the rest is not guaranteed.
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Fetched the build queue", body = BuildQueue)
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[get("/system/queue", wrap = "RequireApiKey")]
+async fn get_build_queue(state: Data<AppState>) -> impl Responder {
+    let average = state
+        .db
+        .get_average_build_duration(AVERAGE_BUILD_SAMPLE)
+        .await;
+    let mut queue = BuildQueue {
+        slots: BUILD_SLOTS,
+        average_build_secs: average.map(|millis| millis / 1000),
+        building: vec![],
+        queued: vec![],
+    };
+    // time until the slot is free, only known if there are previous builds to go by
+    let mut wait = average.map(|_| 0);
+    for build in state.manager.get_build_queue().await {
+        let build_started = if build.building {
+            let deployment = state.db.get_deployment(build.deployment).await;
+            deployment.and_then(|deployment| deployment.build_started)
+        } else {
+            None
+        };
+        let entry = QueuedBuildInfo {
+            deployment_id: build.deployment,
+            project_id: build.project,
+            db: build.db,
+            prioritized: build.trigger_access.is_some(),
+            queued_at: build.created,
+            build_started,
+            position: None,
+            slot: None,
+            estimated_wait_secs: None,
+        };
+        if build.building {
+            let elapsed = build_started.map_or(0, |started| now() - started);
+            wait = wait
+                .zip(average)
+                .map(|(wait, average)| wait + (average - elapsed).max(0));
+            queue.building.push(QueuedBuildInfo {
+                slot: Some(queue.building.len() + 1),
+                ..entry
+            });
+        } else {
+            queue.queued.push(QueuedBuildInfo {
+                position: Some(queue.queued.len() + 1),
+                estimated_wait_secs: wait.map(|wait| wait / 1000),
+                ..entry
+            });
+            wait = wait.zip(average).map(|(wait, average)| wait + average);
+        }
+    }
+    HttpResponse::Ok().json(queue)
+}
+
+/// Get the routing table of the proxy
+///
+/// Lists the hostnames the proxy currently routes and the deployment each of them resolves to.
+/// Only available from the server itself, not through the public API hostname
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Fetched the routes", body = [ProxyRoute]),
+        (status = 403, description = "Requested through the proxy", body = ErrorResponse)
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[get("/system/routes", wrap = "RequireApiKey")]
+async fn get_routes(state: Data<AppState>, req: HttpRequest) -> impl Responder {
+    let conf = Conf::read();
+    if !is_local_request(&req, &conf) {
+        return ErrorResponse::new(
+            ErrorCode::LocalOnly,
+            "the routes are only available from the server itself",
+        )
+        .into();
+    }
+    let api_upstream = match &conf.api.socket {
+        Some(socket) => socket.display().to_string(),
+        None => format!("127.0.0.1:{}", conf.api.port),
+    };
+    let mut routes = vec![ProxyRoute {
+        hostname: conf.api_hostname(),
+        kind: RouteKind::Api,
+        project_id: None,
+        deployment_id: None,
+        canary: None,
+        status: None,
+        upstream: Some(api_upstream),
+    }];
+    for route in state.manager.get_routes().await {
+        routes.push(ProxyRoute {
+            hostname: route.hostname,
+            kind: route.kind,
+            project_id: Some(route.project),
+            deployment_id: Some(route.deployment),
+            canary: route.canary.map(|(deployment_id, percent)| CanaryRoute {
+                deployment_id,
+                percent,
+            }),
+            status: Some(route.status),
+            upstream: route.socket.map(|socket| socket.to_string()),
+        });
+    }
+    routes.sort_by(|a, b| a.hostname.cmp(&b.hostname));
+    HttpResponse::Ok().json(routes)
+}
+
+/// Prune unused images
+///
+/// Removes dangling images and the images of deployments that no longer exist.
+/// Images of existing deployments are never removed, even if they are not running,
+/// and neither are images created in the last hour, as they might belong to a running build.
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Pruned the unused images", body = PrunedImages),
+        (status = 500, description = "Failed to list the images", body = ErrorResponse)
+    ),
+    security(
+        ("api_key" = [])
+    )
+)]
+#[post("/system/prune", wrap = "RequireApiKey")]
+async fn prune_images(state: Data<AppState>) -> impl Responder {
+    match state.manager.prune_images().await {
+        Ok(pruned) => HttpResponse::Ok().json(PrunedImages {
+            images: pruned.images,
+            reclaimed_bytes: pruned.reclaimed_bytes,
+        }),
+        Err(error) => ErrorResponse::new(ErrorCode::InternalError, "failed to prune images")
+            .with_details(error.to_string())
+            .into(),
+    }
+}
@@ -1,4 +1,7 @@
+use std::collections::HashSet;
+
 use futures::{stream, StreamExt};
+use log::error;
 
 use crate::db::{Db, InsertDeployment, Project};
 
@@ -16,6 +19,7 @@ pub(super) async fn get_prod_deployment(
         db,
         manager,
         github,
+        ..
     }: &AppState,
     project: i64,
 ) -> Option<ApiDeployment> {
@@ -30,6 +34,38 @@ pub(super) async fn get_prod_deployment(
             is_prod,
             box_domain,
             github,
+            &manager.secrets,
+        )
+        .await,
+    )
+}
+
+pub(super) async fn get_deployment(
+    AppState {
+        db,
+        manager,
+        github,
+        ..
+    }: &AppState,
+    id: i64,
+) -> Option<ApiDeployment> {
+    let db_deployment = db.get_deployment_with_project(id).await?;
+    let deployment = manager.get_deployment(id).await;
+    let is_prod = match deployment.as_deref() {
+        Some(deployment) => {
+            let prod_url_id = manager.get_prod_url_id(deployment.project).await;
+            Some(&deployment.url_id) == prod_url_id.as_ref()
+        }
+        None => false,
+    };
+    Some(
+        ApiDeployment::from(
+            deployment.as_deref(),
+            &db_deployment,
+            is_prod,
+            &manager.box_domain,
+            github,
+            &manager.secrets,
         )
         .await,
     )
@@ -40,6 +76,7 @@ pub(super) async fn get_all_deployments(
         db,
         manager,
         github,
+        ..
     }: &AppState,
     project: i64,
 ) -> Vec<ApiDeployment> {
@@ -62,6 +99,7 @@ pub(super) async fn get_all_deployments(
                     is_prod,
                     box_domain,
                     github,
+                    &manager.secrets,
                 )
                 .await
             })
@@ -71,17 +109,58 @@ pub(super) async fn get_all_deployments(
     deployments
 }
 
-pub(crate) async fn clone_deployment(db: &Db, deployment_id: i64) -> Option<()> {
+/// Clones the active deployments of the project not already queued or building,
+/// returning the ids of the new ones. They are picked up on the next sync with the db.
+/// Previews are skipped once their pull request is closed, or if Github can't tell
+pub(super) async fn redeploy_active_deployments(state: &AppState, project: i64) -> Vec<i64> {
+    let Some(repo_id) = state
+        .db
+        .get_project(project)
+        .await
+        .map(|project| project.repo_id)
+    else {
+        return vec![];
+    };
+    let open_branches: HashSet<_> = match state.github.get_open_pulls(&repo_id).await {
+        Ok(pulls) => pulls.into_iter().map(|pull| pull.head.ref_field).collect(),
+        Err(error) => {
+            error!("failed to get the pull requests of project {project}: {error}");
+            HashSet::new()
+        }
+    };
+    let mut queued = vec![];
+    for deployment in state.db.get_active_deployments_for_project(project).await {
+        let is_open = deployment
+            .branch
+            .as_ref()
+            .map_or(true, |branch| open_branches.contains(branch));
+        if !is_open
+            || state
+                .manager
+                .is_deployment_in_build_queue(deployment.id)
+                .await
+        {
+            continue;
+        }
+        if let Some(new_id) = clone_deployment(&state.db, deployment.id).await {
+            queued.push(new_id);
+        }
+    }
+    queued
+}
+
+/// Returns the id of the new deployment
+pub(crate) async fn clone_deployment(db: &Db, deployment_id: i64) -> Option<i64> {
     let deployment = db.get_deployment(deployment_id).await?;
     let project = db.get_project(deployment.project).await?;
 
     let insert = InsertDeployment {
-        env: project.env.clone(),
+        env: project.resolve_env(deployment.branch.as_deref()),
         sha: deployment.sha.clone(),
         branch: deployment.branch.clone(),
+        tag: deployment.tag.clone(),
         timestamp: deployment.timestamp,
         project: deployment.project,
     };
-    db.insert_deployment(insert).await;
-    Some(())
+    Some(db.insert_deployment(insert).await)
 }
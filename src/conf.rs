@@ -1,13 +1,305 @@
+use anyhow::{bail, Context};
 use serde::Deserialize;
-use std::fs;
+use std::{env, fs, path::PathBuf};
 
-use crate::paths::get_container_root;
+use crate::{api::Status, paths::get_container_root};
 
 #[derive(Deserialize, Clone)]
 pub(crate) struct Conf {
+    /// can be left out of the file and passed through the environment instead, see `apply_secrets`
+    #[serde(default)]
     pub(crate) token: String,
+    /// hex encoded 32 bytes key for the secret env values, they can't be set if missing.
+    /// Can also be passed through the environment, see `apply_secrets`
+    #[serde(default)]
+    pub(crate) secrets_key: Option<String>,
+    /// keys used before the current one, stored secrets are moved to the current key on start
+    #[serde(default)]
+    pub(crate) previous_secrets_keys: Vec<String>,
     pub(crate) hostname: String,
     pub(crate) coordinator: String,
+    /// proxy-side cache for cacheable GET responses, disabled if missing
+    #[serde(default)]
+    pub(crate) cache: Option<CacheConf>,
+    /// endpoints notified on deployment status changes
+    #[serde(default)]
+    pub(crate) webhooks: Vec<WebhookConf>,
+    /// deleted projects can be restored during this time, then they are purged
+    #[serde(default = "default_deleted_project_retention_hours")]
+    pub(crate) deleted_project_retention_hours: u64,
+    /// days the build logs are kept, projects can override it
+    #[serde(default = "default_log_retention_days")]
+    pub(crate) log_retention_days: u64,
+    /// how long the proxy waits to connect to an app container
+    #[serde(default = "default_upstream_connect_timeout_secs")]
+    pub(crate) upstream_connect_timeout_secs: u64,
+    /// how long the proxy waits for an app container to respond, projects can override it
+    #[serde(default = "default_upstream_read_timeout_secs")]
+    pub(crate) upstream_read_timeout_secs: u64,
+    /// times GET and HEAD requests are retried when the app container refuses the connection
+    #[serde(default = "default_upstream_connect_retries")]
+    pub(crate) upstream_connect_retries: u32,
+    #[serde(default = "default_upstream_connect_retry_delay_ms")]
+    pub(crate) upstream_connect_retry_delay_ms: u64,
+    /// requests taking longer than this are logged as warnings, disabled if missing
+    #[serde(default)]
+    pub(crate) slow_request_threshold_ms: Option<u64>,
+    /// how long idle client connections are kept open between requests, 0 closes them after each response
+    #[serde(default = "default_keepalive_timeout_secs")]
+    pub(crate) keepalive_timeout_secs: u64,
+    /// how long the proxy keeps serving the open connections after a graceful shutdown
+    /// stops it from accepting new ones, before the process exits
+    #[serde(default = "default_drain_timeout_secs")]
+    pub(crate) drain_timeout_secs: u64,
+    /// automatic cleanup of preview deployments
+    #[serde(default)]
+    pub(crate) previews: PreviewRetentionConf,
+    /// opt-in for the find/replace rules projects can set on their response bodies
+    #[serde(default)]
+    pub(crate) allow_body_rewrites: bool,
+    /// removed from the app responses, as they tell the server software to scanners. Empty keeps them all
+    #[serde(default = "default_strip_response_headers")]
+    pub(crate) strip_response_headers: Vec<String>,
+    /// mark the first response after waking a container up from standby
+    #[serde(default)]
+    pub(crate) cold_start_header: bool,
+    /// Retry-After of the loading page when there is no estimate of the start, like during builds
+    #[serde(default = "default_loading_retry_after_secs")]
+    pub(crate) loading_retry_after_secs: u64,
+    /// times a container start is retried after a docker error before the deployment fails
+    #[serde(default = "default_container_start_retries")]
+    pub(crate) container_start_retries: u32,
+    /// where the API server listens, only reachable from the same machine either way
+    #[serde(default)]
+    pub(crate) api: ApiConf,
+    /// protocol versions and ciphers accepted by the HTTPS listener
+    #[serde(default)]
+    pub(crate) tls: TlsConf,
+    /// CIDRs of load balancers in front of the instance, their X-Forwarded-Host and
+    /// X-Forwarded-Proto headers are used to build the auth callback URL
+    #[serde(default)]
+    pub(crate) trusted_proxies: Vec<String>,
+    /// periodic rebuilds of the deployments whose base image got updated, disabled if missing
+    #[serde(default)]
+    pub(crate) base_image_updates: Option<BaseImageUpdatesConf>,
+    /// engine running the containers, docker by default
+    #[serde(default)]
+    pub(crate) container_runtime: RuntimeConf,
+    /// for the repos the Github app installation can't access, like the ones of other organizations
+    #[serde(default)]
+    pub(crate) github_tokens: Vec<GithubTokenConf>,
+    /// path prefixes served without auth on private deployments, only the ones under /.well-known/ are honored
+    #[serde(default = "default_public_paths")]
+    pub(crate) public_paths: Vec<String>,
+}
+
+const TOKEN_ENV: &str = "PREZEL_TOKEN";
+const TOKEN_FILE_ENV: &str = "PREZEL_TOKEN_FILE";
+const SECRETS_KEY_ENV: &str = "PREZEL_SECRETS_KEY";
+const SECRETS_KEY_FILE_ENV: &str = "PREZEL_SECRETS_KEY_FILE";
+
+fn default_deleted_project_retention_hours() -> u64 {
+    24 * 7
+}
+
+fn default_log_retention_days() -> u64 {
+    30
+}
+
+fn default_upstream_connect_timeout_secs() -> u64 {
+    10
+}
+
+fn default_upstream_read_timeout_secs() -> u64 {
+    60
+}
+
+fn default_upstream_connect_retries() -> u32 {
+    2
+}
+
+fn default_upstream_connect_retry_delay_ms() -> u64 {
+    250
+}
+
+fn default_strip_response_headers() -> Vec<String> {
+    [
+        "Server",
+        "X-Powered-By",
+        "X-AspNet-Version",
+        "X-AspNetMvc-Version",
+    ]
+    .map(str::to_owned)
+    .to_vec()
+}
+
+fn default_public_paths() -> Vec<String> {
+    [
+        "/.well-known/acme-challenge/",
+        "/.well-known/security.txt",
+        "/.well-known/apple-app-site-association",
+        "/.well-known/assetlinks.json",
+    ]
+    .map(str::to_owned)
+    .to_vec()
+}
+
+fn default_keepalive_timeout_secs() -> u64 {
+    60
+}
+
+fn default_drain_timeout_secs() -> u64 {
+    30
+}
+
+fn default_loading_retry_after_secs() -> u64 {
+    5
+}
+
+fn default_container_start_retries() -> u32 {
+    3
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub(crate) struct WebhookConf {
+    pub(crate) url: String,
+    /// used to sign the payload, sent in the X-Prezel-Signature header
+    pub(crate) secret: String,
+    #[serde(default = "default_webhook_events")]
+    pub(crate) events: Vec<Status>,
+}
+
+fn default_webhook_events() -> Vec<Status> {
+    vec![Status::Building, Status::Ready, Status::Failed]
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub(crate) struct PreviewRetentionConf {
+    /// delete the previews of branches without an open pull request
+    #[serde(default = "default_delete_closed_previews")]
+    pub(crate) delete_closed: bool,
+    /// the oldest previews of a project beyond this number are deleted
+    #[serde(default)]
+    pub(crate) max_per_project: Option<usize>,
+}
+
+impl Default for PreviewRetentionConf {
+    fn default() -> Self {
+        Self {
+            delete_closed: default_delete_closed_previews(),
+            max_per_project: None,
+        }
+    }
+}
+
+fn default_delete_closed_previews() -> bool {
+    true
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub(crate) struct ApiConf {
+    /// localhost port, ignored if a socket is set
+    #[serde(default = "default_api_port")]
+    pub(crate) port: u16,
+    /// unix socket to listen on instead of a port
+    #[serde(default)]
+    pub(crate) socket: Option<PathBuf>,
+}
+
+impl Default for ApiConf {
+    fn default() -> Self {
+        Self {
+            port: default_api_port(),
+            socket: None,
+        }
+    }
+}
+
+fn default_api_port() -> u16 {
+    5045
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+pub(crate) struct TlsConf {
+    #[serde(default)]
+    pub(crate) min_version: TlsVersion,
+    /// OpenSSL cipher list for TLS 1.2, like `ECDHE+AESGCM:ECDHE+CHACHA20`.
+    /// The Mozilla intermediate list is used if not set
+    #[serde(default)]
+    pub(crate) ciphers: Option<String>,
+    /// OpenSSL cipher suites for TLS 1.3, like `TLS_AES_256_GCM_SHA384:TLS_CHACHA20_POLY1305_SHA256`
+    #[serde(default)]
+    pub(crate) ciphersuites: Option<String>,
+}
+
+/// Lowest protocol version accepted
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum TlsVersion {
+    #[default]
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub(crate) struct GithubTokenConf {
+    /// only shown in the logs, usually the organization the token has access to
+    pub(crate) name: String,
+    /// personal access token, fine grained ones can be limited to the repos of an organization
+    pub(crate) token: String,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+pub(crate) struct RuntimeConf {
+    #[serde(default)]
+    pub(crate) kind: RuntimeKind,
+    /// unix socket of the Docker compatible API, the default of the runtime if missing
+    #[serde(default)]
+    pub(crate) socket: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum RuntimeKind {
+    #[default]
+    Docker,
+    Podman,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub(crate) struct BaseImageUpdatesConf {
+    /// how often the base images are pulled to look for updates
+    #[serde(default = "default_base_image_check_interval_hours")]
+    pub(crate) interval_hours: u64,
+    /// names of the projects to check, every project if empty
+    #[serde(default)]
+    pub(crate) projects: Vec<String>,
+    /// also rebuild the latest deployment of every pull request, not only production and releases
+    #[serde(default)]
+    pub(crate) include_previews: bool,
+}
+
+fn default_base_image_check_interval_hours() -> u64 {
+    24
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub(crate) struct CacheConf {
+    /// upper bound for how long a response is served from the cache
+    #[serde(default = "default_cache_ttl_secs")]
+    pub(crate) ttl_secs: u64,
+    /// least recently used responses are evicted past this number
+    #[serde(default = "default_cache_max_entries")]
+    pub(crate) max_entries: usize,
+}
+
+fn default_cache_ttl_secs() -> u64 {
+    10
+}
+
+fn default_cache_max_entries() -> usize {
+    1000
 }
 
 impl Conf {
@@ -16,7 +308,40 @@ impl Conf {
         let conf_path = get_container_root().join("config.json");
         // println!("reading conf from {conf_path:?}");
         let conf_data = fs::read_to_string(conf_path).expect("Unable to find config.json");
-        serde_json::from_str(&conf_data).expect("Invalid content for conf.json")
+        let mut conf: Self =
+            serde_json::from_str(&conf_data).expect("Invalid content for conf.json");
+        conf.apply_secrets(|name| env::var(name).ok())
+            .expect("Invalid secrets");
+        conf.validate().expect("Invalid content for conf.json");
+        conf
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self
+            .cache
+            .as_ref()
+            .is_some_and(|cache| cache.max_entries == 0)
+        {
+            bail!("cache.max_entries needs to be at least 1, leave cache out to disable it");
+        }
+        Ok(())
+    }
+
+    /// Secrets in the environment take precedence over the config file, first the value
+    /// itself and then a file holding it, so they don't need to be written to disk by prezel
+    fn apply_secrets(&mut self, var: impl Fn(&str) -> Option<String>) -> anyhow::Result<()> {
+        if let Some(token) = read_secret(&var, TOKEN_ENV, TOKEN_FILE_ENV)? {
+            self.token = token;
+        }
+        if let Some(key) = read_secret(&var, SECRETS_KEY_ENV, SECRETS_KEY_FILE_ENV)? {
+            self.secrets_key = Some(key);
+        }
+        if self.token.is_empty() {
+            bail!(
+                "missing instance token, set {TOKEN_ENV}, {TOKEN_FILE_ENV} or token in config.json"
+            );
+        }
+        Ok(())
     }
 
     pub(crate) fn api_hostname(&self) -> String {
@@ -24,3 +349,62 @@ impl Conf {
         format!("api.{}", self.hostname)
     }
 }
+
+fn read_secret(
+    var: impl Fn(&str) -> Option<String>,
+    name: &str,
+    file_name: &str,
+) -> anyhow::Result<Option<String>> {
+    if let Some(value) = var(name).filter(|value| !value.is_empty()) {
+        Ok(Some(value))
+    } else if let Some(path) = var(file_name).filter(|path| !path.is_empty()) {
+        let value = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {file_name} at {path}"))?;
+        Ok(Some(value.trim().to_owned()))
+    } else {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod conf_tests {
+    use std::{collections::HashMap, fs};
+
+    use tempfile::TempDir;
+
+    use super::Conf;
+
+    fn conf(token: &str) -> Conf {
+        let json = format!(
+            r#"{{ "token": "{token}", "hostname": "example.com", "coordinator": "https://prezel.app" }}"#
+        );
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn test_secrets_precedence() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("token");
+        fs::write(&file, "from-file\n").unwrap();
+        let file = file.to_str().unwrap().to_owned();
+
+        let apply = |token: &str, vars: &[(&str, &str)]| {
+            let vars: HashMap<_, _> = vars.iter().cloned().collect();
+            let mut conf = conf(token);
+            conf.apply_secrets(|name| vars.get(name).map(|value| value.to_string()))
+                .map(|_| conf.token)
+        };
+        let both = [
+            ("PREZEL_TOKEN", "from-env"),
+            ("PREZEL_TOKEN_FILE", file.as_str()),
+        ];
+        assert_eq!(apply("from-conf", &both).unwrap(), "from-env");
+        assert_eq!(
+            apply("from-conf", &[("PREZEL_TOKEN_FILE", &file)]).unwrap(),
+            "from-file"
+        );
+        assert_eq!(apply("from-conf", &[]).unwrap(), "from-conf");
+        assert!(apply("", &[]).is_err());
+        assert!(apply("from-conf", &[("PREZEL_TOKEN_FILE", "/missing")]).is_err());
+    }
+}
@@ -0,0 +1,76 @@
+use std::{collections::HashMap, path::Path};
+
+use tokio::fs;
+
+use crate::docker::get_image_id;
+
+/// Images the stages of the Dockerfile start from. Stages built on top of previous stages,
+/// `scratch` and images depending on build args are left out
+pub(crate) fn parse_base_images(dockerfile: &str) -> Vec<String> {
+    let mut stages: Vec<String> = vec![];
+    let mut images: Vec<String> = vec![];
+    for line in dockerfile.lines() {
+        let mut words = line.split_whitespace();
+        if !words
+            .next()
+            .is_some_and(|word| word.eq_ignore_ascii_case("FROM"))
+        {
+            continue;
+        }
+        let mut words = words.filter(|word| !word.starts_with("--"));
+        let Some(image) = words.next() else {
+            continue;
+        };
+        let is_stage = stages.iter().any(|stage| stage.eq_ignore_ascii_case(image));
+        let skip = is_stage || image.eq_ignore_ascii_case("scratch") || image.contains('$');
+        if !skip && !images.iter().any(|known| known == image) {
+            images.push(image.to_owned());
+        }
+        if words
+            .next()
+            .is_some_and(|word| word.eq_ignore_ascii_case("AS"))
+        {
+            if let Some(stage) = words.next() {
+                stages.push(stage.to_owned());
+            }
+        }
+    }
+    images
+}
+
+/// Local image ids of the base images in the Dockerfile, as used by the last build
+pub(crate) async fn get_base_image_ids(dockerfile: &Path) -> HashMap<String, String> {
+    let Ok(content) = fs::read_to_string(dockerfile).await else {
+        return HashMap::new();
+    };
+    let mut ids = HashMap::new();
+    for image in parse_base_images(&content) {
+        if let Some(id) = get_image_id(&image).await {
+            ids.insert(image, id);
+        }
+    }
+    ids
+}
+
+#[cfg(test)]
+mod base_image_tests {
+    use super::parse_base_images;
+
+    #[test]
+    fn test_parse_base_images() {
+        let dockerfile = "\
+ARG NODE=20
+FROM --platform=linux/amd64 node:20 AS build
+RUN npm run build
+from ghcr.io/railwayapp/nixpacks:ubuntu-1722297819
+FROM node:${NODE}
+FROM build AS assets
+FROM scratch
+FROM node:20
+";
+        assert_eq!(
+            parse_base_images(dockerfile),
+            ["node:20", "ghcr.io/railwayapp/nixpacks:ubuntu-1722297819"]
+        );
+    }
+}
@@ -0,0 +1,111 @@
+/// Phase the build is in before the docker build starts, while the source is fetched
+/// and the Dockerfile is generated
+pub(crate) const PREPARE_PHASE: &str = "prepare";
+
+/// Follows the output of a docker build to tell which phase of the nixpacks Dockerfile
+/// is running. Nixpacks writes a comment like `# install phase` before the steps of each phase,
+/// and the docker output starts every step with `Step N/M :`
+#[derive(Debug, Default)]
+pub(crate) struct BuildPhases {
+    /// phase of every step of the Dockerfile, in order
+    steps: Vec<Option<String>>,
+    current: Option<String>,
+}
+
+impl BuildPhases {
+    pub(crate) fn from_dockerfile(dockerfile: &str) -> Self {
+        let mut steps = Vec::new();
+        let mut phase = None;
+        let mut continued = false;
+        for line in dockerfile.lines().map(str::trim) {
+            if let Some(comment) = line.strip_prefix('#') {
+                let comment = comment.trim();
+                if let Some(name) = comment.strip_suffix(" phase") {
+                    phase = Some(name.trim().to_owned());
+                } else if comment == "start" {
+                    phase = Some(comment.to_owned());
+                }
+                continue;
+            }
+            if line.is_empty() {
+                continue;
+            }
+            if !continued {
+                steps.push(phase.clone());
+            }
+            continued = line.ends_with('\\');
+        }
+        Self {
+            steps,
+            current: None,
+        }
+    }
+
+    /// The phase this chunk of the build output starts, if it starts a different one
+    pub(crate) fn transition(&mut self, output: &str) -> Option<String> {
+        let step: usize = output
+            .trim_start()
+            .strip_prefix("Step ")?
+            .split('/')
+            .next()?
+            .parse()
+            .ok()?;
+        let phase = self.steps.get(step.checked_sub(1)?)?.clone()?;
+        if self.current.as_ref() == Some(&phase) {
+            return None;
+        }
+        self.current = Some(phase.clone());
+        Some(phase)
+    }
+}
+
+#[cfg(test)]
+mod build_phase_tests {
+    use super::BuildPhases;
+
+    const DOCKERFILE: &str = r#"FROM ghcr.io/railwayapp/nixpacks:ubuntu-1716249803
+
+ENTRYPOINT ["/bin/bash", "-l", "-c"]
+WORKDIR /app/
+
+# setup phase
+# noop
+
+# install phase
+ENV NIXPACKS_PATH=/app/node_modules/.bin:$NIXPACKS_PATH
+COPY . /app/.
+RUN --mount=type=cache,id=s/npm,target=/root/.npm npm ci
+
+# build phase
+COPY . /app/.
+RUN npm run build \
+    && npm prune
+
+
+# start
+COPY . /app
+CMD ["npm run start"]
+"#;
+
+    #[test]
+    fn test_transitions() {
+        let mut phases = BuildPhases::from_dockerfile(DOCKERFILE);
+        assert_eq!(phases.transition("Step 1/10 : FROM ghcr.io/..."), None);
+        assert_eq!(phases.transition(" ---> 4f1b5e3a2c1d\n"), None);
+        assert_eq!(
+            phases.transition("Step 4/10 : ENV NIXPACKS_PATH=..."),
+            Some("install".to_owned())
+        );
+        assert_eq!(phases.transition("Step 6/10 : RUN npm ci"), None);
+        assert_eq!(
+            phases.transition("Step 7/10 : COPY . /app/."),
+            Some("build".to_owned())
+        );
+        assert_eq!(
+            phases.transition("Step 9/10 : COPY . /app"),
+            Some("start".to_owned())
+        );
+        assert_eq!(phases.transition("Step 10/10 : CMD"), None);
+        assert_eq!(phases.transition("Step 11/10 : CMD"), None);
+    }
+}
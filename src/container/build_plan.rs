@@ -0,0 +1,149 @@
+use anyhow::ensure;
+use nixpacks::nixpacks::plan::{phase::Phase, BuildPlan as NixpacksPlan};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// What nixpacks detected in the source and the commands it resolved,
+/// with the repo config and the project env already taken into account
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub(crate) struct BuildPlan {
+    /// Detected providers, like `node` or `python`
+    pub(crate) providers: Vec<String>,
+    pub(crate) install_commands: Vec<String>,
+    pub(crate) build_commands: Vec<String>,
+    /// Command the app container runs, the project one if set
+    pub(crate) start_command: Option<String>,
+    /// The command nixpacks resolved, only set when the project one replaces it
+    #[serde(default)]
+    pub(crate) detected_start_command: Option<String>,
+    /// Docker image the build ran in
+    #[serde(default)]
+    pub(crate) build_image: Option<String>,
+    /// Nix packages installed for the build, both detected and requested by the project
+    #[serde(default)]
+    pub(crate) nix_packages: Vec<String>,
+    /// nixpkgs commit the packages came from
+    #[serde(default)]
+    pub(crate) nixpkgs_archive: Option<String>,
+}
+
+impl From<NixpacksPlan> for BuildPlan {
+    fn from(plan: NixpacksPlan) -> Self {
+        let commands = |name: &str| {
+            plan.phases
+                .as_ref()
+                .and_then(|phases| phases.get(name))
+                .and_then(|phase| phase.cmds.clone())
+                .unwrap_or_default()
+        };
+        let setup = plan.phases.as_ref().and_then(|phases| phases.get("setup"));
+        Self {
+            providers: plan.providers.clone().unwrap_or_default(),
+            install_commands: commands("install"),
+            build_commands: commands("build"),
+            start_command: plan
+                .start_phase
+                .as_ref()
+                .and_then(|start| start.cmd.clone()),
+            detected_start_command: None,
+            build_image: plan.build_image.clone(),
+            nix_packages: setup
+                .and_then(|setup| setup.nix_pkgs.clone())
+                .unwrap_or_default(),
+            nixpkgs_archive: setup.and_then(|setup| setup.nixpkgs_archive.clone()),
+        }
+    }
+}
+
+/// Overrides of the environment the app is built in, for toolchains autodetection gets wrong
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, ToSchema)]
+pub(crate) struct BuildEnvironment {
+    /// Docker image the build runs in instead of the nixpacks one, like `ubuntu:22.04`
+    #[serde(default)]
+    pub(crate) base_image: Option<String>,
+    /// Full sha of the nixpkgs commit the Nix packages come from, pinning their versions
+    #[serde(default)]
+    pub(crate) nixpkgs_commit: Option<String>,
+    /// Nix packages installed next to the detected ones, like `nodejs_20`
+    #[serde(default)]
+    pub(crate) nix_packages: Vec<String>,
+}
+
+impl BuildEnvironment {
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        if let Some(image) = &self.base_image {
+            let reference = Regex::new(
+                r"^([a-z0-9.-]+(:[0-9]+)?/)?[a-z0-9][a-z0-9._/-]*(:[A-Za-z0-9_][A-Za-z0-9_.-]{0,127})?(@sha256:[a-f0-9]{64})?$",
+            )?;
+            ensure!(
+                reference.is_match(image),
+                "{image} is not a valid image reference"
+            );
+        }
+        if let Some(commit) = &self.nixpkgs_commit {
+            ensure!(
+                commit.len() == 40 && commit.chars().all(|char| char.is_ascii_hexdigit()),
+                "the nixpkgs commit needs to be a full 40 characters sha"
+            );
+        }
+        let package = Regex::new(r"^[A-Za-z_][A-Za-z0-9_.+-]*$")?;
+        for name in &self.nix_packages {
+            ensure!(package.is_match(name), "{name} is not a valid Nix package");
+        }
+        Ok(())
+    }
+
+    /// Plan merged by nixpacks on top of the one it detects, None if nothing is overridden
+    pub(crate) fn to_nixpacks_plan(&self) -> Option<NixpacksPlan> {
+        if self == &Self::default() {
+            return None;
+        }
+        let mut setup = Phase::new("setup");
+        if !self.nix_packages.is_empty() {
+            // `...` keeps the detected packages
+            let packages = self.nix_packages.iter().cloned();
+            setup.nix_pkgs = Some(packages.chain(["...".to_owned()]).collect());
+        }
+        setup.nixpkgs_archive = self.nixpkgs_commit.clone();
+        let mut plan = NixpacksPlan::new(&[setup], None);
+        plan.build_image = self.base_image.clone();
+        Some(plan)
+    }
+}
+
+#[cfg(test)]
+mod build_plan_tests {
+    use super::BuildEnvironment;
+
+    #[test]
+    fn test_build_environment() {
+        let environment =
+            |base_image: Option<&str>, commit: Option<&str>, packages: &[&str]| BuildEnvironment {
+                base_image: base_image.map(str::to_owned),
+                nixpkgs_commit: commit.map(str::to_owned),
+                nix_packages: packages.iter().map(|name| name.to_string()).collect(),
+            };
+        assert!(BuildEnvironment::default().to_nixpacks_plan().is_none());
+
+        let valid = environment(
+            Some("registry.example.com:5000/team/builder:22.04"),
+            Some("e24b4c09e963677b1beea49d411cd315a024ad3a"),
+            &["nodejs_20", "python311Packages.pip"],
+        );
+        assert!(valid.validate().is_ok());
+        let plan = valid.to_nixpacks_plan().unwrap();
+        assert_eq!(plan.build_image.as_deref(), valid.base_image.as_deref());
+        let setup = plan.get_phase("setup").unwrap();
+        assert_eq!(
+            setup.nix_pkgs.as_deref().unwrap(),
+            ["nodejs_20", "python311Packages.pip", "..."]
+        );
+
+        assert!(environment(Some("Ubuntu"), None, &[]).validate().is_err());
+        assert!(environment(None, Some("nixos-23.11"), &[])
+            .validate()
+            .is_err());
+        assert!(environment(None, None, &["nodejs; rm"]).validate().is_err());
+    }
+}
@@ -1,16 +1,22 @@
 use nixpacks::{
-    create_docker_image,
+    create_docker_image, generate_build_plan,
     nixpacks::{builder::docker::DockerBuilderOptions, plan::generator::GeneratePlanOptions},
 };
 use std::path::{Path, PathBuf};
 use tempfile::TempDir;
 use tokio::fs;
 
-use crate::{deployment_hooks::StatusHooks, env::EnvVars, github::Github, paths::HostFile};
+use crate::{
+    deployment_hooks::StatusHooks, docker::NamedVolume, env::EnvVars, github::Github,
+    paths::HostFile,
+};
 
 use super::{
+    build_plan::{BuildEnvironment, BuildPlan},
+    repo_config::RepoConfig,
+    sidecar::Sidecar,
     BuildResult, Container, ContainerConfig, ContainerSetup, ContainerStatus, ContextBuilderOutput,
-    FileSystemOutput, WorkerHandle,
+    FileSystemOutput, WorkerHandle, DEFAULT_PORT,
 };
 
 const DB_PATH_ENV_NAME: &str = "DATABASE_URL";
@@ -24,6 +30,7 @@ pub(crate) struct CommitContainer {
     pub(crate) sha: String,
     env: EnvVars,
     root: String,
+    build_environment: BuildEnvironment,
 }
 
 impl CommitContainer {
@@ -35,27 +42,25 @@ impl CommitContainer {
         sha: String,
         deployment: i64,
         env: EnvVars, // TODO: this is duplicated in ContainerConfig...
+        flags: EnvVars,
         root: String,
+        build_environment: BuildEnvironment,
         public: bool, // TODO: should not this be in ContainerConfig
         main_db_file: HostFile,
         cloned_db_file: Option<HostFile>,
+        volume: Option<NamedVolume>,
+        sidecars: Vec<Sidecar>,
+        port: Option<u16>,
         initial_status: ContainerStatus,
         result: Option<BuildResult>,
     ) -> Container {
         let db_file = cloned_db_file
             .clone()
             .unwrap_or_else(|| main_db_file.clone());
-        let default_env = [
-            (
-                DB_PATH_ENV_NAME,
-                db_file.get_container_file().to_str().unwrap(),
-            ),
-            ("HOST", "0.0.0.0"),
-            ("PORT", "80"),
-        ]
-        .as_ref()
-        .into();
-        let extended_env = env + default_env;
+        let default_port = port.unwrap_or(DEFAULT_PORT);
+        let extended_env = get_app_env(env.clone(), &db_file, default_port);
+        // flags only reach the running container, changing them doesn't need a rebuild
+        let runtime_env = get_app_env(env + flags, &db_file, default_port);
 
         let builder = Self {
             github,
@@ -65,6 +70,7 @@ impl CommitContainer {
             sha,
             env: extended_env.clone(),
             root,
+            build_environment,
         };
 
         Container::new(
@@ -72,7 +78,10 @@ impl CommitContainer {
             ContainerConfig {
                 args: extended_env.clone(),
                 host_files: vec![db_file],
-                env: extended_env,
+                volumes: volume.into_iter().collect(),
+                sidecars,
+                port,
+                env: runtime_env,
                 initial_status,
                 result,
             },
@@ -82,7 +91,10 @@ impl CommitContainer {
             hooks,
         )
     }
-    async fn build_context(&self, path: &Path) -> anyhow::Result<PathBuf> {
+    async fn build_context(
+        &self,
+        path: &Path,
+    ) -> anyhow::Result<(PathBuf, RepoConfig, Option<BuildPlan>)> {
         self.github
             .download_commit(&self.repo_id, &self.sha, &path)
             .await
@@ -91,11 +103,19 @@ impl CommitContainer {
 
         let inner_path = path.join(&self.root);
 
-        let env_vec: Vec<String> = self.env.clone().into();
+        let repo_config = RepoConfig::read(&inner_path).await?;
+        let env_vec: Vec<String> = repo_config.build_env(self.env.clone()).into();
+        let envs: Vec<&str> = env_vec.iter().map(String::as_str).collect();
+        let options = GeneratePlanOptions {
+            plan: self.build_environment.to_nixpacks_plan(),
+            ..Default::default()
+        };
+        // nixpacks generates the same plan again when creating the image
+        let plan = generate_build_plan(inner_path.to_str().unwrap(), envs.clone(), &options)?;
         create_docker_image(
             inner_path.to_str().unwrap(),
-            env_vec.iter().map(String::as_str).collect(),
-            &GeneratePlanOptions::default(),
+            envs,
+            &options,
             &DockerBuilderOptions {
                 out_dir: Some(inner_path.to_str().unwrap().to_owned()), // TODO: test what happens if I omit this ?
                 // name: Some(name.clone()),
@@ -121,10 +141,26 @@ impl CommitContainer {
             inner_path.join("Dockerfile"),
         )
         .await?;
-        Ok(inner_path)
+        Ok((inner_path, repo_config, Some(plan.into())))
     }
 }
 
+/// Adds the variables prezel always sets for the app on top of the project env
+pub(crate) fn get_app_env(env: EnvVars, db_file: &HostFile, port: u16) -> EnvVars {
+    let port = port.to_string();
+    let default_env = [
+        (
+            DB_PATH_ENV_NAME,
+            db_file.get_container_file().to_str().unwrap(),
+        ),
+        ("HOST", "0.0.0.0"),
+        ("PORT", &port),
+    ]
+    .as_ref()
+    .into();
+    env + default_env
+}
+
 impl ContainerSetup for CommitContainer {
     fn setup_build_context(&self, path: PathBuf) -> ContextBuilderOutput {
         let builder = self.clone();
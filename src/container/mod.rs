@@ -2,18 +2,31 @@ use anyhow::{anyhow, bail};
 use async_trait::async_trait;
 use futures::lock::{Mutex, MutexGuard};
 use http::StatusCode;
+use log::{error, warn};
 use std::{
+    collections::{HashMap, VecDeque},
     fmt,
     future::Future,
-    net::SocketAddrV4,
+    net::{IpAddr, SocketAddrV4},
     ops::Deref,
     path::{Path, PathBuf},
     pin::Pin,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 use tempfile::TempDir;
-use tokio::{sync::RwLock, time::sleep};
+use tokio::{fs, sync::RwLock, time::sleep};
+
+use base_image::get_base_image_ids;
+use build_phase::{BuildPhases, PREPARE_PHASE};
+use build_plan::BuildPlan;
+use network::NetworkConfig;
+use replicas::{Replica, Replicas, DRAIN_TIMEOUT, SCALE_DOWN_DELAY};
+use repo_config::RepoConfig;
+use sidecar::{RunningSidecar, Sidecar};
 
 use crate::{
     // db::Status,
@@ -22,29 +35,82 @@ use crate::{
     deployment_hooks::DeploymentHooks,
     deployments::{manager::Manager, worker::WorkerHandle},
     docker::{
-        build_dockerfile, create_container, delete_container, delete_image,
-        get_bollard_container_ipv4, get_container_execution_logs, run_container, stop_container,
-        DockerLog,
+        build_dockerfile, copy_from_image, create_container, delete_image,
+        get_bollard_container_ipv4, get_container_execution_logs, get_image_size, DockerLog,
+        NamedVolume,
     },
-    env::EnvVars,
-    listener::{Access, Listener},
-    paths::HostFile,
+    env::{EnvRule, EnvVars},
+    ip_filter::IpFilter,
+    listener::{Access, ColdStart, InFlight, Listener, OpenStreams},
+    paths::{get_image_static_dir, HostFile},
+    proxy::{
+        redirect::RedirectRule, rewrite::BodyRewrite, routes::AllowedRoute,
+        upstream_tls::UpstreamTls,
+    },
+    runtime::runtime,
+    secrets::Secrets,
+    time::now,
 };
 
+pub(crate) mod base_image;
+pub(crate) mod build_phase;
+pub(crate) mod build_plan;
 pub(crate) mod commit;
+pub(crate) mod network;
 pub(crate) mod prisma;
+pub(crate) mod replicas;
+pub(crate) mod repo_config;
+pub(crate) mod sidecar;
+
+pub(crate) const DEFAULT_PORT: u16 = 80;
+/// wait before retrying a failed container start, doubled after every attempt
+const START_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// containers with no requests for this long go to standby, plus the grace period of the project
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// starts the estimate of the next one is averaged over
+const START_HISTORY_SIZE: usize = 5;
+
+/// The app container stopped before passing the health check, so retrying won't help
+#[derive(Debug)]
+struct AppExited(i64);
+
+impl fmt::Display for AppExited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the app exited with code {} while starting", self.0)
+    }
+}
+
+impl std::error::Error for AppExited {}
+
+/// The env doesn't follow the project schema, retrying won't help either
+#[derive(Debug)]
+struct InvalidEnv(String);
+
+impl fmt::Display for InvalidEnv {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for InvalidEnv {}
 
 #[derive(Debug)]
 pub(crate) struct ContainerConfig {
     pub(crate) env: EnvVars,
     pub(crate) args: EnvVars,
     pub(crate) host_files: Vec<HostFile>,
+    pub(crate) volumes: Vec<NamedVolume>,
+    pub(crate) sidecars: Vec<Sidecar>,
+    /// port the container listens on, the proxy connects to it.
+    /// If not set, the one in the repo config or the default one is used
+    pub(crate) port: Option<u16>,
     pub(crate) initial_status: ContainerStatus,
     pub(crate) result: Option<BuildResult>,
 }
 
+/// folder to build, repo config and the build plan if there is one
 pub(crate) type ContextBuilderOutput =
-    Pin<Box<dyn Future<Output = anyhow::Result<PathBuf>> + Send>>;
+    Pin<Box<dyn Future<Output = anyhow::Result<(PathBuf, RepoConfig, Option<BuildPlan>)>> + Send>>;
 pub(crate) type FileSystemOutput = Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>>;
 
 pub(crate) trait ContainerSetup: 'static + Send + Sync + fmt::Debug {
@@ -73,6 +139,7 @@ pub(crate) enum ContainerStatus {
         container: String,
         socket: SocketAddrV4,
         last_access: Arc<RwLock<Instant>>,
+        sidecars: Vec<RunningSidecar>,
     },
     Failed,
 }
@@ -130,6 +197,21 @@ impl ContainerStatus {
         }
     }
 
+    pub(crate) fn get_image(&self) -> Option<String> {
+        match self {
+            Self::StandBy { image } | Self::Ready { image, .. } => Some(image.clone()),
+            _ => None,
+        }
+    }
+
+    fn get_sidecars(&self) -> Vec<RunningSidecar> {
+        if let Self::Ready { sidecars, .. } = self {
+            sidecars.clone()
+        } else {
+            vec![]
+        }
+    }
+
     pub(crate) fn to_status(&self) -> Status {
         match self {
             Self::Built => Status::Built,
@@ -156,6 +238,32 @@ impl ContainerStatus {
     // }
 }
 
+/// Timestamps in ms of the last time the container was started
+#[derive(Clone, Copy, Default, Debug)]
+pub(crate) struct StartTimings {
+    pub(crate) started: Option<i64>,
+    /// only set once the container is ready, not if the start failed
+    pub(crate) ready: Option<i64>,
+}
+
+/// Durations of the last successful starts, the next one is expected to take their average
+#[derive(Default, Debug)]
+struct StartHistory(VecDeque<Duration>);
+
+impl StartHistory {
+    fn record(&mut self, duration: Duration) {
+        if self.0.len() == START_HISTORY_SIZE {
+            self.0.pop_front();
+        }
+        self.0.push_back(duration);
+    }
+
+    fn average(&self) -> Option<Duration> {
+        let count = self.0.len() as u32;
+        (count > 0).then(|| self.0.iter().sum::<Duration>() / count)
+    }
+}
+
 // Potential problems ot be aware of
 // - Two builds should not be started at the same time for the same container
 // - Two docker containers should not be created at the same time for the same container
@@ -170,9 +278,58 @@ pub(crate) struct Container {
     pub(crate) result: RwLock<Option<BuildResult>>,
     setup: Box<dyn ContainerSetup>,
     config: ContainerConfig,
+    /// runtime env, it can change after the image was built
+    env: RwLock<EnvVars>,
+    /// applied the next time the sidecars start, running ones are kept until then
+    sidecars: std::sync::RwLock<Vec<Sidecar>>,
+    /// set while a replacement container is started to apply a new env
+    restarting: AtomicBool,
+    /// stopped containers are not started, not even by requests
+    stopped: AtomicBool,
+    /// set while a start triggered by requests is running, so concurrent requests don't start it again
+    waking: AtomicBool,
     hooks: Box<dyn DeploymentHooks>,
     pub(crate) logging_deployment_id: Option<i64>,
     pub(crate) public: bool,
+    ip_filter: std::sync::RwLock<IpFilter>,
+    read_timeout: std::sync::RwLock<Option<Duration>>,
+    upstream_tls: std::sync::RwLock<UpstreamTls>,
+    body_rewrites: std::sync::RwLock<Arc<Vec<BodyRewrite>>>,
+    redirects: std::sync::RwLock<Arc<Vec<RedirectRule>>>,
+    route_allowlist: std::sync::RwLock<Arc<Vec<AllowedRoute>>>,
+    env_schema: std::sync::RwLock<Vec<EnvRule>>,
+    cold_start: ColdStart,
+    start_timings: std::sync::RwLock<StartTimings>,
+    start_history: std::sync::Mutex<StartHistory>,
+    /// the container is not put on standby while clients are listening to its events
+    open_streams: OpenStreams,
+    max_connections: std::sync::RwLock<Option<usize>>,
+    /// extra time the container keeps running once idle, so bursty traffic avoids cold starts
+    standby_grace: std::sync::RwLock<Duration>,
+    /// warmed up containers are not put on standby before this, idle time counts from then on
+    warm_until: std::sync::RwLock<Option<Instant>>,
+    /// folder of the image served by the proxy instead of running the app
+    static_dir: std::sync::RwLock<Option<String>>,
+    /// applied the next time the container starts
+    network: std::sync::RwLock<NetworkConfig>,
+    /// replaces the command of the image, applied the next time the container starts
+    start_command: std::sync::RwLock<Option<String>>,
+    /// starts failing for other reasons than the app exiting are retried these many times
+    start_retries: AtomicU32,
+    /// bounds the replicas are scaled between
+    replica_bounds: std::sync::RwLock<Replicas>,
+    /// app containers running next to the one in the status, sharing its sidecars
+    replicas: std::sync::Mutex<Vec<Replica>>,
+    /// app containers created but not referenced by the status or the replicas yet
+    starting: std::sync::Mutex<Vec<String>>,
+    /// requests in flight to the container in the status
+    in_flight: Arc<AtomicUsize>,
+    /// set while replicas are being added or removed
+    scaling: AtomicBool,
+    /// since when there are more replicas than the load needs
+    overprovisioned_since: std::sync::Mutex<Option<Instant>>,
+    /// read from the source at build time
+    repo_config: std::sync::RwLock<RepoConfig>,
     build_queue: WorkerHandle,
 }
 
@@ -190,19 +347,204 @@ impl Container {
             status: config.initial_status.clone().into(),
             result: RwLock::new(config.result),
             setup: Box::new(setup),
+            env: RwLock::new(config.env.clone()),
+            sidecars: std::sync::RwLock::new(config.sidecars.clone()),
+            restarting: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+            waking: AtomicBool::new(false),
             config,
             hooks: Box::new(hooks),
             logging_deployment_id,
             public,
+            ip_filter: Default::default(),
+            read_timeout: Default::default(),
+            upstream_tls: Default::default(),
+            body_rewrites: Default::default(),
+            redirects: Default::default(),
+            route_allowlist: Default::default(),
+            env_schema: Default::default(),
+            cold_start: Default::default(),
+            start_timings: Default::default(),
+            start_history: Default::default(),
+            open_streams: Default::default(),
+            max_connections: Default::default(),
+            standby_grace: Default::default(),
+            warm_until: Default::default(),
+            static_dir: Default::default(),
+            network: Default::default(),
+            start_command: Default::default(),
+            start_retries: Default::default(),
+            replica_bounds: Default::default(),
+            replicas: Default::default(),
+            starting: Default::default(),
+            in_flight: Default::default(),
+            scaling: AtomicBool::new(false),
+            overprovisioned_since: Default::default(),
+            repo_config: Default::default(),
             build_queue,
         }
     }
 
+    pub(crate) fn start_timings(&self) -> StartTimings {
+        *self.start_timings.read().unwrap()
+    }
+
+    pub(crate) fn set_ip_filter(&self, ip_filter: IpFilter) {
+        *self.ip_filter.write().unwrap() = ip_filter;
+    }
+
+    pub(crate) fn set_read_timeout(&self, read_timeout: Option<Duration>) {
+        *self.read_timeout.write().unwrap() = read_timeout;
+    }
+
+    pub(crate) fn set_upstream_tls(&self, upstream_tls: UpstreamTls) {
+        *self.upstream_tls.write().unwrap() = upstream_tls;
+    }
+
+    pub(crate) fn set_start_command(&self, start_command: Option<String>) {
+        *self.start_command.write().unwrap() = start_command;
+    }
+
+    pub(crate) fn set_sidecars(&self, sidecars: Vec<Sidecar>) {
+        *self.sidecars.write().unwrap() = sidecars;
+    }
+
+    pub(crate) fn set_start_retries(&self, retries: u32) {
+        self.start_retries.store(retries, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_replica_bounds(&self, bounds: Replicas) {
+        *self.replica_bounds.write().unwrap() = bounds;
+    }
+
+    pub(crate) fn set_max_connections(&self, max_connections: Option<usize>) {
+        *self.max_connections.write().unwrap() = max_connections;
+    }
+
+    pub(crate) fn set_standby_grace(&self, grace: Duration) {
+        *self.standby_grace.write().unwrap() = grace;
+    }
+
+    pub(crate) fn set_static_dir(&self, static_dir: Option<String>) {
+        *self.static_dir.write().unwrap() = static_dir;
+    }
+
+    pub(crate) fn set_network(&self, network: NetworkConfig) {
+        *self.network.write().unwrap() = network;
+    }
+
+    pub(crate) fn is_static(&self) -> bool {
+        self.static_dir.read().unwrap().is_some()
+    }
+
+    pub(crate) fn set_body_rewrites(&self, body_rewrites: Vec<BodyRewrite>) {
+        *self.body_rewrites.write().unwrap() = body_rewrites.into();
+    }
+
+    pub(crate) fn set_redirects(&self, redirects: Vec<RedirectRule>) {
+        *self.redirects.write().unwrap() = redirects.into();
+    }
+
+    pub(crate) fn set_route_allowlist(&self, routes: Vec<AllowedRoute>) {
+        *self.route_allowlist.write().unwrap() = routes.into();
+    }
+
+    pub(crate) fn set_env_schema(&self, schema: Vec<EnvRule>) {
+        *self.env_schema.write().unwrap() = schema;
+    }
+
+    /// where the proxy reaches the container, if running
+    pub(crate) async fn get_socket(&self) -> Option<SocketAddrV4> {
+        match *self.status.read().await {
+            ContainerStatus::Ready { socket, .. } => Some(socket),
+            _ => None,
+        }
+    }
+
     // TODO: review, do we really need to expose the container id in the api?
     pub(crate) async fn get_container_id(&self) -> Option<String> {
         self.status.read().await.get_container_id()
     }
 
+    pub(crate) fn set_stopped(&self, stopped: bool) {
+        self.stopped.store(stopped, Ordering::Relaxed);
+    }
+
+    pub(crate) fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    /// Releases the running container right away instead of waiting for it to be unused,
+    /// the docker worker removes it afterwards
+    pub(crate) async fn release(&self) {
+        let status = self.status.aquire().await;
+        let image = match status.read().await.deref() {
+            ContainerStatus::Ready { image, .. } => image.clone(),
+            _ => return,
+        };
+        *status.write().await = ContainerStatus::StandBy { image };
+        self.drain_replicas(usize::MAX);
+    }
+
+    pub(crate) async fn get_status(&self) -> Status {
+        if self.is_stopped() {
+            Status::Stopped
+        } else if self.restarting.load(Ordering::Relaxed) {
+            Status::Restarting
+        } else {
+            self.status.read().await.to_status()
+        }
+    }
+
+    pub(crate) async fn get_running_sidecars(&self) -> Vec<RunningSidecar> {
+        self.status.read().await.get_sidecars()
+    }
+
+    /// ids of the app docker container, its replicas and its sidecars, if running.
+    /// Draining replicas are included until they are removed
+    pub(crate) async fn get_all_container_ids(&self) -> Vec<String> {
+        let status = self.status.read().await;
+        let sidecars = status
+            .get_sidecars()
+            .into_iter()
+            .map(|sidecar| sidecar.container);
+        let replicas: Vec<_> = self
+            .replicas
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|replica| replica.container.clone())
+            .collect();
+        let starting = self.starting.lock().unwrap().clone();
+        status
+            .get_container_id()
+            .into_iter()
+            .chain(replicas)
+            .chain(starting)
+            .chain(sidecars)
+            .collect()
+    }
+
+    /// Called once a started container is referenced elsewhere, or to let the docker worker remove it
+    fn forget_starting(&self, container: &str) {
+        self.starting
+            .lock()
+            .unwrap()
+            .retain(|starting| starting != container);
+    }
+
+    /// app containers taking requests, 0 if not running
+    pub(crate) async fn get_replica_count(&self) -> usize {
+        if !matches!(*self.status.read().await, ContainerStatus::Ready { .. }) {
+            return 0;
+        }
+        let replicas = self.replicas.lock().unwrap();
+        1 + replicas
+            .iter()
+            .filter(|replica| !replica.is_draining())
+            .count()
+    }
+
     pub(crate) async fn get_logs(&self) -> Box<dyn Iterator<Item = DockerLog>> {
         if let Some(container) = self.get_container_id().await {
             Box::new(get_container_execution_logs(&container).await)
@@ -226,15 +568,23 @@ impl Container {
     }
 
     pub(crate) async fn downgrade_if_unused(&self) {
+        if self.open_streams.load(Ordering::Relaxed) > 0 {
+            return;
+        }
+        let idle_timeout = IDLE_TIMEOUT + *self.standby_grace.read().unwrap();
         let status = self.status.aquire().await;
 
         let new_status = if let ContainerStatus::Ready {
             image, last_access, ..
         } = status.read().await.deref()
         {
-            let last_access = last_access.read().await;
-            let elapsed = Instant::now().checked_duration_since(*last_access);
-            if elapsed.is_some_and(|elapsed| elapsed > Duration::from_secs(30)) {
+            let last_access = *last_access.read().await;
+            let idle_since = match *self.warm_until.read().unwrap() {
+                Some(warm_until) => last_access.max(warm_until),
+                None => last_access,
+            };
+            let elapsed = Instant::now().checked_duration_since(idle_since);
+            if elapsed.is_some_and(|elapsed| elapsed > idle_timeout) {
                 Some(ContainerStatus::StandBy {
                     image: image.clone(),
                 })
@@ -315,48 +665,79 @@ impl Container {
     async fn build_with_result(&self) -> anyhow::Result<String> {
         let tempdir = TempDir::new()?;
         let path = tempdir.as_ref();
-        let path = self.setup.setup_build_context(path.to_path_buf()).await?;
-        let image = build_dockerfile(&path, self.config.args.clone(), &mut |chunk| async {
-            if let Some(stream) = chunk.stream {
-                self.hooks.on_build_log(&stream, false).await
-            } else if let Some(error) = chunk.error {
-                self.hooks.on_build_log(&error, true).await
+        self.hooks.on_build_phase(PREPARE_PHASE).await;
+        let (path, repo_config, plan) = self.setup.setup_build_context(path.to_path_buf()).await?;
+        if let Some(plan) = &plan {
+            self.hooks.on_build_plan(plan).await;
+        }
+        let args = repo_config.build_env(self.config.args.clone());
+        *self.repo_config.write().unwrap() = repo_config;
+        let dockerfile = fs::read_to_string(path.join("Dockerfile")).await?;
+        let mut phases = BuildPhases::from_dockerfile(&dockerfile);
+        let image = build_dockerfile(&path, args, &mut |chunk| {
+            let phase = chunk
+                .stream
+                .as_deref()
+                .and_then(|stream| phases.transition(stream));
+            async move {
+                // the marker goes before the output of the step starting the phase
+                if let Some(phase) = phase {
+                    self.hooks.on_build_phase(&phase).await
+                }
+                if let Some(stream) = chunk.stream {
+                    self.hooks.on_build_log(&stream, false).await
+                } else if let Some(error) = chunk.error {
+                    self.hooks.on_build_log(&error, true).await
+                }
             }
         })
         .await?;
+        // the base images were pulled or reused by the build, so their local ids are the ones used
+        let base_images = get_base_image_ids(&path.join("Dockerfile")).await;
+        self.hooks.on_base_images(&base_images).await;
+        if let Some(size) = get_image_size(&image).await {
+            self.hooks.on_image_size(size).await;
+        }
 
         Ok(image)
     }
 
     pub(crate) async fn start(&self) -> anyhow::Result<SocketAddrV4> {
         let status = self.status.aquire().await;
+        if self.is_stopped() {
+            bail!("the container is stopped");
+        }
         let cloned_status = status.read().await.clone();
         if let ContainerStatus::StandBy { image } = cloned_status {
-            let container = create_container(
-                image.clone(),
-                self.config.env.clone(),
-                self.config.host_files.iter(),
-            )
-            .await?;
-            run_container(&container).await?;
-
-            let ip = get_bollard_container_ipv4(&container)
-                .await
-                .ok_or(anyhow!("Could not get IP for container"))?;
-            let socket = SocketAddrV4::new(ip, 80);
-            while !is_online(&socket.to_string()).await {
-                sleep(Duration::from_millis(200)).await;
-            }
+            let started = Instant::now();
+            *self.start_timings.write().unwrap() = StartTimings {
+                started: Some(now()),
+                ready: None,
+            };
+            let (container, socket, sidecars) =
+                match self.run_image_with_retries(&image, None).await {
+                    Ok(result) => result,
+                    Err(error) => {
+                        *status.write().await = ContainerStatus::Failed;
+                        return Err(error);
+                    }
+                };
+            *self.cold_start.lock().unwrap() = Some(started.elapsed());
+            self.start_history.lock().unwrap().record(started.elapsed());
 
             // FIXME: this will deadlock as status has a read lock on it
             // what im doing seems fundamentally wrong
             // maybe I should not be able to create a read lock on a WritableStatus
             *status.write().await = ContainerStatus::Ready {
                 image: image.clone(),
-                container,
+                container: container.clone(),
                 socket,
                 last_access: RwLock::new(Instant::now()).into(),
+                sidecars,
             };
+            self.forget_starting(&container);
+            self.start_timings.write().unwrap().ready = Some(now());
+            self.hooks.on_container_ready().await;
 
             Ok(socket)
         } else if let ContainerStatus::Ready { socket, .. } = cloned_status {
@@ -366,6 +747,376 @@ impl Container {
         }
     }
 
+    /// Starts the container ahead of traffic, optionally keeping it running for the pin duration.
+    /// Afterwards it goes to standby like after a request, once idle for the timeout and the grace period
+    pub(crate) async fn warm_up(&self, pin: Option<Duration>) -> anyhow::Result<()> {
+        if let Some(pin) = pin {
+            *self.warm_until.write().unwrap() = Some(Instant::now() + pin);
+        }
+        if self.is_static() {
+            return Ok(());
+        }
+        if let ContainerStatus::Ready { last_access, .. } = self.status.read().await.deref() {
+            *last_access.write().await = Instant::now();
+            return Ok(());
+        }
+        self.start().await?;
+        Ok(())
+    }
+
+    /// Starts the container in the background unless a request already did. Requests get
+    /// the loading page meanwhile, and all of them see the same failure if the start fails
+    fn wake_up(self: &Arc<Self>) {
+        if self.waking.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let container = self.clone();
+        tokio::spawn(async move {
+            if let Err(error) = container.start().await {
+                error!("failed to wake up container: {error}");
+            }
+            container.waking.store(false, Ordering::SeqCst);
+        });
+    }
+
+    /// Sets the env for the next container runs. If the container is running, a replacement
+    /// is started with the new env and traffic only moves to it once it is online
+    pub(crate) async fn restart_with_env(&self, env: EnvVars) -> anyhow::Result<()> {
+        *self.env.write().await = env;
+
+        let status = self.status.aquire().await;
+        let cloned_status = status.read().await.clone();
+        if let ContainerStatus::Ready {
+            image,
+            last_access,
+            sidecars,
+            ..
+        } = cloned_status
+        {
+            // requests keep reaching the old container in the meantime,
+            // it is removed by the docker worker once it is not referenced anymore.
+            // Sidecars don't get the project env, so the running ones are kept
+            self.restarting.store(true, Ordering::Relaxed);
+            let result = self.run_image_with_retries(&image, Some(sidecars)).await;
+            self.restarting.store(false, Ordering::Relaxed);
+            let (container, socket, sidecars) = result?;
+            *status.write().await = ContainerStatus::Ready {
+                image,
+                container: container.clone(),
+                socket,
+                last_access,
+                sidecars,
+            };
+            self.forget_starting(&container);
+            // the autoscaler replaces them with containers getting the new env
+            self.drain_replicas(usize::MAX);
+        }
+        Ok(())
+    }
+
+    /// Adds or drains replicas following the load, in the background so slow starts don't
+    /// hold back the other deployments. Only one scaling runs at a time for each container
+    pub(crate) fn autoscale(self: &Arc<Self>) {
+        if self.scaling.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let container = self.clone();
+        tokio::spawn(async move {
+            if let Err(error) = container.scale().await {
+                error!("failed to scale {}: {error}", container.log_name());
+            }
+            container.scaling.store(false, Ordering::SeqCst);
+        });
+    }
+
+    /// Replicas are started when the requests in flight per container go over the target,
+    /// passing the same health check as the main container before they get traffic.
+    /// They are drained one at a time once there are too many of them for `SCALE_DOWN_DELAY`,
+    /// and removed as soon as their container exits
+    async fn scale(&self) -> anyhow::Result<()> {
+        self.remove_exited_replicas().await;
+        self.remove_drained_replicas();
+
+        let bounds = *self.replica_bounds.read().unwrap();
+        let running = match self.status.read().await.deref() {
+            ContainerStatus::Ready {
+                image, sidecars, ..
+            } if !self.is_stopped() => Some((image.clone(), sidecars.clone())),
+            _ => None,
+        };
+        let Some((image, sidecars)) = running else {
+            // replicas follow the main container to standby
+            self.drain_replicas(usize::MAX);
+            return Ok(());
+        };
+
+        let (serving, in_flight) = {
+            let replicas = self.replicas.lock().unwrap();
+            let active = replicas.iter().filter(|replica| !replica.is_draining());
+            let in_flight = active
+                .clone()
+                .map(|replica| replica.in_flight.load(Ordering::Relaxed))
+                .sum::<usize>()
+                + self.in_flight.load(Ordering::Relaxed);
+            (1 + active.count(), in_flight)
+        };
+        let wanted = bounds.wanted(in_flight);
+
+        if wanted > serving {
+            *self.overprovisioned_since.lock().unwrap() = None;
+            for _ in serving..wanted {
+                let (container, socket, _) = self
+                    .run_image_with_retries(&image, Some(sidecars.clone()))
+                    .await?;
+                // if the main container was replaced or put on standby in the meantime,
+                // the docker worker removes the new one as it is not referenced
+                let current = self.status.read().await.get_image();
+                if self.is_stopped() || current.as_ref() != Some(&image) {
+                    self.forget_starting(&container);
+                    return Ok(());
+                }
+                self.replicas.lock().unwrap().push(Replica {
+                    container: container.clone(),
+                    socket,
+                    in_flight: Default::default(),
+                    draining_since: None,
+                });
+                self.forget_starting(&container);
+            }
+        } else if wanted < serving {
+            let now = Instant::now();
+            let mut overprovisioned_since = self.overprovisioned_since.lock().unwrap();
+            let since = *overprovisioned_since.get_or_insert(now);
+            if now.duration_since(since) >= SCALE_DOWN_DELAY {
+                self.drain_replicas(1);
+                // the next one waits for a full delay again
+                *overprovisioned_since = None;
+            }
+        } else {
+            *self.overprovisioned_since.lock().unwrap() = None;
+        }
+        Ok(())
+    }
+
+    /// Stops sending requests to the newest replicas, up to `count` of them
+    fn drain_replicas(&self, count: usize) {
+        let now = Instant::now();
+        let mut replicas = self.replicas.lock().unwrap();
+        let active = replicas
+            .iter_mut()
+            .rev()
+            .filter(|replica| !replica.is_draining());
+        for replica in active.take(count) {
+            replica.draining_since = Some(now);
+        }
+    }
+
+    /// Replicas are forgotten once their requests are done or after `DRAIN_TIMEOUT`,
+    /// the docker worker removes their containers afterwards
+    fn remove_drained_replicas(&self) {
+        self.replicas.lock().unwrap().retain(|replica| {
+            replica.draining_since.map_or(true, |since| {
+                replica.in_flight.load(Ordering::Relaxed) > 0 && since.elapsed() < DRAIN_TIMEOUT
+            })
+        });
+    }
+
+    async fn remove_exited_replicas(&self) {
+        let containers: Vec<_> = self
+            .replicas
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|replica| replica.container.clone())
+            .collect();
+        let mut exited = vec![];
+        for container in containers {
+            if let Ok(Some(code)) = runtime().get_container_exit_code(&container).await {
+                warn!("replica of {} exited with code {code}", self.log_name());
+                exited.push(container);
+            }
+        }
+        self.replicas
+            .lock()
+            .unwrap()
+            .retain(|replica| !exited.contains(&replica.container));
+    }
+
+    /// Least busy of the main container and the replicas taking requests, the main one wins ties
+    fn pick_socket(&self, main: SocketAddrV4) -> SocketAddrV4 {
+        let replicas = self.replicas.lock().unwrap();
+        let mut least_busy = (self.in_flight.load(Ordering::Relaxed), main);
+        for replica in replicas.iter().filter(|replica| !replica.is_draining()) {
+            let in_flight = replica.in_flight.load(Ordering::Relaxed);
+            if in_flight < least_busy.0 {
+                least_busy = (in_flight, replica.socket);
+            }
+        }
+        least_busy.1
+    }
+
+    fn log_name(&self) -> String {
+        match self.logging_deployment_id {
+            Some(id) => format!("deployment {id}"),
+            None => "db container".to_owned(),
+        }
+    }
+
+    /// Docker errors are usually transient, so they are retried with backoff,
+    /// but there is no point in retrying if the app itself exits
+    async fn run_image_with_retries(
+        &self,
+        image: &str,
+        sidecars: Option<Vec<RunningSidecar>>,
+    ) -> anyhow::Result<(String, SocketAddrV4, Vec<RunningSidecar>)> {
+        let attempts = self.start_retries.load(Ordering::Relaxed) + 1;
+        let container = self.log_name();
+        let mut delay = START_RETRY_DELAY;
+        let mut attempt = 1;
+        loop {
+            let error = match self.run_image(image, sidecars.clone()).await {
+                Ok(result) => return Ok(result),
+                Err(error) => error,
+            };
+            if attempt >= attempts || error.is::<AppExited>() || error.is::<InvalidEnv>() {
+                error!("start {attempt}/{attempts} of {container} failed, giving up: {error}");
+                return Err(error);
+            }
+            warn!(
+                "start {attempt}/{attempts} of {container} failed, retrying in {delay:?}: {error}"
+            );
+            sleep(delay).await;
+            delay *= 2;
+            attempt += 1;
+        }
+    }
+
+    /// Starts the sidecars, unless already running ones are provided, and then the app container
+    async fn run_image(
+        &self,
+        image: &str,
+        sidecars: Option<Vec<RunningSidecar>>,
+    ) -> anyhow::Result<(String, SocketAddrV4, Vec<RunningSidecar>)> {
+        let repo_config = self.repo_config.read().unwrap().clone();
+        let port = self
+            .config
+            .port
+            .or(repo_config.port)
+            .unwrap_or(DEFAULT_PORT);
+        let port_env = port.to_string();
+        let env = self.env.read().await.clone() + EnvVars::new(&[("PORT", &port_env)]);
+        let health_check_path = repo_config.health_check_path.as_deref().unwrap_or("/");
+        let network = self.network.read().unwrap().clone();
+        let start_command = self.start_command.read().unwrap().clone();
+
+        // checked before anything is started, the errors end up next to the build logs
+        let env_map: HashMap<String, String> = env.clone().into();
+        let mut undecrypted: Vec<_> = env_map
+            .iter()
+            .filter(|(_, value)| Secrets::is_secret(value))
+            .map(|(name, _)| name.as_str())
+            .collect();
+        if !undecrypted.is_empty() {
+            undecrypted.sort();
+            let message = format!(
+                "failed to decrypt the secrets {}, the key that encrypted them is not configured",
+                undecrypted.join(", ")
+            );
+            self.hooks.on_build_log(&message, true).await;
+            return Err(InvalidEnv(message).into());
+        }
+        let schema = self.env_schema.read().unwrap().clone();
+        if let Err(error) = env.validate(&schema) {
+            let message = error.to_string();
+            self.hooks.on_build_log(&message, true).await;
+            return Err(InvalidEnv(message).into());
+        }
+
+        let sidecars = match sidecars {
+            Some(sidecars) => sidecars,
+            None => {
+                // if any of these fails, the ones already started are removed by the docker worker
+                let mut running: Vec<RunningSidecar> = vec![];
+                let configured = self.sidecars.read().unwrap().clone();
+                for sidecar in Sidecar::start_order(&configured)? {
+                    let extra_hosts = running
+                        .iter()
+                        .filter(|started| sidecar.depends_on.contains(&started.name))
+                        .map(RunningSidecar::host_entry)
+                        .collect();
+                    running.push(sidecar.start(extra_hosts).await?);
+                }
+                running
+            }
+        };
+        let extra_hosts = sidecars.iter().map(RunningSidecar::host_entry).collect();
+
+        let container = create_container(
+            image.to_owned(),
+            env,
+            self.config.host_files.iter(),
+            &self.config.volumes,
+            extra_hosts,
+            &network,
+            start_command,
+        )
+        .await?;
+        // kept away from the docker worker until the caller references it
+        self.starting.lock().unwrap().push(container.clone());
+        match self
+            .start_app_container(&container, port, health_check_path)
+            .await
+        {
+            Ok(socket) => Ok((container, socket, sidecars)),
+            Err(error) => {
+                self.forget_starting(&container);
+                Err(error)
+            }
+        }
+    }
+
+    async fn start_app_container(
+        &self,
+        container: &str,
+        port: u16,
+        health_check_path: &str,
+    ) -> anyhow::Result<SocketAddrV4> {
+        runtime().start_container(container).await?;
+
+        let ip = get_bollard_container_ipv4(container)
+            .await
+            .ok_or(anyhow!("Could not get IP for container"))?;
+        let socket = SocketAddrV4::new(ip, port);
+        let tls = self.upstream_tls.read().unwrap().is_enabled();
+        while !is_online(&socket.to_string(), health_check_path, tls).await {
+            if let Some(exit_code) = runtime().get_container_exit_code(container).await? {
+                return Err(AppExited(exit_code).into());
+            }
+            sleep(Duration::from_millis(200)).await;
+        }
+        Ok(socket)
+    }
+
+    /// Folder with the static files of the image, they are copied out of it on the first access
+    async fn get_static_files(&self, image: &str, static_dir: &str) -> anyhow::Result<PathBuf> {
+        let root = get_image_static_dir(image).join(hex::encode(static_dir));
+        if fs::try_exists(&root).await? {
+            return Ok(root);
+        }
+        // holding the status lock so concurrent requests don't copy the files twice
+        let _status = self.status.aquire().await;
+        if !fs::try_exists(&root).await? {
+            let partial = root.with_extension("partial");
+            if fs::try_exists(&partial).await? {
+                fs::remove_dir_all(&partial).await?;
+            }
+            fs::create_dir_all(&partial).await?;
+            copy_from_image(image, static_dir, &partial).await?;
+            fs::rename(&partial, &root).await?;
+        }
+        Ok(root)
+    }
+
     // async fn commit_access(&self) -> anyhow::Result<RwLockReadGuard<ContainerStatus>> {
     //     let status = self.status.read().await;
     //     if let ContainerStatus::Ready {last_access, ..} = status.deref() {
@@ -383,7 +1134,78 @@ impl Listener for Arc<Container> {
         self.public
     }
 
+    fn is_ip_allowed(&self, ip: IpAddr) -> bool {
+        self.ip_filter.read().unwrap().is_allowed(ip)
+    }
+
+    fn read_timeout(&self) -> Option<Duration> {
+        *self.read_timeout.read().unwrap()
+    }
+
+    fn upstream_tls(&self) -> UpstreamTls {
+        *self.upstream_tls.read().unwrap()
+    }
+
+    fn body_rewrites(&self) -> Arc<Vec<BodyRewrite>> {
+        self.body_rewrites.read().unwrap().clone()
+    }
+
+    fn redirects(&self) -> Arc<Vec<RedirectRule>> {
+        self.redirects.read().unwrap().clone()
+    }
+
+    fn route_allowlist(&self) -> Arc<Vec<AllowedRoute>> {
+        self.route_allowlist.read().unwrap().clone()
+    }
+
+    fn cold_start(&self) -> Option<ColdStart> {
+        Some(self.cold_start.clone())
+    }
+
+    fn open_streams(&self) -> Option<OpenStreams> {
+        Some(self.open_streams.clone())
+    }
+
+    fn max_connections(&self) -> Option<usize> {
+        *self.max_connections.read().unwrap()
+    }
+
+    /// Only known while a start triggered by requests is running, and once the container
+    /// started before. Zero if the start already takes longer than usual
+    fn ready_in(&self) -> Option<Duration> {
+        if !self.waking.load(Ordering::SeqCst) {
+            return None;
+        }
+        let StartTimings {
+            started: Some(started),
+            ready: None,
+        } = self.start_timings()
+        else {
+            return None;
+        };
+        let average = self.start_history.lock().unwrap().average()?;
+        let elapsed = Duration::from_millis(now().saturating_sub(started).max(0) as u64);
+        Some(average.saturating_sub(elapsed))
+    }
+
+    /// sockets other than the ones of the replicas belong to the main container
+    fn in_flight(&self, socket: SocketAddrV4) -> Option<InFlight> {
+        let replicas = self.replicas.lock().unwrap();
+        let counter = match replicas.iter().find(|replica| replica.socket == socket) {
+            Some(replica) => replica.in_flight.clone(),
+            None => self.in_flight.clone(),
+        };
+        Some(InFlight::new(counter))
+    }
+
+    fn is_stopped(&self) -> bool {
+        Container::is_stopped(self)
+    }
+
     async fn access(&self) -> anyhow::Result<Access> {
+        if self.is_stopped() {
+            return Ok(Access::Stopped);
+        }
         let socket = match self.status.read().await.deref() {
             ContainerStatus::Ready {
                 socket,
@@ -391,7 +1213,7 @@ impl Listener for Arc<Container> {
                 ..
             } => {
                 *last_access.write().await = Instant::now();
-                Some(socket.clone())
+                Some(self.pick_socket(*socket))
             }
             _ => None,
         };
@@ -417,11 +1239,20 @@ impl Listener for Arc<Container> {
                     } => {
                         // FIXME: boilerplate in here
                         *last_access.write().await = Instant::now();
-                        Ok(Access::Socket(socket.clone()))
+                        Ok(Access::Socket(self.pick_socket(socket)))
                     }
-                    ContainerStatus::StandBy { .. } => {
-                        let socket = self.start().await?;
-                        Ok(Access::Socket(socket))
+                    ContainerStatus::StandBy { image } => {
+                        let static_dir = self.static_dir.read().unwrap().clone();
+                        match static_dir {
+                            Some(static_dir) => {
+                                let root = self.get_static_files(&image, &static_dir).await?;
+                                Ok(Access::Static(root))
+                            }
+                            None => {
+                                self.wake_up();
+                                Ok(Access::Loading)
+                            }
+                        }
                     }
                     ContainerStatus::Built => {
                         *self.status.aquire().await.write().await = ContainerStatus::Queued {
@@ -434,7 +1265,7 @@ impl Listener for Arc<Container> {
                         Ok(Access::Loading)
                     }
                     ContainerStatus::Failed => {
-                        bail!("container failed to build")
+                        bail!("container failed to build or start")
                     }
                 }
             }
@@ -444,9 +1275,19 @@ impl Listener for Arc<Container> {
 
 // FIXME: this might fail, especially for some API server with no / route
 // there has to be another way
-async fn is_online(host: &str) -> bool {
-    let url = format!("http://{host}");
-    let response = reqwest::get(url).await;
+/// With TLS the certificate is not checked, as the container is reached by its ip.
+/// The proxy checks it on the actual requests, unless told to skip it
+async fn is_online(host: &str, path: &str, tls: bool) -> bool {
+    let path = path.trim_start_matches('/');
+    let scheme = if tls { "https" } else { "http" };
+    let url = format!("{scheme}://{host}/{path}");
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build();
+    let Ok(client) = client else {
+        return false;
+    };
+    let response = client.get(url).send().await;
     match response {
         Ok(response) => response.status() == StatusCode::OK,
         Err(_) => false,
@@ -0,0 +1,59 @@
+use std::net::IpAddr;
+
+use anyhow::{bail, ensure};
+
+use crate::docker::NETWORK_NAME;
+
+/// Docker networking of the app container on top of the prezel network
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct NetworkConfig {
+    /// existing docker network the container joins as well
+    pub(crate) network: Option<String>,
+    pub(crate) dns_servers: Vec<String>,
+}
+
+pub(crate) fn validate_network(network: &str) -> anyhow::Result<()> {
+    let mut chars = network.chars();
+    let valid_name = chars
+        .next()
+        .is_some_and(|char| char.is_ascii_alphanumeric())
+        && chars.all(|char| char.is_ascii_alphanumeric() || matches!(char, '_' | '.' | '-'));
+    ensure!(valid_name, "invalid docker network name: {network}");
+    // containers are always attached to the prezel network, which can't be mixed with these
+    if matches!(network, "host" | "none" | NETWORK_NAME) {
+        bail!("the {network} network can not be used");
+    }
+    Ok(())
+}
+
+pub(crate) fn validate_dns_servers(servers: &[String]) -> anyhow::Result<()> {
+    for server in servers {
+        if server.parse::<IpAddr>().is_err() {
+            bail!("invalid DNS server {server}, it needs to be an IP address");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod network_tests {
+    use super::{validate_dns_servers, validate_network};
+
+    #[test]
+    fn test_validate_network() {
+        assert!(validate_network("shared-db_net.1").is_ok());
+        assert!(validate_network("").is_err());
+        assert!(validate_network("-net").is_err());
+        assert!(validate_network("my net").is_err());
+        assert!(validate_network("host").is_err());
+        assert!(validate_network("prezel").is_err());
+    }
+
+    #[test]
+    fn test_validate_dns_servers() {
+        assert!(
+            validate_dns_servers(&["1.1.1.1".to_owned(), "2606:4700::1111".to_owned()]).is_ok()
+        );
+        assert!(validate_dns_servers(&["dns.example.com".to_owned()]).is_err());
+    }
+}
@@ -10,8 +10,8 @@ use crate::{
 };
 
 use super::{
-    BuildResult, Container, ContainerConfig, ContainerSetup, ContainerStatus, ContextBuilderOutput,
-    FileSystemOutput,
+    build_plan::BuildPlan, repo_config::RepoConfig, BuildResult, Container, ContainerConfig,
+    ContainerSetup, ContainerStatus, ContextBuilderOutput, FileSystemOutput, DEFAULT_PORT,
 };
 
 const PRISMA_DOCKERFILE: &'static str = include_str!("../../resources/prisma.Dockerfile");
@@ -28,6 +28,9 @@ impl PrismaContainer {
             ContainerConfig {
                 args: EnvVars::empty(),
                 host_files: vec![db_file.clone()],
+                volumes: vec![],
+                sidecars: vec![],
+                port: Some(DEFAULT_PORT),
                 env: [(
                     "DATABASE_URL",
                     db_file.get_container_file().to_str().unwrap(),
@@ -43,10 +46,12 @@ impl PrismaContainer {
             NoopHooks,
         )
     }
-    async fn build_context(path: PathBuf) -> anyhow::Result<PathBuf> {
+    async fn build_context(
+        path: PathBuf,
+    ) -> anyhow::Result<(PathBuf, RepoConfig, Option<BuildPlan>)> {
         let dockerfile = path.join("Dockerfile");
         fs::write(dockerfile, PRISMA_DOCKERFILE).await?;
-        Ok(path)
+        Ok((path, RepoConfig::default(), None))
     }
 }
 
@@ -0,0 +1,134 @@
+use std::{
+    net::SocketAddrV4,
+    sync::{atomic::AtomicUsize, Arc},
+    time::{Duration, Instant},
+};
+
+use anyhow::ensure;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// how often the replicas of every deployment are adjusted to its load
+pub(crate) const AUTOSCALE_TICK: Duration = Duration::from_secs(5);
+/// the load needs to stay low for this long before a replica is drained, so short dips don't cause churn
+pub(crate) const SCALE_DOWN_DELAY: Duration = Duration::from_secs(60);
+/// draining replicas are removed after this even if some requests are still in flight
+pub(crate) const DRAIN_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_REPLICAS: u32 = 16;
+
+/// Bounds the number of app containers of each deployment is scaled between,
+/// based on the requests in flight to them
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+pub(crate) struct Replicas {
+    /// Containers kept running while the deployment is running
+    #[serde(default = "default_min")]
+    pub(crate) min: u32,
+    /// Upper bound, up to 16. 1 turns autoscaling off
+    #[serde(default = "default_max")]
+    pub(crate) max: u32,
+    /// Concurrent requests each container is meant to take, more containers are started above it
+    #[serde(default = "default_target_concurrency")]
+    pub(crate) target_concurrency: u32,
+}
+
+fn default_min() -> u32 {
+    1
+}
+
+fn default_max() -> u32 {
+    1
+}
+
+fn default_target_concurrency() -> u32 {
+    10
+}
+
+impl Default for Replicas {
+    fn default() -> Self {
+        Self {
+            min: default_min(),
+            max: default_max(),
+            target_concurrency: default_target_concurrency(),
+        }
+    }
+}
+
+impl Replicas {
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        ensure!(
+            self.min >= 1,
+            "the min number of replicas needs to be at least 1"
+        );
+        ensure!(
+            self.min <= self.max,
+            "the min number of replicas can't be greater than the max"
+        );
+        ensure!(
+            self.max <= MAX_REPLICAS,
+            "the max number of replicas can't be greater than {MAX_REPLICAS}"
+        );
+        ensure!(
+            self.target_concurrency >= 1,
+            "the target concurrency needs to be at least 1"
+        );
+        Ok(())
+    }
+
+    /// Containers needed for the requests in flight, within the bounds
+    pub(crate) fn wanted(&self, in_flight: usize) -> usize {
+        let target = self.target_concurrency.max(1) as usize;
+        in_flight
+            .div_ceil(target)
+            .clamp(self.min as usize, self.max as usize)
+    }
+}
+
+/// Extra app container of a deployment, next to the one in its status
+#[derive(Debug)]
+pub(crate) struct Replica {
+    pub(crate) container: String,
+    pub(crate) socket: SocketAddrV4,
+    pub(crate) in_flight: Arc<AtomicUsize>,
+    /// set once it stops getting new requests, it is removed when the ones in flight are done
+    pub(crate) draining_since: Option<Instant>,
+}
+
+impl Replica {
+    pub(crate) fn is_draining(&self) -> bool {
+        self.draining_since.is_some()
+    }
+}
+
+#[cfg(test)]
+mod replicas_tests {
+    use super::Replicas;
+
+    #[test]
+    fn test_wanted_replicas() {
+        let replicas = Replicas {
+            min: 2,
+            max: 5,
+            target_concurrency: 10,
+        };
+        replicas.validate().unwrap();
+        assert_eq!(replicas.wanted(0), 2);
+        assert_eq!(replicas.wanted(20), 2);
+        assert_eq!(replicas.wanted(21), 3);
+        assert_eq!(replicas.wanted(1000), 5);
+
+        assert_eq!(Replicas::default().wanted(1000), 1);
+    }
+
+    #[test]
+    fn test_invalid_replicas() {
+        let replicas = |min, max, target_concurrency| Replicas {
+            min,
+            max,
+            target_concurrency,
+        };
+        assert!(replicas(0, 1, 10).validate().is_err());
+        assert!(replicas(3, 2, 10).validate().is_err());
+        assert!(replicas(1, 17, 10).validate().is_err());
+        assert!(replicas(1, 2, 0).validate().is_err());
+    }
+}
@@ -0,0 +1,149 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::Context;
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::fs;
+use utoipa::ToSchema;
+
+use crate::env::EnvVars;
+
+pub(crate) const REPO_CONFIG_FILE: &str = "prezel.json";
+
+/// Deployment settings that live with the code, the project settings take precedence over them
+#[derive(Deserialize, Debug, Clone, Default, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RepoConfig {
+    /// Replaces the install command detected by nixpacks
+    pub(crate) install_command: Option<String>,
+    /// Replaces the build command detected by nixpacks
+    pub(crate) build_command: Option<String>,
+    /// Replaces the start command detected by nixpacks
+    pub(crate) start_command: Option<String>,
+    /// Defaults for the project env
+    #[serde(default)]
+    pub(crate) env: HashMap<String, String>,
+    /// Path that needs to respond with 200 before the deployment gets traffic
+    pub(crate) health_check_path: Option<String>,
+    /// Port the app listens on, unless the project sets one
+    pub(crate) port: Option<u16>,
+}
+
+impl RepoConfig {
+    /// A missing file is the same as an empty one
+    pub(crate) async fn read(root: &Path) -> anyhow::Result<Self> {
+        let path = root.join(REPO_CONFIG_FILE);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = fs::read_to_string(&path).await?;
+        Self::parse(&content).with_context(|| format!("invalid {REPO_CONFIG_FILE}"))
+    }
+
+    /// Same parsing the builds use, the api validates the file with it as well
+    pub(crate) fn parse(content: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(content)
+    }
+
+    /// JSON schema of the file, derived from this same type so both can't diverge
+    pub(crate) fn json_schema() -> Value {
+        let (_, schema) = <Self as ToSchema>::schema();
+        let mut schema = serde_json::to_value(schema).unwrap();
+        to_json_schema(&mut schema);
+        if let Value::Object(object) = &mut schema {
+            object.insert(
+                "$schema".to_owned(),
+                "http://json-schema.org/draft-07/schema#".into(),
+            );
+            object.insert("title".to_owned(), REPO_CONFIG_FILE.into());
+        }
+        schema
+    }
+
+    /// Env for the nixpacks build, the build commands are passed the same way nixpacks
+    /// reads them from the environment, so the ones in the project env still win
+    pub(crate) fn build_env(&self, project_env: EnvVars) -> EnvVars {
+        let commands = [
+            ("NIXPACKS_INSTALL_CMD", &self.install_command),
+            ("NIXPACKS_BUILD_CMD", &self.build_command),
+            ("NIXPACKS_START_CMD", &self.start_command),
+        ];
+        let commands: HashMap<_, _> = commands
+            .into_iter()
+            .filter_map(|(name, command)| Some((name.to_owned(), command.clone()?)))
+            .collect();
+        EnvVars::from(self.env.clone()) + commands.into() + project_env
+    }
+}
+
+/// The schemas are OpenAPI ones, which mark optional values as `nullable`
+/// instead of adding `null` to the allowed types
+fn to_json_schema(schema: &mut Value) {
+    match schema {
+        Value::Object(object) => {
+            if object.remove("nullable") == Some(Value::Bool(true)) {
+                if let Some(Value::String(kind)) = object.get("type") {
+                    let kind = kind.clone();
+                    object.insert("type".to_owned(), serde_json::json!([kind, "null"]));
+                }
+            }
+            object.values_mut().for_each(to_json_schema);
+        }
+        Value::Array(values) => values.iter_mut().for_each(to_json_schema),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod repo_config_tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use super::RepoConfig;
+    use crate::env::EnvVars;
+
+    #[test]
+    fn test_project_env_wins() {
+        let config: RepoConfig = serde_json::from_str(
+            r#"{ "build_command": "npm run build", "env": { "A": "file", "B": "file" } }"#,
+        )
+        .unwrap();
+        let env: HashMap<_, _> = config.build_env(EnvVars::new(&[("B", "db")])).into();
+        assert_eq!(env["A"], "file");
+        assert_eq!(env["B"], "db");
+        assert_eq!(env["NIXPACKS_BUILD_CMD"], "npm run build");
+        assert!(!env.contains_key("NIXPACKS_START_CMD"));
+    }
+
+    #[test]
+    fn test_json_schema() {
+        let schema = RepoConfig::json_schema();
+        let properties = &schema["properties"];
+        assert_eq!(
+            properties["build_command"]["type"],
+            json!(["string", "null"])
+        );
+        assert_eq!(properties["env"]["type"], "object");
+        assert!(!schema.to_string().contains("nullable"));
+        // every field the parser reads is in the schema
+        let config = json!({ "install_command": "a", "build_command": "b", "start_command": "c", "env": {}, "health_check_path": "/", "port": 3000 });
+        for key in config.as_object().unwrap().keys() {
+            assert!(
+                properties.get(key).is_some(),
+                "{key} missing from the schema"
+            );
+        }
+        assert!(RepoConfig::parse(&config.to_string()).is_ok());
+    }
+
+    #[test]
+    fn test_unknown_key() {
+        // typos are reported instead of being silently ignored
+        assert!(RepoConfig::parse(r#"{ "build_comand": "npm run build" }"#).is_err());
+        assert_eq!(
+            RepoConfig::json_schema()["additionalProperties"],
+            json!(false)
+        );
+    }
+}
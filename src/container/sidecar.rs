@@ -0,0 +1,188 @@
+use std::{net::Ipv4Addr, time::Duration};
+
+use anyhow::{anyhow, bail};
+use serde::{Deserialize, Serialize};
+use tokio::{net::TcpStream, time::sleep};
+use utoipa::ToSchema;
+
+use crate::{
+    docker::{create_sidecar_container, get_bollard_container_ipv4, pull_image},
+    runtime::runtime,
+};
+
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Additional container started and stopped together with the app container of a deployment.
+/// Sidecars only get the env declared for them, the project env is never passed down
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub(crate) struct Sidecar {
+    /// Hostname the app container can use to reach the sidecar
+    pub(crate) name: String,
+    /// Docker image, pulled from the registry if missing
+    pub(crate) image: String,
+    /// Env vars for the sidecar, in the same format as the project env
+    #[serde(default)]
+    pub(crate) env: String,
+    /// If set, the app container is not started until the sidecar accepts TCP connections on this port
+    pub(crate) port: Option<u16>,
+    /// Names of the sidecars that need to be ready before this one starts
+    #[serde(default)]
+    pub(crate) depends_on: Vec<String>,
+}
+
+impl Sidecar {
+    pub(crate) fn validate(sidecars: &[Sidecar]) -> anyhow::Result<()> {
+        for (index, sidecar) in sidecars.iter().enumerate() {
+            let valid_name = !sidecar.name.is_empty()
+                && sidecar
+                    .name
+                    .chars()
+                    .all(|char| char.is_ascii_lowercase() || char.is_ascii_digit() || char == '-');
+            if !valid_name {
+                bail!("invalid sidecar name: {}", sidecar.name);
+            }
+            if sidecars[..index]
+                .iter()
+                .any(|other| other.name == sidecar.name)
+            {
+                bail!("duplicated sidecar name: {}", sidecar.name);
+            }
+            if sidecar.image.is_empty() {
+                bail!("missing image for sidecar {}", sidecar.name);
+            }
+        }
+        Self::start_order(sidecars)?;
+        Ok(())
+    }
+
+    /// Every sidecar comes after the ones it depends on, otherwise the declared order is kept.
+    /// Fails if a dependency is missing or some sidecars depend on each other
+    pub(crate) fn start_order(sidecars: &[Sidecar]) -> anyhow::Result<Vec<&Sidecar>> {
+        fn visit<'a>(
+            sidecar: &'a Sidecar,
+            sidecars: &'a [Sidecar],
+            visiting: &mut Vec<&'a str>,
+            order: &mut Vec<&'a Sidecar>,
+        ) -> anyhow::Result<()> {
+            if order.iter().any(|added| added.name == sidecar.name) {
+                return Ok(());
+            }
+            if visiting.contains(&sidecar.name.as_str()) {
+                bail!(
+                    "sidecars depend on each other: {} -> {}",
+                    visiting.join(" -> "),
+                    sidecar.name
+                );
+            }
+            visiting.push(&sidecar.name);
+            for dependency in &sidecar.depends_on {
+                let Some(dependency) = sidecars.iter().find(|other| &other.name == dependency)
+                else {
+                    bail!(
+                        "sidecar {} depends on unknown sidecar {dependency}",
+                        sidecar.name
+                    );
+                };
+                visit(dependency, sidecars, visiting, order)?;
+            }
+            visiting.pop();
+            order.push(sidecar);
+            Ok(())
+        }
+
+        let mut order = vec![];
+        for sidecar in sidecars {
+            visit(sidecar, sidecars, &mut vec![], &mut order)?;
+        }
+        Ok(order)
+    }
+
+    /// Only returns once the sidecar is ready, so the ones depending on it can start next.
+    /// `extra_hosts` are the entries of the sidecars it depends on
+    pub(crate) async fn start(&self, extra_hosts: Vec<String>) -> anyhow::Result<RunningSidecar> {
+        pull_image(&self.image).await?;
+        let container =
+            create_sidecar_container(&self.image, self.env.as_str().into(), extra_hosts).await?;
+        runtime().start_container(&container).await?;
+        let ip = get_bollard_container_ipv4(&container)
+            .await
+            .ok_or(anyhow!("Could not get IP for sidecar {}", self.name))?;
+
+        if let Some(port) = self.port {
+            let started = tokio::time::Instant::now();
+            while TcpStream::connect((ip, port)).await.is_err() {
+                if started.elapsed() > HEALTH_CHECK_TIMEOUT {
+                    bail!("sidecar {} did not open port {port}", self.name);
+                }
+                sleep(Duration::from_millis(200)).await;
+            }
+        }
+
+        Ok(RunningSidecar {
+            name: self.name.clone(),
+            container,
+            ip,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct RunningSidecar {
+    pub(crate) name: String,
+    pub(crate) container: String,
+    ip: Ipv4Addr,
+}
+
+impl RunningSidecar {
+    /// entry for the app container /etc/hosts
+    pub(crate) fn host_entry(&self) -> String {
+        format!("{}:{}", self.name, self.ip)
+    }
+}
+
+#[cfg(test)]
+mod sidecar_tests {
+    use super::Sidecar;
+
+    fn sidecar(name: &str) -> Sidecar {
+        Sidecar {
+            name: name.to_owned(),
+            image: "redis:7".to_owned(),
+            env: "".to_owned(),
+            port: Some(6379),
+            depends_on: vec![],
+        }
+    }
+
+    fn depending(name: &str, depends_on: &[&str]) -> Sidecar {
+        Sidecar {
+            depends_on: depends_on.iter().map(|name| name.to_string()).collect(),
+            ..sidecar(name)
+        }
+    }
+
+    #[test]
+    fn test_start_order() {
+        let sidecars = [
+            depending("worker", &["db", "redis"]),
+            depending("redis", &[]),
+            depending("db", &["redis"]),
+        ];
+        let order = Sidecar::start_order(&sidecars).unwrap();
+        let names: Vec<_> = order.iter().map(|sidecar| sidecar.name.as_str()).collect();
+        assert_eq!(names, ["redis", "db", "worker"]);
+
+        let cycle = [depending("a", &["b"]), depending("b", &["a"])];
+        assert!(Sidecar::validate(&cycle).is_err());
+        assert!(Sidecar::validate(&[depending("a", &["a"])]).is_err());
+        assert!(Sidecar::validate(&[depending("a", &["missing"])]).is_err());
+    }
+
+    #[test]
+    fn test_validate_sidecars() {
+        assert!(Sidecar::validate(&[sidecar("redis"), sidecar("worker-1")]).is_ok());
+        assert!(Sidecar::validate(&[sidecar("redis"), sidecar("redis")]).is_err());
+        assert!(Sidecar::validate(&[sidecar("Redis")]).is_err());
+        assert!(Sidecar::validate(&[sidecar("")]).is_err());
+    }
+}
@@ -4,12 +4,25 @@ use futures::{future::join_all, stream, StreamExt};
 use log::info;
 use nanoid::nanoid;
 use serde::Deserialize;
-use sqlx::{sqlite::SqlitePool, FromRow, Pool, Sqlite};
+use sqlx::{sqlite::SqlitePool, FromRow, Pool, Sqlite, Transaction};
 use utoipa::ToSchema;
 
 use crate::{
     alphabet,
+    container::{
+        build_plan::{BuildEnvironment, BuildPlan},
+        replicas::Replicas,
+        sidecar::Sidecar,
+    },
+    env::{EnvRule, EnvVars},
     paths::get_instance_db_path,
+    proxy::{
+        redirect::{CanonicalHost, RedirectRule},
+        rewrite::BodyRewrite,
+        routes::AllowedRoute,
+        upstream_tls::UpstreamTls,
+    },
+    secrets::Secrets,
     time::{self, now},
 };
 
@@ -29,6 +42,43 @@ pub(crate) struct PlainProject {
     pub(crate) env: String,
     pub(crate) root: String,
     pub(crate) prod_id: Option<i64>,
+    pub(crate) volume_path: Option<String>,
+    pub(crate) ip_allowlist: String,
+    pub(crate) ip_denylist: String,
+    pub(crate) sidecars: String,
+    pub(crate) container_port: Option<i64>,
+    pub(crate) deleted: Option<i64>,
+    pub(crate) delete_volume: i64,
+    pub(crate) request_timeout_secs: Option<i64>,
+    pub(crate) canary_deployment_id: Option<i64>,
+    pub(crate) canary_percent: i64,
+    pub(crate) fallback_deployment_id: Option<i64>,
+    pub(crate) poll_interval_secs: Option<i64>,
+    pub(crate) pin_env: i64,
+    pub(crate) body_rewrites: String,
+    pub(crate) max_connections: Option<i64>,
+    pub(crate) redirects: String,
+    pub(crate) env_profiles: String,
+    pub(crate) static_dir: Option<String>,
+    pub(crate) docker_network: Option<String>,
+    pub(crate) dns_servers: String,
+    pub(crate) standby_grace_secs: Option<i64>,
+    pub(crate) hostname_prefix: Option<String>,
+    pub(crate) route_allowlist: String,
+    pub(crate) rebuild_schedule: Option<String>,
+    pub(crate) env_schema: String,
+    pub(crate) build_environment: String,
+    pub(crate) canonical_host: String,
+    pub(crate) prod_branch: Option<String>,
+    pub(crate) version: i64,
+    pub(crate) log_retention_days: Option<i64>,
+    pub(crate) upstream_tls: String,
+    pub(crate) env_groups: String,
+    pub(crate) mirror_deployment_id: Option<i64>,
+    pub(crate) mirror_percent: i64,
+    pub(crate) mirror_all_methods: i64,
+    pub(crate) start_command: Option<String>,
+    pub(crate) replicas: String,
 }
 
 #[derive(Clone, Debug)]
@@ -40,11 +90,72 @@ pub(crate) struct Project {
     pub(crate) env: String,
     pub(crate) root: String,
     pub(crate) prod_id: Option<i64>,
+    pub(crate) volume_path: Option<String>,
+    pub(crate) ip_allowlist: Vec<String>,
+    pub(crate) ip_denylist: Vec<String>,
+    pub(crate) sidecars: Vec<Sidecar>,
+    pub(crate) container_port: Option<i64>,
+    pub(crate) request_timeout_secs: Option<i64>,
+    /// deployment getting `canary_percent` of the production traffic
+    pub(crate) canary_deployment_id: Option<i64>,
+    pub(crate) canary_percent: i64,
+    /// hot standby the production requests fail over to
+    pub(crate) fallback_deployment_id: Option<i64>,
+    /// shadow deployment getting a copy of `mirror_percent` of the production requests
+    pub(crate) mirror_deployment_id: Option<i64>,
+    pub(crate) mirror_percent: i64,
+    /// mirror every method instead of only GET, HEAD and OPTIONS
+    pub(crate) mirror_all_methods: bool,
+    pub(crate) poll_interval_secs: Option<i64>,
+    /// deployments keep the env they were created with instead of following the project env
+    pub(crate) pin_env: bool,
+    pub(crate) body_rewrites: Vec<BodyRewrite>,
+    /// concurrent requests the proxy sends to each deployment
+    pub(crate) max_connections: Option<i64>,
+    pub(crate) redirects: Vec<RedirectRule>,
+    /// requests not matching any of them are rejected by the proxy, any request if empty
+    pub(crate) route_allowlist: Vec<AllowedRoute>,
+    /// env overrides by profile name, see `env_profile`
+    pub(crate) env_profiles: HashMap<String, String>,
+    /// checked against the env before the app container starts
+    pub(crate) env_schema: Vec<EnvRule>,
+    /// applied to the builds of the deployments created afterwards
+    pub(crate) build_environment: BuildEnvironment,
+    /// whether the apex or the www custom domain is served, the other one redirects to it
+    pub(crate) canonical_host: CanonicalHost,
+    /// folder of the built image served as static files instead of running the app
+    pub(crate) static_dir: Option<String>,
+    /// docker network the app containers join besides the prezel one
+    pub(crate) docker_network: Option<String>,
+    pub(crate) dns_servers: Vec<String>,
+    /// extra time idle containers keep running before going to standby
+    pub(crate) standby_grace_secs: Option<i64>,
+    /// used in the hostnames instead of the name, see `hostname_label`
+    pub(crate) hostname_prefix: Option<String>,
+    /// cron expression for periodic rebuilds of the production deployment
+    pub(crate) rebuild_schedule: Option<String>,
+    /// production deployments come from this branch instead of the default one of the repo
+    pub(crate) prod_branch: Option<String>,
+    /// incremented on every update of the project
+    pub(crate) version: i64,
+    /// days the build logs are kept, the server default if not set
+    pub(crate) log_retention_days: Option<i64>,
+    pub(crate) upstream_tls: UpstreamTls,
+    /// replaces the start command detected at build time
+    pub(crate) start_command: Option<String>,
+    pub(crate) replicas: Replicas,
+    /// names of the attached env groups, see `resolve_env`
+    pub(crate) env_groups: Vec<String>,
+    /// env of each attached group in the same order
+    pub(crate) group_envs: Vec<String>,
+    /// when the project was soft deleted
+    pub(crate) deleted: Option<i64>,
+    pub(crate) delete_volume: bool,
     pub(crate) custom_domains: Vec<String>,
 }
 
 impl Project {
-    fn new(project: PlainProject, custom_domains: Vec<String>) -> Self {
+    fn new(project: PlainProject, custom_domains: Vec<String>, group_envs: Vec<String>) -> Self {
         Self {
             id: project.id,
             name: project.name,
@@ -53,9 +164,125 @@ impl Project {
             env: project.env,
             root: project.root,
             prod_id: project.prod_id,
+            volume_path: project.volume_path,
+            ip_allowlist: split_list(&project.ip_allowlist),
+            ip_denylist: split_list(&project.ip_denylist),
+            // the api validates sidecars before storing them
+            sidecars: serde_json::from_str(&project.sidecars).unwrap_or_default(),
+            container_port: project.container_port,
+            request_timeout_secs: project.request_timeout_secs,
+            canary_deployment_id: project.canary_deployment_id,
+            canary_percent: project.canary_percent,
+            fallback_deployment_id: project.fallback_deployment_id,
+            mirror_deployment_id: project.mirror_deployment_id,
+            mirror_percent: project.mirror_percent,
+            mirror_all_methods: project.mirror_all_methods != 0,
+            poll_interval_secs: project.poll_interval_secs,
+            pin_env: project.pin_env != 0,
+            // validated by the api as well
+            body_rewrites: serde_json::from_str(&project.body_rewrites).unwrap_or_default(),
+            max_connections: project.max_connections,
+            redirects: serde_json::from_str(&project.redirects).unwrap_or_default(),
+            route_allowlist: serde_json::from_str(&project.route_allowlist).unwrap_or_default(),
+            env_profiles: serde_json::from_str(&project.env_profiles).unwrap_or_default(),
+            env_schema: serde_json::from_str(&project.env_schema).unwrap_or_default(),
+            build_environment: serde_json::from_str(&project.build_environment).unwrap_or_default(),
+            canonical_host: CanonicalHost::parse(&project.canonical_host),
+            static_dir: project.static_dir,
+            docker_network: project.docker_network,
+            dns_servers: split_list(&project.dns_servers),
+            standby_grace_secs: project.standby_grace_secs,
+            hostname_prefix: project.hostname_prefix,
+            rebuild_schedule: project.rebuild_schedule,
+            prod_branch: project.prod_branch,
+            version: project.version,
+            log_retention_days: project.log_retention_days,
+            upstream_tls: UpstreamTls::parse(&project.upstream_tls),
+            start_command: project.start_command,
+            replicas: serde_json::from_str(&project.replicas).unwrap_or_default(),
+            env_groups: split_list(&project.env_groups),
+            group_envs,
+            deleted: project.deleted,
+            delete_volume: project.delete_volume != 0,
             custom_domains,
         }
     }
+
+    /// The label identifying the project in the hostnames of its deployments
+    pub(crate) fn hostname_label(&self) -> &str {
+        self.hostname_prefix.as_deref().unwrap_or(&self.name)
+    }
+
+    /// Names of the secret variables in the env and the profiles, sorted
+    pub(crate) fn secret_env(&self) -> Vec<String> {
+        let envs = [&self.env].into_iter().chain(self.env_profiles.values());
+        let mut names: Vec<String> = envs
+            .flat_map(|env| HashMap::from(EnvVars::from(env.as_str())))
+            .filter(|(_, value)| Secrets::is_secret(value))
+            .map(|(name, _)| name)
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// The profile named after the branch if there is one, otherwise `production`
+    /// for the default branch and tags and `preview` for the rest of branches
+    pub(crate) fn env_profile(&self, branch: Option<&str>) -> Option<&str> {
+        let name = match branch {
+            Some(branch) if self.env_profiles.contains_key(branch) => branch,
+            Some(_) => "preview",
+            None => "production",
+        };
+        let (name, _) = self.env_profiles.get_key_value(name)?;
+        Some(name)
+    }
+
+    /// Env for new deployments of the branch. The attached groups go first, each one overriding
+    /// the ones before it, then the project env and the profile values override them all
+    pub(crate) fn resolve_env(&self, branch: Option<&str>) -> String {
+        let profile = self
+            .env_profile(branch)
+            .map(|profile| self.env_profiles[profile].as_str());
+        // later lines win when parsing the env
+        self.group_envs
+            .iter()
+            .map(String::as_str)
+            .chain([self.env.as_str()])
+            .chain(profile)
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Named env shared by the projects attaching it
+#[derive(Clone, Debug)]
+pub(crate) struct EnvGroup {
+    pub(crate) id: i64,
+    pub(crate) name: String,
+    pub(crate) env: String,
+    pub(crate) created: i64,
+}
+
+/// Custom domains are unique across projects, including the deleted ones until they are purged
+#[derive(Debug)]
+pub(crate) struct DomainConflict {
+    pub(crate) domain: String,
+}
+
+#[derive(Debug)]
+pub(crate) enum UpdateConflict {
+    Domain(DomainConflict),
+    /// the project changed since the version the update was based on
+    Version {
+        current: i64,
+    },
+}
+
+impl From<DomainConflict> for UpdateConflict {
+    fn from(value: DomainConflict) -> Self {
+        Self::Domain(value)
+    }
 }
 
 #[derive(Deserialize, Debug, ToSchema)]
@@ -64,13 +291,179 @@ pub(crate) struct InsertProject {
     pub(crate) repo_id: String,
     pub(crate) env: String,
     pub(crate) root: String,
+    #[serde(default)]
+    pub(crate) custom_domains: Vec<String>,
+    /// Path inside the container where a persistent volume is mounted
+    pub(crate) volume_path: Option<String>,
+    /// CIDRs allowed to access the deployments, empty to allow any address
+    #[serde(default)]
+    pub(crate) ip_allowlist: Vec<String>,
+    /// CIDRs denied access to the deployments, takes precedence over the allowlist
+    #[serde(default)]
+    pub(crate) ip_denylist: Vec<String>,
+    /// Additional containers started together with every deployment
+    #[serde(default)]
+    pub(crate) sidecars: Vec<Sidecar>,
+    /// Port the app listens on inside the container, 80 if not set
+    pub(crate) container_port: Option<u16>,
+    /// Seconds the proxy waits for the app to respond, the server default if not set
+    pub(crate) request_timeout_secs: Option<u32>,
+    /// Seconds between checks for new commits, the server default if not set
+    pub(crate) poll_interval_secs: Option<u32>,
+    /// Deployments keep the env they were created with, env changes only apply to new deployments
+    #[serde(default)]
+    pub(crate) pin_env: bool,
+    /// Find and replace rules for text responses, only applied if the server allows body rewrites
+    #[serde(default)]
+    pub(crate) body_rewrites: Vec<BodyRewrite>,
+    /// Concurrent requests sent to each deployment, unlimited if not set
+    pub(crate) max_connections: Option<u32>,
+    /// Redirects answered by the proxy, the first matching rule wins
+    #[serde(default)]
+    pub(crate) redirects: Vec<RedirectRule>,
+    /// Methods and paths forwarded to the deployments, the rest of requests get a 403.
+    /// Any request is forwarded if empty
+    #[serde(default)]
+    pub(crate) route_allowlist: Vec<AllowedRoute>,
+    /// Env applied on top of the project env, by profile name. Deployments use the profile
+    /// named after their branch, otherwise `production` or `preview`
+    #[serde(default)]
+    pub(crate) env_profiles: HashMap<String, String>,
+    /// Rules the env needs to follow, deployments fail to start naming the variables breaking them
+    #[serde(default)]
+    pub(crate) env_schema: Vec<EnvRule>,
+    /// Base image, nixpkgs commit and extra Nix packages for the build, autodetected if missing
+    #[serde(default)]
+    pub(crate) build_environment: BuildEnvironment,
+    /// `www` or `apex` to redirect the other domain to it, when both are custom domains of the project
+    #[serde(default)]
+    pub(crate) canonical_host: CanonicalHost,
+    /// Absolute path of the build output inside the image, like `/app/dist`. If set,
+    /// the proxy serves its files directly and no container is started for the app
+    pub(crate) static_dir: Option<String>,
+    /// Existing docker network the app containers join besides the prezel one,
+    /// so they can reach other containers in it
+    pub(crate) docker_network: Option<String>,
+    /// DNS servers for the app containers, the docker default if empty
+    #[serde(default)]
+    pub(crate) dns_servers: Vec<String>,
+    /// Seconds idle containers keep running before going to standby, on top of the default 30
+    pub(crate) standby_grace_secs: Option<u32>,
+    /// Used in the hostnames of the deployments instead of the project name
+    pub(crate) hostname_prefix: Option<String>,
+    /// Cron expression in UTC, like `0 3 * * *`, to rebuild the production deployment periodically
+    pub(crate) rebuild_schedule: Option<String>,
+    /// Branch production deployments come from, the default branch of the repo if not set
+    pub(crate) prod_branch: Option<String>,
+    /// Days the build logs are kept, the server default if not set
+    pub(crate) log_retention_days: Option<u32>,
+    /// `on` or `skip_verify` to connect to the app container over HTTPS instead of plain HTTP
+    #[serde(default)]
+    pub(crate) upstream_tls: UpstreamTls,
+    /// Names of shared env groups merged under the project env, later groups override earlier ones
+    #[serde(default)]
+    pub(crate) env_groups: Vec<String>,
+    /// Command the app container runs instead of the detected one, like `node dist/server.js`
+    pub(crate) start_command: Option<String>,
+    /// Bounds the app containers of each deployment are scaled between, a single one by default
+    #[serde(default)]
+    pub(crate) replicas: Replicas,
+    /// Branch or commit sha to create a production deployment from right away, instead of
+    /// waiting for the production branch to be polled. Not stored with the project
+    pub(crate) deploy_ref: Option<String>,
 }
 
 #[derive(Deserialize, Debug, ToSchema)]
 pub(crate) struct UpdateProject {
-    name: Option<String>,
+    pub(crate) name: Option<String>,
     env: Option<String>,
-    custom_domains: Option<Vec<String>>,
+    pub(crate) custom_domains: Option<Vec<String>>,
+    /// Path inside the container where a persistent volume is mounted, empty to remove it
+    pub(crate) volume_path: Option<String>,
+    /// CIDRs allowed to access the deployments, empty to allow any address
+    pub(crate) ip_allowlist: Option<Vec<String>>,
+    /// CIDRs denied access to the deployments, takes precedence over the allowlist
+    pub(crate) ip_denylist: Option<Vec<String>>,
+    /// Additional containers started together with every deployment,
+    /// running deployments pick the changes up the next time they start
+    pub(crate) sidecars: Option<Vec<Sidecar>>,
+    /// Port the app listens on inside the container, 0 to go back to the default
+    pub(crate) container_port: Option<u16>,
+    /// Seconds the proxy waits for the app to respond, 0 to go back to the server default
+    pub(crate) request_timeout_secs: Option<u32>,
+    /// Deployment receiving `canary_percent` of the production traffic, 0 to remove it
+    pub(crate) canary_deployment_id: Option<i64>,
+    /// Percentage of the production traffic sent to the canary deployment, from 0 to 100
+    pub(crate) canary_percent: Option<u8>,
+    /// Previous deployment production requests fail over to when the production one is down
+    /// or answers with a 5xx, 0 to remove it
+    pub(crate) fallback_deployment_id: Option<i64>,
+    /// Deployment getting a copy of `mirror_percent` of the production requests, 0 to remove it.
+    /// Its responses are discarded, clients only get the ones from production
+    pub(crate) mirror_deployment_id: Option<i64>,
+    /// Percentage of the production requests mirrored, from 0 to 100
+    pub(crate) mirror_percent: Option<u8>,
+    /// Mirror every method, by default only GET, HEAD and OPTIONS requests are mirrored
+    pub(crate) mirror_all_methods: Option<bool>,
+    /// Seconds between checks for new commits, 0 to go back to the server default
+    pub(crate) poll_interval_secs: Option<u32>,
+    /// Deployments keep the env they were created with, env changes only apply to new deployments
+    pub(crate) pin_env: Option<bool>,
+    /// Find and replace rules for text responses, only applied if the server allows body rewrites
+    pub(crate) body_rewrites: Option<Vec<BodyRewrite>>,
+    /// Concurrent requests sent to each deployment, 0 to remove the limit
+    pub(crate) max_connections: Option<u32>,
+    /// Redirects answered by the proxy, the first matching rule wins
+    pub(crate) redirects: Option<Vec<RedirectRule>>,
+    /// Methods and paths forwarded to the deployments, the rest of requests get a 403.
+    /// Empty to forward any request
+    pub(crate) route_allowlist: Option<Vec<AllowedRoute>>,
+    /// Env applied on top of the project env, by profile name. Deployments use the profile
+    /// named after their branch, otherwise `production` or `preview`
+    pub(crate) env_profiles: Option<HashMap<String, String>>,
+    /// Rules the env needs to follow, deployments fail to start naming the variables breaking them.
+    /// Running deployments pick the changes up the next time they start
+    pub(crate) env_schema: Option<Vec<EnvRule>>,
+    /// Base image, nixpkgs commit and extra Nix packages for the build, `{}` to go back to autodetection.
+    /// Only deployments created afterwards are built with it
+    pub(crate) build_environment: Option<BuildEnvironment>,
+    /// `www` or `apex` to redirect the other domain to it, when both are custom domains of the project.
+    /// `off` serves both of them again
+    pub(crate) canonical_host: Option<CanonicalHost>,
+    /// Absolute path of the build output inside the image served as static files,
+    /// empty to run the app again
+    pub(crate) static_dir: Option<String>,
+    /// Existing docker network the app containers join besides the prezel one, empty to remove it.
+    /// Running deployments pick the changes up the next time they start
+    pub(crate) docker_network: Option<String>,
+    /// DNS servers for the app containers, empty to go back to the docker default
+    pub(crate) dns_servers: Option<Vec<String>>,
+    /// Seconds idle containers keep running before going to standby, on top of the default 30.
+    /// 0 to remove the grace period
+    pub(crate) standby_grace_secs: Option<u32>,
+    /// Used in the hostnames of the deployments instead of the project name,
+    /// empty to go back to the project name
+    pub(crate) hostname_prefix: Option<String>,
+    /// Cron expression in UTC, like `0 3 * * *`, to rebuild the production deployment periodically,
+    /// empty to remove it
+    pub(crate) rebuild_schedule: Option<String>,
+    /// Branch production deployments come from, empty to go back to the default branch of the repo
+    pub(crate) prod_branch: Option<String>,
+    /// Days the build logs are kept, 0 to go back to the server default
+    pub(crate) log_retention_days: Option<u32>,
+    /// `on` or `skip_verify` to connect to the app container over HTTPS, `off` for plain HTTP
+    pub(crate) upstream_tls: Option<UpstreamTls>,
+    /// Names of shared env groups merged under the project env, later groups override earlier ones.
+    /// Empty to detach them all
+    pub(crate) env_groups: Option<Vec<String>>,
+    /// Command the app container runs instead of the detected one, empty to go back to the detected one.
+    /// Running deployments pick the changes up the next time they start
+    pub(crate) start_command: Option<String>,
+    /// Bounds the app containers of each deployment are scaled between, applied right away
+    pub(crate) replicas: Option<Replicas>,
+    /// Version of the project the update is based on, it fails if the project changed since.
+    /// Updates without it always go through
+    pub(crate) version: Option<i64>,
 }
 
 // #[derive(Clone, Debug)]
@@ -98,6 +491,18 @@ pub(crate) struct Deployment {
     pub(crate) build_started: Option<i64>,
     pub(crate) build_finished: Option<i64>,
     pub(crate) project: i64,
+    /// feature flags, applied on top of env without rebuilding
+    pub(crate) flags: String,
+    /// set for release deployments created from a git tag
+    pub(crate) tag: Option<String>,
+    /// replaces the url id in the hostname if set
+    pub(crate) slug: Option<String>,
+    /// the container is not started until the deployment is started again
+    pub(crate) stopped: i64,
+    /// bytes of the built image, null until built
+    pub(crate) image_size: Option<i64>,
+    /// metadata only, stored like the flags
+    pub(crate) labels: String,
 }
 
 #[derive(FromRow)]
@@ -107,6 +512,17 @@ pub(crate) struct BuildLog {
     pub(crate) timestamp: i64,
     pub(crate) error: i64,
     pub(crate) deployment: i64,
+    /// set for the markers of the build phases, which have no content
+    pub(crate) phase: Option<String>,
+}
+
+/// Set while the box is under maintenance
+#[derive(FromRow, Clone)]
+pub(crate) struct ReadOnly {
+    pub(crate) message: Option<String>,
+    /// whether new deployments are paused as well
+    pub(crate) pause_deployments: bool,
+    pub(crate) since: i64,
 }
 
 pub(crate) struct DeploymentWithProject {
@@ -127,9 +543,18 @@ pub(crate) struct InsertDeployment {
     pub(crate) sha: String,
     pub(crate) timestamp: i64,
     pub(crate) branch: Option<String>,
+    pub(crate) tag: Option<String>,
     pub(crate) project: i64,
 }
 
+fn split_list(list: &str) -> Vec<String> {
+    list.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
 fn create_deployment_url_id() -> String {
     nanoid!(10, &alphabet::LOWERCASE_PLUS_NUMBERS)
 }
@@ -168,40 +593,278 @@ impl Db {
     pub(crate) async fn get_project(&self, id: i64) -> Option<Project> {
         let project = sqlx::query_as!(
             PlainProject,
-            "select * from projects where projects.id = ?",
+            "select * from projects where projects.id = ? and deleted is null",
             id
         )
         .fetch_optional(&self.conn)
         .await
         .unwrap()?;
-        Some(self.append_custom_domains(project).await)
+        Some(self.append_relations(project).await)
+    }
+
+    /// Replaces the whole project env in a single write
+    pub(crate) async fn set_project_env(&self, id: i64, env: &str) {
+        sqlx::query!("update projects set env = ? where id = ?", env, id)
+            .execute(&self.conn)
+            .await
+            .unwrap();
+        self.refresh_deployments_env(id).await;
+    }
+
+    /// Encrypts the stored secrets with the current key, so the previous ones can be dropped
+    pub(crate) async fn rotate_secrets(&self, secrets: &Secrets) {
+        let projects = sqlx::query!("select id, env, env_profiles from projects")
+            .fetch_all(&self.conn)
+            .await
+            .unwrap();
+        for project in projects {
+            if let Some(env) = secrets.rotate_env(&project.env) {
+                sqlx::query!("update projects set env = ? where id = ?", env, project.id)
+                    .execute(&self.conn)
+                    .await
+                    .unwrap();
+            }
+            let mut profiles: HashMap<String, String> =
+                serde_json::from_str(&project.env_profiles).unwrap_or_default();
+            let mut rotated = false;
+            for env in profiles.values_mut() {
+                if let Some(rotated_env) = secrets.rotate_env(env) {
+                    *env = rotated_env;
+                    rotated = true;
+                }
+            }
+            if rotated {
+                let profiles = serde_json::to_string(&profiles).unwrap();
+                sqlx::query!(
+                    "update projects set env_profiles = ? where id = ?",
+                    profiles,
+                    project.id
+                )
+                .execute(&self.conn)
+                .await
+                .unwrap();
+            }
+        }
+
+        let groups = self.get_env_groups().await;
+        for group in groups {
+            if let Some(env) = secrets.rotate_env(&group.env) {
+                sqlx::query!("update env_groups set env = ? where id = ?", env, group.id)
+                    .execute(&self.conn)
+                    .await
+                    .unwrap();
+            }
+        }
+
+        let deployments = sqlx::query!("select id, env from deployments")
+            .fetch_all(&self.conn)
+            .await
+            .unwrap();
+        for deployment in deployments {
+            if let Some(env) = secrets.rotate_env(&deployment.env) {
+                sqlx::query!(
+                    "update deployments set env = ? where id = ?",
+                    env,
+                    deployment.id
+                )
+                .execute(&self.conn)
+                .await
+                .unwrap();
+            }
+        }
+    }
+
+    /// Unless the env is pinned, existing deployments pick up the new project env and profiles
+    async fn refresh_deployments_env(&self, project: i64) {
+        let Some(project) = self.get_project(project).await else {
+            return;
+        };
+        if project.pin_env {
+            return;
+        }
+        let deployments = sqlx::query!(
+            "select id, branch from deployments where project = ?",
+            project.id
+        )
+        .fetch_all(&self.conn)
+        .await
+        .unwrap();
+        for deployment in deployments {
+            let env = project.resolve_env(deployment.branch.as_deref());
+            sqlx::query!(
+                "update deployments set env = ? where id = ?",
+                env,
+                deployment.id
+            )
+            .execute(&self.conn)
+            .await
+            .unwrap();
+        }
+    }
+
+    pub(crate) async fn get_env_groups(&self) -> Vec<EnvGroup> {
+        sqlx::query_as!(EnvGroup, "select * from env_groups order by name")
+            .fetch_all(&self.conn)
+            .await
+            .unwrap()
+    }
+
+    pub(crate) async fn get_env_group(&self, name: &str) -> Option<EnvGroup> {
+        sqlx::query_as!(EnvGroup, "select * from env_groups where name = ?", name)
+            .fetch_optional(&self.conn)
+            .await
+            .unwrap()
+    }
+
+    pub(crate) async fn insert_env_group(&self, name: &str, env: &str) {
+        let created = now();
+        sqlx::query!(
+            "insert into env_groups (name, env, created) values (?, ?, ?)",
+            name,
+            env,
+            created
+        )
+        .execute(&self.conn)
+        .await
+        .unwrap();
+    }
+
+    /// Unless their env is pinned, the deployments of the attached projects pick up the new env
+    pub(crate) async fn set_env_group_env(&self, name: &str, env: &str) {
+        sqlx::query!("update env_groups set env = ? where name = ?", env, name)
+            .execute(&self.conn)
+            .await
+            .unwrap();
+        for project in self.get_env_group_projects(name).await {
+            self.refresh_deployments_env(project).await;
+        }
+    }
+
+    pub(crate) async fn delete_env_group(&self, name: &str) {
+        sqlx::query!("delete from env_groups where name = ?", name)
+            .execute(&self.conn)
+            .await
+            .unwrap();
+    }
+
+    /// Ids of the projects the group is attached to, including the deleted ones until they are purged
+    pub(crate) async fn get_env_group_projects(&self, name: &str) -> Vec<i64> {
+        sqlx::query!("select id, env_groups from projects")
+            .fetch_all(&self.conn)
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|project| {
+                split_list(&project.env_groups)
+                    .iter()
+                    .any(|group| group == name)
+            })
+            .map(|project| project.id)
+            .collect()
     }
 
     pub(crate) async fn get_project_by_name(&self, name: &str) -> Option<Project> {
         let project = sqlx::query_as!(
             PlainProject,
-            "select * from projects where projects.name = ?",
+            "select * from projects where projects.name = ? and deleted is null",
             name
         )
         .fetch_optional(&self.conn)
         .await
         .unwrap()?;
-        Some(self.append_custom_domains(project).await)
+        Some(self.append_relations(project).await)
+    }
+
+    /// Unlike get_project_by_name, this takes into account soft deleted projects still holding the name
+    pub(crate) async fn get_project_id_by_name(&self, name: &str) -> Option<i64> {
+        sqlx::query!("select id from projects where name = ?", name)
+            .fetch_optional(&self.conn)
+            .await
+            .unwrap()
+            .map(|record| record.id)
+    }
+
+    /// The project whose hostnames use `label`, either as prefix or as name without a prefix
+    pub(crate) async fn get_project_id_by_hostname_label(&self, label: &str) -> Option<i64> {
+        sqlx::query!(
+            "select id from projects where coalesce(hostname_prefix, name) = ?",
+            label
+        )
+        .fetch_optional(&self.conn)
+        .await
+        .unwrap()
+        .map(|record| record.id)
     }
 
     pub(crate) async fn get_projects(&self) -> Vec<Project> {
-        let projects = sqlx::query_as!(PlainProject, "select * from projects")
-            .fetch_all(&self.conn)
+        let projects =
+            sqlx::query_as!(PlainProject, "select * from projects where deleted is null")
+                .fetch_all(&self.conn)
+                .await
+                .unwrap();
+
+        stream::iter(projects)
+            .then(|project| self.append_relations(project))
+            .collect()
             .await
-            .unwrap();
+    }
+
+    pub(crate) async fn get_deleted_projects(&self) -> Vec<Project> {
+        let projects = sqlx::query_as!(
+            PlainProject,
+            "select * from projects where deleted is not null"
+        )
+        .fetch_all(&self.conn)
+        .await
+        .unwrap();
 
         stream::iter(projects)
-            .then(|project| self.append_custom_domains(project))
+            .then(|project| self.append_relations(project))
             .collect()
             .await
     }
 
-    async fn append_custom_domains(&self, project: PlainProject) -> Project {
+    /// Returns the id and name of the project owning the domain, if any
+    pub(crate) async fn get_domain_project(&self, domain: &str) -> Option<(i64, String)> {
+        sqlx::query!(
+            "select projects.id, projects.name from domains join projects on domains.project = projects.id where domain = ?",
+            domain
+        )
+        .fetch_optional(&self.conn)
+        .await
+        .unwrap()
+        .map(|record| (record.id, record.name))
+    }
+
+    async fn insert_domains(
+        tx: &mut Transaction<'_, Sqlite>,
+        project: i64,
+        domains: &[String],
+    ) -> Result<(), DomainConflict> {
+        for domain in domains {
+            let result = sqlx::query!(
+                "insert into domains (domain, project) values (?, ?)",
+                domain,
+                project
+            )
+            .execute(&mut **tx)
+            .await;
+            match result {
+                Err(sqlx::Error::Database(error)) if error.is_unique_violation() => {
+                    return Err(DomainConflict {
+                        domain: domain.clone(),
+                    })
+                }
+                result => {
+                    result.unwrap();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads the custom domains and the env of the attached groups
+    async fn append_relations(&self, project: PlainProject) -> Project {
         let custom_domains = sqlx::query!("select * from domains where project = ?", project.id)
             .fetch_all(&self.conn)
             .await
@@ -209,7 +872,13 @@ impl Db {
             .into_iter()
             .map(|record| record.domain)
             .collect();
-        Project::new(project, custom_domains)
+        let mut group_envs = vec![];
+        for name in split_list(&project.env_groups) {
+            if let Some(group) = self.get_env_group(&name).await {
+                group_envs.push(group.env);
+            }
+        }
+        Project::new(project, custom_domains, group_envs)
     }
 
     pub(crate) async fn insert_project(
@@ -219,20 +888,97 @@ impl Db {
             repo_id,
             env,
             root,
+            custom_domains,
+            volume_path,
+            ip_allowlist,
+            ip_denylist,
+            sidecars,
+            container_port,
+            request_timeout_secs,
+            poll_interval_secs,
+            pin_env,
+            body_rewrites,
+            max_connections,
+            redirects,
+            env_profiles,
+            static_dir,
+            docker_network,
+            dns_servers,
+            standby_grace_secs,
+            hostname_prefix,
+            route_allowlist,
+            rebuild_schedule,
+            env_schema,
+            build_environment,
+            canonical_host,
+            prod_branch,
+            log_retention_days,
+            upstream_tls,
+            env_groups,
+            start_command,
+            replicas,
+            deploy_ref: _,
         }: InsertProject,
-    ) {
+    ) -> Result<i64, DomainConflict> {
         let created = time::now();
-        sqlx::query!(
-            "insert into projects (name, repo_id, created, env, root) values (?, ?, ?, ?, ?)",
+        let ip_allowlist = ip_allowlist.join("\n");
+        let ip_denylist = ip_denylist.join("\n");
+        let sidecars = serde_json::to_string(&sidecars).unwrap();
+        let body_rewrites = serde_json::to_string(&body_rewrites).unwrap();
+        let redirects = serde_json::to_string(&redirects).unwrap();
+        let route_allowlist = serde_json::to_string(&route_allowlist).unwrap();
+        let env_profiles = serde_json::to_string(&env_profiles).unwrap();
+        let env_schema = serde_json::to_string(&env_schema).unwrap();
+        let build_environment = serde_json::to_string(&build_environment).unwrap();
+        let canonical_host = canonical_host.as_str();
+        let upstream_tls = upstream_tls.as_str();
+        let dns_servers = dns_servers.join("\n");
+        let env_groups = env_groups.join("\n");
+        let replicas = serde_json::to_string(&replicas).unwrap();
+        let mut tx = self.conn.begin().await.unwrap();
+        let id = sqlx::query!(
+            "insert into projects (name, repo_id, created, env, root, volume_path, ip_allowlist, ip_denylist, sidecars, container_port, request_timeout_secs, poll_interval_secs, pin_env, body_rewrites, max_connections, redirects, env_profiles, static_dir, docker_network, dns_servers, standby_grace_secs, hostname_prefix, route_allowlist, rebuild_schedule, env_schema, build_environment, canonical_host, prod_branch, log_retention_days, upstream_tls, env_groups, start_command, replicas) values (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
             name,
             repo_id,
             created,
             env,
-            root
+            root,
+            volume_path,
+            ip_allowlist,
+            ip_denylist,
+            sidecars,
+            container_port,
+            request_timeout_secs,
+            poll_interval_secs,
+            pin_env,
+            body_rewrites,
+            max_connections,
+            redirects,
+            env_profiles,
+            static_dir,
+            docker_network,
+            dns_servers,
+            standby_grace_secs,
+            hostname_prefix,
+            route_allowlist,
+            rebuild_schedule,
+            env_schema,
+            build_environment,
+            canonical_host,
+            prod_branch,
+            log_retention_days,
+            upstream_tls,
+            env_groups,
+            start_command,
+            replicas
         )
-        .execute(&self.conn)
+        .execute(&mut *tx)
         .await
-        .unwrap();
+        .unwrap()
+        .last_insert_rowid();
+        Self::insert_domains(&mut tx, id, &custom_domains).await?;
+        tx.commit().await.unwrap();
+        Ok(id)
     }
 
     pub(crate) async fn update_project(
@@ -242,40 +988,519 @@ impl Db {
             name,
             env,
             custom_domains,
+            volume_path,
+            ip_allowlist,
+            ip_denylist,
+            sidecars,
+            container_port,
+            request_timeout_secs,
+            canary_deployment_id,
+            canary_percent,
+            fallback_deployment_id,
+            mirror_deployment_id,
+            mirror_percent,
+            mirror_all_methods,
+            poll_interval_secs,
+            pin_env,
+            body_rewrites,
+            max_connections,
+            redirects,
+            env_profiles,
+            static_dir,
+            docker_network,
+            dns_servers,
+            standby_grace_secs,
+            hostname_prefix,
+            route_allowlist,
+            rebuild_schedule,
+            env_schema,
+            build_environment,
+            canonical_host,
+            prod_branch,
+            version,
+            log_retention_days,
+            upstream_tls,
+            env_groups,
+            start_command,
+            replicas,
         }: UpdateProject,
-    ) {
-        if let Some(name) = name {
-            sqlx::query!("update projects set name = ? where id = ?", name, id)
-                .execute(&self.conn)
+    ) -> Result<(), UpdateConflict> {
+        // first, so nothing is changed if the project changed in the meantime or some domain is taken
+        let mut tx = self.conn.begin().await.unwrap();
+        let bumped = sqlx::query!(
+            "update projects set version = version + 1 where id = ? and version = coalesce(?, version)",
+            id,
+            version
+        )
+        .execute(&mut *tx)
+        .await
+        .unwrap()
+        .rows_affected();
+        if bumped == 0 {
+            let current = sqlx::query!("select version from projects where id = ?", id)
+                .fetch_optional(&mut *tx)
+                .await
+                .unwrap()
+                .map_or(0, |record| record.version);
+            return Err(UpdateConflict::Version { current });
+        }
+        if let Some(custom_domains) = custom_domains {
+            sqlx::query!("delete from domains WHERE project = ?", id)
+                .execute(&mut *tx)
                 .await
                 .unwrap();
+            Self::insert_domains(&mut tx, id, &custom_domains).await?;
         }
-
-        if let Some(env) = env {
-            sqlx::query!("update projects set env = ? where id = ?", env, id)
-                .execute(&self.conn)
+        if let Some(pin_env) = pin_env {
+            sqlx::query!("update projects set pin_env = ? where id = ?", pin_env, id)
+                .execute(&mut *tx)
                 .await
                 .unwrap();
         }
 
-        if let Some(custom_domains) = custom_domains {
-            let mut tx = self.conn.begin().await.unwrap();
-            sqlx::query!("delete from domains WHERE project = ?", id)
+        if let Some(name) = name {
+            sqlx::query!("update projects set name = ? where id = ?", name, id)
                 .execute(&mut *tx)
                 .await
                 .unwrap();
-            for domain in custom_domains {
-                sqlx::query!(
-                    "insert into domains (domain, project) values (?, ?)",
-                    domain,
-                    id
-                )
+        }
+
+        let env_changed = env.is_some() || env_profiles.is_some() || env_groups.is_some();
+        if let Some(env) = env {
+            sqlx::query!("update projects set env = ? where id = ?", env, id)
                 .execute(&mut *tx)
                 .await
                 .unwrap();
-            }
-            tx.commit().await.unwrap();
         }
+
+        if let Some(env_profiles) = env_profiles {
+            let env_profiles = serde_json::to_string(&env_profiles).unwrap();
+            sqlx::query!(
+                "update projects set env_profiles = ? where id = ?",
+                env_profiles,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+
+        if let Some(env_groups) = env_groups {
+            let env_groups = env_groups.join("\n");
+            sqlx::query!(
+                "update projects set env_groups = ? where id = ?",
+                env_groups,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+
+        if let Some(volume_path) = volume_path {
+            // an empty path removes the volume from future deployments
+            let volume_path = Some(volume_path).filter(|path| !path.is_empty());
+            sqlx::query!(
+                "update projects set volume_path = ? where id = ?",
+                volume_path,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+
+        if let Some(ip_allowlist) = ip_allowlist {
+            let ip_allowlist = ip_allowlist.join("\n");
+            sqlx::query!(
+                "update projects set ip_allowlist = ? where id = ?",
+                ip_allowlist,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+
+        if let Some(ip_denylist) = ip_denylist {
+            let ip_denylist = ip_denylist.join("\n");
+            sqlx::query!(
+                "update projects set ip_denylist = ? where id = ?",
+                ip_denylist,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+
+        if let Some(sidecars) = sidecars {
+            let sidecars = serde_json::to_string(&sidecars).unwrap();
+            sqlx::query!(
+                "update projects set sidecars = ? where id = ?",
+                sidecars,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+
+        if let Some(body_rewrites) = body_rewrites {
+            let body_rewrites = serde_json::to_string(&body_rewrites).unwrap();
+            sqlx::query!(
+                "update projects set body_rewrites = ? where id = ?",
+                body_rewrites,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+
+        if let Some(redirects) = redirects {
+            let redirects = serde_json::to_string(&redirects).unwrap();
+            sqlx::query!(
+                "update projects set redirects = ? where id = ?",
+                redirects,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+
+        if let Some(route_allowlist) = route_allowlist {
+            let route_allowlist = serde_json::to_string(&route_allowlist).unwrap();
+            sqlx::query!(
+                "update projects set route_allowlist = ? where id = ?",
+                route_allowlist,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+
+        if let Some(env_schema) = env_schema {
+            let env_schema = serde_json::to_string(&env_schema).unwrap();
+            sqlx::query!(
+                "update projects set env_schema = ? where id = ?",
+                env_schema,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+
+        if let Some(build_environment) = build_environment {
+            let build_environment = serde_json::to_string(&build_environment).unwrap();
+            sqlx::query!(
+                "update projects set build_environment = ? where id = ?",
+                build_environment,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+
+        if let Some(start_command) = start_command {
+            let start_command = Some(start_command).filter(|command| !command.is_empty());
+            sqlx::query!(
+                "update projects set start_command = ? where id = ?",
+                start_command,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+
+        if let Some(replicas) = replicas {
+            let replicas = serde_json::to_string(&replicas).unwrap();
+            sqlx::query!(
+                "update projects set replicas = ? where id = ?",
+                replicas,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+
+        if let Some(upstream_tls) = upstream_tls {
+            let upstream_tls = upstream_tls.as_str();
+            sqlx::query!(
+                "update projects set upstream_tls = ? where id = ?",
+                upstream_tls,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+
+        if let Some(canonical_host) = canonical_host {
+            let canonical_host = canonical_host.as_str();
+            sqlx::query!(
+                "update projects set canonical_host = ? where id = ?",
+                canonical_host,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+
+        if let Some(static_dir) = static_dir {
+            let static_dir = Some(static_dir).filter(|dir| !dir.is_empty());
+            sqlx::query!(
+                "update projects set static_dir = ? where id = ?",
+                static_dir,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+
+        if let Some(docker_network) = docker_network {
+            let docker_network = Some(docker_network).filter(|network| !network.is_empty());
+            sqlx::query!(
+                "update projects set docker_network = ? where id = ?",
+                docker_network,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+
+        if let Some(standby_grace_secs) = standby_grace_secs {
+            let standby_grace_secs = Some(standby_grace_secs).filter(|secs| *secs != 0);
+            sqlx::query!(
+                "update projects set standby_grace_secs = ? where id = ?",
+                standby_grace_secs,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+
+        if let Some(hostname_prefix) = hostname_prefix {
+            let hostname_prefix = Some(hostname_prefix).filter(|prefix| !prefix.is_empty());
+            sqlx::query!(
+                "update projects set hostname_prefix = ? where id = ?",
+                hostname_prefix,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+
+        if let Some(prod_branch) = prod_branch {
+            let prod_branch = Some(prod_branch).filter(|branch| !branch.is_empty());
+            sqlx::query!(
+                "update projects set prod_branch = ? where id = ?",
+                prod_branch,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+
+        if let Some(log_retention_days) = log_retention_days {
+            let log_retention_days = Some(log_retention_days).filter(|days| *days != 0);
+            sqlx::query!(
+                "update projects set log_retention_days = ? where id = ?",
+                log_retention_days,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+
+        if let Some(rebuild_schedule) = rebuild_schedule {
+            let rebuild_schedule = Some(rebuild_schedule).filter(|schedule| !schedule.is_empty());
+            sqlx::query!(
+                "update projects set rebuild_schedule = ? where id = ?",
+                rebuild_schedule,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+
+        if let Some(dns_servers) = dns_servers {
+            let dns_servers = dns_servers.join("\n");
+            sqlx::query!(
+                "update projects set dns_servers = ? where id = ?",
+                dns_servers,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+
+        if let Some(max_connections) = max_connections {
+            let max_connections = Some(max_connections).filter(|max| *max != 0);
+            sqlx::query!(
+                "update projects set max_connections = ? where id = ?",
+                max_connections,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+
+        if let Some(container_port) = container_port {
+            let container_port = Some(container_port).filter(|port| *port != 0);
+            sqlx::query!(
+                "update projects set container_port = ? where id = ?",
+                container_port,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+
+        if let Some(request_timeout_secs) = request_timeout_secs {
+            let request_timeout_secs = Some(request_timeout_secs).filter(|secs| *secs != 0);
+            sqlx::query!(
+                "update projects set request_timeout_secs = ? where id = ?",
+                request_timeout_secs,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+
+        if let Some(poll_interval_secs) = poll_interval_secs {
+            let poll_interval_secs = Some(poll_interval_secs).filter(|secs| *secs != 0);
+            sqlx::query!(
+                "update projects set poll_interval_secs = ? where id = ?",
+                poll_interval_secs,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+
+        if let Some(canary_deployment_id) = canary_deployment_id {
+            let canary_deployment_id = Some(canary_deployment_id).filter(|id| *id != 0);
+            sqlx::query!(
+                "update projects set canary_deployment_id = ? where id = ?",
+                canary_deployment_id,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+
+        if let Some(canary_percent) = canary_percent {
+            sqlx::query!(
+                "update projects set canary_percent = ? where id = ?",
+                canary_percent,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+
+        if let Some(fallback_deployment_id) = fallback_deployment_id {
+            let fallback_deployment_id = Some(fallback_deployment_id).filter(|id| *id != 0);
+            sqlx::query!(
+                "update projects set fallback_deployment_id = ? where id = ?",
+                fallback_deployment_id,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+
+        if let Some(mirror_deployment_id) = mirror_deployment_id {
+            let mirror_deployment_id = Some(mirror_deployment_id).filter(|id| *id != 0);
+            sqlx::query!(
+                "update projects set mirror_deployment_id = ? where id = ?",
+                mirror_deployment_id,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+
+        if let Some(mirror_percent) = mirror_percent {
+            sqlx::query!(
+                "update projects set mirror_percent = ? where id = ?",
+                mirror_percent,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+
+        if let Some(mirror_all_methods) = mirror_all_methods {
+            sqlx::query!(
+                "update projects set mirror_all_methods = ? where id = ?",
+                mirror_all_methods,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+
+        tx.commit().await.unwrap();
+
+        // the deployments get the env of the committed project
+        if env_changed {
+            self.refresh_deployments_env(id).await;
+        }
+        Ok(())
+    }
+
+    /// The project is kept around, hidden, until purged
+    pub(crate) async fn soft_delete_project(&self, id: i64, delete_volume: bool) {
+        let deleted = now();
+        sqlx::query!(
+            "update projects set deleted = ?, delete_volume = ? where id = ?",
+            deleted,
+            delete_volume,
+            id
+        )
+        .execute(&self.conn)
+        .await
+        .unwrap();
+    }
+
+    /// Returns false if the project does not exist or was not deleted
+    pub(crate) async fn restore_project(&self, id: i64) -> bool {
+        sqlx::query!(
+            "update projects set deleted = null, delete_volume = 0 where id = ? and deleted is not null",
+            id
+        )
+        .execute(&self.conn)
+        .await
+        .unwrap()
+        .rows_affected()
+            > 0
     }
 
     pub(crate) async fn delete_project(&self, id: i64) {
@@ -288,7 +1513,7 @@ impl Db {
     pub(crate) async fn get_deployment(&self, deployment: i64) -> Option<Deployment> {
         sqlx::query_as!(
             Deployment,
-            r#"select id, url_id, timestamp, created, env, sha, branch, result as "result: BuildResult", build_started, build_finished,project from deployments where deployments.id = ?"#,
+            r#"select id, url_id, timestamp, created, env, sha, branch, result as "result: BuildResult", build_started, build_finished, project, flags, tag, slug, stopped, image_size, labels from deployments where deployments.id = ?"#,
             deployment
         )
         .fetch_optional(&self.conn)
@@ -296,6 +1521,50 @@ impl Db {
         .unwrap()
     }
 
+    /// url ids are only unique within a project, but being random they match a single deployment in practice
+    pub(crate) async fn get_deployment_by_url_id(&self, url_id: &str) -> Option<Deployment> {
+        sqlx::query_as!(
+            Deployment,
+            r#"select id, url_id, timestamp, created, env, sha, branch, result as "result: BuildResult", build_started, build_finished, project, flags, tag, slug, stopped, image_size, labels from deployments where deployments.url_id = ? order by id desc limit 1"#,
+            url_id
+        )
+        .fetch_optional(&self.conn)
+        .await
+        .unwrap()
+    }
+
+    /// Deployments of the commit across the projects that are not deleted, `sha` can be a prefix
+    pub(crate) async fn get_deployments_by_sha(&self, sha: &str) -> Vec<Deployment> {
+        let pattern = format!("{sha}%");
+        sqlx::query_as!(
+            Deployment,
+            r#"select id, url_id, timestamp, created, env, sha, branch, result as "result: BuildResult", build_started, build_finished, project, flags, tag, slug, stopped, image_size, labels from deployments where deployments.sha like ? and deployments.project in (select id from projects where deleted is null) order by project, id desc"#,
+            pattern
+        )
+        .fetch_all(&self.conn)
+        .await
+        .unwrap()
+    }
+
+    /// zero if there are no deployments yet
+    pub(crate) async fn get_last_deployment_id(&self) -> i64 {
+        sqlx::query_scalar!(r#"select coalesce(max(id), 0) as "id!: i64" from deployments"#)
+            .fetch_one(&self.conn)
+            .await
+            .unwrap()
+    }
+
+    pub(crate) async fn get_deployments_after(&self, id: i64) -> Vec<Deployment> {
+        sqlx::query_as!(
+            Deployment,
+            r#"select id, url_id, timestamp, created, env, sha, branch, result as "result: BuildResult", build_started, build_finished, project, flags, tag, slug, stopped, image_size, labels from deployments where deployments.id > ? order by id"#,
+            id
+        )
+        .fetch_all(&self.conn)
+        .await
+        .unwrap()
+    }
+
     pub(crate) async fn delete_deployment(&self, id: i64) {
         sqlx::query!("delete from deployments where id = ?", id)
             .execute(&self.conn)
@@ -306,7 +1575,7 @@ impl Db {
     pub(crate) async fn get_deployments(&self) -> impl Iterator<Item = Deployment> {
         sqlx::query_as!(
             Deployment,
-            r#"select id, url_id, timestamp, created, env, sha, branch, result as "result: BuildResult", build_started, build_finished, project from deployments"#
+            r#"select id, url_id, timestamp, created, env, sha, branch, result as "result: BuildResult", build_started, build_finished, project, flags, tag, slug, stopped, image_size, labels from deployments"#
         )
         .fetch_all(&self.conn)
         .await
@@ -323,12 +1592,33 @@ impl Db {
             .get_deployments()
             .await
             .filter(|deployment| deployment.project == project && deployment.branch == None)
+            .filter(|deployment| deployment.tag.is_none())
             .filter(|deployment| deployment.result != Some(BuildResult::Failed))
             .collect();
         deployments.sort_by_key(|deployment| deployment.timestamp);
         deployments.pop()
     }
 
+    /// The latest deployment for each branch and release of the project, including the default branch
+    pub(crate) async fn get_active_deployments_for_project(&self, project: i64) -> Vec<Deployment> {
+        sqlx::query_as!(
+            Deployment,
+            r#"select id, url_id, timestamp, created, env, sha, branch, result as "result: BuildResult", build_started, build_finished, project, flags, tag, slug, stopped, image_size, labels
+            from deployments as deployment
+            where project = ? and not exists (
+                select 1 from deployments as newer
+                where newer.project = deployment.project
+                and newer.branch is deployment.branch
+                and newer.tag is deployment.tag
+                and (newer.created > deployment.created or (newer.created = deployment.created and newer.id > deployment.id))
+            )"#,
+            project
+        )
+        .fetch_all(&self.conn)
+        .await
+        .unwrap()
+    }
+
     pub(crate) async fn get_deployment_with_project(
         &self,
         deployment: i64,
@@ -356,22 +1646,38 @@ impl Db {
         })
     }
 
-    pub(crate) async fn insert_deployment(&self, deployment: InsertDeployment) {
+    pub(crate) async fn insert_deployment(&self, deployment: InsertDeployment) -> i64 {
         let created = time::now();
         let url_id = create_deployment_url_id();
         sqlx::query!(
-            "insert into deployments (url_id, timestamp, created, env, sha, branch, project) values (?, ?, ?, ?, ?, ?, ?)",
+            "insert into deployments (url_id, timestamp, created, env, sha, branch, tag, project) values (?, ?, ?, ?, ?, ?, ?, ?)",
             url_id,
             deployment.timestamp,
             created,
             deployment.env,
             deployment.sha,
             deployment.branch,
+            deployment.tag,
             deployment.project
         )
         .execute(&self.conn)
         .await
-        .unwrap();
+        .unwrap()
+        .last_insert_rowid()
+    }
+
+    pub(crate) async fn update_deployment_flags(&self, id: i64, flags: &str) {
+        sqlx::query!("update deployments set flags = ? where id = ?", flags, id)
+            .execute(&self.conn)
+            .await
+            .unwrap();
+    }
+
+    pub(crate) async fn update_deployment_labels(&self, id: i64, labels: &str) {
+        sqlx::query!("update deployments set labels = ? where id = ?", labels, id)
+            .execute(&self.conn)
+            .await
+            .unwrap();
     }
 
     pub(crate) async fn update_deployment_result(&self, id: i64, status: BuildResult) {
@@ -392,6 +1698,73 @@ impl Db {
         .unwrap();
     }
 
+    pub(crate) async fn update_deployment_slug(&self, id: i64, slug: Option<&str>) {
+        sqlx::query!("update deployments set slug = ? where id = ?", slug, id)
+            .execute(&self.conn)
+            .await
+            .unwrap();
+    }
+
+    pub(crate) async fn update_deployment_stopped(&self, id: i64, stopped: bool) {
+        sqlx::query!(
+            "update deployments set stopped = ? where id = ?",
+            stopped,
+            id
+        )
+        .execute(&self.conn)
+        .await
+        .unwrap();
+    }
+
+    pub(crate) async fn update_deployment_build_plan(&self, id: i64, plan: &BuildPlan) {
+        let plan = serde_json::to_string(plan).unwrap();
+        sqlx::query!(
+            "update deployments set build_plan = ? where id = ?",
+            plan,
+            id
+        )
+        .execute(&self.conn)
+        .await
+        .unwrap();
+    }
+
+    /// None if the deployment was never built or it doesn't exist
+    pub(crate) async fn get_deployment_build_plan(&self, id: i64) -> Option<BuildPlan> {
+        let record = sqlx::query!("select build_plan from deployments where id = ?", id)
+            .fetch_optional(&self.conn)
+            .await
+            .unwrap()?;
+        serde_json::from_str(&record.build_plan?).ok()
+    }
+
+    /// Image ids by base image, for the image the deployment was last built from
+    pub(crate) async fn update_deployment_base_images(
+        &self,
+        id: i64,
+        images: &HashMap<String, String>,
+    ) {
+        let images = serde_json::to_string(images).unwrap();
+        sqlx::query!(
+            "update deployments set base_images = ? where id = ?",
+            images,
+            id
+        )
+        .execute(&self.conn)
+        .await
+        .unwrap();
+    }
+
+    /// Empty if the deployment was never built or it doesn't exist
+    pub(crate) async fn get_deployment_base_images(&self, id: i64) -> HashMap<String, String> {
+        let record = sqlx::query!("select base_images from deployments where id = ?", id)
+            .fetch_optional(&self.conn)
+            .await
+            .unwrap();
+        record
+            .and_then(|record| serde_json::from_str(&record.base_images?).ok())
+            .unwrap_or_default()
+    }
+
     pub(crate) async fn update_deployment_build_end(&self, id: i64, build_finished: i64) {
         sqlx::query!(
             "update deployments set build_finished = ? where id = ?",
@@ -403,6 +1776,17 @@ impl Db {
         .unwrap();
     }
 
+    pub(crate) async fn update_deployment_image_size(&self, id: i64, image_size: Option<i64>) {
+        sqlx::query!(
+            "update deployments set image_size = ? where id = ?",
+            image_size,
+            id
+        )
+        .execute(&self.conn)
+        .await
+        .unwrap();
+    }
+
     pub(crate) async fn reset_deployment_build_end(&self, id: i64) {
         sqlx::query!(
             "update deployments set build_finished = NULL where id = ?",
@@ -413,6 +1797,69 @@ impl Db {
         .unwrap();
     }
 
+    /// Fails if the database can't be written to, like when some other connection holds the lock
+    pub(crate) async fn check_writable(&self) -> anyhow::Result<()> {
+        // changes nothing, but still needs the write lock
+        sqlx::query!("update read_only set since = since where 0")
+            .execute(&self.conn)
+            .await?;
+        Ok(())
+    }
+
+    pub(crate) async fn get_read_only(&self) -> Option<ReadOnly> {
+        sqlx::query_as!(
+            ReadOnly,
+            r#"select message, pause_deployments as "pause_deployments: bool", since from read_only"#
+        )
+        .fetch_optional(&self.conn)
+        .await
+        .unwrap()
+    }
+
+    /// Keeps the original time if the box was already read only
+    pub(crate) async fn set_read_only(&self, message: Option<&str>, pause_deployments: bool) {
+        let since = now();
+        sqlx::query!(
+            "insert into read_only (id, message, pause_deployments, since) values (1, ?, ?, ?)
+            on conflict(id) do update set message = excluded.message, pause_deployments = excluded.pause_deployments",
+            message,
+            pause_deployments,
+            since
+        )
+        .execute(&self.conn)
+        .await
+        .unwrap();
+    }
+
+    pub(crate) async fn clear_read_only(&self) {
+        sqlx::query!("delete from read_only")
+            .execute(&self.conn)
+            .await
+            .unwrap();
+    }
+
+    pub(crate) async fn are_deployments_paused(&self) -> bool {
+        self.get_read_only()
+            .await
+            .is_some_and(|read_only| read_only.pause_deployments)
+    }
+
+    /// Average duration in millis of the latest finished builds
+    pub(crate) async fn get_average_build_duration(&self, builds: i64) -> Option<i64> {
+        let average = sqlx::query_scalar!(
+            r#"select avg(build_finished - build_started) as "average: f64" from (
+                select build_started, build_finished from deployments
+                where build_started is not null and build_finished is not null
+                order by build_finished desc limit ?
+            )"#,
+            builds
+        )
+        .fetch_one(&self.conn)
+        .await
+        .unwrap();
+        average.map(|average| average as i64)
+    }
+
     pub(crate) async fn get_deployment_build_logs(&self, deployment: i64) -> Vec<BuildLog> {
         sqlx::query_as!(
             BuildLog,
@@ -424,6 +1871,25 @@ impl Db {
         .unwrap()
     }
 
+    /// Logs with an id over `after`, oldest first
+    pub(crate) async fn get_deployment_build_logs_after(
+        &self,
+        deployment: i64,
+        after: i64,
+        limit: i64,
+    ) -> Vec<BuildLog> {
+        sqlx::query_as!(
+            BuildLog,
+            r#"select * from build where build.deployment = ? and build.id > ? order by build.id limit ?"#,
+            deployment,
+            after,
+            limit
+        )
+        .fetch_all(&self.conn)
+        .await
+        .unwrap()
+    }
+
     pub(crate) async fn insert_deployment_build_log(
         &self,
         deployment: i64,
@@ -444,6 +1910,20 @@ impl Db {
         .unwrap();
     }
 
+    /// Marker line telling the phase starting from that point of the build logs
+    pub(crate) async fn insert_deployment_build_phase(&self, deployment: i64, phase: &str) {
+        let time = now();
+        sqlx::query!(
+            "insert into build (timestamp, content, error, deployment, phase) values (?, '', 0, ?, ?)",
+            time,
+            deployment,
+            phase
+        )
+        .execute(&self.conn)
+        .await
+        .unwrap();
+    }
+
     pub(crate) async fn clear_deployment_build_logs(&self, deployment: i64) {
         sqlx::query!("delete from build where build.deployment = ?", deployment)
             .execute(&self.conn)
@@ -451,11 +1931,41 @@ impl Db {
             .unwrap();
     }
 
+    /// Deletes the build logs of the project deployments written before `threshold`,
+    /// returns how many lines were removed
+    pub(crate) async fn delete_build_logs_before(&self, project: i64, threshold: i64) -> u64 {
+        sqlx::query!(
+            "delete from build where build.timestamp < ? and build.deployment in (select id from deployments where project = ?)",
+            threshold,
+            project
+        )
+        .execute(&self.conn)
+        .await
+        .unwrap()
+        .rows_affected()
+    }
+
+    pub(crate) async fn tag_exists(&self, project: i64, tag: &str) -> bool {
+        sqlx::query!(
+            "select id from deployments where project = ? and tag = ?",
+            project,
+            tag
+        )
+        .fetch_optional(&self.conn)
+        .await
+        .unwrap()
+        .is_some()
+    }
+
     pub(crate) async fn hash_exists(&self, sha: &str) -> bool {
-        sqlx::query!("select id from deployments where deployments.sha=?", sha)
-            .fetch_optional(&self.conn)
-            .await
-            .unwrap()
-            .is_some()
+        // releases are tracked by tag instead
+        sqlx::query!(
+            "select id from deployments where deployments.sha=? and tag is null",
+            sha
+        )
+        .fetch_optional(&self.conn)
+        .await
+        .unwrap()
+        .is_some()
     }
 }
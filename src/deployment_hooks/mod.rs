@@ -1,10 +1,17 @@
-use std::fmt;
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 use async_trait::async_trait;
 
 use crate::{
+    api::Status,
+    container::build_plan::BuildPlan,
     db::{BuildResult, Db},
     time::now,
+    webhooks::{WebhookNotifier, WebhookPayload},
 };
 
 // type DeploymentHooks = Box<dyn DeploymentHooksOps>;
@@ -12,24 +19,68 @@ use crate::{
 #[async_trait]
 pub(crate) trait DeploymentHooks: 'static + Send + Sync + fmt::Debug {
     async fn on_build_log(&self, output: &str, error: bool);
+    async fn on_build_phase(&self, phase: &str);
     async fn on_build_started(&self);
+    async fn on_build_plan(&self, plan: &BuildPlan);
+    async fn on_base_images(&self, images: &HashMap<String, String>);
+    async fn on_image_size(&self, size: i64);
     async fn on_build_finished(&self);
     async fn on_build_failed(&self);
+    async fn on_container_ready(&self);
+}
+
+/// What the webhooks need to know about the deployment the hooks belong to
+#[derive(Debug, Clone)]
+pub(crate) struct DeploymentInfo {
+    pub(crate) project_id: i64,
+    pub(crate) project_name: String,
+    pub(crate) sha: String,
+    pub(crate) url: String,
 }
 
 #[derive(Debug)]
 pub(crate) struct StatusHooks {
     db: Db,
     id: i64,
+    info: DeploymentInfo,
+    webhooks: WebhookNotifier,
+    /// the container becomes ready on every wake up, but only the first time after a build is notified
+    ready_notified: AtomicBool,
 }
 
 impl StatusHooks {
-    pub(crate) fn new(db: Db, deployment_id: i64) -> Self {
+    pub(crate) fn new(
+        db: Db,
+        deployment_id: i64,
+        info: DeploymentInfo,
+        webhooks: WebhookNotifier,
+    ) -> Self {
         Self {
             db,
             id: deployment_id,
+            info,
+            webhooks,
+            ready_notified: false.into(),
         }
     }
+
+    fn notify(&self, status: Status) {
+        let DeploymentInfo {
+            project_id,
+            project_name,
+            sha,
+            url,
+        } = self.info.clone();
+        self.webhooks.notify(WebhookPayload {
+            deployment: self.id,
+            project_id,
+            project: project_name,
+            sha,
+            status,
+            url,
+            time: now(),
+        });
+    }
 }
 
 // TODO: write also error status to db, and send updates to github!!
@@ -41,10 +92,31 @@ impl DeploymentHooks for StatusHooks {
             .await;
     }
 
+    async fn on_build_phase(&self, phase: &str) {
+        self.db.insert_deployment_build_phase(self.id, phase).await;
+    }
+
     async fn on_build_started(&self) {
         self.db.clear_deployment_build_logs(self.id).await;
         self.db.update_deployment_build_start(self.id, now()).await;
         self.db.reset_deployment_build_end(self.id).await;
+        self.db.update_deployment_image_size(self.id, None).await;
+        self.ready_notified.store(false, Ordering::Relaxed);
+        self.notify(Status::Building);
+    }
+
+    async fn on_build_plan(&self, plan: &BuildPlan) {
+        self.db.update_deployment_build_plan(self.id, plan).await;
+    }
+
+    async fn on_base_images(&self, images: &HashMap<String, String>) {
+        self.db.update_deployment_base_images(self.id, images).await;
+    }
+
+    async fn on_image_size(&self, size: i64) {
+        self.db
+            .update_deployment_image_size(self.id, Some(size))
+            .await;
     }
 
     async fn on_build_finished(&self) {
@@ -58,7 +130,14 @@ impl DeploymentHooks for StatusHooks {
         self.db.update_deployment_build_end(self.id, now()).await;
         self.db
             .update_deployment_result(self.id, BuildResult::Failed)
-            .await
+            .await;
+        self.notify(Status::Failed);
+    }
+
+    async fn on_container_ready(&self) {
+        if !self.ready_notified.swap(true, Ordering::Relaxed) {
+            self.notify(Status::Ready);
+        }
     }
 }
 
@@ -68,7 +147,12 @@ pub(crate) struct NoopHooks;
 #[async_trait]
 impl DeploymentHooks for NoopHooks {
     async fn on_build_log(&self, _output: &str, error: bool) {}
+    async fn on_build_phase(&self, _phase: &str) {}
     async fn on_build_started(&self) {}
+    async fn on_build_plan(&self, _plan: &BuildPlan) {}
+    async fn on_base_images(&self, _images: &HashMap<String, String>) {}
+    async fn on_image_size(&self, _size: i64) {}
     async fn on_build_finished(&self) {}
     async fn on_build_failed(&self) {}
+    async fn on_container_ready(&self) {}
 }
@@ -0,0 +1,127 @@
+use anyhow::{bail, ensure};
+use chrono::{DateTime, Datelike, Timelike, Utc};
+
+/// Standard five field cron expression: minute, hour, day of the month, month and day of the week,
+/// evaluated in UTC. Fields take `*`, values, ranges, lists and steps, like `*/15` or `1-5`
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct CronSchedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// as in cron, if both days and weekdays are restricted matching either of them is enough
+    any_day: bool,
+}
+
+impl CronSchedule {
+    pub(crate) fn parse(expression: &str) -> anyhow::Result<Self> {
+        let expression = match expression.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            expression => expression,
+        };
+        let fields: Vec<_> = expression.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            bail!("cron expressions need 5 fields: minute, hour, day, month and weekday");
+        };
+        // 7 is also sunday
+        let weekdays_bits = parse_field(weekdays, 0, 7)?;
+        let weekdays_bits = (weekdays_bits | weekdays_bits >> 7) & 0x7f;
+        Ok(Self {
+            minutes: parse_field(minutes, 0, 59)?,
+            hours: parse_field(hours, 0, 23)?,
+            days: parse_field(days, 1, 31)?,
+            months: parse_field(months, 1, 12)?,
+            weekdays: weekdays_bits,
+            any_day: !days.starts_with('*') && !weekdays.starts_with('*'),
+        })
+    }
+
+    pub(crate) fn matches(&self, time: &DateTime<Utc>) -> bool {
+        let has = |bits: u64, value: u32| bits & (1 << value) != 0;
+        let day = has(self.days, time.day());
+        let weekday = has(self.weekdays, time.weekday().num_days_from_sunday());
+        let day_matches = if self.any_day {
+            day || weekday
+        } else {
+            day && weekday
+        };
+        has(self.minutes, time.minute())
+            && has(self.hours, time.hour())
+            && has(self.months, time.month())
+            && day_matches
+    }
+}
+
+/// Bitmask with the values the field allows
+fn parse_field(field: &str, min: u32, max: u32) -> anyhow::Result<u64> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>()?),
+            None => (part, 1),
+        };
+        ensure!(step > 0, "invalid step in {field}");
+        let (start, end) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((start, end)) => (start.parse()?, end.parse()?),
+                // like in cron, 5/10 means from 5 to the end every 10
+                None if part.contains('/') => (range.parse()?, max),
+                None => {
+                    let value = range.parse()?;
+                    (value, value)
+                }
+            },
+        };
+        ensure!(
+            min <= start && start <= end && end <= max,
+            "{part} is out of the {min}-{max} range"
+        );
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+#[cfg(test)]
+mod cron_tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::CronSchedule;
+
+    #[test]
+    fn test_cron_schedule() {
+        let time = |day, hour, minute| Utc.with_ymd_and_hms(2024, 1, day, hour, minute, 0).unwrap();
+
+        // 2024-01-01 is a monday
+        let weekdays = CronSchedule::parse("*/15 9-17 * * 1-5").unwrap();
+        assert!(weekdays.matches(&time(1, 9, 30)));
+        assert!(!weekdays.matches(&time(1, 9, 31)));
+        assert!(!weekdays.matches(&time(1, 18, 0)));
+        assert!(!weekdays.matches(&time(6, 9, 30)));
+
+        let daily = CronSchedule::parse("@daily").unwrap();
+        assert!(daily.matches(&time(7, 0, 0)));
+        assert!(!daily.matches(&time(7, 1, 0)));
+
+        // either the day of the month or sunday, written as 7
+        let either = CronSchedule::parse("0 0 15 * 7").unwrap();
+        assert!(either.matches(&time(15, 0, 0)));
+        assert!(either.matches(&time(7, 0, 0)));
+        assert!(!either.matches(&time(8, 0, 0)));
+    }
+
+    #[test]
+    fn test_invalid_cron_schedule() {
+        assert!(CronSchedule::parse("* * * *").is_err());
+        assert!(CronSchedule::parse("60 * * * *").is_err());
+        assert!(CronSchedule::parse("*/0 * * * *").is_err());
+        assert!(CronSchedule::parse("5-1 * * * *").is_err());
+        assert!(CronSchedule::parse("a * * * *").is_err());
+    }
+}
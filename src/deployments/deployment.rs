@@ -1,31 +1,49 @@
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
-use crate::container::commit::CommitContainer;
+use log::error;
+
+use crate::container::commit::{get_app_env, CommitContainer};
+use crate::container::network::NetworkConfig;
 use crate::container::prisma::PrismaContainer;
-use crate::container::ContainerStatus;
+use crate::container::{ContainerStatus, DEFAULT_PORT};
 use crate::db::{BuildResult, Deployment as DbDeployment};
-use crate::deployment_hooks::StatusHooks;
+use crate::deployment_hooks::{DeploymentInfo, StatusHooks};
+use crate::docker::NamedVolume;
+use crate::env::EnvVars;
+use crate::ip_filter::IpFilter;
 use crate::paths::HostFile;
+use crate::secrets::Secrets;
+use crate::webhooks::WebhookNotifier;
 use crate::{
     container::Container,
-    db::{Db, DeploymentWithProject},
+    db::{Db, DeploymentWithProject, Project},
     github::Github,
 };
 
-use super::label::Label;
+use super::label::{tag_to_label, Label};
 use super::manager::Manager;
 use super::worker::WorkerHandle;
 
 #[derive(Debug)]
 pub(crate) struct Deployment {
     pub(crate) branch: Option<String>,
+    /// release deployments are reachable through a label derived from the tag
+    pub(crate) tag: Option<String>,
+    /// takes precedence over the tag and the url id in the hostname
+    pub(crate) slug: Option<String>,
     pub(crate) sha: String,
     pub(crate) id: i64,
     pub(crate) project: i64,
     pub(crate) url_id: String,
     pub(crate) timestamp: i64,
     pub(crate) created: i64,
+    pub(crate) env: String,
+    pub(crate) flags: String,
+    db_file: HostFile,
+    /// set by the project, otherwise the app one or the default one is used
+    port: Option<u16>,
     // pub(crate) target_hostname: String,
     // pub(crate) deployment_hostname: String,
     // pub(crate) prisma_hostname: String,
@@ -44,11 +62,16 @@ impl Deployment {
         [self.app_container.clone(), self.prisma_container.clone()].into_iter()
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         deployment: DeploymentWithProject,
         build_queue: WorkerHandle,
         github: Github,
         db: Db,
+        box_domain: &str,
+        webhooks: WebhookNotifier,
+        secrets: &Secrets,
+        start_retries: u32,
     ) -> Self {
         let DeploymentWithProject {
             deployment,
@@ -58,17 +81,23 @@ impl Deployment {
             sha,
             env,
             branch,
+            tag,
+            slug,
             id,
             url_id,
             timestamp,
             created,
+            flags,
             ..
         } = deployment;
 
-        let env = env.into();
+        let raw_env = env.clone();
+        let env = get_metadata_env(id, &sha, branch.as_deref(), tag.as_deref())
+            + secrets.decrypt_env(env.into());
 
         let dbs_path = get_dbs_path(project.id);
-        let cloned_db_file = if branch.is_some() {
+        // releases run old code, so they don't touch the main db either
+        let cloned_db_file = if branch.is_some() || tag.is_some() {
             let path = dbs_path.join(id.to_string());
             Some(HostFile::new(path, "preview.db"))
         } else {
@@ -82,9 +111,29 @@ impl Deployment {
             .unwrap_or_else(|| main_db_file.clone());
 
         let public = branch.is_none();
+        let port = project
+            .container_port
+            .and_then(|port| u16::try_from(port).ok());
 
-        let hooks = StatusHooks::new(db, id);
+        let volume = project
+            .volume_path
+            .clone()
+            .map(|path| NamedVolume::for_project(&project, path));
 
+        let hostname = Label::Deployment {
+            project: project.hostname_label().to_owned(),
+            deployment: label_id(slug.as_deref(), tag.as_deref(), &url_id),
+        }
+        .format_hostname(box_domain);
+        let info = DeploymentInfo {
+            project_id: project.id,
+            project_name: project.name.clone(),
+            sha: sha.clone(),
+            url: format!("https://{hostname}"),
+        };
+        let hooks = StatusHooks::new(db, id, info, webhooks);
+
+        let stopped = deployment.stopped != 0;
         let (inistial_status, build_result) = match deployment.result {
             Some(BuildResult::Failed) => (ContainerStatus::Failed, Some(BuildResult::Failed)),
             Some(BuildResult::Built) => (ContainerStatus::Built, Some(BuildResult::Built)),
@@ -104,33 +153,185 @@ impl Deployment {
             sha.clone(),
             id,
             env,
+            flags.as_str().into(),
             project.root.clone(),
+            project.build_environment.clone(),
             public,
             main_db_file,
             cloned_db_file,
+            volume,
+            project.sidecars.clone(),
+            port,
             inistial_status,
             build_result,
         );
-        let prisma_container = PrismaContainer::new(db_file, build_queue);
+        let prisma_container = PrismaContainer::new(db_file.clone(), build_queue);
 
-        Self {
+        let deployment = Self {
             branch,
+            tag,
+            slug,
             sha,
             id,
             project: project.id,
             url_id,
             timestamp,
             created,
+            env: raw_env,
+            flags,
+            db_file,
+            port,
             forced_prod: project.prod_id.is_some_and(|prod_id| id == prod_id),
             app_container: commit_container.into(),
             prisma_container: prisma_container.into(),
+        };
+        for container in deployment.get_all_containers() {
+            container.set_start_retries(start_retries);
+        }
+        deployment.update_ip_filter(&project);
+        deployment.update_read_timeout(&project);
+        deployment.update_body_rewrites(&project);
+        deployment.update_redirects(&project);
+        deployment.update_route_allowlist(&project);
+        deployment.update_env_schema(&project);
+        deployment.update_max_connections(&project);
+        deployment.update_static_dir(&project);
+        deployment.update_network(&project);
+        deployment.update_standby_grace(&project);
+        deployment.update_upstream_tls(&project);
+        deployment.update_start_command(&project);
+        deployment.update_replicas(&project);
+        deployment.app_container.set_stopped(stopped);
+        deployment
+    }
+
+    pub(crate) fn update_ip_filter(&self, project: &Project) {
+        let ip_filter =
+            IpFilter::new(&project.ip_allowlist, &project.ip_denylist).unwrap_or_else(|error| {
+                error!("invalid ip filter for project {}: {error}", project.id);
+                IpFilter::deny_all()
+            });
+        for container in self.get_all_containers() {
+            container.set_ip_filter(ip_filter.clone());
         }
     }
 
+    pub(crate) fn update_read_timeout(&self, project: &Project) {
+        let read_timeout = project
+            .request_timeout_secs
+            .map(|secs| Duration::from_secs(secs as u64));
+        for container in self.get_all_containers() {
+            container.set_read_timeout(read_timeout);
+        }
+    }
+
+    /// only the app responses are rewritten, not the ones from the db container
+    pub(crate) fn update_body_rewrites(&self, project: &Project) {
+        self.app_container
+            .set_body_rewrites(project.body_rewrites.clone());
+    }
+
+    /// The canonical host redirects go first, so the project rules only see the canonical host
+    pub(crate) fn update_redirects(&self, project: &Project) {
+        let mut redirects = project.canonical_host.redirects(&project.custom_domains);
+        redirects.extend(project.redirects.iter().cloned());
+        self.app_container.set_redirects(redirects);
+    }
+
+    pub(crate) fn update_route_allowlist(&self, project: &Project) {
+        self.app_container
+            .set_route_allowlist(project.route_allowlist.clone());
+    }
+
+    pub(crate) fn update_env_schema(&self, project: &Project) {
+        self.app_container
+            .set_env_schema(project.env_schema.clone());
+    }
+
+    pub(crate) fn update_max_connections(&self, project: &Project) {
+        let max_connections = project.max_connections.map(|max| max as usize);
+        self.app_container.set_max_connections(max_connections);
+    }
+
+    pub(crate) fn update_standby_grace(&self, project: &Project) {
+        let grace = project.standby_grace_secs.unwrap_or(0) as u64;
+        self.app_container
+            .set_standby_grace(Duration::from_secs(grace));
+    }
+
+    /// the db container always speaks plain HTTP
+    pub(crate) fn update_upstream_tls(&self, project: &Project) {
+        self.app_container.set_upstream_tls(project.upstream_tls);
+    }
+
+    pub(crate) fn update_start_command(&self, project: &Project) {
+        self.app_container
+            .set_start_command(project.start_command.clone());
+    }
+
+    pub(crate) fn update_sidecars(&self, project: &Project) {
+        self.app_container.set_sidecars(project.sidecars.clone());
+    }
+
+    /// the db container always runs a single container
+    pub(crate) fn update_replicas(&self, project: &Project) {
+        self.app_container.set_replica_bounds(project.replicas);
+    }
+
+    pub(crate) fn update_network(&self, project: &Project) {
+        self.app_container.set_network(NetworkConfig {
+            network: project.docker_network.clone(),
+            dns_servers: project.dns_servers.clone(),
+        });
+    }
+
+    pub(crate) fn update_static_dir(&self, project: &Project) {
+        self.app_container
+            .set_static_dir(project.static_dir.clone());
+    }
+
+    /// Restarts the app container in the background if the env or the flags changed
+    pub(crate) fn update_env(&mut self, env: &str, flags: &str, secrets: &Secrets) {
+        if self.env == env && self.flags == flags {
+            return;
+        }
+        self.env = env.to_owned();
+        self.flags = flags.to_owned();
+        let metadata = get_metadata_env(
+            self.id,
+            &self.sha,
+            self.branch.as_deref(),
+            self.tag.as_deref(),
+        );
+        let env = metadata + secrets.decrypt_env(EnvVars::from(env)) + EnvVars::from(flags);
+        let env = get_app_env(env, &self.db_file, self.port.unwrap_or(DEFAULT_PORT));
+        let container = self.app_container.clone();
+        let id = self.id;
+        tokio::spawn(async move {
+            if let Err(error) = container.restart_with_env(env).await {
+                error!("failed to apply new env to deployment {id}: {error}");
+            }
+        });
+    }
+
+    /// the slug if set, the tag label for releases, the url id otherwise
+    pub(crate) fn get_label_id(&self) -> String {
+        label_id(self.slug.as_deref(), self.tag.as_deref(), &self.url_id)
+    }
+
     pub(crate) fn get_app_hostname(&self, box_domain: &str, project_name: &str) -> String {
         Label::Deployment {
             project: project_name.to_string(),
-            deployment: self.url_id.to_string(),
+            deployment: self.get_label_id(),
+        }
+        .format_hostname(box_domain)
+    }
+
+    /// based on the url id, so for releases it still points to this exact deployment
+    pub(crate) fn get_commit_hostname(&self, box_domain: &str, project_name: &str) -> String {
+        Label::Deployment {
+            project: project_name.to_string(),
+            deployment: self.url_id.clone(),
         }
         .format_hostname(box_domain)
     }
@@ -145,12 +346,44 @@ impl Deployment {
     pub(crate) fn get_db_hostname(&self, box_domain: &str, project_name: &str) -> String {
         Label::Db {
             project: project_name.to_string(),
-            deployment: self.url_id.to_string(),
+            deployment: self.get_label_id(),
         }
         .format_hostname(box_domain)
     }
 }
 
+/// Set for both the build and the app, the project env takes precedence over them
+fn get_metadata_env(id: i64, sha: &str, branch: Option<&str>, tag: Option<&str>) -> EnvVars {
+    let id = id.to_string();
+    let short_sha = &sha[..sha.len().min(7)];
+    let is_prod = if branch.is_none() && tag.is_none() {
+        "true"
+    } else {
+        "false"
+    };
+    let mut env = vec![
+        ("PREZEL_DEPLOYMENT_ID", id.as_str()),
+        ("PREZEL_GIT_SHA", sha),
+        ("PREZEL_GIT_SHORT_SHA", short_sha),
+        ("PREZEL_IS_PROD", is_prod),
+    ];
+    if let Some(branch) = branch {
+        env.push(("PREZEL_GIT_BRANCH", branch));
+    }
+    if let Some(tag) = tag {
+        env.push(("PREZEL_GIT_TAG", tag));
+    }
+    EnvVars::new(&env)
+}
+
+fn label_id(slug: Option<&str>, tag: Option<&str>, url_id: &str) -> String {
+    match (slug, tag) {
+        (Some(slug), _) => slug.to_owned(),
+        (None, Some(tag)) => tag_to_label(tag),
+        (None, None) => url_id.to_owned(),
+    }
+}
+
 fn get_dbs_path(project_id: i64) -> PathBuf {
     Path::new("sqlite").join(project_id.to_string()) // FIXME: should use the id!!!!!!!!!!
 }
@@ -1,5 +1,8 @@
 use anyhow::ensure;
 
+/// longest DNS label allowed
+const MAX_LABEL_LENGTH: usize = 63;
+
 /// The prefix of the hostname that refers to a resource of a particular app hosted in the server
 #[derive(Debug)]
 pub(crate) enum Label {
@@ -37,26 +40,148 @@ impl Label {
     }
 }
 
-fn parse_label(label: &str) -> Vec<Label> {
-    let parsed = match label.split("-").collect::<Vec<_>>().as_slice() {
-        [project @ .., deployment, "db"] => Some(Label::Db {
-            project: project.join("-"),
-            deployment: deployment.to_string(),
-        }),
-        [project @ .., deployment] => Some(Label::Deployment {
-            project: project.join("-"),
-            deployment: deployment.to_string(),
-        }),
-        _ => None,
-    };
+/// Label used in the hostname of a release deployment, e.g. v1-2-3 for the v1.2.3 tag
+pub(crate) fn tag_to_label(tag: &str) -> String {
+    let label: String = tag
+        .to_lowercase()
+        .chars()
+        .map(|char| {
+            if char.is_ascii_alphanumeric() {
+                char
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    label.trim_matches('-').to_owned()
+}
+
+/// Slugs end up in a DNS label together with the project name, so they follow the same rules.
+/// A trailing `-db` would make the hostname point to the deployment database instead
+pub(crate) fn validate_slug(slug: &str, project: &str) -> anyhow::Result<()> {
+    ensure!(
+        !slug.is_empty()
+            && slug
+                .chars()
+                .all(|char| char.is_ascii_lowercase() || char.is_ascii_digit() || char == '-'),
+        "the slug can only contain lowercase letters, digits and dashes"
+    );
+    ensure!(
+        !slug.starts_with('-') && !slug.ends_with('-'),
+        "the slug can not start or end with a dash"
+    );
+    ensure!(
+        slug != "db" && !slug.ends_with("-db"),
+        "the slug can not end with db"
+    );
+    let label = format!("{project}-{slug}");
+    ensure!(
+        label.len() <= MAX_LABEL_LENGTH,
+        "{label} is longer than {MAX_LABEL_LENGTH} characters"
+    );
+    Ok(())
+}
 
+/// Prefixes replace the project name in the hostnames, so they need to be valid DNS labels
+/// and can not be confused with the hostname of a deployment database
+pub(crate) fn validate_hostname_prefix(prefix: &str) -> anyhow::Result<()> {
+    ensure!(
+        !prefix.is_empty()
+            && prefix
+                .chars()
+                .all(|char| char.is_ascii_lowercase() || char.is_ascii_digit() || char == '-'),
+        "the hostname prefix can only contain lowercase letters, digits and dashes"
+    );
+    ensure!(
+        !prefix.starts_with('-') && !prefix.ends_with('-'),
+        "the hostname prefix can not start or end with a dash"
+    );
+    ensure!(
+        prefix != "api" && !prefix.ends_with("-db"),
+        "the hostname prefix can not be api or end with db"
+    );
+    ensure!(
+        prefix.len() <= MAX_LABEL_LENGTH,
+        "the hostname prefix is longer than {MAX_LABEL_LENGTH} characters"
+    );
+    Ok(())
+}
+
+fn parse_label(label: &str) -> Vec<Label> {
     let production_label = Label::Prod {
         project: label.to_owned(),
     };
+    let parts = label.split("-").collect::<Vec<_>>();
+    // deployment ids have no dashes but release labels might,
+    // so every split is a candidate, starting with the shortest deployment
+    let parsed: Vec<_> = match parts.as_slice() {
+        [rest @ .., "db"] => splits(rest)
+            .map(|(project, deployment)| Label::Db {
+                project,
+                deployment,
+            })
+            .collect(),
+        parts => splits(parts)
+            .map(|(project, deployment)| Label::Deployment {
+                project,
+                deployment,
+            })
+            .collect(),
+    };
+    [production_label].into_iter().chain(parsed).collect()
+}
+
+fn splits<'a>(parts: &'a [&str]) -> impl Iterator<Item = (String, String)> + 'a {
+    (1..parts.len())
+        .rev()
+        .map(|index| (parts[..index].join("-"), parts[index..].join("-")))
+}
+
+#[cfg(test)]
+mod label_tests {
+    use super::{parse_label, tag_to_label, validate_hostname_prefix, validate_slug, Label};
+
+    #[test]
+    fn test_release_labels() {
+        assert_eq!(tag_to_label("v1.2.3"), "v1-2-3");
+        assert_eq!(tag_to_label("Release/2024_01"), "release-2024-01");
+
+        let deployments: Vec<_> = parse_label("my-app-v1-2-3")
+            .into_iter()
+            .filter_map(|label| match label {
+                Label::Deployment {
+                    project,
+                    deployment,
+                } => Some((project, deployment)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(deployments[0], ("my-app-v1-2".to_owned(), "3".to_owned()));
+        assert!(deployments.contains(&("my-app".to_owned(), "v1-2-3".to_owned())));
+    }
+
+    #[test]
+    fn test_validate_slug() {
+        assert!(validate_slug("pr-42", "my-app").is_ok());
+        assert!(validate_slug("PR-42", "my-app").is_err());
+        assert!(validate_slug("pr_42", "my-app").is_err());
+        assert!(validate_slug("-pr", "my-app").is_err());
+        assert!(validate_slug("", "my-app").is_err());
+        assert!(validate_slug("preview-db", "my-app").is_err());
+        assert!(validate_slug(&"a".repeat(56), "my-app").is_ok());
+        assert!(validate_slug(&"a".repeat(57), "my-app").is_err());
+    }
 
-    if let Some(parsed) = parsed {
-        vec![production_label, parsed]
-    } else {
-        vec![production_label]
+    #[test]
+    fn test_validate_hostname_prefix() {
+        assert!(validate_hostname_prefix("shop").is_ok());
+        assert!(validate_hostname_prefix("shop-2").is_ok());
+        assert!(validate_hostname_prefix("Shop").is_err());
+        assert!(validate_hostname_prefix("shop.eu").is_err());
+        assert!(validate_hostname_prefix("-shop").is_err());
+        assert!(validate_hostname_prefix("").is_err());
+        assert!(validate_hostname_prefix("api").is_err());
+        assert!(validate_hostname_prefix("shop-db").is_err());
+        assert!(validate_hostname_prefix(&"a".repeat(64)).is_err());
     }
 }
@@ -1,27 +1,113 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddrV4},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use futures::{stream, StreamExt};
-use tokio::sync::{RwLock, RwLockReadGuard};
+use log::{error, info};
+use serde::Serialize;
+use tokio::sync::{Mutex, RwLock, RwLockReadGuard};
+use utoipa::ToSchema;
 
-use crate::{container::Container, db::Db, github::Github, tls::CertificateStore};
+use crate::{
+    api::Status,
+    conf::{Conf, PreviewRetentionConf},
+    container::{replicas::AUTOSCALE_TICK, Container, ContainerStatus},
+    db::{self, Db, Project},
+    docker::{delete_image, delete_volume, get_project_volume_name, list_unused_images},
+    github::Github,
+    paths::get_image_static_dir,
+    secrets::Secrets,
+    time::now,
+    tls::CertificateStore,
+    webhooks::WebhookNotifier,
+};
 
 use super::{
     deployment::Deployment,
     label::Label,
     map::DeploymentMap,
     worker::{Worker, WorkerHandle},
-    workers::{build::BuildWorker, docker::DockerWorker, github::GithubWorker},
+    workers::{
+        base_image::BaseImageWorker,
+        build::BuildWorker,
+        docker::DockerWorker,
+        github::{GithubWorker, PollSchedule},
+        schedule::ScheduleWorker,
+    },
 };
 
+/// how often projects are checked for being due for a poll, shorter poll intervals are rounded up to this
+const POLL_TICK: Duration = Duration::from_secs(10);
+
+/// images newer than this might come from a build that didn't record them in its container yet
+const PRUNE_MIN_IMAGE_AGE: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Default)]
+pub(crate) struct PrunedImages {
+    pub(crate) images: Vec<String>,
+    pub(crate) reclaimed_bytes: u64,
+}
+
+/// Container waiting for or occupying the build slot
+pub(crate) struct QueuedBuild {
+    pub(crate) deployment: i64,
+    pub(crate) project: i64,
+    /// the prisma container of the deployment instead of the app one
+    pub(crate) db: bool,
+    pub(crate) building: bool,
+    /// set for containers requested through the proxy, these are built first
+    pub(crate) trigger_access: Option<Instant>,
+    pub(crate) created: i64,
+}
+
+#[derive(Serialize, ToSchema, Debug, Clone, Copy, PartialEq)]
+pub(crate) enum RouteKind {
+    /// The API, reached through the proxy
+    Api,
+    CustomDomain,
+    Production,
+    Deployment,
+    Database,
+}
+
+/// Hostname the proxy routes to a deployment container
+pub(crate) struct Route {
+    pub(crate) hostname: String,
+    pub(crate) kind: RouteKind,
+    pub(crate) project: i64,
+    pub(crate) deployment: i64,
+    /// candidate deployment and the percentage of the traffic it gets instead
+    pub(crate) canary: Option<(i64, u8)>,
+    pub(crate) status: Status,
+    pub(crate) socket: Option<SocketAddrV4>,
+}
+
+/// Shadow deployment getting a copy of the requests of the production one
+pub(crate) struct MirrorTarget {
+    pub(crate) deployment: i64,
+    pub(crate) container: Arc<Container>,
+    /// percentage of the requests mirrored
+    pub(crate) percent: u8,
+    /// whether every method is mirrored or only GET, HEAD and OPTIONS
+    pub(crate) all_methods: bool,
+}
+
 #[derive(Clone, Debug)]
 pub(crate) struct Manager {
     pub(crate) box_domain: String,
     deployments: Arc<RwLock<DeploymentMap>>,
     build_worker: Arc<WorkerHandle>,
     github_worker: Arc<WorkerHandle>,
+    poll_schedule: PollSchedule,
+    /// held for the whole github sweep so the timer and manual syncs never overlap
+    github_sweep: Arc<Mutex<()>>,
     docker_worker: Arc<WorkerHandle>,
     db: Db,
     github: Github,
+    pub(crate) secrets: Arc<Secrets>,
 }
 
 // workers:
@@ -31,12 +117,26 @@ pub(crate) struct Manager {
 
 impl Manager {
     pub(crate) fn new(
-        box_domain: String,
+        conf: &Conf,
         github: Github,
         db: Db,
         certificates: CertificateStore,
+        webhooks: WebhookNotifier,
+        secrets: Arc<Secrets>,
     ) -> Self {
-        let deployments: Arc<_> = RwLock::new(DeploymentMap::new(certificates)).into();
+        let box_domain = conf.hostname.clone();
+        let deleted_project_retention =
+            Duration::from_secs(conf.deleted_project_retention_hours * 60 * 60);
+        let log_retention_days = conf.log_retention_days;
+        let previews = conf.previews.clone();
+        let deployments: Arc<_> = RwLock::new(DeploymentMap::new(
+            box_domain.clone(),
+            certificates,
+            webhooks,
+            secrets.clone(),
+            conf.container_start_retries,
+        ))
+        .into();
 
         // TODO: add docker or clean worker and trigger it at the end of the deployment worker flow
 
@@ -51,9 +151,12 @@ impl Manager {
         })
         .into();
 
+        let poll_schedule = PollSchedule::default();
         let github_worker = GithubWorker::start(|_| GithubWorker {
             github: github.clone(),
             db: db.clone(),
+            schedule: poll_schedule.clone(),
+            ignored_tags: Default::default(),
         })
         .into();
 
@@ -68,47 +171,112 @@ impl Manager {
             box_domain,
             build_worker,
             github_worker,
+            poll_schedule,
+            github_sweep: Default::default(),
             docker_worker,
             db,
             github,
+            secrets,
         };
 
-        // TODO: reset the timer every time full_sync_with_github is executed triggered by something else
+        // each project is only polled once its own interval has passed
+        let cloned_manager = manager.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(POLL_TICK);
+            loop {
+                interval.tick().await;
+                cloned_manager.poll_github().await;
+            }
+        });
+
+        let cloned_manager = manager.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+            loop {
+                interval.tick().await;
+                cloned_manager
+                    .purge_deleted_projects(deleted_project_retention)
+                    .await;
+                cloned_manager.cleanup_previews(&previews).await;
+                cloned_manager.prune_build_logs(log_retention_days).await;
+            }
+        });
+
         let cloned_manager = manager.clone();
         tokio::spawn(async move {
-            // let mut interval = tokio::time::interval(Duration::from_secs(60 * 5)); // Every 5 minutes
-            let mut interval = tokio::time::interval(Duration::from_secs(30)); // Every 5 minutes // FIXME: change this back
+            let mut interval = tokio::time::interval(AUTOSCALE_TICK);
             loop {
                 interval.tick().await;
-                cloned_manager.full_sync_with_github().await;
+                cloned_manager.autoscale().await;
             }
         });
 
+        let schedule_worker = ScheduleWorker::start(|_| {
+            ScheduleWorker::new(manager.db.clone(), manager.deployments.clone())
+        });
+        let cloned_manager = manager.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                schedule_worker.trigger_and_wait().await;
+                cloned_manager.sync_with_db().await;
+            }
+        });
+
+        if let Some(updates) = conf.base_image_updates.clone() {
+            let period = Duration::from_secs(updates.interval_hours.max(1) * 60 * 60);
+            let base_image_worker = BaseImageWorker::start(|_| BaseImageWorker {
+                db: manager.db.clone(),
+                conf: updates,
+            });
+            let cloned_manager = manager.clone();
+            tokio::spawn(async move {
+                // the first check waits a full period, so restarts don't trigger a pull of every image
+                let start = tokio::time::Instant::now() + period;
+                let mut interval = tokio::time::interval_at(start, period);
+                loop {
+                    interval.tick().await;
+                    base_image_worker.trigger_and_wait().await;
+                    cloned_manager.sync_with_db().await;
+                }
+            });
+        }
+
         manager
     }
 
-    pub(crate) async fn get_container_by_hostname(&self, hostname: &str) -> Option<Arc<Container>> {
+    /// The client ip is used to decide which side of a canary split the request goes to
+    pub(crate) async fn get_container_by_hostname(
+        &self,
+        hostname: &str,
+        client: Option<IpAddr>,
+    ) -> Option<Arc<Container>> {
         let container = self
             .deployments
             .read()
             .await
-            .get_custom_domain(hostname)
+            .get_custom_domain(hostname, client)
             .map(|deployment| deployment.app_container.clone());
         if let Some(container) = container {
             Some(container)
         } else {
             let labels = Label::strip_from_domain(hostname, &self.box_domain).ok()?;
             let containers =
-                stream::iter(labels).filter_map(|label| self.get_container_by_label(label));
+                stream::iter(labels).filter_map(|label| self.get_container_by_label(label, client));
             Box::pin(containers).next().await
         }
     }
 
-    async fn get_container_by_label(&self, label: Label) -> Option<Arc<Container>> {
+    async fn get_container_by_label(
+        &self,
+        label: Label,
+        client: Option<IpAddr>,
+    ) -> Option<Arc<Container>> {
         let map = self.deployments.read().await;
         match &label {
             Label::Prod { project } => {
-                let deployment = map.get_prod(project)?;
+                let deployment = map.route_prod(project, client)?;
                 Some(deployment.app_container.clone())
             }
             Label::Deployment {
@@ -128,6 +296,28 @@ impl Manager {
         }
     }
 
+    /// Only the production and canary deployments of a project fail over to its fallback
+    pub(crate) async fn get_fallback_container(
+        &self,
+        deployment: i64,
+    ) -> Option<(i64, Arc<Container>)> {
+        let map = self.deployments.read().await;
+        map.get_fallback(deployment)
+            .map(|fallback| (fallback.id, fallback.app_container.clone()))
+    }
+
+    /// Only the production and canary deployments of a project are mirrored
+    pub(crate) async fn get_mirror(&self, deployment: i64) -> Option<MirrorTarget> {
+        let map = self.deployments.read().await;
+        map.get_mirror(deployment)
+            .map(|(mirror, percent, all_methods)| MirrorTarget {
+                deployment: mirror.id,
+                container: mirror.app_container.clone(),
+                percent,
+                all_methods,
+            })
+    }
+
     pub(crate) async fn get_deployment(&self, id: i64) -> Option<RwLockReadGuard<Deployment>> {
         let map = self.deployments.read().await;
         RwLockReadGuard::try_map(map, |map| {
@@ -152,11 +342,174 @@ impl Manager {
         .ok()
     }
 
+    /// Removes the running container of the deployment right away
+    pub(crate) async fn stop_deployment(&self, id: i64) {
+        if let Some(deployment) = self.get_deployment(id).await {
+            deployment.app_container.release().await;
+        }
+        self.docker_worker.trigger();
+    }
+
+    /// Queues a failed deployment to be built again as it is, so the steps completed by the
+    /// previous attempt come from the build cache. False if the deployment is not a failed one
+    pub(crate) async fn retry_failed_build(&self, id: i64) -> bool {
+        let Some(container) = self
+            .get_deployment(id)
+            .await
+            .map(|deployment| deployment.app_container.clone())
+        else {
+            return false;
+        };
+        if !matches!(*container.status.read().await, ContainerStatus::Failed) {
+            return false;
+        }
+        container.enqueue().await;
+        self.build_worker.trigger();
+        true
+    }
+
+    pub(crate) async fn is_deployment_in_build_queue(&self, id: i64) -> bool {
+        match self.get_deployment(id).await {
+            Some(deployment) => matches!(
+                *deployment.app_container.status.read().await,
+                ContainerStatus::Queued { .. } | ContainerStatus::Building
+            ),
+            None => false,
+        }
+    }
+
+    /// Containers building and waiting to be built, in the order the build worker picks them.
+    /// Queued containers nobody requested are actually picked at random, they go last by creation
+    pub(crate) async fn get_build_queue(&self) -> Vec<QueuedBuild> {
+        let map = self.deployments.read().await;
+        let mut queue = vec![];
+        for deployment in map.deployments.values() {
+            let containers = [
+                (false, &deployment.app_container),
+                (true, &deployment.prisma_container),
+            ];
+            for (db, container) in containers {
+                let (building, trigger_access) = match *container.status.read().await {
+                    ContainerStatus::Building => (true, None),
+                    ContainerStatus::Queued { trigger_access } => (false, trigger_access),
+                    _ => continue,
+                };
+                queue.push(QueuedBuild {
+                    deployment: deployment.id,
+                    project: deployment.project,
+                    db,
+                    building,
+                    trigger_access,
+                    created: deployment.created,
+                });
+            }
+        }
+        queue.sort_by_key(|build| {
+            (
+                !build.building,
+                build.trigger_access.is_none(),
+                build.trigger_access,
+                build.created,
+            )
+        });
+        queue
+    }
+
+    /// Hostnames currently routed by the proxy, resolved the same way it does. Deployment
+    /// hostnames are listed both by url id and by label, as both reach the deployment
+    pub(crate) async fn get_routes(&self) -> Vec<Route> {
+        let map = self.deployments.read().await;
+        let mut candidates = vec![];
+        for (domain, project) in &map.custom_domains {
+            candidates.push((domain.clone(), RouteKind::CustomDomain, *project));
+        }
+        for (label, project) in &map.names {
+            let prod = Label::Prod {
+                project: label.clone(),
+            };
+            candidates.push((
+                prod.format_hostname(&self.box_domain),
+                RouteKind::Production,
+                *project,
+            ));
+        }
+
+        let mut routes = vec![];
+        for (hostname, kind, project) in candidates {
+            let Some(prod) = map.get_prod_from_id(project) else {
+                continue;
+            };
+            let canary = map.canaries.get(&project).and_then(|(url_id, percent)| {
+                let canary = map.deployments.get(&(project, url_id.clone()))?;
+                Some((canary.id, *percent))
+            });
+            routes.push(Route {
+                hostname,
+                kind,
+                project,
+                deployment: prod.id,
+                canary,
+                status: prod.app_container.get_status().await,
+                socket: prod.app_container.get_socket().await,
+            });
+        }
+
+        let mut hostnames = HashSet::new();
+        for (label, project) in &map.names {
+            let deployments = map
+                .deployments
+                .values()
+                .filter(|deployment| deployment.project == *project);
+            for deployment in deployments {
+                for id in [deployment.url_id.clone(), deployment.get_label_id()] {
+                    let Some(resolved) = map.get_deployment(label, &id) else {
+                        continue;
+                    };
+                    let app = Label::Deployment {
+                        project: label.clone(),
+                        deployment: id.clone(),
+                    };
+                    let db = Label::Db {
+                        project: label.clone(),
+                        deployment: id,
+                    };
+                    let containers = [
+                        (app, RouteKind::Deployment, &resolved.app_container),
+                        (db, RouteKind::Database, &resolved.prisma_container),
+                    ];
+                    for (label, kind, container) in containers {
+                        let hostname = label.format_hostname(&self.box_domain);
+                        if !hostnames.insert(hostname.clone()) {
+                            continue;
+                        }
+                        routes.push(Route {
+                            hostname,
+                            kind,
+                            project: *project,
+                            deployment: resolved.id,
+                            canary: None,
+                            status: container.get_status().await,
+                            socket: container.get_socket().await,
+                        });
+                    }
+                }
+            }
+        }
+        routes
+    }
+
     pub(crate) async fn get_prod_url_id(&self, project: i64) -> Option<String> {
         let map = self.deployments.read().await;
         Some(map.prod.get(&project)?.to_owned())
     }
 
+    /// the containers scale in the background, so the map is not locked while they start
+    async fn autoscale(&self) {
+        for container in self.deployments.read().await.iter_containers() {
+            container.autoscale();
+        }
+    }
+
     pub(crate) async fn sync_with_db(&self) {
         self.deployments
             .write()
@@ -167,8 +520,184 @@ impl Manager {
         self.docker_worker.trigger();
     }
 
-    /// this triggers all the sync workflows downstream
-    pub(crate) async fn full_sync_with_github(&self) {
+    /// the project deployments should already be gone from the db when calling this
+    pub(crate) async fn delete_project_volume(&self, project: &Project) -> anyhow::Result<()> {
+        self.sync_with_db().await;
+        // make sure no container is still holding the volume before removing it
+        self.docker_worker.trigger_and_wait().await;
+        delete_volume(&get_project_volume_name(project)).await
+    }
+
+    /// Permanently removes the projects deleted longer than `retention` ago
+    async fn purge_deleted_projects(&self, retention: Duration) {
+        let threshold = now() - retention.as_millis() as i64;
+        for project in self.db.get_deleted_projects().await {
+            if project.deleted.is_some_and(|deleted| deleted < threshold) {
+                self.db.delete_project(project.id).await;
+                if project.delete_volume {
+                    if let Err(err) = self.delete_project_volume(&project).await {
+                        error!("Failed to delete volume for project {}: {err}", project.id);
+                    }
+                }
+                info!("purged deleted project {}", project.id);
+            }
+        }
+    }
+
+    /// Deletes the build logs older than the retention of each project, `default_days` for
+    /// the projects with no retention of their own
+    async fn prune_build_logs(&self, default_days: u64) {
+        for project in self.db.get_projects().await {
+            let days = project
+                .log_retention_days
+                .map_or(default_days, |days| days as u64);
+            let threshold = now() - Duration::from_secs(days * 24 * 60 * 60).as_millis() as i64;
+            let removed = self
+                .db
+                .delete_build_logs_before(project.id, threshold)
+                .await;
+            if removed > 0 {
+                info!("pruned {removed} build log lines of project {}", project.id);
+            }
+        }
+    }
+
+    /// Deletes the previews of closed pull requests and the oldest ones beyond the cap,
+    /// together with their containers and images. Production deployments are never removed
+    async fn cleanup_previews(&self, conf: &PreviewRetentionConf) {
+        let mut images = vec![];
+        let mut removed = 0;
+        for project in self.db.get_projects().await {
+            let open_branches = if conf.delete_closed {
+                match self.github.get_open_pulls(&project.repo_id).await {
+                    Ok(pulls) => Some(
+                        pulls
+                            .into_iter()
+                            .map(|pull| pull.head.ref_field)
+                            .collect::<HashSet<_>>(),
+                    ),
+                    Err(error) => {
+                        error!("Failed to get pull requests for {}: {error}", project.name);
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+
+            let prod_url_id = self.get_prod_url_id(project.id).await;
+            let mut previews = self
+                .db
+                .get_deployments()
+                .await
+                .filter(|deployment| deployment.project == project.id)
+                .filter(|deployment| {
+                    deployment.branch.is_some()
+                        && Some(deployment.id) != project.prod_id
+                        && Some(deployment.id) != project.canary_deployment_id
+                        && Some(deployment.id) != project.fallback_deployment_id
+                        && Some(deployment.id) != project.mirror_deployment_id
+                        && prod_url_id.as_ref() != Some(&deployment.url_id)
+                })
+                .collect::<Vec<_>>();
+            previews.sort_by_key(|deployment| -deployment.created); // newest first
+
+            let mut kept = 0;
+            for deployment in previews {
+                let closed = open_branches.as_ref().is_some_and(|open| {
+                    deployment
+                        .branch
+                        .as_ref()
+                        .is_some_and(|branch| !open.contains(branch))
+                });
+                let over_cap = conf.max_per_project.is_some_and(|max| kept >= max);
+                if !closed && !over_cap {
+                    kept += 1;
+                    continue;
+                }
+                let reason = if closed {
+                    "pull request closed"
+                } else {
+                    "too many previews"
+                };
+                info!(
+                    "cleaning up preview deployment {} of {} ({reason})",
+                    deployment.id, project.name
+                );
+                if let Some(deployment) = self.get_deployment(deployment.id).await {
+                    images.extend(deployment.app_container.status.read().await.get_image());
+                }
+                self.db.delete_deployment(deployment.id).await;
+                removed += 1;
+            }
+        }
+
+        if removed > 0 {
+            self.sync_with_db().await;
+            // containers need to be gone before their images can be removed
+            self.docker_worker.trigger_and_wait().await;
+            for image in images {
+                if let Err(error) = delete_image(&image).await {
+                    error!("Failed to delete image {image}: {error}");
+                }
+            }
+        }
+    }
+
+    /// Removes dangling images and the ones built for deployments that are gone.
+    /// Images of the deployments still around are kept, even if they are on stand by
+    pub(crate) async fn prune_images(&self) -> anyhow::Result<PrunedImages> {
+        let mut keep = HashSet::new();
+        {
+            let map = self.deployments.read().await;
+            for container in map.iter_containers() {
+                keep.extend(container.status.read().await.get_image());
+            }
+        }
+
+        let threshold = now() / 1000 - PRUNE_MIN_IMAGE_AGE.as_secs() as i64;
+        let mut pruned = PrunedImages::default();
+        for image in list_unused_images(&keep).await? {
+            if image.created > threshold {
+                continue;
+            }
+            match delete_image(&image.id).await {
+                Ok(()) => {
+                    info!("pruned image {} ({} bytes)", image.id, image.size);
+                    let static_files = get_image_static_dir(&image.id);
+                    if static_files.exists() {
+                        if let Err(error) = std::fs::remove_dir_all(&static_files) {
+                            error!("Failed to remove static files of {}: {error}", image.id);
+                        }
+                    }
+                    pruned.reclaimed_bytes += image.size;
+                    pruned.images.push(image.id);
+                }
+                Err(error) => error!("Failed to prune image {}: {error}", image.id),
+            }
+        }
+        info!(
+            "pruned {} images, {} bytes reclaimed",
+            pruned.images.len(),
+            pruned.reclaimed_bytes
+        );
+        Ok(pruned)
+    }
+
+    /// this triggers all the sync workflows downstream, polling every project regardless of its interval.
+    /// Waits for any sweep in progress and returns the deployments created by this one
+    pub(crate) async fn full_sync_with_github(&self) -> Vec<db::Deployment> {
+        let _sweep = self.github_sweep.lock().await;
+        let last = self.db.get_last_deployment_id().await;
+        self.poll_schedule.lock().unwrap().clear();
+        self.github_worker.trigger_and_wait().await;
+        self.sync_with_db().await;
+        self.db.get_deployments_after(last).await
+    }
+
+    /// polls only the projects whose interval has passed
+    async fn poll_github(&self) {
+        let _sweep = self.github_sweep.lock().await;
         self.github_worker.trigger_and_wait().await;
         self.sync_with_db().await;
     }
@@ -1,5 +1,7 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    net::IpAddr,
     sync::Arc,
 };
 
@@ -9,7 +11,9 @@ use crate::{
     container::{Container, ContainerStatus},
     db::{BuildResult, Db},
     github::Github,
+    secrets::Secrets,
     tls::CertificateStore,
+    webhooks::WebhookNotifier,
 };
 
 use super::{deployment::Deployment, worker::WorkerHandle};
@@ -20,20 +24,45 @@ pub(crate) struct DeploymentMap {
     pub(crate) deployments: HashMap<(i64, String), Deployment>,
     /// values here used to be options, but removing them from the map should be enough
     pub(crate) prod: HashMap<i64, String>,
+    /// candidate deployment and the percentage of the prod traffic it gets
+    pub(crate) canaries: HashMap<i64, (String, u8)>,
+    /// hot standby deployment the production traffic fails over to
+    pub(crate) fallbacks: HashMap<i64, String>,
+    /// shadow deployment, the percentage of the prod requests it gets a copy of and whether
+    /// every method is mirrored
+    pub(crate) mirrors: HashMap<i64, (String, u8, bool)>,
     // pub(crate) ideal_prod: HashMap<i64, Option<String>>,
+    /// project ids by the label used in their hostnames
     pub(crate) names: HashMap<String, i64>,
     pub(crate) certificates: CertificateStore,
     pub(crate) custom_domains: HashMap<String, i64>,
+    box_domain: String,
+    webhooks: WebhookNotifier,
+    secrets: Arc<Secrets>,
+    container_start_retries: u32,
 }
 
 impl DeploymentMap {
-    pub(crate) fn new(store: CertificateStore) -> Self {
+    pub(crate) fn new(
+        box_domain: String,
+        store: CertificateStore,
+        webhooks: WebhookNotifier,
+        secrets: Arc<Secrets>,
+        container_start_retries: u32,
+    ) -> Self {
         Self {
             deployments: Default::default(),
             prod: Default::default(),
+            canaries: Default::default(),
+            fallbacks: Default::default(),
+            mirrors: Default::default(),
             names: Default::default(),
             custom_domains: Default::default(),
             certificates: store,
+            box_domain,
+            webhooks,
+            secrets,
+            container_start_retries,
         }
     }
     pub(crate) fn iter_containers(&self) -> impl Iterator<Item = Arc<Container>> + '_ {
@@ -46,24 +75,76 @@ impl DeploymentMap {
         })
     }
 
+    /// Looks the deployment up by url id, by slug or, for releases, by the label derived from the tag
     pub(crate) fn get_deployment(&self, project: &str, deployment: &str) -> Option<&Deployment> {
         let project_id = self.names.get(project)?;
-        self.deployments.get(&(*project_id, deployment.to_string()))
+        self.deployments
+            .get(&(*project_id, deployment.to_string()))
+            .or_else(|| {
+                self.deployments
+                    .values()
+                    .filter(|other| other.project == *project_id)
+                    .filter(|other| other.tag.is_some() || other.slug.is_some())
+                    .filter(|other| other.get_label_id() == deployment)
+                    .max_by_key(|other| other.created)
+            })
     }
 
-    fn get_prod_from_id(&self, id: i64) -> Option<&Deployment> {
+    pub(crate) fn get_prod_from_id(&self, id: i64) -> Option<&Deployment> {
         let prod_id = self.prod.get(&id)?;
         self.deployments.get(&(id, prod_id.to_string()))
     }
 
+    /// Clients always land on the same side of the split as long as their ip doesn't change
+    fn route_prod_from_id(&self, id: i64, client: Option<IpAddr>) -> Option<&Deployment> {
+        if let Some((canary_id, percent)) = self.canaries.get(&id) {
+            if client.is_some_and(|ip| canary_bucket(ip) < *percent) {
+                return self.deployments.get(&(id, canary_id.to_string()));
+            }
+        }
+        self.get_prod_from_id(id)
+    }
+
     pub(crate) fn get_prod(&self, project: &str) -> Option<&Deployment> {
         let project_id = self.names.get(project)?;
         self.get_prod_from_id(*project_id)
     }
 
-    pub(crate) fn get_custom_domain(&self, domain: &str) -> Option<&Deployment> {
-        let project = self.custom_domains.get(domain)?;
-        self.get_prod_from_id(*project)
+    /// Same as get_prod but taking the canary split into account
+    pub(crate) fn route_prod(&self, project: &str, client: Option<IpAddr>) -> Option<&Deployment> {
+        let project_id = self.names.get(project)?;
+        self.route_prod_from_id(*project_id, client)
+    }
+
+    /// The fallback of the project if the deployment is the one serving its production traffic
+    pub(crate) fn get_fallback(&self, deployment: i64) -> Option<&Deployment> {
+        let serving = self
+            .iter_serving_deployments()
+            .find(|serving| serving.id == deployment)?;
+        let url_id = self.fallbacks.get(&serving.project)?;
+        let fallback = self.deployments.get(&(serving.project, url_id.clone()))?;
+        (fallback.id != deployment).then_some(fallback)
+    }
+
+    /// The mirror of the project if the deployment is the one serving its production traffic,
+    /// along with the percentage of the requests mirrored and whether every method is
+    pub(crate) fn get_mirror(&self, deployment: i64) -> Option<(&Deployment, u8, bool)> {
+        let serving = self
+            .iter_serving_deployments()
+            .find(|serving| serving.id == deployment)?;
+        let (url_id, percent, all_methods) = self.mirrors.get(&serving.project)?;
+        let mirror = self.deployments.get(&(serving.project, url_id.clone()))?;
+        (mirror.id != deployment).then_some((mirror, *percent, *all_methods))
+    }
+
+    pub(crate) fn get_custom_domain(
+        &self,
+        domain: &str,
+        client: Option<IpAddr>,
+    ) -> Option<&Deployment> {
+        // stored lowercased
+        let project = self.custom_domains.get(&domain.to_lowercase())?;
+        self.route_prod_from_id(*project, client)
     }
 
     // TODO: this is currently kind of a mutex because is getting &mut,
@@ -88,7 +169,7 @@ impl DeploymentMap {
             .collect::<HashMap<_, _>>();
         self.names = projects
             .iter()
-            .map(|(id, project)| (project.name.clone(), *id))
+            .map(|(id, project)| (project.hostname_label().to_owned(), *id))
             .collect();
 
         // sync map.custom_domains
@@ -113,15 +194,37 @@ impl DeploymentMap {
 
         // sync map.deployments
         for deployment in required_deployments {
-            if !self
-                .deployments
-                .contains_key(&(deployment.project.id, deployment.deployment.url_id.clone()))
-            {
-                let project = deployment.project.id;
-                let url_id = deployment.deployment.url_id.clone();
-                let deployment =
-                    Deployment::new(deployment, build_queue.clone(), github.clone(), db.clone());
-                self.deployments.insert((project, url_id), deployment);
+            let key = (deployment.project.id, deployment.deployment.url_id.clone());
+            if let Some(existing) = self.deployments.get_mut(&key) {
+                existing.update_env(&deployment.env, &deployment.flags, &self.secrets);
+                existing.update_ip_filter(&deployment.project);
+                existing.update_read_timeout(&deployment.project);
+                existing.update_body_rewrites(&deployment.project);
+                existing.update_redirects(&deployment.project);
+                existing.update_route_allowlist(&deployment.project);
+                existing.update_env_schema(&deployment.project);
+                existing.update_max_connections(&deployment.project);
+                existing.update_static_dir(&deployment.project);
+                existing.update_network(&deployment.project);
+                existing.update_standby_grace(&deployment.project);
+                existing.update_upstream_tls(&deployment.project);
+                existing.update_start_command(&deployment.project);
+                existing.update_sidecars(&deployment.project);
+                existing.update_replicas(&deployment.project);
+                existing.slug = deployment.slug.clone();
+                existing.app_container.set_stopped(deployment.stopped != 0);
+            } else {
+                let deployment = Deployment::new(
+                    deployment,
+                    build_queue.clone(),
+                    github.clone(),
+                    db.clone(),
+                    &self.box_domain,
+                    self.webhooks.clone(),
+                    &self.secrets,
+                    self.container_start_retries,
+                );
+                self.deployments.insert(key, deployment);
             }
         }
         let existing_ids = self.deployments.keys().cloned().collect::<Vec<_>>();
@@ -131,6 +234,66 @@ impl DeploymentMap {
             }
         }
 
+        // sync map.canaries, only built candidates get traffic
+        let mut canaries = HashMap::new();
+        for (id, project) in &projects {
+            let Some(canary_id) = project.canary_deployment_id else {
+                continue;
+            };
+            let candidate = self
+                .deployments
+                .values()
+                .find(|deployment| deployment.id == canary_id && deployment.project == *id);
+            if let Some(candidate) = candidate {
+                let built =
+                    *candidate.app_container.result.read().await == Some(BuildResult::Built);
+                if built && project.canary_percent > 0 {
+                    let percent = project.canary_percent.min(100) as u8;
+                    canaries.insert(*id, (candidate.url_id.clone(), percent));
+                }
+            }
+        }
+        self.canaries = canaries;
+
+        // sync map.fallbacks, same as the canaries
+        let mut fallbacks = HashMap::new();
+        for (id, project) in &projects {
+            let Some(fallback_id) = project.fallback_deployment_id else {
+                continue;
+            };
+            let fallback = self
+                .deployments
+                .values()
+                .find(|deployment| deployment.id == fallback_id && deployment.project == *id);
+            if let Some(fallback) = fallback {
+                if *fallback.app_container.result.read().await == Some(BuildResult::Built) {
+                    fallbacks.insert(*id, fallback.url_id.clone());
+                }
+            }
+        }
+        self.fallbacks = fallbacks;
+
+        // sync map.mirrors, same as the canaries
+        let mut mirrors = HashMap::new();
+        for (id, project) in &projects {
+            let Some(mirror_id) = project.mirror_deployment_id else {
+                continue;
+            };
+            let mirror = self
+                .deployments
+                .values()
+                .find(|deployment| deployment.id == mirror_id && deployment.project == *id);
+            if let Some(mirror) = mirror {
+                let built = *mirror.app_container.result.read().await == Some(BuildResult::Built);
+                if built && project.mirror_percent > 0 {
+                    let percent = project.mirror_percent.min(100) as u8;
+                    let all_methods = project.mirror_all_methods;
+                    mirrors.insert(*id, (mirror.url_id.clone(), percent, all_methods));
+                }
+            }
+        }
+        self.mirrors = mirrors;
+
         // sync map.prod
         self.prod = stream::iter(projects)
             .map(|(id, _)| {
@@ -139,6 +302,8 @@ impl DeploymentMap {
                     .iter()
                     .map(|(_, deployment)| deployment)
                     .filter(|deployment| deployment.project == id)
+                    // releases never become prod on their own
+                    .filter(|deployment| deployment.tag.is_none())
                     .map(|deployment| {
                         (
                             deployment.app_container.clone(),
@@ -200,10 +365,11 @@ impl DeploymentMap {
         //     .await;
 
         // force build and start for prod containers
-        for deployment in self.iter_prod_deployments() {
+        for deployment in self.iter_running_deployments() {
             let status = deployment.app_container.status.read().await.clone();
             match status {
-                ContainerStatus::StandBy { .. } => {
+                // static deployments have nothing to start
+                ContainerStatus::StandBy { .. } if !deployment.app_container.is_static() => {
                     deployment.app_container.start().await;
                 }
                 // the logic to put containers into the queue is a bit duplicated.
@@ -228,9 +394,34 @@ impl DeploymentMap {
             .filter_map(|project| self.get_prod(project))
     }
 
+    /// prod deployments plus the canary ones, all of them are kept running
+    fn iter_serving_deployments(&self) -> impl Iterator<Item = &Deployment> {
+        let canaries = self
+            .canaries
+            .iter()
+            .filter_map(|(id, (url_id, _))| self.deployments.get(&(*id, url_id.clone())));
+        self.iter_prod_deployments().chain(canaries)
+    }
+
+    /// the fallbacks are kept running as well so failing over to them is instant,
+    /// and the mirrors so they take the copied requests the way production would
+    fn iter_running_deployments(&self) -> impl Iterator<Item = &Deployment> {
+        let fallbacks = self
+            .fallbacks
+            .iter()
+            .filter_map(|(id, url_id)| self.deployments.get(&(*id, url_id.clone())));
+        let mirrors = self
+            .mirrors
+            .iter()
+            .filter_map(|(id, (url_id, ..))| self.deployments.get(&(*id, url_id.clone())));
+        self.iter_serving_deployments()
+            .chain(fallbacks)
+            .chain(mirrors)
+    }
+
     async fn get_all_non_prod_containers(&self) -> Vec<Arc<Container>> {
         let prod_deployment_ids = self
-            .iter_prod_deployments()
+            .iter_running_deployments()
             .map(|deployment| deployment.id)
             .collect::<Vec<_>>();
         let all_containers_from_non_prod_deployments = self
@@ -240,7 +431,7 @@ impl DeploymentMap {
             .flat_map(|deployment| deployment.iter_arc_containers());
 
         let prisma_containers_from_prod_deployments = self
-            .iter_prod_deployments()
+            .iter_running_deployments()
             .map(|deployment| deployment.prisma_container.clone());
 
         all_containers_from_non_prod_deployments
@@ -248,3 +439,10 @@ impl DeploymentMap {
             .collect()
     }
 }
+
+/// Stable bucket in 0..100 for a client
+fn canary_bucket(ip: IpAddr) -> u8 {
+    let mut hasher = DefaultHasher::new();
+    ip.hash(&mut hasher);
+    (hasher.finish() % 100) as u8
+}
@@ -1,5 +1,6 @@
+pub(crate) mod cron;
 pub(crate) mod deployment;
-mod label;
+pub(crate) mod label;
 pub(crate) mod manager;
 mod map;
 pub(crate) mod worker;
@@ -0,0 +1,83 @@
+use std::collections::{HashMap, HashSet};
+
+use log::{info, warn};
+
+use crate::{
+    conf::BaseImageUpdatesConf,
+    db::{Db, InsertDeployment},
+    deployments::worker::Worker,
+    docker::{get_image_id, pull_image},
+};
+
+/// Pulls the base images of the active deployments and rebuilds the ones built from an older version
+#[derive(Clone)]
+pub(crate) struct BaseImageWorker {
+    pub(crate) db: Db,
+    pub(crate) conf: BaseImageUpdatesConf,
+}
+
+impl Worker for BaseImageWorker {
+    async fn work(&self) {
+        if self.db.are_deployments_paused().await {
+            return;
+        }
+        let projects = self.db.get_projects().await.into_iter().filter(|project| {
+            self.conf.projects.is_empty() || self.conf.projects.contains(&project.name)
+        });
+        let mut deployments = vec![];
+        for project in projects {
+            for deployment in self.db.get_active_deployments_for_project(project.id).await {
+                let is_preview = deployment.branch.is_some();
+                if deployment.stopped != 0 || (is_preview && !self.conf.include_previews) {
+                    continue;
+                }
+                // deployments not built yet record their base images once they are
+                let images = self.db.get_deployment_base_images(deployment.id).await;
+                if !images.is_empty() {
+                    deployments.push((project.clone(), deployment, images));
+                }
+            }
+        }
+
+        let images: HashSet<_> = deployments
+            .iter()
+            .flat_map(|(_, _, images)| images.keys().cloned())
+            .collect();
+        let mut latest = HashMap::new();
+        for image in images {
+            if let Err(error) = pull_image(&image).await {
+                warn!("failed to pull base image {image}: {error}");
+                continue;
+            }
+            if let Some(id) = get_image_id(&image).await {
+                latest.insert(image, id);
+            }
+        }
+
+        for (project, deployment, images) in deployments {
+            let updated: Vec<_> = images
+                .iter()
+                .filter(|(image, id)| latest.get(*image).is_some_and(|latest| latest != *id))
+                .map(|(image, _)| image.as_str())
+                .collect();
+            if updated.is_empty() {
+                continue;
+            }
+            let insert = InsertDeployment {
+                env: project.resolve_env(deployment.branch.as_deref()),
+                sha: deployment.sha.clone(),
+                branch: deployment.branch.clone(),
+                tag: deployment.tag.clone(),
+                timestamp: deployment.timestamp,
+                project: project.id,
+            };
+            let new_id = self.db.insert_deployment(insert).await;
+            info!(
+                "rebuilding deployment {} of project {} as {new_id}, updated base images: {}",
+                deployment.id,
+                project.name,
+                updated.join(", ")
+            );
+        }
+    }
+}
@@ -4,7 +4,8 @@ use tokio::sync::RwLock;
 
 use crate::{
     deployments::{map::DeploymentMap, worker::Worker},
-    docker::{delete_container, list_managed_container_ids, stop_container},
+    docker::list_managed_container_ids,
+    runtime::runtime,
 };
 
 pub(crate) struct DockerWorker {
@@ -20,8 +21,8 @@ impl Worker for DockerWorker {
                 dbg!(&container);
                 if !self.is_container_in_use(&container).await {
                     dbg!("stopping");
-                    stop_container(&container).await;
-                    delete_container(&container).await;
+                    runtime().stop_container(&container).await;
+                    runtime().delete_container(&container).await;
                 }
             }
 
@@ -35,7 +36,7 @@ impl Worker for DockerWorker {
 impl DockerWorker {
     async fn is_container_in_use(&self, id: &String) -> bool {
         for container in self.map.read().await.iter_containers() {
-            if container.get_container_id().await.as_ref() == Some(id) {
+            if container.get_all_container_ids().await.contains(id) {
                 return true;
             }
         }
@@ -1,4 +1,8 @@
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use tracing::error;
 
@@ -8,20 +12,95 @@ use crate::{
     github::{Commit, Github},
 };
 
+/// used for projects without their own poll interval
+pub(crate) const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// When each project is due for its next poll, projects missing here are polled right away
+pub(crate) type PollSchedule = Arc<Mutex<HashMap<i64, Instant>>>;
+
 #[derive(Clone)]
 pub(crate) struct GithubWorker {
     pub(crate) github: Github,
     pub(crate) db: Db,
+    pub(crate) schedule: PollSchedule,
+    /// tags older than their project, so they are not deployed and don't need to be checked again
+    pub(crate) ignored_tags: Arc<Mutex<HashSet<(i64, String)>>>,
+}
+
+impl GithubWorker {
+    /// Returns whether the project needs to be polled now, scheduling its next poll if so.
+    /// The next poll is scheduled upfront so failing projects also wait for a full interval
+    fn take_if_due(&self, project: &Project, now: Instant) -> bool {
+        let mut schedule = self.schedule.lock().unwrap();
+        if schedule.get(&project.id).is_some_and(|next| *next > now) {
+            return false;
+        }
+        let interval = project
+            .poll_interval_secs
+            .map_or(DEFAULT_POLL_INTERVAL, |secs| {
+                Duration::from_secs(secs as u64)
+            });
+        schedule.insert(project.id, now + interval);
+        true
+    }
+
+    /// Every tag gets a release deployment once, tags pointing to commits older than
+    /// the project are skipped so adding a project doesn't deploy its whole history
+    async fn deploy_new_tags(&self, project: &Project) -> anyhow::Result<()> {
+        let repo_id = &project.repo_id;
+        let project_created = project.created;
+        // tags are deployed the same way as the default branch
+        let env = project.resolve_env(None);
+        let project = project.id;
+        for tag in self.github.get_tags(repo_id).await? {
+            let key = (project, tag.clone());
+            if self.ignored_tags.lock().unwrap().contains(&key)
+                || self.db.tag_exists(project, &tag).await
+            {
+                continue;
+            }
+            let Some(commit) = self.github.get_latest_commit(repo_id, &tag).await? else {
+                continue;
+            };
+            if commit.timestamp < project_created {
+                self.ignored_tags.lock().unwrap().insert(key);
+                continue;
+            }
+            let deployment = InsertDeployment {
+                env: env.clone(),
+                sha: commit.sha,
+                timestamp: commit.timestamp,
+                branch: None,
+                tag: Some(tag),
+                project,
+            };
+            self.db.insert_deployment(deployment).await;
+        }
+        Ok(())
+    }
 }
 
 impl Worker for GithubWorker {
     fn work(&self) -> impl std::future::Future<Output = ()> + Send {
         async {
-            for Project {
-                repo_id, env, id, ..
-            } in self.db.get_projects().await
-            {
-                let commit = get_latest_commit_for_default_branch(&self.github, &repo_id).await;
+            // projects are not marked as polled either, so they are all polled once resumed
+            if self.db.are_deployments_paused().await {
+                return;
+            }
+            let now = Instant::now();
+            let projects = self.db.get_projects().await;
+            // forget about deleted projects
+            self.schedule
+                .lock()
+                .unwrap()
+                .retain(|id, _| projects.iter().any(|project| project.id == *id));
+            let due = projects
+                .into_iter()
+                .filter(|project| self.take_if_due(project, now));
+            for project in due {
+                let repo_id = &project.repo_id;
+                let id = project.id;
+                let commit = get_latest_commit_for_prod_branch(&self.github, &project).await;
                 match commit {
                     Err(error) => {
                         error!("Got error when trying to read from Github: {error}");
@@ -32,10 +111,11 @@ impl Worker for GithubWorker {
                         if let Some(commit) = commit {
                             // TODO: review, doesn't seem to make much sense that this is an Option
                             let deployment = InsertDeployment {
-                                env: env.to_owned(),
+                                env: project.resolve_env(None),
                                 sha: commit.sha,
                                 timestamp: commit.timestamp,
                                 branch: None,
+                                tag: None,
                                 project: id,
                             };
                             add_deployment_to_db_if_missing(&self.db, deployment).await;
@@ -43,11 +123,11 @@ impl Worker for GithubWorker {
                     }
                 }
 
-                let pulls = self.github.get_open_pulls(&repo_id).await.unwrap();
+                let pulls = self.github.get_open_pulls(repo_id).await.unwrap();
                 for pull in pulls {
                     let branch = pull.head.ref_field;
                     // FIXME: some duplicated code in here as in above
-                    let commit = self.github.get_latest_commit(&repo_id, &branch).await;
+                    let commit = self.github.get_latest_commit(repo_id, &branch).await;
                     match commit {
                         Err(error) => {
                             error!("Got error when trying to read from Github: {error}");
@@ -57,10 +137,11 @@ impl Worker for GithubWorker {
                         Ok(commit) => {
                             if let Some(commit) = commit {
                                 let deployment = InsertDeployment {
-                                    env: env.to_owned(),
+                                    env: project.resolve_env(Some(&branch)),
                                     sha: commit.sha,
                                     timestamp: commit.timestamp,
                                     branch: Some(branch),
+                                    tag: None,
                                     project: id,
                                 };
                                 add_deployment_to_db_if_missing(&self.db, deployment).await;
@@ -68,22 +149,28 @@ impl Worker for GithubWorker {
                         }
                     }
                 }
+
+                if let Err(error) = self.deploy_new_tags(&project).await {
+                    error!("Got error when trying to read tags from Github: {error}");
+                }
             }
         }
     }
 }
 
-async fn get_latest_commit_for_default_branch(
+async fn get_latest_commit_for_prod_branch(
     github: &Github,
-    repo_id: &str,
+    project: &Project,
 ) -> anyhow::Result<Option<Commit>> {
-    let default_branch = github.get_default_branch(repo_id).await?;
-    let commit = github.get_latest_commit(repo_id, &default_branch).await?;
+    let prod_branch = github.get_prod_branch(project).await?;
+    let commit = github
+        .get_latest_commit(&project.repo_id, &prod_branch)
+        .await?;
     Ok(commit)
 }
 
 async fn add_deployment_to_db_if_missing(db: &Db, deployment: InsertDeployment) {
     if !db.hash_exists(&deployment.sha).await {
-        db.insert_deployment(deployment).await
+        db.insert_deployment(deployment).await;
     }
 }
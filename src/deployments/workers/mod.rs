@@ -1,3 +1,5 @@
+pub(crate) mod base_image;
 pub(crate) mod build;
 pub(crate) mod docker;
 pub(crate) mod github;
+pub(crate) mod schedule;
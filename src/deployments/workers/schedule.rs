@@ -0,0 +1,122 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use log::{info, warn};
+use tokio::sync::RwLock;
+
+use crate::{
+    container::ContainerStatus,
+    db::{Db, InsertDeployment, Project},
+    deployments::{cron::CronSchedule, map::DeploymentMap, worker::Worker},
+};
+
+/// Rebuilds the production deployment of the projects with a rebuild schedule when it is due
+pub(crate) struct ScheduleWorker {
+    db: Db,
+    map: Arc<RwLock<DeploymentMap>>,
+    /// last minute checked, schedules missed while prezel was not running are not caught up
+    last_check: Mutex<DateTime<Utc>>,
+}
+
+impl Worker for ScheduleWorker {
+    async fn work(&self) {
+        let now = current_minute();
+        let since = std::mem::replace(&mut *self.last_check.lock().unwrap(), now);
+        // like while prezel is not running, rebuilds due during maintenance are skipped
+        if self.db.are_deployments_paused().await {
+            return;
+        }
+        for project in self.db.get_projects().await {
+            let Some(schedule) = project.rebuild_schedule.as_deref() else {
+                continue;
+            };
+            let schedule = match CronSchedule::parse(schedule) {
+                Ok(schedule) => schedule,
+                Err(error) => {
+                    warn!(
+                        "invalid rebuild schedule for project {}: {error}",
+                        project.name
+                    );
+                    continue;
+                }
+            };
+            let mut minute = since + Duration::minutes(1);
+            let mut due = false;
+            while minute <= now && !due {
+                due = schedule.matches(&minute);
+                minute += Duration::minutes(1);
+            }
+            if due {
+                self.rebuild_prod(&project).await;
+            }
+        }
+    }
+}
+
+impl ScheduleWorker {
+    pub(crate) fn new(db: Db, map: Arc<RwLock<DeploymentMap>>) -> Self {
+        Self {
+            db,
+            map,
+            last_check: Mutex::new(current_minute()),
+        }
+    }
+
+    async fn rebuild_prod(&self, project: &Project) {
+        if project.prod_id.is_some() {
+            info!(
+                "skipping scheduled rebuild of project {}, production is pinned to a deployment",
+                project.name
+            );
+            return;
+        }
+        let Some(prod) = self
+            .db
+            .get_latest_successful_prod_deployment_for_project(project.id)
+            .await
+        else {
+            return;
+        };
+        if self.is_sha_building(project.id, &prod.sha).await {
+            info!(
+                "skipping scheduled rebuild of project {}, {} is already being built",
+                project.name, prod.sha
+            );
+            return;
+        }
+        let insert = InsertDeployment {
+            env: project.resolve_env(None),
+            sha: prod.sha.clone(),
+            branch: None,
+            tag: None,
+            timestamp: prod.timestamp,
+            project: project.id,
+        };
+        let new_id = self.db.insert_deployment(insert).await;
+        info!(
+            "scheduled rebuild of deployment {} of project {} as {new_id}",
+            prod.id, project.name
+        );
+    }
+
+    async fn is_sha_building(&self, project: i64, sha: &str) -> bool {
+        let map = self.map.read().await;
+        for deployment in map.deployments.values() {
+            if deployment.project != project || deployment.sha != sha {
+                continue;
+            }
+            let status = deployment.app_container.status.read().await;
+            if matches!(
+                *status,
+                ContainerStatus::Queued { .. } | ContainerStatus::Building
+            ) {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+fn current_minute() -> DateTime<Utc> {
+    Utc::now().duration_trunc(Duration::minutes(1)).unwrap()
+}
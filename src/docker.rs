@@ -1,13 +1,14 @@
 // TODO: maybe this should be as well on the container module
 
-use anyhow::anyhow;
+use anyhow::{anyhow, bail, ensure};
 use bollard::{
     container::{
-        Config, CreateContainerOptions, ListContainersOptions, LogOutput, LogsOptions,
-        NetworkingConfig, StartContainerOptions,
+        Config, CreateContainerOptions, DownloadFromContainerOptions, ListContainersOptions,
+        LogOutput, LogsOptions, NetworkingConfig,
     },
     errors::Error as DockerError,
-    image::BuildImageOptions,
+    image::{BuildImageOptions, CreateImageOptions, ListImagesOptions},
+    network::{ConnectNetworkOptions, InspectNetworkOptions},
     secret::{BuildInfo, EndpointSettings, HostConfig},
     Docker as BollardDoker,
 };
@@ -25,29 +26,76 @@ use hyper::body::Bytes;
 use nanoid::nanoid;
 use serde::Serialize;
 use std::{
-    error::Error,
+    collections::{HashMap, HashSet},
     fmt::format,
     future::{self, Future},
     net::Ipv4Addr,
-    path::Path,
+    path::{Component, Path, PathBuf},
     pin::Pin,
     sync::Arc,
 };
 use tokio::sync::{Mutex, MutexGuard};
 use utoipa::ToSchema;
 
-use crate::{alphabet, env::EnvVars, paths::HostFile};
+use crate::{
+    alphabet, container::network::NetworkConfig, db::Project, env::EnvVars, paths::HostFile,
+    runtime::runtime,
+};
 
 // pub(crate) fn legacy_docker_client() -> Docker {
 //     Docker::unix("/var/run/docker.sock")
 // }
 
+/// Docker compatible API of the configured runtime
 pub(crate) fn docker_client() -> BollardDoker {
-    BollardDoker::connect_with_unix_defaults().unwrap()
+    runtime().client().clone()
 }
 
-const NETWORK_NAME: &'static str = "prezel";
+/// the proxy reaches the containers through this network
+pub(crate) const NETWORK_NAME: &'static str = "prezel";
 const CONTAINER_PREFIX: &'static str = "prezel-";
+const VOLUME_PREFIX: &str = "prezel-volume-";
+/// set on every image built for a deployment, so they can be told apart when pruning
+const MANAGED_IMAGE_LABEL: &str = "prezel.managed";
+
+/// A docker named volume that outlives the containers it gets attached to
+#[derive(Debug, Clone)]
+pub(crate) struct NamedVolume {
+    pub(crate) name: String,
+    pub(crate) path: String,
+}
+
+impl NamedVolume {
+    pub(crate) fn for_project(project: &Project, path: String) -> Self {
+        Self {
+            name: get_project_volume_name(project),
+            path,
+        }
+    }
+
+    /// Docker only mounts on absolute paths, and `..` would escape the intended folder
+    pub(crate) fn validate_path(path: &str) -> anyhow::Result<()> {
+        let mut components = Path::new(path).components();
+        ensure!(
+            components.next() == Some(Component::RootDir),
+            "{path} needs to be an absolute path"
+        );
+        let mut components = components.peekable();
+        ensure!(components.peek().is_some(), "{path} can not be the root");
+        ensure!(
+            components.all(|component| matches!(component, Component::Normal(_))),
+            "{path} can not contain . or .."
+        );
+        Ok(())
+    }
+}
+
+/// sqlite might reuse the id of a deleted project, so the creation time is included
+/// to avoid a new project inheriting the volume of an old one
+pub(crate) fn get_project_volume_name(project: &Project) -> String {
+    let Project { id, created, .. } = project;
+    format!("{VOLUME_PREFIX}{id}-{created}")
+}
 
 pub(crate) async fn get_bollard_container_ipv4(container_id: &str) -> Option<Ipv4Addr> {
     let docker = docker_client();
@@ -122,15 +170,32 @@ pub(crate) async fn create_container<'a, I: Iterator<Item = &'a HostFile>>(
     image: String,
     env: EnvVars,
     host_files: I,
+    volumes: &[NamedVolume],
+    extra_hosts: Vec<String>,
+    network: &NetworkConfig,
+    command: Option<String>,
 ) -> anyhow::Result<String> {
     let docker = docker_client();
-    let binds = host_files
-        .map(|file| {
-            let host = file.get_host_folder().to_str().unwrap().to_owned();
-            let container = file.get_container_folder().to_str().unwrap().to_owned();
-            format!("{host}:{container}")
-        })
-        .collect();
+    if let Some(network) = &network.network {
+        // checked first so no container is left behind
+        if docker
+            .inspect_network(network, None::<InspectNetworkOptions<String>>)
+            .await
+            .is_err()
+        {
+            bail!("the docker network {network} does not exist");
+        }
+    }
+    let file_binds = host_files.map(|file| {
+        let host = file.get_host_folder().to_str().unwrap().to_owned();
+        let container = file.get_container_folder().to_str().unwrap().to_owned();
+        format!("{host}:{container}")
+    });
+    // docker creates the named volume on the fly if it does not exist yet
+    let volume_binds = volumes
+        .iter()
+        .map(|NamedVolume { name, path }| format!("{name}:{path}"));
+    let binds = file_binds.chain(volume_binds).collect();
     let id = nanoid!(21, &alphabet::LOWERCASE_PLUS_NUMBERS);
     let name = format!("{CONTAINER_PREFIX}{id}",);
     let response = docker
@@ -142,8 +207,12 @@ pub(crate) async fn create_container<'a, I: Iterator<Item = &'a HostFile>>(
             Config {
                 image: Some(image),
                 env: Some(env.into()),
+                // nixpacks images run their command through a login shell entrypoint
+                cmd: command.map(|command| vec![command]),
                 host_config: Some(HostConfig {
                     binds: Some(binds),
+                    extra_hosts: Some(extra_hosts),
+                    dns: Some(network.dns_servers.clone()).filter(|dns| !dns.is_empty()),
                     ..Default::default()
                 }),
                 networking_config: Some(NetworkingConfig {
@@ -153,14 +222,88 @@ pub(crate) async fn create_container<'a, I: Iterator<Item = &'a HostFile>>(
             },
         )
         .await?;
+    // docker only takes one network when creating the container
+    if let Some(network) = &network.network {
+        docker
+            .connect_network(
+                network,
+                ConnectNetworkOptions {
+                    container: response.id.as_str(),
+                    endpoint_config: Default::default(),
+                },
+            )
+            .await?;
+    }
     Ok(response.id)
 }
 
-pub(crate) async fn run_container(id: &str) -> Result<(), impl Error> {
+/// Sidecars get the same name prefix as app containers, so the docker worker takes care of them
+pub(crate) async fn create_sidecar_container(
+    image: &str,
+    env: EnvVars,
+    extra_hosts: Vec<String>,
+) -> anyhow::Result<String> {
     let docker = docker_client();
-    docker
-        .start_container(id, None::<StartContainerOptions<String>>)
-        .await
+    let id = nanoid!(21, &alphabet::LOWERCASE_PLUS_NUMBERS);
+    let name = format!("{CONTAINER_PREFIX}{id}",);
+    let response = docker
+        .create_container::<String, _>(
+            Some(CreateContainerOptions {
+                name,
+                platform: None,
+            }),
+            Config {
+                image: Some(image.to_owned()),
+                env: Some(env.into()),
+                host_config: Some(HostConfig {
+                    extra_hosts: Some(extra_hosts),
+                    ..Default::default()
+                }),
+                networking_config: Some(NetworkingConfig {
+                    endpoints_config: [(NETWORK_NAME.to_owned(), Default::default())].into(),
+                }),
+                ..Default::default()
+            },
+        )
+        .await?;
+    Ok(response.id)
+}
+
+/// None if the image is not present locally
+pub(crate) async fn get_image_id(image: &str) -> Option<String> {
+    docker_client().inspect_image(image).await.ok()?.id
+}
+
+/// Size in bytes, None if the image is not present locally
+pub(crate) async fn get_image_size(image: &str) -> Option<i64> {
+    docker_client().inspect_image(image).await.ok()?.size
+}
+
+pub(crate) async fn pull_image(image: &str) -> anyhow::Result<()> {
+    let docker = docker_client();
+    // with no tag docker would pull every tag of the image
+    let has_tag = image.contains('@')
+        || image
+            .rsplit('/')
+            .next()
+            .is_some_and(|last| last.contains(':'));
+    let image = if has_tag {
+        image.to_owned()
+    } else {
+        format!("{image}:latest")
+    };
+    let mut stream = docker.create_image(
+        Some(CreateImageOptions {
+            from_image: image.as_str(),
+            ..Default::default()
+        }),
+        None,
+        None,
+    );
+    while let Some(chunk) = stream.next().await {
+        chunk?;
+    }
+    Ok(())
 }
 
 pub(crate) async fn build_dockerfile<O: Future<Output = ()> + Send, F: FnMut(BuildInfo) -> O>(
@@ -181,6 +324,7 @@ pub(crate) async fn build_dockerfile<O: Future<Output = ()> + Send, F: FnMut(Bui
             BuildImageOptions {
                 t: image_name.clone(),
                 buildargs: buildargs.into(),
+                labels: HashMap::from([(MANAGED_IMAGE_LABEL.to_owned(), "true".to_owned())]),
                 rm: true,
                 forcerm: true, // rm intermediate containers even if the build fails
                 ..Default::default()
@@ -294,21 +438,119 @@ pub(crate) async fn build_dockerfile<O: Future<Output = ()> + Send, F: FnMut(Bui
 //     }
 // }
 
-pub(crate) async fn stop_container(name: &str) -> anyhow::Result<()> {
+/// Copies the content of a folder of the image into `dest`, only regular files and folders
+/// are kept so nothing in there can point outside of it
+pub(crate) async fn copy_from_image(image: &str, path: &str, dest: &Path) -> anyhow::Result<()> {
     let docker = docker_client();
-    docker.stop_container(name, None).await?;
+    // never started and not prefixed, so it is removed here instead of by the docker worker
+    let container = docker
+        .create_container::<String, _>(
+            None,
+            Config {
+                image: Some(image.to_owned()),
+                ..Default::default()
+            },
+        )
+        .await?
+        .id;
+    let mut stream = docker.download_from_container(
+        &container,
+        Some(DownloadFromContainerOptions {
+            path: path.to_owned(),
+        }),
+    );
+    let mut archive = vec![];
+    let downloaded = async {
+        while let Some(chunk) = stream.next().await {
+            archive.extend_from_slice(&chunk?);
+        }
+        anyhow::Ok(())
+    }
+    .await;
+    docker.remove_container(&container, None).await?;
+    downloaded?;
+
+    let mut archive = tar::Archive::new(archive.as_slice());
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_type = entry.header().entry_type();
+        if !entry_type.is_file() && !entry_type.is_dir() {
+            continue;
+        }
+        // the archive root is the folder itself
+        let entry_path = entry.path()?.into_owned();
+        let relative: PathBuf = entry_path.components().skip(1).collect();
+        let inside = relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        if inside {
+            let target = dest.join(relative);
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            entry.unpack(target)?;
+        }
+    }
     Ok(())
 }
 
-pub(crate) async fn delete_container(name: &str) -> anyhow::Result<()> {
+pub(crate) async fn delete_image(name: &str) -> anyhow::Result<()> {
     let docker = docker_client();
-    docker.remove_container(name, None).await?;
+    docker.remove_image(name, None, None).await?;
     Ok(())
 }
 
-pub(crate) async fn delete_image(name: &str) -> anyhow::Result<()> {
+/// Image that can be removed without breaking any container
+pub(crate) struct UnusedImage {
+    pub(crate) id: String,
+    pub(crate) size: u64,
+    /// seconds since the epoch
+    pub(crate) created: i64,
+}
+
+/// Dangling images and images built by prezel, except for the ones in `keep`
+/// or used by any container, running or not
+pub(crate) async fn list_unused_images(keep: &HashSet<String>) -> anyhow::Result<Vec<UnusedImage>> {
     let docker = docker_client();
-    docker.remove_image(name, None, None).await?;
+    let containers = docker
+        .list_containers(Some(ListContainersOptions::<String> {
+            all: true,
+            ..Default::default()
+        }))
+        .await?;
+    let used: HashSet<_> = containers
+        .into_iter()
+        .filter_map(|container| container.image_id)
+        .collect();
+
+    // docker ANDs different filters, so each kind needs its own request
+    let filters = [
+        ("dangling", "true".to_owned()),
+        ("label", MANAGED_IMAGE_LABEL.to_owned()),
+    ];
+    let mut images: Vec<UnusedImage> = vec![];
+    for (name, value) in filters {
+        let options = ListImagesOptions {
+            filters: HashMap::from([(name.to_owned(), vec![value])]),
+            ..Default::default()
+        };
+        for image in docker.list_images(Some(options)).await? {
+            let known = images.iter().any(|unused| unused.id == image.id);
+            if !known && !used.contains(&image.id) && !keep.contains(&image.id) {
+                images.push(UnusedImage {
+                    id: image.id,
+                    size: image.size.max(0) as u64,
+                    created: image.created,
+                });
+            }
+        }
+    }
+    Ok(images)
+}
+
+pub(crate) async fn delete_volume(name: &str) -> anyhow::Result<()> {
+    let docker = docker_client();
+    docker.remove_volume(name, None).await?;
     Ok(())
 }
 
@@ -354,8 +596,9 @@ pub(crate) async fn list_managed_container_ids() -> anyhow::Result<impl Iterator
 #[cfg(test)]
 mod docker_tests {
     use crate::{
-        docker::{create_container, get_bollard_container_ipv4, run_container},
+        docker::{create_container, get_bollard_container_ipv4, NamedVolume},
         paths::HostFile,
+        runtime::{init_runtime, runtime},
     };
 
     // #[tokio::test]
@@ -363,6 +606,15 @@ mod docker_tests {
     //     let ids = list_container_ids().await.unwrap();
     // }
 
+    #[test]
+    fn test_volume_path() {
+        assert!(NamedVolume::validate_path("/data").is_ok());
+        assert!(NamedVolume::validate_path("/var/lib/app/").is_ok());
+        assert!(NamedVolume::validate_path("data").is_err());
+        assert!(NamedVolume::validate_path("/").is_err());
+        assert!(NamedVolume::validate_path("/data/../etc").is_err());
+    }
+
     #[tokio::test]
     async fn test_creating_and_running_container() {
         // let image = build_dockerfile(path, self.config.args.clone(), &mut |chunk| {
@@ -372,10 +624,19 @@ mod docker_tests {
         // let image = image.inspect().await?;
         // let image_id = image.id.ok_or(anyhow!("Image not found"));
 
-        let container = create_container("busybox".to_owned(), Default::default(), [].into_iter())
-            .await
-            .unwrap();
-        run_container(&container).await.unwrap();
+        init_runtime(&Default::default()).unwrap();
+        let container = create_container(
+            "busybox".to_owned(),
+            Default::default(),
+            [].into_iter(),
+            &[],
+            vec![],
+            &Default::default(),
+            None,
+        )
+        .await
+        .unwrap();
+        runtime().start_container(&container).await.unwrap();
         let ip = get_bollard_container_ipv4(&container).await.unwrap();
 
         // run_container("zen_wright").await.unwrap();
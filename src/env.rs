@@ -1,5 +1,13 @@
 use std::{collections::HashMap, ops::Add};
 
+use anyhow::{bail, ensure, Context};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use url::Url;
+use utoipa::ToSchema;
+
+const MASK: &str = "********";
+
 #[derive(Debug, Clone, Default)]
 pub(crate) struct EnvVars(HashMap<String, String>);
 
@@ -11,6 +19,72 @@ impl EnvVars {
     pub(crate) fn empty() -> Self {
         Self(Default::default())
     }
+
+    /// Names with their values hidden, safe to return from the API
+    pub(crate) fn masked(self) -> HashMap<String, String> {
+        self.0
+            .into_keys()
+            .map(|name| (name, MASK.to_owned()))
+            .collect()
+    }
+
+    /// Strict version of the `From<&str>` conversion, reporting the lines it can't read
+    pub(crate) fn parse_dotenv(text: &str) -> anyhow::Result<Self> {
+        let mut env = HashMap::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match parse_env(line) {
+                Some((name, value)) => env.insert(name, value),
+                None => bail!("invalid line {}: {line}", index + 1),
+            };
+        }
+        Ok(Self(env))
+    }
+
+    /// Checks the variables against the rules, naming every missing or invalid one.
+    /// The values are left out of the error as they might be secrets
+    pub(crate) fn validate(&self, schema: &[EnvRule]) -> anyhow::Result<()> {
+        let mut problems = vec![];
+        for rule in schema {
+            let name = &rule.name;
+            let Some(value) = self.0.get(name) else {
+                if rule.required {
+                    problems.push(format!("{name} is missing"));
+                }
+                continue;
+            };
+            if let Some(kind) = rule.kind {
+                if !kind.accepts(value) {
+                    problems.push(format!("{name} is not a valid {kind}"));
+                }
+            }
+            if let Some(pattern) = &rule.pattern {
+                if !rule.regex(pattern)?.is_match(value) {
+                    problems.push(format!("{name} does not match {pattern}"));
+                }
+            }
+        }
+        if !problems.is_empty() {
+            bail!("invalid env: {}", problems.join(", "));
+        }
+        Ok(())
+    }
+
+    /// One `NAME=value` line per variable, sorted by name
+    pub(crate) fn to_dotenv(&self, mask: bool) -> String {
+        let mut names: Vec<_> = self.0.keys().collect();
+        names.sort();
+        names
+            .into_iter()
+            .map(|name| {
+                let value = if mask { MASK } else { &self.0[name] };
+                format!("{name}={value}\n")
+            })
+            .collect()
+    }
 }
 
 impl IntoIterator for EnvVars {
@@ -57,12 +131,19 @@ impl From<EnvVars> for Vec<String> {
     }
 }
 
+impl From<EnvVars> for String {
+    fn from(value: EnvVars) -> Self {
+        let lines: Vec<String> = value.into();
+        lines.join("\n")
+    }
+}
+
 impl From<&str> for EnvVars {
     fn from(value: &str) -> Self {
         value
             .split("\n")
             .map(|line| line.trim())
-            .filter(|&line| line != "")
+            .filter(|&line| line != "" && !line.starts_with('#'))
             .filter_map(parse_env)
             .collect::<HashMap<String, String>>()
             .into()
@@ -75,11 +156,97 @@ impl From<String> for EnvVars {
     }
 }
 
+/// The env text with `name` set to `value`, other lines are kept as they are
+pub(crate) fn set_var(env: &str, name: &str, value: &str) -> String {
+    let mut lines: Vec<_> = env
+        .lines()
+        .filter(|line| parse_env(line.trim()).map_or(true, |(other, _)| other != name))
+        .map(str::to_owned)
+        .collect();
+    lines.push(format!("{name}={value}"));
+    lines.join("\n")
+}
+
+/// Values can contain `=`, only the first one splits the name off
 fn parse_env(env: &str) -> Option<(String, String)> {
-    let tuple: Vec<_> = env.split("=").collect();
-    match tuple[..] {
-        [name, value] => Some((name.to_owned(), value.to_owned())),
-        _ => None,
+    let env = env.strip_prefix("export ").unwrap_or(env);
+    let (name, value) = env.split_once('=')?;
+    let name = name.trim();
+    let valid = !name.is_empty() && !name.contains(char::is_whitespace);
+    valid.then(|| (name.to_owned(), value.to_owned()))
+}
+
+/// Constraint on a variable of the env the app container starts with
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub(crate) struct EnvRule {
+    pub(crate) name: String,
+    /// Optional variables are only checked if set
+    #[serde(default = "default_required")]
+    pub(crate) required: bool,
+    #[serde(default, rename = "type")]
+    pub(crate) kind: Option<EnvType>,
+    /// Regular expression the whole value needs to match
+    #[serde(default)]
+    pub(crate) pattern: Option<String>,
+}
+
+fn default_required() -> bool {
+    true
+}
+
+impl EnvRule {
+    pub(crate) fn validate(schema: &[EnvRule]) -> anyhow::Result<()> {
+        for rule in schema {
+            ensure!(
+                !rule.name.is_empty() && !rule.name.contains(['=', ' ']),
+                "invalid variable name {}",
+                rule.name
+            );
+            if let Some(pattern) = &rule.pattern {
+                rule.regex(pattern)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn regex(&self, pattern: &str) -> anyhow::Result<Regex> {
+        Regex::new(&format!("^(?:{pattern})$"))
+            .with_context(|| format!("invalid pattern for {}", self.name))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum EnvType {
+    Integer,
+    Number,
+    /// `true`, `false`, `1` or `0`
+    Boolean,
+    Url,
+}
+
+impl EnvType {
+    fn accepts(&self, value: &str) -> bool {
+        match self {
+            Self::Integer => value.parse::<i64>().is_ok(),
+            Self::Number => value.parse::<f64>().is_ok_and(f64::is_finite),
+            Self::Boolean => ["true", "false", "1", "0"]
+                .iter()
+                .any(|accepted| value.eq_ignore_ascii_case(accepted)),
+            Self::Url => Url::parse(value).is_ok(),
+        }
+    }
+}
+
+impl std::fmt::Display for EnvType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Integer => "integer",
+            Self::Number => "number",
+            Self::Boolean => "boolean",
+            Self::Url => "url",
+        };
+        f.write_str(name)
     }
 }
 
@@ -91,3 +258,55 @@ impl Add for EnvVars {
         Self(self.0.into_iter().chain(other.0).collect())
     }
 }
+
+#[cfg(test)]
+mod env_tests {
+    use std::collections::HashMap;
+
+    use super::{EnvRule, EnvType, EnvVars};
+
+    #[test]
+    fn test_later_lines_win() {
+        let env: HashMap<_, _> = EnvVars::from("A=base\nB=base\nA=profile").into();
+        assert_eq!(env["A"], "profile");
+        assert_eq!(env["B"], "base");
+    }
+
+    #[test]
+    fn test_dotenv_round_trip() {
+        let text = "# comment\nexport B=x=y\n\nA=1\n";
+        let env = EnvVars::parse_dotenv(text).unwrap();
+        assert_eq!(env.to_dotenv(false), "A=1\nB=x=y\n");
+        assert_eq!(env.to_dotenv(true), "A=********\nB=********\n");
+        assert!(EnvVars::parse_dotenv("A=1\nnot an assignment").is_err());
+    }
+
+    #[test]
+    fn test_env_schema() {
+        let rule = |name: &str, required, kind, pattern: Option<&str>| EnvRule {
+            name: name.to_owned(),
+            required,
+            kind,
+            pattern: pattern.map(str::to_owned),
+        };
+        let schema = vec![
+            rule("DATABASE_URL", true, Some(EnvType::Url), None),
+            rule("WORKERS", false, Some(EnvType::Integer), None),
+            rule("MODE", true, None, Some("dev|prod")),
+        ];
+        EnvRule::validate(&schema).unwrap();
+
+        let valid = EnvVars::from("DATABASE_URL=postgres://db:5432/app\nMODE=prod");
+        valid.validate(&schema).unwrap();
+
+        let invalid = EnvVars::from("WORKERS=many\nMODE=production");
+        let error = invalid.validate(&schema).unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "invalid env: DATABASE_URL is missing, WORKERS is not a valid integer, MODE does not match dev|prod"
+        );
+
+        assert!(EnvRule::validate(&[rule("A", true, None, Some("("))]).is_err());
+        assert!(EnvRule::validate(&[rule("", true, None, None)]).is_err());
+    }
+}
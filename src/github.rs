@@ -1,25 +1,35 @@
-use anyhow::ensure;
+use anyhow::{anyhow, ensure};
 use flate2::read::GzDecoder;
 use http::StatusCode;
 use http_body_util::BodyExt;
-use log::info;
+use log::{info, warn};
 use octocrab::{
     models::{pulls::PullRequest, InstallationRepositories, IssueState, Repository},
     params::{
         checks::{CheckRunConclusion, CheckRunStatus},
         repos::Commitish,
+        State,
     },
     Octocrab, Result as OctocrabResult,
 };
 use serde::Serialize;
-use std::{io::Cursor, path::Path, sync::Arc};
+use std::{collections::HashMap, io::Cursor, iter, path::Path, sync::Arc};
 use tar::Archive;
 use tokio::sync::RwLock;
+use utoipa::ToSchema;
 
-use crate::{conf::Conf, time::now};
+use crate::{
+    conf::{Conf, GithubTokenConf},
+    db::Project,
+    time::now,
+};
 
 const CHECK_NAME: &str = "prezel";
 const COMMENT_START: &'static str = "[prezel]: authored";
+/// a warning is logged when any of the limits has fewer calls left than this
+const RATE_LIMIT_WARNING_THRESHOLD: usize = 100;
+/// how often the limits are sampled while the client is being used
+const RATE_LIMIT_REFRESH_MILLIS: i64 = 60 * 1000;
 
 #[derive(Serialize, Debug)]
 struct RequestBody {
@@ -38,9 +48,41 @@ struct Token {
     millis: i64,
 }
 
+#[derive(Serialize, ToSchema, Clone, Debug)]
+pub(crate) struct RateLimit {
+    pub(crate) limit: usize,
+    pub(crate) remaining: usize,
+    /// unix timestamp in seconds when the limit resets
+    pub(crate) reset: u64,
+}
+
+impl From<octocrab::models::Rate> for RateLimit {
+    fn from(rate: octocrab::models::Rate) -> Self {
+        Self {
+            limit: rate.limit,
+            remaining: rate.remaining,
+            reset: rate.reset,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema, Clone, Debug)]
+pub(crate) struct RateLimits {
+    pub(crate) core: RateLimit,
+    pub(crate) search: RateLimit,
+    /// millis timestamp of when these values were read from Github
+    pub(crate) updated: i64,
+}
+
 #[derive(Clone, Debug)]
 pub(crate) struct Github {
     token: Arc<RwLock<Token>>,
+    /// tried in order for the repos the app installation can't access
+    extra_tokens: Arc<Vec<GithubTokenConf>>,
+    /// index in `extra_tokens` of the token used for each repo, None for the installation
+    repo_tokens: Arc<std::sync::RwLock<HashMap<String, Option<usize>>>>,
+    /// only for the app installation
+    rate_limits: Arc<std::sync::RwLock<Option<RateLimits>>>,
 }
 
 impl Github {
@@ -52,46 +94,100 @@ impl Github {
                     .expect("Failed to get app installation token on startup"),
             )
             .into(),
+            extra_tokens: Arc::new(Conf::read().github_tokens),
+            repo_tokens: Default::default(),
+            rate_limits: Default::default(),
+        }
+    }
+
+    /// Latest rate limits, fetching them if the cached values are not fresh
+    pub(crate) async fn get_rate_limits(&self) -> anyhow::Result<RateLimits> {
+        match self.cached_rate_limits() {
+            Some(limits) if !is_rate_limit_stale(&limits) => Ok(limits),
+            _ => {
+                self.update_token().await?;
+                refresh_rate_limits(&self.build_crab().await, &self.rate_limits).await
+            }
         }
     }
+
+    /// Fails if the app installation can't authenticate, always going to Github
+    pub(crate) async fn check_auth(&self) -> anyhow::Result<()> {
+        self.update_token().await?;
+        refresh_rate_limits(&self.build_crab().await, &self.rate_limits).await?;
+        Ok(())
+    }
+
+    fn cached_rate_limits(&self) -> Option<RateLimits> {
+        self.rate_limits.read().unwrap().clone()
+    }
+
+    /// Every page of them, a missing pull request would look closed to the preview cleanup
     pub(crate) async fn get_open_pulls(&self, repo_id: &str) -> anyhow::Result<Vec<PullRequest>> {
-        let crab = self.get_crab().await?;
-        let (owner, name) = self.get_owner_and_name(repo_id).await?;
-        let pulls = crab.pulls(owner, name).list().send().await?;
+        let (crab, owner, name) = self.get_repo_crab(repo_id).await?;
+        let page = crab
+            .pulls(owner, name)
+            .list()
+            .state(State::Open)
+            .per_page(100)
+            .send()
+            .await?;
+        let pulls = crab.all_pages(page).await?;
         Ok(pulls
             .into_iter()
             .filter(|pull| pull.state == Some(IssueState::Open))
             .collect())
     }
 
+    /// Names of the most recent tags of the repository
+    pub(crate) async fn get_tags(&self, repo_id: &str) -> anyhow::Result<Vec<String>> {
+        let (crab, owner, name) = self.get_repo_crab(repo_id).await?;
+        let tags = crab.repos(owner, name).list_tags().send().await?;
+        Ok(tags.into_iter().map(|tag| tag.name).collect())
+    }
+
     pub(crate) async fn get_repo(&self, id: &str) -> anyhow::Result<Option<Repository>> {
-        let crab = self.get_crab().await?;
-        Ok(crab
-            .get(format!("/repositories/{id}"), None::<&()>)
-            .await
-            .unwrap())
+        Ok(self.find_repo(id).await?.map(|(_, repo)| repo))
     }
 
+    /// Repos of the app installation followed by the ones of the extra tokens
     pub(crate) async fn get_repos(&self) -> anyhow::Result<Vec<Repository>> {
         let crab = self.get_crab().await?;
-        let installation_repos: InstallationRepositories = crab
-            .get("/installation/repositories", None::<&()>)
-            .await
-            .unwrap();
-        Ok(installation_repos.repositories)
+        let installation_repos: InstallationRepositories =
+            crab.get("/installation/repositories", None::<&()>).await?;
+        let mut repos = installation_repos.repositories;
+        for extra in self.extra_tokens.iter() {
+            let user_repos: Vec<Repository> = build_crab(&extra.token)
+                .get("/user/repos", None::<&()>)
+                .await?;
+            for repo in user_repos {
+                if repos.iter().all(|known| known.id != repo.id) {
+                    repos.push(repo);
+                }
+            }
+        }
+        Ok(repos)
     }
 
     pub(crate) async fn get_pull(&self, repo_id: &str, number: u64) -> anyhow::Result<PullRequest> {
-        let crab = self.get_crab().await?;
-        let (owner, name) = self.get_owner_and_name(repo_id).await?;
+        let (crab, owner, name) = self.get_repo_crab(repo_id).await?;
         Ok(crab.pulls(owner, name).get(number).await?)
     }
 
     pub(crate) async fn get_default_branch(&self, repo_id: &str) -> anyhow::Result<String> {
-        let crab = self.get_crab().await?;
-        let (owner, name) = self.get_owner_and_name(repo_id).await?;
-        let repository = crab.repos(owner, name).get().await.unwrap();
-        Ok(repository.default_branch.unwrap())
+        let (crab, owner, name) = self.get_repo_crab(repo_id).await?;
+        let repository = crab.repos(owner, name).get().await?;
+        repository
+            .default_branch
+            .ok_or_else(|| anyhow!("repo {repo_id} has no default branch"))
+    }
+
+    /// Branch the production deployments of the project come from
+    pub(crate) async fn get_prod_branch(&self, project: &Project) -> anyhow::Result<String> {
+        match &project.prod_branch {
+            Some(branch) => Ok(branch.clone()),
+            None => self.get_default_branch(&project.repo_id).await,
+        }
     }
 
     pub(crate) async fn get_latest_commit(
@@ -99,8 +195,7 @@ impl Github {
         repo_id: &str,
         branch: &str,
     ) -> anyhow::Result<Option<Commit>> {
-        let crab = self.get_crab().await?;
-        let (owner, name) = self.get_owner_and_name(repo_id).await?;
+        let (crab, owner, name) = self.get_repo_crab(repo_id).await?;
         Ok(Self::get_latest_commit_option(&crab, &owner, &name, branch).await)
     }
 
@@ -126,8 +221,7 @@ impl Github {
         sha: &str,
         path: &Path,
     ) -> anyhow::Result<()> {
-        let crab = self.get_crab().await?;
-        let (owner, name) = self.get_owner_and_name(repo_id).await?;
+        let (crab, owner, name) = self.get_repo_crab(repo_id).await?;
         let response = crab
             .repos(owner, name)
             .download_tarball(sha.to_owned())
@@ -155,8 +249,7 @@ impl Github {
         status: CheckRunStatus,
         conclusion: Option<CheckRunConclusion>,
     ) -> anyhow::Result<()> {
-        let crab = self.get_crab().await?;
-        let (owner, name) = self.get_owner_and_name(repo_id).await?;
+        let (crab, owner, name) = self.get_repo_crab(repo_id).await?;
         let check_handler = crab.checks(owner, name);
         let checks = check_handler
             .list_check_runs_for_git_ref(Commitish(sha.into()))
@@ -200,8 +293,7 @@ impl Github {
         content: &str,
         pull: u64,
     ) -> anyhow::Result<()> {
-        let crab = self.get_crab().await?;
-        let (owner, name) = self.get_owner_and_name(repo_id).await?;
+        let (crab, owner, name) = self.get_repo_crab(repo_id).await?;
         // let app: octocrab::models::App = crab.get("/app", None::<&()>).await.unwrap();
         // let app_slug = app.slug.unwrap();
         // let app_name_in_comments = format!("{app_slug}[bot]"); // TODO: maybe there is another field in the comments that is not user.login
@@ -237,18 +329,72 @@ impl Github {
         Ok(())
     }
 
-    // TODO: make this receive crab as argument
-    async fn get_owner_and_name(&self, id: &str) -> anyhow::Result<(String, String)> {
-        let repo = self.get_repo(id).await?.unwrap();
-        Ok((repo.owner.unwrap().login, repo.name))
+    /// Client able to access the repo along with its owner and name
+    async fn get_repo_crab(&self, id: &str) -> anyhow::Result<(Octocrab, String, String)> {
+        let (crab, repo) = self
+            .find_repo(id)
+            .await?
+            .ok_or_else(|| anyhow!("repo {id} is not accessible by any Github token"))?;
+        Ok((crab, repo.owner.unwrap().login, repo.name))
+    }
+
+    /// Tries the token that last worked for the repo, then the installation and the extra tokens
+    async fn find_repo(&self, id: &str) -> anyhow::Result<Option<(Octocrab, Repository)>> {
+        let known = self.repo_tokens.read().unwrap().get(id).copied();
+        let candidates = known
+            .into_iter()
+            .chain(iter::once(None))
+            .chain((0..self.extra_tokens.len()).map(Some));
+        for index in candidates {
+            let crab = match index {
+                Some(index) => build_crab(&self.extra_tokens[index].token),
+                None => self.get_crab().await?,
+            };
+            let repo: OctocrabResult<Option<Repository>> =
+                crab.get(format!("/repositories/{id}"), None::<&()>).await;
+            // only a missing repo means the next token might have access to it
+            let repo = match repo {
+                Err(error) if is_not_found(&error) => None,
+                repo => repo?,
+            };
+            if let Some(repo) = repo {
+                if known != Some(index) {
+                    if let Some(index) = index {
+                        let name = &self.extra_tokens[index].name;
+                        info!("using the Github token {name} for repo {id}");
+                    }
+                    self.repo_tokens
+                        .write()
+                        .unwrap()
+                        .insert(id.to_owned(), index);
+                }
+                return Ok(Some((crab, repo)));
+            }
+        }
+        Ok(None)
     }
 
     async fn get_crab(&self) -> anyhow::Result<Octocrab> {
         self.update_token().await?;
-        Ok(octocrab::OctocrabBuilder::default()
-            .user_access_token(self.token.read().await.secret.clone())
-            .build()
-            .unwrap())
+        let crab = self.build_crab().await;
+        let stale = self
+            .cached_rate_limits()
+            .map_or(true, |limits| is_rate_limit_stale(&limits));
+        if stale {
+            // reading /rate_limit does not count against the limits
+            let crab = crab.clone();
+            let rate_limits = self.rate_limits.clone();
+            tokio::spawn(async move {
+                if let Err(error) = refresh_rate_limits(&crab, &rate_limits).await {
+                    warn!("failed to read Github rate limits: {error}");
+                }
+            });
+        }
+        Ok(crab)
+    }
+
+    async fn build_crab(&self) -> Octocrab {
+        build_crab(&self.token.read().await.secret)
     }
 
     async fn update_token(&self) -> anyhow::Result<()> {
@@ -266,6 +412,43 @@ impl Github {
     }
 }
 
+fn build_crab(token: &str) -> Octocrab {
+    octocrab::OctocrabBuilder::default()
+        .user_access_token(token.to_owned())
+        .build()
+        .unwrap()
+}
+
+fn is_not_found(error: &octocrab::Error) -> bool {
+    matches!(error, octocrab::Error::GitHub { source, .. } if source.status_code == StatusCode::NOT_FOUND)
+}
+
+fn is_rate_limit_stale(limits: &RateLimits) -> bool {
+    now() - limits.updated > RATE_LIMIT_REFRESH_MILLIS
+}
+
+async fn refresh_rate_limits(
+    crab: &Octocrab,
+    rate_limits: &std::sync::RwLock<Option<RateLimits>>,
+) -> anyhow::Result<RateLimits> {
+    let resources = crab.ratelimit().get().await?.resources;
+    let limits = RateLimits {
+        core: resources.core.into(),
+        search: resources.search.into(),
+        updated: now(),
+    };
+    for (name, limit) in [("core", &limits.core), ("search", &limits.search)] {
+        if limit.remaining < RATE_LIMIT_WARNING_THRESHOLD {
+            warn!(
+                "Github {name} rate limit is running low: {}/{} left, resets at {}",
+                limit.remaining, limit.limit, limit.reset
+            );
+        }
+    }
+    *rate_limits.write().unwrap() = Some(limits.clone());
+    Ok(limits)
+}
+
 fn is_token_too_old(token: &Token) -> bool {
     let age = now() - token.millis;
     age > 30 * 60 * 1000
@@ -276,6 +459,7 @@ async fn get_installation_access_token() -> anyhow::Result<Token> {
         coordinator,
         token,
         hostname: id,
+        ..
     } = Conf::read();
 
     let client = reqwest::Client::new();
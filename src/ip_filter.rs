@@ -0,0 +1,82 @@
+use std::net::IpAddr;
+
+use anyhow::anyhow;
+use ipnet::IpNet;
+
+/// Per project allow/deny lists of CIDRs. Deny rules take precedence,
+/// and an empty allow list means every address not denied is allowed
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct IpFilter {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl IpFilter {
+    pub(crate) fn new(allow: &[String], deny: &[String]) -> anyhow::Result<Self> {
+        Ok(Self {
+            allow: parse_cidrs(allow)?,
+            deny: parse_cidrs(deny)?,
+        })
+    }
+
+    /// Used when the stored lists can not be parsed, so a broken config never opens access
+    pub(crate) fn deny_all() -> Self {
+        Self {
+            allow: vec![],
+            deny: vec!["0.0.0.0/0".parse().unwrap(), "::/0".parse().unwrap()],
+        }
+    }
+
+    pub(crate) fn is_allowed(&self, ip: IpAddr) -> bool {
+        // IPv4 clients might show up as IPv4-mapped IPv6 addresses on dual stack sockets
+        let ip = ip.to_canonical();
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            false
+        } else {
+            self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+        }
+    }
+}
+
+/// Accepts both CIDRs and plain addresses, which are treated as a single host
+pub(crate) fn parse_cidrs(cidrs: &[String]) -> anyhow::Result<Vec<IpNet>> {
+    cidrs
+        .iter()
+        .map(|cidr| {
+            let cidr = cidr.trim();
+            cidr.parse::<IpNet>()
+                .or_else(|_| cidr.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| anyhow!("invalid CIDR: {cidr}"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod ip_filter_tests {
+    use super::IpFilter;
+
+    fn filter(allow: &[&str], deny: &[&str]) -> IpFilter {
+        let to_vec = |list: &[&str]| list.iter().map(|cidr| cidr.to_string()).collect::<Vec<_>>();
+        IpFilter::new(&to_vec(allow), &to_vec(deny)).unwrap()
+    }
+
+    #[test]
+    fn test_ip_filter() {
+        let open = filter(&[], &[]);
+        assert!(open.is_allowed("8.8.8.8".parse().unwrap()));
+
+        let filter = filter(&["10.0.0.0/8", "2001:db8::/32"], &["10.0.1.0/24"]);
+        assert!(filter.is_allowed("10.1.2.3".parse().unwrap()));
+        assert!(!filter.is_allowed("10.0.1.7".parse().unwrap()));
+        assert!(!filter.is_allowed("192.168.0.1".parse().unwrap()));
+        assert!(filter.is_allowed("2001:db8::1".parse().unwrap()));
+        assert!(!filter.is_allowed("2001:db9::1".parse().unwrap()));
+        assert!(filter.is_allowed("::ffff:10.1.2.3".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_invalid_cidr() {
+        assert!(IpFilter::new(&["10.0.0.0/33".to_owned()], &[]).is_err());
+        assert!(IpFilter::new(&[], &["1.2.3.4".to_owned()]).is_ok());
+    }
+}
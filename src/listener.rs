@@ -1,10 +1,49 @@
-use std::net::SocketAddrV4;
+use std::{
+    net::{IpAddr, SocketAddrV4},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
 use async_trait::async_trait;
 
+use crate::proxy::{
+    redirect::RedirectRule, rewrite::BodyRewrite, routes::AllowedRoute, upstream_tls::UpstreamTls,
+};
+
+/// How long the last wake up from standby took, until a response reports it
+pub(crate) type ColdStart = Arc<Mutex<Option<Duration>>>;
+
+/// Number of event streams currently open against a listener
+pub(crate) type OpenStreams = Arc<AtomicUsize>;
+
+/// Request in flight to an upstream, counted until dropped
+pub(crate) struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    pub(crate) fn new(counter: Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Self(counter)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 pub(crate) enum Access {
     Socket(SocketAddrV4),
+    UnixSocket(PathBuf),
+    /// folder with the files to serve, no app is running
+    Static(PathBuf),
     Loading,
+    /// the deployment was stopped on purpose
+    Stopped,
 }
 
 impl From<SocketAddrV4> for Access {
@@ -17,4 +56,50 @@ impl From<SocketAddrV4> for Access {
 pub(crate) trait Listener: Send {
     async fn access(&self) -> anyhow::Result<Access>;
     fn is_public(&self) -> bool;
+    fn is_ip_allowed(&self, _ip: IpAddr) -> bool {
+        true
+    }
+    /// overrides the default time the proxy waits for a response
+    fn read_timeout(&self) -> Option<Duration> {
+        None
+    }
+    fn upstream_tls(&self) -> UpstreamTls {
+        UpstreamTls::Off
+    }
+    /// find and replace rules for the response bodies
+    fn body_rewrites(&self) -> Arc<Vec<BodyRewrite>> {
+        Default::default()
+    }
+    /// redirects answered before reaching the listener
+    fn redirects(&self) -> Arc<Vec<RedirectRule>> {
+        Default::default()
+    }
+    /// methods and paths forwarded to the listener, any request if empty
+    fn route_allowlist(&self) -> Arc<Vec<AllowedRoute>> {
+        Default::default()
+    }
+    fn cold_start(&self) -> Option<ColdStart> {
+        None
+    }
+    /// counter the proxy keeps up to date while it streams events from the listener
+    fn open_streams(&self) -> Option<OpenStreams> {
+        None
+    }
+    /// concurrent requests the proxy sends to the listener, unlimited if None
+    fn max_connections(&self) -> Option<usize> {
+        None
+    }
+    /// expected time until the listener is ready, while it answers with `Access::Loading`
+    fn ready_in(&self) -> Option<Duration> {
+        None
+    }
+    /// counts a request sent to the socket the listener gave access to, so the load of
+    /// the listeners running several containers is balanced
+    fn in_flight(&self, _socket: SocketAddrV4) -> Option<InFlight> {
+        None
+    }
+    /// same as `access` answering `Access::Stopped`, without starting anything
+    fn is_stopped(&self) -> bool {
+        false
+    }
 }
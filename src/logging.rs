@@ -1,26 +1,48 @@
 use std::{
     fs::{self, File},
     io::{self, Write},
+    net::IpAddr,
     path::Path,
-    sync::mpsc::{self, Sender},
+    sync::{
+        mpsc::{self, Sender},
+        OnceLock,
+    },
     thread::{self, JoinHandle},
+    time::Duration,
 };
 
+use anyhow::{anyhow, Context};
+use log::warn;
+
 use file_rotate::{
     compression::Compression,
     suffix::{AppendTimestamp, FileLimit},
     ContentLimit, FileRotate,
 };
 use serde::{Deserialize, Serialize};
+use tracing_subscriber::{reload, EnvFilter, Registry};
 use utoipa::ToSchema;
 
 use crate::{
+    analytics::Analytics,
     db::BuildLog,
     docker::{DockerLog, LogType},
     paths::get_instance_log_dir,
 };
 
-const LOG_FILE_PREFIX: &str = "log";
+// bincode is not self describing, so the prefix changes every time RequestLog does
+// and files written with an older layout are removed on startup
+const LOG_FILE_PREFIX: &str = "requests-v2";
+const LOG_FILES: usize = 10;
+
+/// How far back request logs can be read, only the rotated hourly files are complete
+pub(crate) const REQUEST_LOG_RETENTION: Duration =
+    Duration::from_secs((LOG_FILES as u64 - 1) * 60 * 60);
+/// upper bound for the number of buckets in a metrics response
+pub(crate) const MAX_METRICS_BUCKETS: u64 = 1440;
+
+/// handle to swap the filter of the stdout logs while running
+static LOG_FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
 
 #[derive(Serialize, Deserialize, ToSchema)]
 pub(crate) enum Level {
@@ -28,6 +50,101 @@ pub(crate) enum Level {
     ERROR,
 }
 
+/// Verbosity of the server logs, from the most to the least verbose
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl LogLevel {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "TRACE" => Some(Self::Trace),
+            "DEBUG" => Some(Self::Debug),
+            "INFO" => Some(Self::Info),
+            "WARN" => Some(Self::Warn),
+            "ERROR" => Some(Self::Error),
+            _ => None,
+        }
+    }
+}
+
+/// Filter for the stdout logs that can be replaced later on with `set_log_filter`
+pub(crate) fn reloadable_log_filter(directives: &str) -> reload::Layer<EnvFilter, Registry> {
+    let (filter, handle) = reload::Layer::new(EnvFilter::new(directives));
+    LOG_FILTER.set(handle).ok();
+    filter
+}
+
+/// Takes effect for every thread right away, `directives` follows the `RUST_LOG` syntax,
+/// like `debug` or `info,prezel::proxy=trace`
+pub(crate) fn set_log_filter(directives: &str) -> anyhow::Result<()> {
+    let filter = EnvFilter::try_new(directives).context("invalid log filter")?;
+    LOG_FILTER
+        .get()
+        .ok_or(anyhow!("the log filter is not reloadable"))?
+        .reload(filter)?;
+    // the log crate drops records over its own max level before they reach the filter
+    log::set_max_level(log::LevelFilter::Trace);
+    Ok(())
+}
+
+pub(crate) fn get_log_filter() -> Option<String> {
+    LOG_FILTER.get()?.with_current(ToString::to_string).ok()
+}
+
+/// Keeps the lines at `min_level` or above logged after `since`. The level is read from
+/// the start of every line, lines with no level like the ones of multi line events get
+/// the level of the line before, or the one of the stream if there is none
+pub(crate) fn filter_system_logs(
+    logs: impl Iterator<Item = DockerLog>,
+    min_level: Option<LogLevel>,
+    since: Option<i64>,
+) -> Vec<DockerLog> {
+    let mut current = None;
+    logs.filter(|log| {
+        let stream_level = match log.log_type {
+            LogType::Out => LogLevel::Info,
+            LogType::Err => LogLevel::Error,
+        };
+        let level = match line_level(&log.message) {
+            Some(level) => {
+                current = Some(level);
+                level
+            }
+            None => current.unwrap_or(stream_level),
+        };
+        min_level.map_or(true, |min_level| level >= min_level)
+            && since.map_or(true, |since| log.time >= since)
+    })
+    .collect()
+}
+
+fn line_level(line: &str) -> Option<LogLevel> {
+    let line = strip_ansi(line);
+    // the timestamp goes first
+    line.split_whitespace().take(3).find_map(LogLevel::parse)
+}
+
+fn strip_ansi(line: &str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(char) = chars.next() {
+        if char == '\x1b' {
+            // skip until the end of the escape sequence
+            chars.by_ref().find(|char| char.is_ascii_alphabetic());
+        } else {
+            stripped.push(char);
+        }
+    }
+    stripped
+}
+
 #[derive(Serialize, Deserialize)]
 pub(crate) struct RequestLog {
     pub(crate) time: i64,
@@ -37,6 +154,9 @@ pub(crate) struct RequestLog {
     pub(crate) method: String, // TODO: make enum out of this?
     pub(crate) path: String,
     pub(crate) status: u16,
+    pub(crate) request_id: String,
+    /// from the request being received to the response being sent, including the upstream time
+    pub(crate) duration_ms: u64,
     // pub(crate) message: String,
 }
 
@@ -50,6 +170,11 @@ pub(crate) struct Log {
     pub(crate) path: Option<String>,
     pub(crate) status: Option<u16>,
     pub(crate) message: Option<String>,
+    pub(crate) request_id: Option<String>,
+    /// Milliseconds the proxy took to answer, only for request logs
+    pub(crate) duration_ms: Option<u64>,
+    /// Build phase starting at this line, only for the phase markers of the build logs
+    pub(crate) phase: Option<String>,
 }
 
 impl Log {
@@ -70,6 +195,9 @@ impl Log {
             path: None,
             status: None,
             message: Some(value.message),
+            request_id: None,
+            duration_ms: None,
+            phase: None,
         }
     }
 }
@@ -85,6 +213,9 @@ impl From<RequestLog> for Log {
             path: Some(value.path),
             status: Some(value.status),
             message: None,
+            request_id: Some(value.request_id),
+            duration_ms: Some(value.duration_ms),
+            phase: None,
         }
     }
 }
@@ -105,14 +236,18 @@ impl From<BuildLog> for Log {
             method: None,
             path: None,
             status: None,
-            message: Some(value.content),
+            // markers have no output of their own
+            message: value.phase.is_none().then_some(value.content),
+            request_id: None,
+            duration_ms: None,
+            phase: value.phase,
         }
     }
 }
 
 #[derive(Default)]
 pub(crate) struct RequestLogger {
-    sender: Option<Sender<RequestLog>>,
+    sender: Option<Sender<(RequestLog, Option<IpAddr>)>>,
     join_handle: Option<JoinHandle<()>>,
 }
 
@@ -126,21 +261,26 @@ impl Drop for RequestLogger {
 }
 
 impl RequestLogger {
-    pub(crate) fn new() -> Self {
+    /// The client address only reaches the analytics, it is not written to the log files
+    pub(crate) fn new(analytics: Analytics) -> Self {
         // FIXME: what happens with restarts here?
-        let (sender, receiver) = mpsc::channel::<RequestLog>();
+        let (sender, receiver) = mpsc::channel::<(RequestLog, Option<IpAddr>)>();
 
         let join_handle = thread::spawn(move || {
+            if let Err(error) = remove_outdated_log_files() {
+                warn!("failed to remove outdated request log files: {error}");
+            }
             let file_path = get_instance_log_dir().join(LOG_FILE_PREFIX);
             let mut log = FileRotate::new(
                 file_path,
-                AppendTimestamp::default(FileLimit::MaxFiles(10)),
+                AppendTimestamp::default(FileLimit::MaxFiles(LOG_FILES)),
                 ContentLimit::Time(file_rotate::TimeFrequency::Hourly),
                 Compression::None,
                 None,
             );
 
-            for event in receiver {
+            for (event, client_ip) in receiver {
+                analytics.record(&event, client_ip);
                 let encoded: Vec<u8> = bincode::serialize(&event).unwrap();
                 log.write_all(encoded.as_slice());
             }
@@ -152,9 +292,9 @@ impl RequestLogger {
         }
     }
 
-    pub(crate) fn log(&self, event: RequestLog) {
+    pub(crate) fn log(&self, event: RequestLog, client_ip: Option<IpAddr>) {
         if let Some(sender) = &self.sender {
-            sender.send(event);
+            sender.send((event, client_ip));
         }
     }
 }
@@ -172,18 +312,49 @@ impl EventIter {
 }
 
 impl Iterator for EventIter {
-    type Item = Log;
+    type Item = RequestLog;
     fn next(&mut self) -> Option<Self::Item> {
-        let event: RequestLog = bincode::deserialize_from(&self.file).ok()?;
-        Some(event.into())
+        bincode::deserialize_from(&self.file).ok()
     }
 }
 
 pub(crate) fn read_request_event_logs() -> io::Result<impl Iterator<Item = Log>> {
-    // TODO: accept window
+    let events = read_log_files()?.take(2).flatten().map(Log::from);
+    Ok(events)
+}
+
+/// Every request logged after `since`, memory usage does not depend on the number of them
+pub(crate) fn read_request_logs_since(since: i64) -> io::Result<impl Iterator<Item = RequestLog>> {
+    let events = read_log_files()?
+        .flatten()
+        .filter(move |event| event.time >= since);
+    Ok(events)
+}
+
+fn is_current_log_file(name: &str) -> bool {
+    name.split('.').next() == Some(LOG_FILE_PREFIX)
+}
 
+/// The folder only holds request logs, so anything written with another prefix is outdated
+fn remove_outdated_log_files() -> io::Result<()> {
+    let dir = get_instance_log_dir();
+    if !dir.exists() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if entry.file_type()?.is_file() && !name.to_str().is_some_and(is_current_log_file) {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+fn read_log_files() -> io::Result<impl Iterator<Item = EventIter>> {
     let mut paths: Vec<_> = fs::read_dir(get_instance_log_dir())?
         .filter_map(|entry| Some(entry.ok()?))
+        .filter(|entry| entry.file_name().to_str().is_some_and(is_current_log_file))
         .collect();
 
     paths.sort_by_key(|path| path.file_name());
@@ -194,15 +365,65 @@ pub(crate) fn read_request_event_logs() -> io::Result<impl Iterator<Item = Log>>
     if let Some(current_position) = current_position {
         paths.swap(current_position, 0);
     }
-    // here paths is ordered like: log, log.20241023T072726, log.20241023T062746, ...
+    // here paths is ordered like: requests, requests.20241023T072726, requests.20241023T062746, ...
     // i.e. starting from the most recent
 
-    let events = paths
+    let files = paths
         .into_iter()
-        .filter_map(|path| EventIter::new(&path.path()).ok())
-        .take(2)
-        .flatten();
-    Ok(events)
+        .filter_map(|path| EventIter::new(&path.path()).ok());
+    Ok(files)
+}
+
+/// Request counts by status class for one time bucket
+#[derive(Serialize, ToSchema, Debug, Clone, Default, PartialEq)]
+pub(crate) struct MetricsBucket {
+    /// Start of the bucket, in milliseconds since the epoch
+    pub(crate) time: i64,
+    pub(crate) total: u64,
+    pub(crate) status_2xx: u64,
+    pub(crate) status_3xx: u64,
+    pub(crate) status_4xx: u64,
+    pub(crate) status_5xx: u64,
+}
+
+/// Buckets the requests of a deployment in the `window` ending at `end`, empty buckets included
+pub(crate) fn aggregate_request_metrics(
+    events: impl Iterator<Item = RequestLog>,
+    deployment: i64,
+    end: i64,
+    window: Duration,
+    bucket: Duration,
+) -> Vec<MetricsBucket> {
+    let bucket_millis = bucket.as_millis() as i64;
+    let count = (window.as_millis() as i64 / bucket_millis).max(1);
+    // buckets are aligned to their size so consecutive calls return the same boundaries
+    let last_start = end - end.rem_euclid(bucket_millis);
+    let first_start = last_start - (count - 1) * bucket_millis;
+    let mut buckets: Vec<_> = (0..count)
+        .map(|index| MetricsBucket {
+            time: first_start + index * bucket_millis,
+            ..Default::default()
+        })
+        .collect();
+
+    for event in events.filter(|event| event.deployment == deployment) {
+        let index = (event.time - first_start).div_euclid(bucket_millis);
+        let Some(bucket) = usize::try_from(index)
+            .ok()
+            .and_then(|index| buckets.get_mut(index))
+        else {
+            continue;
+        };
+        bucket.total += 1;
+        match event.status / 100 {
+            2 => bucket.status_2xx += 1,
+            3 => bucket.status_3xx += 1,
+            4 => bucket.status_4xx += 1,
+            5 => bucket.status_5xx += 1,
+            _ => {}
+        }
+    }
+    buckets
 }
 
 // #[cfg(test)]
@@ -247,3 +468,105 @@ pub(crate) fn read_request_event_logs() -> io::Result<impl Iterator<Item = Log>>
 //         assert_eq!(target, decoded);
 //     }
 // }
+
+#[cfg(test)]
+mod metrics_tests {
+    use std::time::Duration;
+
+    use super::{aggregate_request_metrics, Level, RequestLog};
+
+    fn event(deployment: i64, time: i64, status: u16) -> RequestLog {
+        RequestLog {
+            time,
+            level: Level::INFO,
+            deployment,
+            host: "example.com".to_owned(),
+            method: "GET".to_owned(),
+            path: "/".to_owned(),
+            status,
+            request_id: String::new(),
+            duration_ms: 0,
+        }
+    }
+
+    #[test]
+    fn test_aggregate_request_metrics() {
+        let minute = 60 * 1000;
+        let end = 10 * minute + 30 * 1000;
+        let events = vec![
+            event(1, 10 * minute + 1, 200),
+            event(1, 10 * minute + 2, 503),
+            event(1, 9 * minute, 404),
+            event(1, 8 * minute - 1, 200), // before the window
+            event(2, 10 * minute, 200),
+        ];
+        let buckets = aggregate_request_metrics(
+            events.into_iter(),
+            1,
+            end,
+            Duration::from_secs(3 * 60),
+            Duration::from_secs(60),
+        );
+        let times: Vec<_> = buckets.iter().map(|bucket| bucket.time).collect();
+        assert_eq!(times, vec![8 * minute, 9 * minute, 10 * minute]);
+        assert_eq!(buckets[0].total, 0);
+        assert_eq!(buckets[1].status_4xx, 1);
+        assert_eq!(buckets[2].total, 2);
+        assert_eq!(buckets[2].status_2xx, 1);
+        assert_eq!(buckets[2].status_5xx, 1);
+    }
+}
+
+#[cfg(test)]
+mod system_logs_tests {
+    use super::{filter_system_logs, LogLevel};
+    use crate::docker::{DockerLog, LogType};
+
+    fn log(time: i64, message: &str, log_type: LogType) -> DockerLog {
+        DockerLog {
+            time,
+            message: message.to_owned(),
+            log_type,
+        }
+    }
+
+    #[test]
+    fn test_filter_system_logs() {
+        let logs = vec![
+            log(
+                1,
+                "  2024-10-23T07:27:26Z DEBUG prezel::proxy: idle",
+                LogType::Out,
+            ),
+            log(
+                2,
+                "  \x1b[2m2024-10-23T07:27:27Z\x1b[0m \x1b[33m WARN\x1b[0m prezel::container: slow",
+                LogType::Out,
+            ),
+            log(3, "    at src/container/mod.rs:42", LogType::Out),
+            log(4, "thread 'main' panicked", LogType::Err),
+            log(
+                5,
+                "  2024-10-23T07:27:29Z  INFO prezel::api: started",
+                LogType::Out,
+            ),
+        ];
+        let times = |logs: Vec<DockerLog>| logs.iter().map(|log| log.time).collect::<Vec<_>>();
+        assert_eq!(
+            times(filter_system_logs(
+                logs.clone().into_iter(),
+                Some(LogLevel::Warn),
+                None
+            )),
+            vec![2, 3, 4]
+        );
+        assert_eq!(
+            times(filter_system_logs(
+                logs.into_iter(),
+                Some(LogLevel::Info),
+                Some(3)
+            )),
+            vec![3, 4, 5]
+        );
+    }
+}
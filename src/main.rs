@@ -1,17 +1,24 @@
+use analytics::Analytics;
 use api::server::run_api_server;
 use conf::Conf;
 use db::Db;
 use deployments::manager::Manager;
 use github::Github;
+use logging::reloadable_log_filter;
 use proxy::run_proxy;
+use runtime::init_runtime;
+use secrets::Secrets;
+use std::sync::Arc;
 use tls::CertificateStore;
 use tracing_subscriber::{
     layer::{Filter, SubscriberExt},
     util::SubscriberInitExt,
-    EnvFilter, Layer, Registry,
+    Layer, Registry,
 };
+use webhooks::WebhookNotifier;
 
 mod alphabet;
+mod analytics;
 mod api;
 mod conf;
 mod container;
@@ -22,12 +29,16 @@ mod docker;
 mod docker_bridge;
 mod env;
 mod github;
+mod ip_filter;
 mod listener;
 mod logging;
 mod paths;
 mod proxy;
+mod runtime;
+mod secrets;
 mod time;
 mod tls;
+mod webhooks;
 
 pub(crate) const DOCKER_PORT: u16 = 5046;
 
@@ -56,7 +67,8 @@ async fn main() {
     let stdout_layer = tracing_subscriber::fmt::layer()
         .pretty()
         .with_writer(std::io::stdout)
-        .with_filter(EnvFilter::new("info")); // TODO: read from env
+        // TODO: read from env
+        .with_filter(reloadable_log_filter("info"));
 
     // env_logger::init_from_env(env_logger::Env::new().default_filter_or("info")); -> old version
 
@@ -67,25 +79,43 @@ async fn main() {
 
     let conf = Conf::read();
     let cloned_conf = conf.clone();
+    init_runtime(&conf.container_runtime).expect("Failed to connect to the container runtime");
 
     let db = Db::setup().await;
     let github = Github::new().await;
 
     let certificates = CertificateStore::load(&conf).await;
+    let webhooks = WebhookNotifier::new(&conf);
+    let secrets = Arc::new(Secrets::new(&conf).expect("Invalid secrets key"));
+    db.rotate_secrets(&secrets).await;
     let manager = Manager::new(
-        conf.hostname.clone(),
+        &conf,
         github.clone(),
         db.clone(),
         certificates.clone(),
+        webhooks,
+        secrets,
     );
     let cloned_manager = manager.clone();
+    let analytics = Analytics::new();
+    let cloned_analytics = analytics.clone();
 
-    tokio::task::spawn_blocking(|| run_proxy(cloned_manager, cloned_conf, certificates));
+    tokio::task::spawn_blocking(|| {
+        run_proxy(cloned_manager, cloned_conf, certificates, cloned_analytics)
+    });
 
     manager.full_sync_with_github().await;
 
     let api_hostname = format!("api.{}", &conf.hostname);
-    run_api_server(manager, db, github, &api_hostname, conf.coordinator)
-        .await
-        .unwrap();
+    run_api_server(
+        manager,
+        db,
+        github,
+        analytics,
+        &api_hostname,
+        conf.coordinator,
+        conf.api,
+    )
+    .await
+    .unwrap();
 }
@@ -6,6 +6,7 @@ use std::{
 
 const DB_NAME: &str = "app.db";
 const LOG_FILE: &str = "log";
+const STATIC_DIR: &str = "static";
 
 pub(crate) fn get_instance_db_path() -> PathBuf {
     get_container_root().join(DB_NAME)
@@ -15,6 +16,12 @@ pub(crate) fn get_instance_log_dir() -> PathBuf {
     get_container_root().join(LOG_FILE)
 }
 
+/// static files copied out of an image
+pub(crate) fn get_image_static_dir(image: &str) -> PathBuf {
+    let id = image.trim_start_matches("sha256:");
+    get_container_root().join(STATIC_DIR).join(id)
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct HostFile {
     relative_folder_path: PathBuf,
@@ -0,0 +1,477 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use http::{header, HeaderMap, StatusCode};
+use hyper::body::Bytes;
+use pingora::http::ResponseHeader;
+
+use crate::conf::CacheConf;
+
+/// responses with bigger bodies than this are never cached
+pub(crate) const MAX_CACHED_BODY_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    deployment: Option<i64>,
+    host: String,
+    path: String,
+    /// values of the request headers listed in the Vary response header
+    vary: Vec<Option<String>>,
+}
+
+/// (deployment, host, path) shared by all the variants of a response
+type PathKey = (Option<i64>, String, String);
+
+impl CacheKey {
+    fn path_key(&self) -> PathKey {
+        (self.deployment, self.host.clone(), self.path.clone())
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct CachedResponse {
+    pub(crate) header: ResponseHeader,
+    pub(crate) body: Bytes,
+    stored: Instant,
+    expires: Instant,
+}
+
+impl CachedResponse {
+    pub(crate) fn age(&self) -> Duration {
+        Instant::now().duration_since(self.stored)
+    }
+
+    pub(crate) fn etag(&self) -> Option<&[u8]> {
+        Some(self.header.headers.get(header::ETAG)?.as_bytes())
+    }
+}
+
+struct Entry {
+    response: CachedResponse,
+    last_access: u64,
+}
+
+/// Vary header names last seen for a path, and the variants stored with them
+struct CachedPath {
+    vary_names: Vec<String>,
+    keys: HashSet<CacheKey>,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<CacheKey, Entry>,
+    /// keys by last access, the first one is the least recently used
+    lru: BTreeMap<u64, CacheKey>,
+    paths: HashMap<PathKey, CachedPath>,
+    clock: u64,
+}
+
+impl Inner {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn touch(&mut self, key: &CacheKey) {
+        let tick = self.tick();
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.lru.remove(&entry.last_access);
+            entry.last_access = tick;
+            self.lru.insert(tick, key.clone());
+        }
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.last_access);
+        }
+        let path_key = key.path_key();
+        if let Some(path) = self.paths.get_mut(&path_key) {
+            path.keys.remove(key);
+            if path.keys.is_empty() {
+                self.paths.remove(&path_key);
+            }
+        }
+    }
+
+    /// removes every variant of the path
+    fn remove_path(&mut self, path_key: &PathKey) {
+        if let Some(path) = self.paths.remove(path_key) {
+            for key in path.keys {
+                if let Some(entry) = self.entries.remove(&key) {
+                    self.lru.remove(&entry.last_access);
+                }
+            }
+        }
+    }
+
+    /// returns false if there was nothing to evict
+    fn evict_lru(&mut self) -> bool {
+        match self.lru.first_key_value() {
+            Some((_, key)) => {
+                let key = key.clone();
+                self.remove(&key);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Small in-memory cache for GET responses that upstreams explicitly mark as cacheable
+pub(crate) struct ResponseCache {
+    inner: Mutex<Inner>,
+    ttl: Duration,
+    max_entries: usize,
+}
+
+/// The request a cacheable response might be stored for, collected along the proxy phases
+pub(crate) struct PendingEntry {
+    deployment: Option<i64>,
+    host: String,
+    path: String,
+    request_headers: HeaderMap,
+    response: Option<(ResponseHeader, Duration)>,
+    body: Vec<u8>,
+}
+
+impl PendingEntry {
+    pub(crate) fn new(
+        deployment: Option<i64>,
+        host: String,
+        path: String,
+        request_headers: HeaderMap,
+    ) -> Self {
+        Self {
+            deployment,
+            host,
+            path,
+            request_headers,
+            response: None,
+            body: vec![],
+        }
+    }
+
+    /// returns false if the body got too big to be cached
+    pub(crate) fn push_body(&mut self, chunk: &[u8]) -> bool {
+        self.body.extend_from_slice(chunk);
+        self.body.len() <= MAX_CACHED_BODY_SIZE
+    }
+}
+
+impl ResponseCache {
+    pub(crate) fn new(conf: &CacheConf) -> Self {
+        Self {
+            inner: Default::default(),
+            ttl: Duration::from_secs(conf.ttl_secs),
+            max_entries: conf.max_entries,
+        }
+    }
+
+    pub(crate) fn get(
+        &self,
+        deployment: Option<i64>,
+        host: &str,
+        path: &str,
+        request_headers: &HeaderMap,
+    ) -> Option<CachedResponse> {
+        let mut inner = self.inner.lock().unwrap();
+        let path_key = (deployment, host.to_owned(), path.to_owned());
+        let names = &inner.paths.get(&path_key)?.vary_names;
+        let key = CacheKey {
+            deployment,
+            host: host.to_owned(),
+            path: path.to_owned(),
+            vary: vary_values(names, request_headers),
+        };
+        let response = inner.entries.get(&key)?.response.clone();
+        if response.expires <= Instant::now() {
+            inner.remove(&key);
+            return None;
+        }
+        inner.touch(&key);
+        Some(response)
+    }
+
+    /// Records the response header for a pending entry, dropping it if it is not cacheable
+    pub(crate) fn accept_response(
+        &self,
+        pending: &mut Option<PendingEntry>,
+        response: &ResponseHeader,
+    ) {
+        let ttl = pending
+            .as_ref()
+            .and_then(|entry| cacheable_ttl(&entry.request_headers, response, self.ttl));
+        match (pending.as_mut(), ttl) {
+            (Some(entry), Some(ttl)) => entry.response = Some((response.clone(), ttl)),
+            _ => *pending = None,
+        }
+    }
+
+    pub(crate) fn insert(&self, pending: PendingEntry) {
+        let Some((header, ttl)) = pending.response else {
+            return;
+        };
+        let names = vary_names(&header.headers);
+        let key = CacheKey {
+            deployment: pending.deployment,
+            host: pending.host.clone(),
+            path: pending.path.clone(),
+            vary: vary_values(&names, &pending.request_headers),
+        };
+        let now = Instant::now();
+        let response = CachedResponse {
+            header,
+            body: pending.body.into(),
+            stored: now,
+            expires: now + ttl,
+        };
+
+        let mut inner = self.inner.lock().unwrap();
+        // the variants stored with other Vary names can't be reached anymore
+        let path_key = key.path_key();
+        if inner
+            .paths
+            .get(&path_key)
+            .is_some_and(|path| path.vary_names != names)
+        {
+            inner.remove_path(&path_key);
+        }
+        inner.remove(&key);
+        while inner.entries.len() >= self.max_entries {
+            if !inner.evict_lru() {
+                return;
+            }
+        }
+        inner
+            .paths
+            .entry(path_key)
+            .or_insert_with(|| CachedPath {
+                vary_names: names,
+                keys: HashSet::new(),
+            })
+            .keys
+            .insert(key.clone());
+        let last_access = inner.tick();
+        inner.lru.insert(last_access, key.clone());
+        inner.entries.insert(
+            key,
+            Entry {
+                response,
+                last_access,
+            },
+        );
+    }
+}
+
+/// Whether a request is allowed to be answered from the cache at all
+pub(crate) fn is_cacheable_request(method: &http::Method, headers: &HeaderMap) -> bool {
+    method == http::Method::GET
+        && !headers.contains_key(header::AUTHORIZATION)
+        && !cache_control(headers)
+            .any(|(directive, _)| directive == "no-cache" || directive == "no-store")
+}
+
+/// Returns for how long the response can be cached, if at all
+fn cacheable_ttl(
+    request_headers: &HeaderMap,
+    response: &ResponseHeader,
+    max_ttl: Duration,
+) -> Option<Duration> {
+    if response.status != StatusCode::OK
+        || response.headers.contains_key(header::SET_COOKIE)
+        || vary_names(&response.headers).iter().any(|name| name == "*")
+        || request_headers.contains_key(header::AUTHORIZATION)
+    {
+        return None;
+    }
+
+    let mut public = false;
+    let mut max_age = None;
+    let mut s_maxage = None;
+    for (directive, value) in cache_control(&response.headers) {
+        match directive.as_str() {
+            "no-store" | "no-cache" | "private" => return None,
+            "public" => public = true,
+            "max-age" => max_age = value.and_then(|value| value.parse::<u64>().ok()),
+            "s-maxage" => s_maxage = value.and_then(|value| value.parse::<u64>().ok()),
+            _ => {}
+        }
+    }
+
+    let ttl = match s_maxage.or(max_age) {
+        Some(0) => return None,
+        Some(secs) => Duration::from_secs(secs).min(max_ttl),
+        None if public => max_ttl,
+        None => return None,
+    };
+    Some(ttl)
+}
+
+fn cache_control(headers: &HeaderMap) -> impl Iterator<Item = (String, Option<String>)> + '_ {
+    headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|directive| match directive.split_once('=') {
+            Some((name, value)) => (
+                name.trim().to_lowercase(),
+                Some(value.trim().trim_matches('"').to_owned()),
+            ),
+            None => (directive.trim().to_lowercase(), None),
+        })
+}
+
+fn vary_names(headers: &HeaderMap) -> Vec<String> {
+    let mut names: Vec<_> = headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+fn vary_values(names: &[String], headers: &HeaderMap) -> Vec<Option<String>> {
+    names
+        .iter()
+        .map(|name| {
+            let value = headers.get(name.as_str())?.to_str().ok()?;
+            Some(value.to_owned())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod cache_tests {
+    use std::time::Duration;
+
+    use http::HeaderMap;
+    use pingora::http::ResponseHeader;
+
+    use super::{cacheable_ttl, PendingEntry, ResponseCache};
+    use crate::conf::CacheConf;
+
+    fn response(cache_control: &str) -> ResponseHeader {
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        response
+            .insert_header("Cache-Control", cache_control)
+            .unwrap();
+        response
+    }
+
+    #[test]
+    fn test_cacheable_ttl() {
+        let max = Duration::from_secs(10);
+        let request = HeaderMap::new();
+        let ttl = |cache_control| cacheable_ttl(&request, &response(cache_control), max);
+
+        assert_eq!(ttl("public, max-age=3"), Some(Duration::from_secs(3)));
+        assert_eq!(ttl("max-age=3600"), Some(max));
+        assert_eq!(ttl("public"), Some(max));
+        assert_eq!(ttl("max-age=60, s-maxage=5"), Some(Duration::from_secs(5)));
+        assert_eq!(ttl("private, max-age=60"), None);
+        assert_eq!(ttl("no-store"), None);
+        assert_eq!(ttl("max-age=0"), None);
+        assert_eq!(ttl("must-revalidate"), None);
+    }
+
+    #[test]
+    fn test_uncacheable_responses() {
+        let request = HeaderMap::new();
+        let max = Duration::from_secs(10);
+
+        let mut vary_star = response("public");
+        vary_star.insert_header("Vary", "*").unwrap();
+        assert_eq!(cacheable_ttl(&request, &vary_star, max), None);
+
+        let mut with_cookie = response("public");
+        with_cookie.insert_header("Set-Cookie", "a=b").unwrap();
+        assert_eq!(cacheable_ttl(&request, &with_cookie, max), None);
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let cache = ResponseCache::new(&CacheConf {
+            ttl_secs: 10,
+            max_entries: 2,
+        });
+        let headers = HeaderMap::new();
+        let insert = |path: &str| {
+            let mut pending = Some(PendingEntry::new(
+                None,
+                "host".to_owned(),
+                path.to_owned(),
+                headers.clone(),
+            ));
+            cache.accept_response(&mut pending, &response("public"));
+            cache.insert(pending.unwrap());
+        };
+        let get = |path: &str| cache.get(None, "host", path, &headers).is_some();
+
+        insert("/a");
+        insert("/b");
+        assert!(get("/a")); // /b is now the least recently used
+        insert("/c");
+        assert!(get("/a"));
+        assert!(!get("/b"));
+        assert!(get("/c"));
+    }
+
+    #[test]
+    fn test_no_room() {
+        let cache = ResponseCache::new(&CacheConf {
+            ttl_secs: 10,
+            max_entries: 0,
+        });
+        let headers = HeaderMap::new();
+        let mut pending = Some(PendingEntry::new(
+            None,
+            "host".to_owned(),
+            "/a".to_owned(),
+            headers.clone(),
+        ));
+        cache.accept_response(&mut pending, &response("public"));
+        cache.insert(pending.unwrap());
+        assert!(cache.get(None, "host", "/a", &headers).is_none());
+    }
+
+    #[test]
+    fn test_vary_change() {
+        let cache = ResponseCache::new(&CacheConf {
+            ttl_secs: 10,
+            max_entries: 10,
+        });
+        let insert = |vary: &str, language: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("Accept-Language", language.parse().unwrap());
+            let mut pending = Some(PendingEntry::new(
+                None,
+                "host".to_owned(),
+                "/a".to_owned(),
+                headers,
+            ));
+            let mut response = response("public");
+            response.insert_header("Vary", vary).unwrap();
+            cache.accept_response(&mut pending, &response);
+            cache.insert(pending.unwrap());
+        };
+
+        insert("Accept-Language", "en");
+        insert("Accept-Language", "fr");
+        assert_eq!(cache.inner.lock().unwrap().entries.len(), 2);
+        // the variants of the old Vary names are dropped with it
+        insert("Accept-Encoding", "en");
+        let inner = cache.inner.lock().unwrap();
+        assert_eq!(inner.entries.len(), 1);
+        assert_eq!(inner.lru.len(), 1);
+    }
+}
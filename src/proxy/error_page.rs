@@ -0,0 +1,80 @@
+use http::{header, StatusCode};
+use pingora::http::RequestHeader;
+
+/// Error answered by the proxy itself instead of the app
+pub(crate) struct ProxyError {
+    pub(crate) status: StatusCode,
+    /// same format as the codes of the api errors
+    pub(crate) code: &'static str,
+    pub(crate) message: &'static str,
+    pub(crate) page: &'static [u8],
+}
+
+pub(crate) const NOT_FOUND: ProxyError = ProxyError {
+    status: StatusCode::NOT_FOUND,
+    code: "DEPLOYMENT_NOT_FOUND",
+    message: "There is no deployment at this address",
+    page: include_bytes!("../../resources/not_found.html"),
+};
+
+pub(crate) const STOPPED: ProxyError = ProxyError {
+    status: StatusCode::SERVICE_UNAVAILABLE,
+    code: "DEPLOYMENT_STOPPED",
+    message: "The deployment is stopped",
+    page: include_bytes!("../../resources/stopped.html"),
+};
+
+pub(crate) const TIMEOUT: ProxyError = ProxyError {
+    status: StatusCode::GATEWAY_TIMEOUT,
+    code: "UPSTREAM_TIMEOUT",
+    message: "The deployment took too long to respond",
+    page: include_bytes!("../../resources/timeout.html"),
+};
+
+/// Whether the client asks for JSON before HTML, so API clients get an error they can parse.
+/// Browsers and clients that don't say get the page
+pub(crate) fn prefers_json(request: &RequestHeader) -> bool {
+    let mimes = request
+        .headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|mime| mime.split(';').next().unwrap_or_default().trim());
+    for mime in mimes {
+        if mime.eq_ignore_ascii_case("text/html") {
+            return false;
+        }
+        if mime.eq_ignore_ascii_case("application/json") || mime.ends_with("+json") {
+            return true;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod error_page_tests {
+    use pingora::http::RequestHeader;
+
+    use super::prefers_json;
+
+    fn accepting(accept: Option<&str>) -> bool {
+        let mut request = RequestHeader::build("GET", b"/", None).unwrap();
+        if let Some(accept) = accept {
+            request.insert_header("Accept", accept).unwrap();
+        }
+        prefers_json(&request)
+    }
+
+    #[test]
+    fn test_prefers_json() {
+        assert!(!accepting(None));
+        assert!(!accepting(Some("*/*")));
+        assert!(accepting(Some("application/json")));
+        assert!(accepting(Some("application/problem+json; q=0.9")));
+        assert!(accepting(Some("Application/JSON, text/plain")));
+        assert!(!accepting(Some(
+            "text/html,application/xhtml+xml,application/json;q=0.9"
+        )));
+    }
+}
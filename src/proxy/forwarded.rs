@@ -0,0 +1,81 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+use url::Url;
+
+/// Whether the forwarding headers set by the peer can be believed
+pub(crate) fn is_trusted_proxy(trusted: &[IpNet], ip: Option<IpAddr>) -> bool {
+    ip.is_some_and(|ip| {
+        let ip = ip.to_canonical();
+        trusted.iter().any(|net| net.contains(&ip))
+    })
+}
+
+/// Scheme and host the client used to reach the instance, like `https://example.com`.
+/// The forwarded values are only used if they look sane, otherwise the Host header wins
+pub(crate) fn public_origin(
+    host: &str,
+    forwarded_host: Option<&str>,
+    forwarded_proto: Option<&str>,
+) -> String {
+    // proxies in a chain append their own value, the first one is the client facing one
+    let first = |value: &str| {
+        value
+            .split(',')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_owned()
+    };
+    let proto = forwarded_proto
+        .map(first)
+        .map(|proto| proto.to_lowercase())
+        .filter(|proto| proto == "http" || proto == "https")
+        .unwrap_or_else(|| "https".to_owned());
+    let forwarded_host = forwarded_host.map(first).filter(|host| {
+        !host.is_empty()
+            && !host
+                .chars()
+                .any(|c| c.is_whitespace() || matches!(c, '/' | '\\' | '?' | '#' | '@'))
+            && Url::parse(&format!("{proto}://{host}")).is_ok()
+    });
+    let host = forwarded_host.as_deref().unwrap_or(host);
+    format!("{proto}://{host}")
+}
+
+#[cfg(test)]
+mod forwarded_tests {
+    use std::net::IpAddr;
+
+    use crate::ip_filter::parse_cidrs;
+
+    use super::{is_trusted_proxy, public_origin};
+
+    #[test]
+    fn test_public_origin() {
+        assert_eq!(public_origin("a.com", None, None), "https://a.com");
+        assert_eq!(
+            public_origin("a.com", Some("b.com, c.com"), Some("HTTP, https")),
+            "http://b.com"
+        );
+        assert_eq!(
+            public_origin("a.com", Some("evil.com/x@"), Some("javascript")),
+            "https://a.com"
+        );
+        assert_eq!(
+            public_origin("a.com", Some("b.com:99999"), None),
+            "https://a.com"
+        );
+    }
+
+    #[test]
+    fn test_is_trusted_proxy() {
+        let trusted = parse_cidrs(&["10.0.0.0/8".to_owned()]).unwrap();
+        let ip = |ip: &str| Some(ip.parse::<IpAddr>().unwrap());
+        assert!(is_trusted_proxy(&trusted, ip("10.1.2.3")));
+        assert!(is_trusted_proxy(&trusted, ip("::ffff:10.1.2.3")));
+        assert!(!is_trusted_proxy(&trusted, ip("192.168.1.1")));
+        assert!(!is_trusted_proxy(&trusted, None));
+        assert!(!is_trusted_proxy(&[], ip("10.1.2.3")));
+    }
+}
@@ -0,0 +1,46 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::timeout,
+};
+
+/// how long a request waits for a free connection before getting a 503
+pub(crate) const CONNECTION_QUEUE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Concurrent requests in flight to each deployment
+#[derive(Default)]
+pub(crate) struct ConnectionLimits {
+    /// the limit each semaphore was created with, so it can be replaced if the limit changes
+    semaphores: Mutex<HashMap<i64, (usize, Arc<Semaphore>)>>,
+}
+
+impl ConnectionLimits {
+    /// Waits for a free connection, returns None if none got free in time.
+    /// The connection is released when the permit is dropped
+    pub(crate) async fn acquire(
+        &self,
+        deployment: i64,
+        limit: usize,
+    ) -> Option<OwnedSemaphorePermit> {
+        let semaphore = {
+            let mut semaphores = self.semaphores.lock().unwrap();
+            let entry = semaphores
+                .entry(deployment)
+                .or_insert_with(|| (limit, Semaphore::new(limit).into()));
+            if entry.0 != limit {
+                // requests holding a permit of the old one finish normally
+                *entry = (limit, Semaphore::new(limit).into());
+            }
+            entry.1.clone()
+        };
+        timeout(CONNECTION_QUEUE_TIMEOUT, semaphore.acquire_owned())
+            .await
+            .ok()?
+            .ok()
+    }
+}
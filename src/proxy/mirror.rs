@@ -0,0 +1,153 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use http::{header, HeaderMap, Method};
+use hyper::body::Bytes;
+use log::{info, warn};
+use pingora::http::RequestHeader;
+use rand::Rng;
+use tokio::sync::Semaphore;
+
+use crate::{
+    deployments::manager::MirrorTarget,
+    listener::{Access, Listener},
+};
+
+/// sent to the shadow deployment so the app can tell the mirrored requests apart
+const MIRROR_HEADER: &str = "Prezel-Mirror";
+/// requests with larger bodies are not mirrored, so the proxy doesn't buffer them
+const MAX_MIRRORED_BODY: usize = 1024 * 1024;
+/// mirrored requests in flight across every deployment, the ones over it are dropped
+const MAX_MIRRORS_IN_FLIGHT: usize = 256;
+
+/// Sends copies of the production requests to the shadow deployments, outside of the
+/// request path so the clients never wait for them
+pub(crate) struct Mirroring {
+    client: reqwest::Client,
+    in_flight: Arc<Semaphore>,
+    timeout: Duration,
+}
+
+impl Mirroring {
+    /// Like the health check, the certificate is not checked as the container is reached by its ip
+    pub(crate) fn new(timeout: Duration) -> Self {
+        let client = reqwest::Client::builder()
+            .danger_accept_invalid_certs(true)
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .expect("Failed to build the mirroring client");
+        Self {
+            client,
+            in_flight: Semaphore::new(MAX_MIRRORS_IN_FLIGHT).into(),
+            timeout,
+        }
+    }
+
+    /// The response of the shadow deployment is only logged, next to the id of the original request
+    pub(crate) fn send(&self, mirror: PendingMirror, request_id: &str) {
+        let Ok(permit) = self.in_flight.clone().try_acquire_owned() else {
+            warn!("too many mirrored requests in flight, dropping the copy of {request_id}");
+            return;
+        };
+        let client = self.client.clone();
+        let timeout = self.timeout;
+        let request_id = request_id.to_owned();
+        tokio::spawn(async move {
+            let _permit = permit;
+            let PendingMirror {
+                target,
+                method,
+                path,
+                mut headers,
+                body,
+            } = mirror;
+            let deployment = target.deployment;
+            let Ok(Access::Socket(socket)) = target.container.access().await else {
+                warn!("deployment {deployment} is not running, dropping the copy of {request_id}");
+                return;
+            };
+            let scheme = if target.container.upstream_tls().is_enabled() {
+                "https"
+            } else {
+                "http"
+            };
+            headers.insert(MIRROR_HEADER, "true".parse().unwrap());
+            let started = Instant::now();
+            let response = client
+                .request(method, format!("{scheme}://{socket}{path}"))
+                .headers(headers)
+                .body(body)
+                .timeout(timeout)
+                .send()
+                .await;
+            let duration = started.elapsed().as_millis();
+            match response {
+                Ok(response) => info!(
+                    "request {request_id} mirrored to deployment {deployment}, answered {} in {duration}ms",
+                    response.status()
+                ),
+                Err(error) => {
+                    warn!("request {request_id} mirrored to deployment {deployment} failed: {error}")
+                }
+            }
+        });
+    }
+}
+
+/// Copy of a request, filled with its body as the proxy reads it
+pub(crate) struct PendingMirror {
+    target: MirrorTarget,
+    method: Method,
+    path: String,
+    headers: HeaderMap,
+    body: Vec<u8>,
+}
+
+impl PendingMirror {
+    /// None if the request is not sampled, or if its method is not mirrored. By default only
+    /// GET, HEAD and OPTIONS are, so the shadow deployment can't repeat the side effects
+    pub(crate) fn new(target: MirrorTarget, request: &RequestHeader) -> Option<Self> {
+        if !target.all_methods && !is_safe_method(&request.method) {
+            return None;
+        }
+        // upgraded connections don't have a response to discard
+        if request.headers.contains_key(header::UPGRADE) {
+            return None;
+        }
+        if rand::thread_rng().gen_range(0..100) >= target.percent {
+            return None;
+        }
+        let mut headers = request.headers.clone();
+        for name in [
+            header::CONNECTION,
+            header::CONTENT_LENGTH,
+            header::TRANSFER_ENCODING,
+            header::TE,
+        ] {
+            headers.remove(name);
+        }
+        let path = request
+            .uri
+            .path_and_query()
+            .map_or("/".to_owned(), |path| path.to_string());
+        Some(Self {
+            target,
+            method: request.method.clone(),
+            path,
+            headers,
+            body: vec![],
+        })
+    }
+
+    /// Returns false once the body is too large to be mirrored
+    pub(crate) fn push(&mut self, chunk: &Bytes) -> bool {
+        self.body.extend_from_slice(chunk);
+        self.body.len() <= MAX_MIRRORED_BODY
+    }
+}
+
+fn is_safe_method(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
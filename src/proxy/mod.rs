@@ -0,0 +1,1194 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddrV4};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use cookie::{Cookie, SameSite};
+use http::{header, Method, Response, StatusCode};
+use hyper::body::Bytes;
+use ipnet::IpNet;
+use log::{info, warn};
+use nanoid::nanoid;
+use pingora::apps::http_app::ServeHttp;
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::listeners::TlsSettings;
+use pingora::prelude::http_proxy_service;
+use pingora::prelude::{HttpPeer, ProxyHttp, Result, Session};
+use pingora::protocols::http::ServerSession;
+use pingora::server::configuration::{Opt, ServerConf};
+use pingora::server::Server;
+use pingora::services::listening::Service;
+use pingora::tls::error::ErrorStack;
+use pingora::tls::ssl::{
+    NameType, SniError, SslContext, SslContextBuilder, SslFiletype, SslMethod, SslVersion,
+};
+use pingora::ErrorType::{
+    ConnectRefused, ConnectTimedout, ConnectionClosed, Custom, HTTPStatus, ReadError, ReadTimedout,
+    WriteError, WriteTimedout,
+};
+use pingora::{Error, ErrorSource};
+use subtle::ConstantTimeEq;
+use tokio::sync::OwnedSemaphorePermit;
+use url::Url;
+
+use crate::alphabet;
+use crate::analytics::Analytics;
+use crate::conf::{ApiConf, Conf, TlsConf, TlsVersion};
+use crate::container::Container;
+use crate::deployments::manager::Manager;
+use crate::ip_filter::parse_cidrs;
+use crate::listener::{Access, ColdStart, InFlight, Listener, OpenStreams};
+use crate::logging::{Level, RequestLog, RequestLogger};
+use crate::time::now;
+use crate::tls::{CertificateStore, TlsState};
+
+use cache::{is_cacheable_request, PendingEntry, ResponseCache};
+use error_page::{prefers_json, ProxyError, NOT_FOUND, STOPPED, TIMEOUT};
+use forwarded::{is_trusted_proxy, public_origin};
+use limits::ConnectionLimits;
+use mirror::{Mirroring, PendingMirror};
+use redirect::{find_redirect, RedirectRule};
+use rewrite::{is_rewritable, BodyRewrite, PendingRewrite};
+use routes::{is_public_path, is_route_allowed};
+use share::{take_share_param, verify_share_token, SHARE_COOKIE};
+use static_files::serve_static;
+use streaming::{accepts_event_stream, is_event_stream, StreamGuard};
+use upstream_tls::UpstreamTls;
+
+mod cache;
+mod error_page;
+mod forwarded;
+mod limits;
+mod mirror;
+pub(crate) mod redirect;
+pub(crate) mod rewrite;
+pub(crate) mod routes;
+pub(crate) mod share;
+pub(crate) mod static_files;
+mod streaming;
+pub(crate) mod upstream_tls;
+
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+const COLD_START_HEADER: &str = "Prezel-Cold-Start";
+/// millis it took to start the container
+const WAKE_DURATION_HEADER: &str = "Prezel-Wake-Duration";
+/// millis until the deployment is expected to be ready, sent with the loading page when known
+const READY_IN_HEADER: &str = "Prezel-Estimated-Ready-In";
+/// incoming ids longer than this are replaced, so clients can't bloat the logs
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+/// lets admins pick the deployment serving the request, ignored for anyone else
+const DEPLOYMENT_OVERRIDE_HEADER: &str = "X-Prezel-Deployment";
+
+struct ApiListener {
+    conf: ApiConf,
+}
+
+// TODO: move this to api mod
+#[async_trait]
+impl Listener for ApiListener {
+    async fn access(&self) -> anyhow::Result<Access> {
+        Ok(match &self.conf.socket {
+            Some(socket) => Access::UnixSocket(socket.clone()),
+            None => SocketAddrV4::new(Ipv4Addr::LOCALHOST, self.conf.port).into(),
+        })
+    }
+    fn is_public(&self) -> bool {
+        true
+    }
+}
+
+struct Peer {
+    listener: Box<dyn Listener>,
+    deployment_id: Option<i64>,
+    fallback: Option<(i64, Arc<Container>)>,
+}
+
+impl<L: Listener + 'static> From<L> for Peer {
+    fn from(value: L) -> Self {
+        Peer {
+            listener: Box::new(value),
+            deployment_id: None,
+            fallback: None,
+        }
+    }
+}
+
+struct ProxyApp {
+    manager: Manager,
+    config: Conf,
+    request_logger: RequestLogger,
+    cache: Option<ResponseCache>,
+    connection_limits: ConnectionLimits,
+    mirroring: Mirroring,
+    trusted_proxies: Vec<IpNet>,
+}
+
+impl ProxyApp {
+    /// Conditional request headers (If-None-Match, If-Modified-Since) are forwarded untouched,
+    /// so upstream ETag/Cache-Control handling keeps working end to end.
+    /// Returns true if the response was already written from the cache
+    async fn serve_from_cache(&self, session: &mut Session, ctx: &mut RequestCtx) -> Result<bool> {
+        let (Some(cache), Some(_)) = (&self.cache, ctx.deployment) else {
+            return Ok(false);
+        };
+        let request = session.req_header();
+        if !is_cacheable_request(&request.method, &request.headers) {
+            return Ok(false);
+        }
+        let Some(host) = session
+            .get_header(header::HOST)
+            .and_then(|host| host.to_str().ok())
+        else {
+            return Ok(false);
+        };
+        let host = host.to_owned();
+        let path = request.uri.to_string();
+        let headers = request.headers.clone();
+
+        match cache.get(ctx.deployment, &host, &path, &headers) {
+            Some(cached) => {
+                let not_modified = cached.etag().is_some_and(|etag| {
+                    headers
+                        .get(header::IF_NONE_MATCH)
+                        .is_some_and(|value| value.as_bytes() == etag)
+                });
+                let mut resp = Box::new(cached.header.clone());
+                resp.insert_header(header::AGE, cached.age().as_secs())?;
+                resp.insert_header(REQUEST_ID_HEADER, &ctx.request_id)?;
+                if not_modified {
+                    resp.set_status(StatusCode::NOT_MODIFIED)?;
+                    resp.remove_header(&header::CONTENT_LENGTH);
+                    session.write_response_header(resp, true).await?;
+                } else {
+                    session.write_response_header(resp, false).await?;
+                    session
+                        .write_response_body(Some(cached.body.clone()), true)
+                        .await?;
+                }
+                Ok(true)
+            }
+            None => {
+                ctx.cache_entry = Some(PendingEntry::new(ctx.deployment, host, path, headers));
+                Ok(false)
+            }
+        }
+    }
+
+    async fn get_listener(&self, session: &Session) -> Option<Peer> {
+        // TODO: try to use session.req_header().uri.host()
+        let host = session.get_header(header::HOST)?.to_str().ok()?;
+
+        if host == self.config.api_hostname() {
+            Some(
+                ApiListener {
+                    conf: self.config.api.clone(),
+                }
+                .into(),
+            )
+        } else {
+            let container = self
+                .manager
+                .get_container_by_hostname(host, get_client_ip(session))
+                .await?;
+            let container = match self.get_deployment_override(session, &container).await {
+                Some(deployment) => deployment,
+                None => container,
+            };
+            let deployment_id = container.logging_deployment_id.clone();
+            let fallback = match deployment_id {
+                Some(id) => self.manager.get_fallback_container(id).await,
+                None => None,
+            };
+            Some(Peer {
+                listener: Box::new(container),
+                deployment_id,
+                fallback,
+            })
+        }
+    }
+
+    /// Another deployment of the same project, if an admin asked for it with the override header.
+    /// This allows testing a specific build on the production hostname
+    async fn get_deployment_override(
+        &self,
+        session: &Session,
+        container: &Arc<Container>,
+    ) -> Option<Arc<Container>> {
+        let value = session
+            .get_header(DEPLOYMENT_OVERRIDE_HEADER)?
+            .to_str()
+            .ok()?;
+        let by = self.authenticated_by(session)?;
+        let id: i64 = value.trim().parse().ok()?;
+        let project = self
+            .manager
+            .get_deployment(container.logging_deployment_id?)
+            .await?
+            .project;
+        let deployment = self.manager.get_deployment(id).await?;
+        if deployment.project != project {
+            return None;
+        }
+        let client = get_client_ip(session).map_or("unknown".to_owned(), |ip| ip.to_string());
+        let host = session.get_header(header::HOST)?.to_str().ok()?;
+        info!("request to {host} served by deployment {id}, overridden with the {by} of {client}");
+        Some(deployment.app_container.clone())
+    }
+
+    /// Browsers get the coordinator cookie, scripts can send the token as a bearer token instead
+    fn is_authenticated(&self, session: &Session) -> bool {
+        self.authenticated_by(session).is_some()
+    }
+
+    /// How the request is authenticated, if it is
+    fn authenticated_by(&self, session: &Session) -> Option<&'static str> {
+        let hostname = &self.config.hostname;
+        let cookie = session
+            .get_header(header::COOKIE)
+            .and_then(|header| header.to_str().ok())
+            .and_then(|cookie_header| {
+                Cookie::split_parse(cookie_header)
+                    .filter_map(|cookie| cookie.ok())
+                    .find(|cookie| {
+                        cookie.name() == hostname && is_token(cookie.value(), &self.config.token)
+                    })
+            })
+            .is_some();
+        let bearer = session
+            .get_header(header::AUTHORIZATION)
+            .and_then(|header| header.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|bearer| is_token(bearer.trim(), &self.config.token));
+        if cookie {
+            Some("session cookie")
+        } else if bearer {
+            Some("bearer token")
+        } else {
+            None
+        }
+    }
+
+    /// Visitors of a share link get a cookie only valid for the deployment it was minted for
+    fn has_share_cookie(&self, session: &Session, deployment: i64) -> bool {
+        session
+            .get_header(header::COOKIE)
+            .and_then(|header| header.to_str().ok())
+            .is_some_and(|cookie_header| {
+                Cookie::split_parse(cookie_header)
+                    .filter_map(|cookie| cookie.ok())
+                    .filter(|cookie| cookie.name() == SHARE_COOKIE)
+                    .any(|cookie| {
+                        verify_share_token(&self.config.token, cookie.value(), deployment, now())
+                            .is_some()
+                    })
+            })
+    }
+
+    /// Moves the token of a share link into a cookie, redirecting to the same url without it
+    /// so the app never sees it. Returns true if the response was already written
+    async fn handle_share_link(&self, session: &mut Session, deployment: i64) -> Result<bool> {
+        let uri = &session.req_header().uri;
+        let Some((token, query)) = uri.query().and_then(take_share_param) else {
+            return Ok(false);
+        };
+        let Some(expires) = verify_share_token(&self.config.token, &token, deployment, now())
+        else {
+            // expired or tampered links are rejected instead of asking for a login
+            session
+                .respond_error(StatusCode::FORBIDDEN.as_u16())
+                .await?;
+            return Ok(true);
+        };
+        let location = match query.as_str() {
+            "" => uri.path().to_owned(),
+            query => format!("{}?{query}", uri.path()),
+        };
+        let cookie = Cookie::build((SHARE_COOKIE, token))
+            .path("/")
+            .secure(true)
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .max_age(cookie::time::Duration::milliseconds(expires - now()))
+            .build();
+        let mut resp: Box<_> = ResponseHeader::build(StatusCode::FOUND, None)?.into();
+        resp.insert_header(header::LOCATION, location)?;
+        resp.insert_header(header::SET_COOKIE, cookie.to_string())?;
+        resp.insert_header(header::CONTENT_LENGTH, 0)?;
+        close_if_body_unread(session);
+        session.write_response_header(resp, true).await?;
+        Ok(true)
+    }
+}
+
+/// constant time comparison, so the token can't be guessed from response times
+fn is_token(value: &str, token: &str) -> bool {
+    value.as_bytes().ct_eq(token.as_bytes()).into()
+}
+
+fn get_client_ip(session: &Session) -> Option<IpAddr> {
+    session
+        .client_addr()
+        .and_then(|addr| addr.as_inet())
+        .map(|addr| addr.ip())
+}
+
+fn get_redirect(session: &Session, rules: &[RedirectRule]) -> Option<(StatusCode, String)> {
+    if rules.is_empty() {
+        return None;
+    }
+    let host = session.get_header(header::HOST)?.to_str().ok()?;
+    let uri = &session.req_header().uri;
+    find_redirect(rules, host, uri.path(), uri.query())
+}
+
+/// The host of the request without the port
+fn get_sni(session: &Session) -> String {
+    let host = session
+        .get_header(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or_default();
+    match host.rsplit_once(':') {
+        Some((name, port)) if port.parse::<u16>().is_ok() => name.to_owned(),
+        _ => host.to_owned(),
+    }
+}
+
+/// Reuses the id set by the client or a load balancer in front, if any
+fn get_request_id(session: &Session) -> String {
+    session
+        .get_header(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LENGTH)
+        .map(str::to_owned)
+        .unwrap_or_else(|| nanoid!(16, &alphabet::LOWERCASE_PLUS_NUMBERS))
+}
+
+#[derive(Default)]
+struct RequestCtx {
+    request_id: String,
+    /// when the request reached the proxy, for the duration in the logs
+    started: Option<Instant>,
+    /// set once the host resolved to a deployment or the api
+    matched: bool,
+    deployment: Option<i64>,
+    socket: Option<SocketAddrV4>,
+    unix_socket: Option<PathBuf>,
+    /// project override for the upstream read timeout
+    read_timeout: Option<Duration>,
+    upstream_tls: UpstreamTls,
+    /// name sent in the TLS handshake with the upstream, the host the client asked for
+    sni: String,
+    /// the client asked for server-sent events, which can be quiet for a long time
+    event_stream: bool,
+    open_streams: Option<OpenStreams>,
+    /// keeps the deployment awake while the events are streamed
+    stream: Option<StreamGuard>,
+    cache_entry: Option<PendingEntry>,
+    body_rewrites: Arc<Vec<BodyRewrite>>,
+    rewrite: Option<PendingRewrite>,
+    cold_start: Option<ColdStart>,
+    /// released once the request is done
+    connection: Option<OwnedSemaphorePermit>,
+    /// counts the request against the replica it goes to, for the load balancing and the autoscaling
+    in_flight: Option<InFlight>,
+    /// times the connection to the upstream was retried after being refused
+    connect_retries: u32,
+    /// deployment the request fails over to, taken on the first failover so there is no second one
+    fallback: Option<(i64, Arc<Container>)>,
+    /// why the request is failing over, set once the request already went upstream
+    /// and applied on the next upstream_peer call
+    failover: Option<String>,
+    /// copy of the request for the shadow deployment, sent once the request is over
+    mirror: Option<PendingMirror>,
+}
+
+#[async_trait]
+impl ProxyHttp for ProxyApp {
+    type CTX = RequestCtx;
+    fn new_ctx(&self) -> Self::CTX {
+        Default::default()
+    }
+
+    async fn upstream_peer(
+        &self,
+        _session: &mut Session,
+        ctx: &mut Self::CTX,
+    ) -> Result<Box<HttpPeer>> {
+        if let Some(reason) = ctx.failover.take() {
+            let Some(Access::Socket(socket)) = fail_over(ctx, &reason).await else {
+                return Err(Error::new_str("fallback deployment not available"));
+            };
+            ctx.socket = Some(socket);
+            ctx.connect_retries = 0;
+        }
+        if ctx.connect_retries > 0 {
+            let delay = Duration::from_millis(self.config.upstream_connect_retry_delay_ms);
+            tokio::time::sleep(delay).await;
+        }
+        let mut proxy_to = match (&ctx.unix_socket, ctx.socket) {
+            (Some(path), _) => {
+                let path = path
+                    .to_str()
+                    .ok_or_else(|| Error::new_str("invalid socket path"))?;
+                HttpPeer::new_uds(path, false, "".to_owned())?
+            }
+            (None, Some(socket)) => {
+                let tls = ctx.upstream_tls.is_enabled();
+                let mut peer = HttpPeer::new(socket, tls, ctx.sni.clone());
+                if ctx.upstream_tls == UpstreamTls::SkipVerify {
+                    peer.options.verify_cert = false;
+                    peer.options.verify_hostname = false;
+                }
+                peer
+            }
+            (None, None) => {
+                return Err(Error::new_str(
+                    "illegal upstream_peer call with empty socket",
+                ))
+            }
+        };
+        proxy_to.options.connection_timeout = Some(Duration::from_secs(
+            self.config.upstream_connect_timeout_secs,
+        ));
+        proxy_to.options.read_timeout = if ctx.event_stream {
+            None
+        } else {
+            Some(
+                ctx.read_timeout
+                    .unwrap_or(Duration::from_secs(self.config.upstream_read_timeout_secs)),
+            )
+        };
+        let peer = Box::new(proxy_to);
+        Ok(peer)
+    }
+
+    /// The app might still be binding its port right after the container is ready,
+    /// so idempotent requests get a few more attempts before failing
+    fn fail_to_connect(
+        &self,
+        session: &mut Session,
+        _peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        mut e: Box<Error>,
+    ) -> Box<Error> {
+        let idempotent = matches!(session.req_header().method, Method::GET | Method::HEAD);
+        if idempotent
+            && *e.etype() == ConnectRefused
+            && ctx.connect_retries < self.config.upstream_connect_retries
+        {
+            ctx.connect_retries += 1;
+            e.set_retry(true);
+        } else if ctx.fallback.is_some() && ctx.failover.is_none() {
+            // nothing was sent upstream, so any request can go to the fallback
+            ctx.failover = Some(format!("failed to connect ({e})"));
+            e.set_retry(true);
+        }
+        e
+    }
+
+    // I never simply return true, so maybe I could simply do the redirect from inside upstream_peer?
+    async fn request_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<bool> {
+        ctx.request_id = get_request_id(session);
+        ctx.started = Some(Instant::now());
+        // pingora takes 0 as no timeout
+        let keepalive = self.config.keepalive_timeout_secs;
+        session.set_keepalive((keepalive > 0).then_some(keepalive));
+
+        let Some(Peer {
+            listener,
+            deployment_id,
+            fallback,
+        }) = self.get_listener(session).await
+        else {
+            close_if_body_unread(session);
+            write_error(session, &NOT_FOUND).await?;
+            return Ok(true);
+        };
+        ctx.matched = true;
+        ctx.deployment = deployment_id;
+        ctx.fallback = fallback;
+        ctx.read_timeout = listener.read_timeout();
+        ctx.upstream_tls = listener.upstream_tls();
+        if ctx.upstream_tls.is_enabled() {
+            ctx.sni = get_sni(session);
+        }
+        ctx.event_stream = accepts_event_stream(session.req_header());
+        if ctx.event_stream {
+            ctx.open_streams = listener.open_streams();
+        }
+        if self.config.allow_body_rewrites {
+            ctx.body_rewrites = listener.body_rewrites();
+        }
+        if self.config.cold_start_header {
+            ctx.cold_start = listener.cold_start();
+        }
+
+        let client_ip = get_client_ip(session);
+        if client_ip.is_some_and(|ip| !listener.is_ip_allowed(ip)) {
+            session
+                .respond_error(StatusCode::FORBIDDEN.as_u16())
+                .await?;
+            return Ok(true);
+        }
+
+        if let Some((code, location)) = get_redirect(session, &listener.redirects()) {
+            let mut resp: Box<_> = ResponseHeader::build(code, None)?.into();
+            resp.insert_header(header::LOCATION, location)?;
+            resp.insert_header(header::CONTENT_LENGTH, 0)?;
+            close_if_body_unread(session);
+            session.write_response_header(resp, true).await?;
+            return Ok(true);
+        }
+
+        let mut shared = false;
+        if let (Some(deployment), false) = (ctx.deployment, listener.is_public()) {
+            if self.handle_share_link(session, deployment).await? {
+                return Ok(true);
+            }
+            shared = self.has_share_cookie(session, deployment);
+        }
+
+        let public_path =
+            is_public_path(&self.config.public_paths, session.req_header().uri.path());
+        // let listener = self.get_listener(session).await?.listener;
+        if listener.is_public() || shared || public_path || self.is_authenticated(session) {
+            let request = session.req_header();
+            if !is_route_allowed(
+                &listener.route_allowlist(),
+                &request.method,
+                request.uri.path(),
+            ) {
+                session
+                    .respond_error(StatusCode::FORBIDDEN.as_u16())
+                    .await?;
+                return Ok(true);
+            }
+            // cached responses of a stopped deployment are not served either
+            if listener.is_stopped() {
+                close_if_body_unread(session);
+                write_error(session, &STOPPED).await?;
+                return Ok(true);
+            }
+            if self.serve_from_cache(session, ctx).await? {
+                return Ok(true);
+            }
+            let access = match listener.access().await {
+                Ok(access) => access,
+                Err(error) => {
+                    let reason = format!("failed to start ({error})");
+                    match fail_over(ctx, &reason).await {
+                        Some(access) => access,
+                        None => {
+                            return Err(Error::create(
+                                Custom("Failed to aquire socket"),
+                                ErrorSource::Unset, // FIXME: is this correct ??
+                                None,
+                                Some(error.into()),
+                            ));
+                        }
+                    }
+                }
+            };
+            match access {
+                Access::Socket(socket) => {
+                    if let (Some(deployment), Some(limit)) =
+                        (ctx.deployment, listener.max_connections())
+                    {
+                        ctx.connection = self.connection_limits.acquire(deployment, limit).await;
+                        if ctx.connection.is_none() {
+                            warn!(
+                                "deployment {deployment} reached its limit of {limit} connections"
+                            );
+                            session
+                                .respond_error(StatusCode::SERVICE_UNAVAILABLE.as_u16())
+                                .await?;
+                            return Ok(true);
+                        }
+                    }
+                    // event streams would keep the copy open for as long as the original
+                    if let (Some(deployment), false) = (ctx.deployment, ctx.event_stream) {
+                        if let Some(target) = self.manager.get_mirror(deployment).await {
+                            ctx.mirror = PendingMirror::new(target, session.req_header());
+                        }
+                    }
+                    ctx.in_flight = listener.in_flight(socket);
+                    ctx.socket = Some(socket);
+                    Ok(false)
+                }
+                Access::UnixSocket(path) => {
+                    ctx.unix_socket = Some(path);
+                    Ok(false)
+                }
+                Access::Static(root) => {
+                    serve_static(session, &root).await?;
+                    Ok(true)
+                }
+                Access::Stopped => {
+                    close_if_body_unread(session);
+                    write_error(session, &STOPPED).await?;
+                    Ok(true)
+                }
+                Access::Loading => {
+                    // the app is not there yet to answer a CORS preflight
+                    let preflight = session.req_header().method == Method::OPTIONS;
+                    let code = if preflight {
+                        StatusCode::NO_CONTENT
+                    } else {
+                        StatusCode::OK
+                    };
+                    let mut resp: Box<_> = ResponseHeader::build(code, None)?.into();
+                    resp.insert_header("Prezel-Loading", "true")?;
+                    // polling clients can come back right when the start is expected to be done
+                    let retry_after = match listener.ready_in() {
+                        Some(ready_in) => {
+                            resp.insert_header(READY_IN_HEADER, ready_in.as_millis().to_string())?;
+                            ready_in.as_secs_f64().ceil().max(1.0) as u64
+                        }
+                        None => self.config.loading_retry_after_secs,
+                    };
+                    resp.insert_header(header::RETRY_AFTER, retry_after)?;
+                    // the browser polls the loading page until the app is up, reusing the connection
+                    close_if_body_unread(session);
+                    if preflight {
+                        resp.insert_header(header::ALLOW, "GET, HEAD, OPTIONS")?;
+                        session.write_response_header(resp, true).await?;
+                    } else {
+                        write_page(
+                            session,
+                            resp,
+                            include_bytes!("../../resources/loading.html"),
+                        )
+                        .await?;
+                    }
+                    Ok(true)
+                }
+            }
+        } else {
+            let host = session.get_header(header::HOST).unwrap().to_str().unwrap();
+            let path = session.req_header().uri.path();
+            let origin = if is_trusted_proxy(&self.trusted_proxies, get_client_ip(session)) {
+                let forwarded = |name| {
+                    session
+                        .get_header(name)
+                        .and_then(|value| value.to_str().ok())
+                };
+                public_origin(
+                    host,
+                    forwarded("X-Forwarded-Host"),
+                    forwarded("X-Forwarded-Proto"),
+                )
+            } else {
+                public_origin(host, None, None)
+            };
+            // the Host header can still be malformed
+            let Ok(callback) = Url::parse(&format!("{origin}{path}")) else {
+                session
+                    .respond_error(StatusCode::BAD_REQUEST.as_u16())
+                    .await?;
+                return Ok(true);
+            };
+
+            let coordinator = &self.config.coordinator;
+            let mut redirect = Url::parse(&format!("{coordinator}/api/instance/auth")).unwrap();
+            redirect
+                .query_pairs_mut()
+                .append_pair("callback", callback.as_str());
+
+            let code = StatusCode::FOUND;
+            let mut resp: Box<_> = ResponseHeader::build(code, None)?.into();
+            resp.insert_header(header::LOCATION, redirect.as_str())?;
+            resp.insert_header(header::CONTENT_LENGTH, 0)?;
+            close_if_body_unread(session);
+            session.write_response_header(resp, true).await?;
+            Ok(true)
+        }
+    }
+
+    async fn request_body_filter(
+        &self,
+        _session: &mut Session,
+        body: &mut Option<Bytes>,
+        _end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        if let (Some(mirror), Some(chunk)) = (&mut ctx.mirror, body.as_ref()) {
+            if !mirror.push(chunk) {
+                ctx.mirror = None;
+            }
+        }
+        Ok(())
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        _session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        upstream_request.insert_header(REQUEST_ID_HEADER, &ctx.request_id)?;
+        Ok(())
+    }
+
+    async fn response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        // nothing was written downstream yet, so the request can still be retried on the fallback.
+        // Only for idempotent requests, as the app might have done something before failing
+        let status = upstream_response.status;
+        let idempotent = matches!(session.req_header().method, Method::GET | Method::HEAD);
+        if status.is_server_error() && idempotent && ctx.fallback.is_some() {
+            ctx.failover = Some(format!("answered with {status}"));
+            let mut e = Error::explain(HTTPStatus(status.as_u16()), "failing over");
+            e.set_retry(true);
+            return Err(e);
+        }
+        // before caching, so the cached responses don't have them either
+        for name in &self.config.strip_response_headers {
+            upstream_response.remove_header(name);
+        }
+        let event_stream = is_event_stream(upstream_response);
+        if event_stream {
+            // chunks are flushed as they arrive, this keeps proxies in front of us from holding them
+            upstream_response.insert_header("X-Accel-Buffering", "no")?;
+            ctx.cache_entry = None;
+            ctx.stream = ctx.open_streams.take().map(StreamGuard::new);
+        }
+        if !event_stream && !ctx.body_rewrites.is_empty() && is_rewritable(upstream_response) {
+            // the rewritten body has a different length and content
+            upstream_response.remove_header(&header::CONTENT_LENGTH);
+            upstream_response.remove_header(&header::ETAG);
+            upstream_response.insert_header(header::TRANSFER_ENCODING, "chunked")?;
+            ctx.rewrite = Some(PendingRewrite::new(ctx.body_rewrites.clone()));
+        }
+        if let Some(cache) = &self.cache {
+            cache.accept_response(&mut ctx.cache_entry, upstream_response);
+        }
+        upstream_response.insert_header(REQUEST_ID_HEADER, &ctx.request_id)?;
+        if upstream_response.status.is_success() {
+            let wake = ctx
+                .cold_start
+                .as_ref()
+                .and_then(|cold_start| cold_start.lock().unwrap().take());
+            if let Some(wake) = wake {
+                upstream_response.insert_header(COLD_START_HEADER, "true")?;
+                upstream_response.insert_header(WAKE_DURATION_HEADER, wake.as_millis() as u64)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn response_body_filter(
+        &self,
+        _session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<Option<Duration>>
+    where
+        Self::CTX: Send + Sync,
+    {
+        if let Some(rewrite) = ctx.rewrite.as_mut() {
+            rewrite.filter(body, end_of_stream);
+        }
+        if let Some(entry) = ctx.cache_entry.as_mut() {
+            let fits = body.as_ref().map_or(true, |chunk| entry.push_body(chunk));
+            if !fits {
+                ctx.cache_entry = None;
+            } else if end_of_stream {
+                if let (Some(cache), Some(entry)) = (&self.cache, ctx.cache_entry.take()) {
+                    cache.insert(entry);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    // async fn response_filter(
+    //     &self,
+    //     _session: &mut Session,
+    //     upstream_response: &mut ResponseHeader,
+    //     _ctx: &mut Self::CTX,
+    // ) -> Result<()>
+    // where
+    //     Self::CTX: Send + Sync,
+    // {
+    //     upstream_response
+    //         .insert_header("Access-Control-Allow-Origin", &self.config.coordinator)
+    //         .unwrap();
+    //     Ok(())
+    // }
+
+    /// Same as the default implementation, except for upstream timeouts,
+    /// which get a 504 with a branded page instead of a plain 502
+    async fn fail_to_proxy(&self, session: &mut Session, e: &Error, _ctx: &mut Self::CTX) -> u16
+    where
+        Self::CTX: Send + Sync,
+    {
+        let timed_out = matches!(e.etype(), ConnectTimedout | ReadTimedout | WriteTimedout);
+        if timed_out && e.esource() == &ErrorSource::Upstream {
+            if session.response_written().is_some() {
+                // the response already started, nothing else can be sent
+                return 0;
+            }
+            // the request may have been cut while being sent upstream, so the connection is not reused
+            session.set_keepalive(None);
+            let _ = write_error(session, &TIMEOUT).await;
+            return StatusCode::GATEWAY_TIMEOUT.as_u16();
+        }
+
+        let code = match e.etype() {
+            HTTPStatus(code) => *code,
+            _ => match e.esource() {
+                ErrorSource::Upstream => 502,
+                ErrorSource::Downstream => match e.etype() {
+                    WriteError | ReadError | ConnectionClosed => 0, // conn already dead
+                    _ => 400,
+                },
+                ErrorSource::Internal | ErrorSource::Unset => 500,
+            },
+        };
+        if code > 0 {
+            session.as_mut().respond_error(code).await;
+        }
+        code
+    }
+
+    async fn logging(
+        &self,
+        session: &mut Session,
+        _e: Option<&pingora::Error>,
+        ctx: &mut Self::CTX,
+    ) {
+        let slow_threshold = self.config.slow_request_threshold_ms;
+        logging(session, ctx, &self.request_logger, slow_threshold);
+        // the copy only goes out once the client got its response, and not for half read bodies
+        if let Some(mirror) = ctx.mirror.take() {
+            if session.is_body_done() {
+                self.mirroring.send(mirror, &ctx.request_id);
+            }
+        }
+    }
+}
+
+/// Switches the request to the fallback deployment, if any. It is taken from the context
+/// so a request fails over once at most and never loops between both deployments
+async fn fail_over(ctx: &mut RequestCtx, reason: &str) -> Option<Access> {
+    let (fallback_id, fallback) = ctx.fallback.take()?;
+    let deployment = ctx.deployment.unwrap_or_default();
+    warn!("deployment {deployment} {reason}, failing over to deployment {fallback_id}");
+    ctx.deployment = Some(fallback_id);
+    // the response of the fallback is not cached as if it was the one of the deployment
+    ctx.cache_entry = None;
+    fallback.access().await.ok()
+}
+
+/// The responses written by the proxy itself don't read the request body and pingora doesn't
+/// drain it, so the connection is only kept alive when there was none to read
+fn close_if_body_unread(session: &mut Session) {
+    if !session.is_body_empty() {
+        session.set_keepalive(None);
+    }
+}
+
+/// Writes one of the errors of the proxy itself, as JSON for the clients asking for it
+async fn write_error(session: &mut Session, error: &ProxyError) -> Result<()> {
+    let resp: Box<_> = ResponseHeader::build(error.status, None)?.into();
+    if prefers_json(session.req_header()) {
+        let body = serde_json::json!({ "code": error.code, "message": error.message });
+        write_body(session, resp, "application/json", body.to_string().into()).await
+    } else {
+        write_page(session, resp, error.page).await
+    }
+}
+
+/// Writes one of the pages served by the proxy itself
+async fn write_page(
+    session: &mut Session,
+    resp: Box<ResponseHeader>,
+    body: &'static [u8],
+) -> Result<()> {
+    write_body(session, resp, "text/html", Bytes::from_static(body)).await
+}
+
+/// Writes a response generated by the proxy itself, without the body for HEAD requests
+async fn write_body(
+    session: &mut Session,
+    mut resp: Box<ResponseHeader>,
+    content_type: &str,
+    body: Bytes,
+) -> Result<()> {
+    resp.insert_header(header::CONTENT_TYPE, content_type)?;
+    resp.insert_header(header::CONTENT_LENGTH, body.len())?;
+    if session.req_header().method == Method::HEAD {
+        session.write_response_header(resp, true).await?;
+    } else {
+        session.write_response_header(resp, false).await?;
+        session.write_response_body(Some(body), true).await?;
+    }
+    Ok(())
+}
+
+fn logging(
+    session: &Session,
+    ctx: &RequestCtx,
+    logger: &RequestLogger,
+    slow_threshold_ms: Option<u64>,
+) -> Option<()> {
+    if !ctx.matched {
+        log_unmatched(session, ctx);
+        return None;
+    }
+    let host = session.get_header(header::HOST)?.to_str().ok()?.to_owned();
+    let path = session.req_header().uri.path().to_owned();
+    let method = session.req_header().method.as_str().to_owned();
+    let deployment = ctx.deployment?;
+    let response = session.response_written()?;
+
+    let level = if response.status.is_client_error() || response.status.is_server_error() {
+        Level::ERROR
+    } else {
+        Level::INFO
+    };
+
+    let duration_ms = ctx
+        .started
+        .map_or(0, |started| started.elapsed().as_millis() as u64);
+    if slow_threshold_ms.is_some_and(|threshold| duration_ms > threshold) {
+        warn!(
+            "slow request: {method} {host}{path} took {duration_ms}ms, deployment={deployment} request_id={}",
+            ctx.request_id
+        );
+    }
+
+    let client_ip = get_client_ip(session);
+    logger.log(
+        RequestLog {
+            level,
+            deployment,
+            time: now(),
+            host,
+            method,
+            path,
+            status: response.status.as_u16(),
+            request_id: ctx.request_id.clone(),
+            duration_ms,
+        },
+        client_ip,
+    );
+
+    Some(())
+}
+
+/// Requests for hosts no deployment answers to, like scans or misconfigured DNS records,
+/// only go to the system log since there is no deployment to attribute them to
+fn log_unmatched(session: &Session, ctx: &RequestCtx) {
+    let request = session.req_header();
+    let host = session
+        .get_header(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or("-");
+    let status = session
+        .response_written()
+        .map_or("-".to_owned(), |response| {
+            response.status.as_u16().to_string()
+        });
+    let client = get_client_ip(session).map_or("-".to_owned(), |ip| ip.to_string());
+    info!(
+        target: "prezel::proxy::unmatched",
+        "{} {host}{} {status} client={client} request_id={}",
+        request.method,
+        request.uri.path(),
+        ctx.request_id
+    );
+}
+
+// struct TlsCallback {
+//     certificate: TlsCertificate,
+// }
+
+// #[async_trait]
+// impl TlsAccept for TlsCallback {
+//     async fn certificate_callback(&self, ssl: &mut ssl::SslRef) {
+//         tls::ext::ssl_use_certificate(ssl, &self.certificate.cert).unwrap();
+//         tls::ext::ssl_use_private_key(ssl, &self.certificate.key).unwrap();
+
+//         //maybe this is enough
+//         // let servername = ssl.servername(NameType::HOST_NAME);
+
+//         // ssl.set_certificate(cert)?;
+//         // ssl.set_private_key(cert)?;
+//     }
+// }
+
+struct HttpHandler {
+    pub(crate) certificates: CertificateStore,
+}
+
+#[async_trait]
+impl ServeHttp for HttpHandler {
+    async fn response(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        // if let Some(host) = session.req_header().uri.host() { // FIXME: maybe I should check both?
+        if let Some(Ok(host)) = session
+            .get_header(header::HOST)
+            .map(|header| header.to_str())
+        {
+            // println!("redirecting HTTP query to {host}");
+            let path = session.req_header().uri.path();
+            if let Some(TlsState::Challenge {
+                challenge_file,
+                challenge_content,
+            }) = self.certificates.get_domain(host)
+            {
+                if path == format!("/.well-known/acme-challenge/{challenge_file}") {
+                    dbg!("challenge path");
+                    let content_length = challenge_content.len();
+                    Response::builder()
+                        .status(StatusCode::OK)
+                        .header(header::CONTENT_TYPE, "text/plain")
+                        .header(header::CONTENT_LENGTH, content_length)
+                        .body(challenge_content.into())
+                        .unwrap()
+                } else {
+                    Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .body("".into()) // should I set the content length?
+                        .unwrap()
+                }
+            } else {
+                let body = "<html><body>301 Moved Permanently</body></html>"
+                    .as_bytes()
+                    .to_owned();
+                Response::builder()
+                    .status(StatusCode::MOVED_PERMANENTLY)
+                    .header(header::CONTENT_TYPE, "text/html")
+                    .header(header::CONTENT_LENGTH, body.len())
+                    .header(header::LOCATION, format!("https://{host}{path}"))
+                    .body(body)
+                    .unwrap()
+            }
+        } else {
+            Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(vec![]) // FIXME: is this ok? how can I return an empty body?
+                .unwrap()
+        }
+    }
+}
+
+pub(crate) fn run_proxy(
+    manager: Manager,
+    config: Conf,
+    store: CertificateStore,
+    analytics: Analytics,
+) {
+    let request_logger = RequestLogger::new(analytics);
+    // SIGTERM stops the listeners and gives the open connections this long to finish,
+    // then the process exits. SIGINT exits right away
+    let server_conf = ServerConf {
+        grace_period_seconds: Some(config.drain_timeout_secs),
+        ..ServerConf::new().unwrap()
+    };
+    let mut server = Server::new_with_opt_and_conf(Opt::default(), server_conf);
+    server.bootstrap();
+    let cache = config.cache.as_ref().map(ResponseCache::new);
+    let tls_conf = config.tls.clone();
+    let trusted_proxies = parse_cidrs(&config.trusted_proxies).expect("Invalid trusted_proxies");
+    let mirroring = Mirroring::new(Duration::from_secs(config.upstream_read_timeout_secs));
+    let proxy_app = ProxyApp {
+        manager,
+        config,
+        request_logger,
+        cache,
+        connection_limits: Default::default(),
+        mirroring,
+        trusted_proxies,
+    };
+    let mut https_service = http_proxy_service(&server.configuration, proxy_app);
+    let certificate = store.get_default_certificate();
+    // let tls_callback = Box::new(TlsCallback { certificate });
+    // let mut tls_settings = TlsSettings::with_callbacks(tls_callback).unwrap();
+    let mut tls_settings = TlsSettings::intermediate(&certificate.cert, &certificate.key).unwrap();
+    apply_tls_conf(&mut tls_settings, &tls_conf).expect("Invalid tls config");
+
+    // TODO: tls_settings.add_extra_chain_cert(cert) !!!!!!!!!!!!!!!!!!!!!!
+    // TODO: tls_settings.enable_h2();
+
+    let cloned = store.clone();
+    tls_settings.set_servername_callback(move |ssl, alert| {
+        let domain = ssl.servername(NameType::HOST_NAME);
+        if let Some(domain) = domain {
+            if let Some(TlsState::Ready(certificate)) = cloned.get_domain(domain) {
+                // ssl.set_certificate(&certificate.cert); // this does not seem to work
+                // ssl.set_private_key(&certificate.key);
+                dbg!();
+                let mut ctx = SslContext::builder(SslMethod::tls()).unwrap();
+                // the context replaces the default one, so it needs the same policy
+                apply_tls_conf(&mut ctx, &tls_conf).unwrap();
+                ctx.set_certificate_chain_file(&certificate.cert).unwrap();
+                ctx.set_private_key_file(&certificate.key, SslFiletype::PEM)
+                    .unwrap();
+                // ctx.set_alpn_select_callback(prefer_h2);
+                let built = ctx.build();
+                ssl.set_ssl_context(&built)
+                    .map_err(|_| SniError::ALERT_FATAL)?;
+            }
+        }
+        Ok(())
+    });
+
+    https_service.add_tls_with_settings("0.0.0.0:443", None, tls_settings);
+    server.add_service(https_service);
+
+    let mut http_service = Service::new(
+        "HTTP service".to_string(), // TODO: review this name ?
+        HttpHandler {
+            certificates: store,
+        },
+    );
+    http_service.add_tcp("0.0.0.0:80");
+    server.add_service(http_service);
+
+    server.run_forever();
+}
+
+fn apply_tls_conf(ctx: &mut SslContextBuilder, conf: &TlsConf) -> Result<(), ErrorStack> {
+    let min_version = match conf.min_version {
+        TlsVersion::Tls12 => SslVersion::TLS1_2,
+        TlsVersion::Tls13 => SslVersion::TLS1_3,
+    };
+    ctx.set_min_proto_version(Some(min_version))?;
+    if let Some(ciphers) = &conf.ciphers {
+        ctx.set_cipher_list(ciphers)?;
+    }
+    if let Some(ciphersuites) = &conf.ciphersuites {
+        ctx.set_ciphersuites(ciphersuites)?;
+    }
+    Ok(())
+}
+
+// TODO: remove
+fn create_ssl_context(
+    cert_path: &str,
+    key_path: &str,
+) -> Result<SslContext, Box<dyn std::error::Error>> {
+    let mut ctx = SslContext::builder(SslMethod::tls())?;
+
+    ctx.set_certificate_chain_file(cert_path)?;
+    ctx.set_private_key_file(key_path, SslFiletype::PEM)?;
+    // ctx.set_alpn_select_callback(prefer_h2); // TODO: review if this is important
+    let built = ctx.build();
+    Ok(built)
+}
@@ -0,0 +1,235 @@
+use anyhow::ensure;
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// Redirect answered by the proxy itself, without reaching the deployment
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub(crate) struct RedirectRule {
+    /// Path to match like `/old`, optionally prefixed with a host like `example.com/old`.
+    /// A trailing `*` matches any suffix
+    pub(crate) from: String,
+    /// Path or absolute URL to redirect to, a trailing `*` is replaced with the matched suffix
+    pub(crate) to: String,
+    /// Either 301 or 302
+    #[serde(default = "default_status")]
+    pub(crate) status: u16,
+}
+
+fn default_status() -> u16 {
+    301
+}
+
+impl RedirectRule {
+    pub(crate) fn validate(rules: &[RedirectRule]) -> anyhow::Result<()> {
+        for rule in rules {
+            let from = &rule.from;
+            ensure!(
+                rule.status == 301 || rule.status == 302,
+                "invalid status {} for {from}, it must be 301 or 302",
+                rule.status
+            );
+            ensure!(
+                !from.trim_end_matches('*').contains('*'),
+                "only a trailing * is allowed in {from}"
+            );
+            ensure!(
+                from.contains('/'),
+                "{from} needs to be a path, optionally prefixed with a host"
+            );
+            let to = &rule.to;
+            ensure!(
+                !to.trim_end_matches('*').contains('*'),
+                "only a trailing * is allowed in {to}"
+            );
+            ensure!(
+                !to.ends_with('*') || from.ends_with('*'),
+                "{to} uses a suffix that {from} does not capture"
+            );
+            ensure!(
+                to.starts_with('/') || to.starts_with("https://") || to.starts_with("http://"),
+                "{to} needs to be a path or an absolute URL"
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns the location to redirect to if the rule matches
+    fn apply(&self, host: &str, path: &str) -> Option<String> {
+        let (rule_host, rule_path) = match self.from.find('/') {
+            Some(0) => (None, self.from.as_str()),
+            Some(index) => (Some(&self.from[..index]), &self.from[index..]),
+            None => return None,
+        };
+        if rule_host.is_some_and(|rule_host| !rule_host.eq_ignore_ascii_case(host)) {
+            return None;
+        }
+        let suffix = match rule_path.strip_suffix('*') {
+            Some(prefix) => path.strip_prefix(prefix)?,
+            None if rule_path == path => "",
+            None => return None,
+        };
+        let location = match self.to.strip_suffix('*') {
+            Some(target) => format!("{target}{suffix}"),
+            None => self.to.clone(),
+        };
+        Some(location)
+    }
+}
+
+/// Which domain of the apex and www pairs in the custom domains of a project is served,
+/// the other one permanently redirects to it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum CanonicalHost {
+    #[default]
+    Off,
+    Www,
+    Apex,
+}
+
+impl CanonicalHost {
+    pub(crate) fn parse(value: &str) -> Self {
+        match value {
+            "www" => Self::Www,
+            "apex" => Self::Apex,
+            _ => Self::Off,
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Www => "www",
+            Self::Apex => "apex",
+        }
+    }
+
+    /// Rules for the domains whose apex and www variants are both registered
+    pub(crate) fn redirects(&self, domains: &[String]) -> Vec<RedirectRule> {
+        let has_domain = |name: &str| {
+            domains
+                .iter()
+                .any(|domain| domain.eq_ignore_ascii_case(name))
+        };
+        domains
+            .iter()
+            .filter(|domain| has_domain(&format!("www.{domain}")))
+            .filter_map(|apex| {
+                let www = format!("www.{apex}");
+                let (from, to) = match self {
+                    Self::Off => return None,
+                    Self::Www => (apex.as_str(), www.as_str()),
+                    Self::Apex => (www.as_str(), apex.as_str()),
+                };
+                Some(RedirectRule {
+                    from: format!("{from}/*"),
+                    to: format!("https://{to}/*"),
+                    status: 301,
+                })
+            })
+            .collect()
+    }
+}
+
+/// First matching rule wins, the query is kept unless the target sets its own
+pub(crate) fn find_redirect(
+    rules: &[RedirectRule],
+    host: &str,
+    path: &str,
+    query: Option<&str>,
+) -> Option<(StatusCode, String)> {
+    // the host header might include the port
+    let host = host.split(':').next().unwrap_or_default();
+    rules.iter().find_map(|rule| {
+        let mut location = rule.apply(host, path)?;
+        if let Some(query) = query.filter(|_| !location.contains('?')) {
+            location = format!("{location}?{query}");
+        }
+        let status = StatusCode::from_u16(rule.status).unwrap_or(StatusCode::MOVED_PERMANENTLY);
+        Some((status, location))
+    })
+}
+
+#[cfg(test)]
+mod redirect_tests {
+    use http::StatusCode;
+
+    use super::{find_redirect, CanonicalHost, RedirectRule};
+
+    fn rule(from: &str, to: &str, status: u16) -> RedirectRule {
+        RedirectRule {
+            from: from.to_owned(),
+            to: to.to_owned(),
+            status,
+        }
+    }
+
+    #[test]
+    fn test_find_redirect() {
+        let rules = vec![
+            rule("/blog/legacy", "/news", 302),
+            rule("/blog/*", "/posts/*", 301),
+            rule("example.com/*", "https://www.example.com/*", 301),
+        ];
+        RedirectRule::validate(&rules).unwrap();
+
+        let redirect = |host, path, query| find_redirect(&rules, host, path, query);
+        assert_eq!(
+            redirect("www.example.com", "/blog/legacy", None),
+            Some((StatusCode::FOUND, "/news".to_owned()))
+        );
+        assert_eq!(
+            redirect("www.example.com", "/blog/a/b", Some("page=2")),
+            Some((
+                StatusCode::MOVED_PERMANENTLY,
+                "/posts/a/b?page=2".to_owned()
+            ))
+        );
+        assert_eq!(
+            redirect("example.com:443", "/about", None),
+            Some((
+                StatusCode::MOVED_PERMANENTLY,
+                "https://www.example.com/about".to_owned()
+            ))
+        );
+        assert_eq!(redirect("www.example.com", "/about", None), None);
+    }
+
+    #[test]
+    fn test_canonical_host() {
+        let domains = ["example.com", "www.example.com", "other.com"].map(str::to_owned);
+        let to_www = CanonicalHost::Www.redirects(&domains);
+        assert_eq!(to_www.len(), 1);
+        RedirectRule::validate(&to_www).unwrap();
+        assert_eq!(
+            find_redirect(&to_www, "example.com", "/a/b", Some("page=2")),
+            Some((
+                StatusCode::MOVED_PERMANENTLY,
+                "https://www.example.com/a/b?page=2".to_owned()
+            ))
+        );
+        assert_eq!(find_redirect(&to_www, "www.example.com", "/", None), None);
+        assert_eq!(find_redirect(&to_www, "other.com", "/", None), None);
+
+        let to_apex = CanonicalHost::Apex.redirects(&domains);
+        assert_eq!(
+            find_redirect(&to_apex, "www.example.com", "/", None),
+            Some((
+                StatusCode::MOVED_PERMANENTLY,
+                "https://example.com/".to_owned()
+            ))
+        );
+        assert!(CanonicalHost::Off.redirects(&domains).is_empty());
+        assert!(CanonicalHost::Www.redirects(&domains[1..]).is_empty());
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        assert!(RedirectRule::validate(&[rule("/a", "/b", 307)]).is_err());
+        assert!(RedirectRule::validate(&[rule("/a/*/b", "/b", 301)]).is_err());
+        assert!(RedirectRule::validate(&[rule("/a", "/b/*", 301)]).is_err());
+        assert!(RedirectRule::validate(&[rule("/a", "b", 301)]).is_err());
+        assert!(RedirectRule::validate(&[rule("a", "/b", 301)]).is_err());
+    }
+}
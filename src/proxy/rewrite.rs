@@ -0,0 +1,156 @@
+use std::sync::Arc;
+
+use anyhow::bail;
+use http::header;
+use hyper::body::Bytes;
+use pingora::http::ResponseHeader;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// bigger bodies are streamed through untouched
+pub(crate) const MAX_REWRITE_BODY_SIZE: usize = 1024 * 1024;
+
+/// Find and replace rule applied by the proxy to text responses of a project
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub(crate) struct BodyRewrite {
+    /// Literal text to look for
+    pub(crate) find: String,
+    /// Text every occurrence is replaced with
+    pub(crate) replace: String,
+}
+
+impl BodyRewrite {
+    pub(crate) fn validate(rules: &[BodyRewrite]) -> anyhow::Result<()> {
+        if rules.iter().any(|rule| rule.find.is_empty()) {
+            bail!("the text to find can not be empty");
+        }
+        Ok(())
+    }
+}
+
+/// Only uncompressed text bodies are rewritten
+pub(crate) fn is_rewritable(response: &ResponseHeader) -> bool {
+    let encoded = response
+        .headers
+        .get(header::CONTENT_ENCODING)
+        .is_some_and(|encoding| encoding.as_bytes() != b"identity");
+    let content_type = response
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_lowercase());
+    let is_text = content_type.is_some_and(|content_type| {
+        let mime = content_type.split(';').next().unwrap_or_default().trim();
+        mime.starts_with("text/")
+            || mime.ends_with("/json")
+            || mime.ends_with("+json")
+            || mime.ends_with("/xml")
+            || mime.ends_with("+xml")
+            || mime == "application/javascript"
+    });
+    !encoded && is_text
+}
+
+/// Response body being buffered until it can be rewritten as a whole
+pub(crate) struct PendingRewrite {
+    rules: Arc<Vec<BodyRewrite>>,
+    buffer: Vec<u8>,
+    passthrough: bool,
+}
+
+impl PendingRewrite {
+    pub(crate) fn new(rules: Arc<Vec<BodyRewrite>>) -> Self {
+        Self {
+            rules,
+            buffer: vec![],
+            passthrough: false,
+        }
+    }
+
+    /// Holds back every chunk until the end of the body, which then gets the rules applied.
+    /// Once the body grows over the limit, what was held back and the rest go through unmodified
+    pub(crate) fn filter(&mut self, body: &mut Option<Bytes>, end_of_stream: bool) {
+        if self.passthrough {
+            return;
+        }
+        if let Some(chunk) = body.take() {
+            self.buffer.extend_from_slice(&chunk);
+        }
+        if self.buffer.len() > MAX_REWRITE_BODY_SIZE {
+            self.passthrough = true;
+            *body = Some(std::mem::take(&mut self.buffer).into());
+        } else if end_of_stream && !self.buffer.is_empty() {
+            let buffer = std::mem::take(&mut self.buffer);
+            *body = Some(match String::from_utf8(buffer) {
+                Ok(text) => self.apply(text).into(),
+                Err(error) => error.into_bytes().into(),
+            });
+        }
+    }
+
+    fn apply(&self, text: String) -> String {
+        self.rules
+            .iter()
+            .fold(text, |text, rule| text.replace(&rule.find, &rule.replace))
+    }
+}
+
+#[cfg(test)]
+mod rewrite_tests {
+    use std::sync::Arc;
+
+    use hyper::body::Bytes;
+    use pingora::http::ResponseHeader;
+
+    use super::{is_rewritable, BodyRewrite, PendingRewrite, MAX_REWRITE_BODY_SIZE};
+
+    fn rewrite() -> PendingRewrite {
+        PendingRewrite::new(Arc::new(vec![BodyRewrite {
+            find: "\"legacy\"".to_owned(),
+            replace: "\"modern\"".to_owned(),
+        }]))
+    }
+
+    #[test]
+    fn test_rewrite_chunked_body() {
+        let mut pending = rewrite();
+        let mut body = Some(Bytes::from("{\"field\": \"leg"));
+        pending.filter(&mut body, false);
+        assert_eq!(body, None);
+        let mut body = Some(Bytes::from("acy\"}"));
+        pending.filter(&mut body, true);
+        assert_eq!(body, Some(Bytes::from("{\"field\": \"modern\"}")));
+    }
+
+    #[test]
+    fn test_big_body_passes_through() {
+        let mut pending = rewrite();
+        let first = "\"legacy\"".repeat(MAX_REWRITE_BODY_SIZE / 8 + 1);
+        let mut body = Some(Bytes::from(first.clone()));
+        pending.filter(&mut body, false);
+        assert_eq!(body, Some(Bytes::from(first)));
+        let mut body = Some(Bytes::from("\"legacy\""));
+        pending.filter(&mut body, true);
+        assert_eq!(body, Some(Bytes::from("\"legacy\"")));
+    }
+
+    #[test]
+    fn test_is_rewritable() {
+        let response = |content_type: &str, encoding: Option<&str>| {
+            let mut response = ResponseHeader::build(200, None).unwrap();
+            response
+                .insert_header("Content-Type", content_type)
+                .unwrap();
+            if let Some(encoding) = encoding {
+                response
+                    .insert_header("Content-Encoding", encoding)
+                    .unwrap();
+            }
+            response
+        };
+        assert!(is_rewritable(&response("application/json", None)));
+        assert!(is_rewritable(&response("text/html; charset=utf-8", None)));
+        assert!(!is_rewritable(&response("application/json", Some("gzip"))));
+        assert!(!is_rewritable(&response("image/png", None)));
+    }
+}
@@ -0,0 +1,137 @@
+use anyhow::ensure;
+use http::Method;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// Method and path forwarded to the deployment when the project has an allowlist
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub(crate) struct AllowedRoute {
+    /// HTTP method like `GET`, or `*` for any method
+    pub(crate) method: String,
+    /// Path prefix like `/api/public`, matched on whole segments
+    pub(crate) path_prefix: String,
+}
+
+impl AllowedRoute {
+    pub(crate) fn validate(routes: &[AllowedRoute]) -> anyhow::Result<()> {
+        for route in routes {
+            let method = &route.method;
+            ensure!(
+                method == "*" || Method::from_bytes(method.as_bytes()).is_ok(),
+                "invalid method {method}"
+            );
+            ensure!(
+                route.path_prefix.starts_with('/'),
+                "{} needs to be a path starting with /",
+                route.path_prefix
+            );
+        }
+        Ok(())
+    }
+
+    fn matches(&self, method: &Method, path: &str) -> bool {
+        let method_matches =
+            self.method == "*" || self.method.eq_ignore_ascii_case(method.as_str());
+        method_matches && matches_prefix(&self.path_prefix, path)
+    }
+}
+
+/// Every request is allowed if there are no routes
+pub(crate) fn is_route_allowed(routes: &[AllowedRoute], method: &Method, path: &str) -> bool {
+    routes.is_empty() || routes.iter().any(|route| route.matches(method, path))
+}
+
+const WELL_KNOWN: &str = "/.well-known/";
+
+/// Whether the path skips the auth of private deployments. Only prefixes under `/.well-known/`
+/// are honored, and paths with dot segments or encoded dots and slashes never match,
+/// as the app might resolve them outside of the prefix
+pub(crate) fn is_public_path(prefixes: &[String], path: &str) -> bool {
+    let lowercase = path.to_ascii_lowercase();
+    if lowercase.contains("%2e") || lowercase.contains("%2f") || lowercase.contains("%5c") {
+        return false;
+    }
+    if path
+        .split('/')
+        .any(|segment| segment == "." || segment == "..")
+    {
+        return false;
+    }
+    prefixes
+        .iter()
+        .filter(|prefix| prefix.starts_with(WELL_KNOWN))
+        .any(|prefix| matches_prefix(prefix, path))
+}
+
+/// /api matches /api and /api/users but not /apis
+fn matches_prefix(prefix: &str, path: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod routes_tests {
+    use http::Method;
+
+    use super::{is_public_path, is_route_allowed, AllowedRoute};
+
+    fn route(method: &str, path_prefix: &str) -> AllowedRoute {
+        AllowedRoute {
+            method: method.to_owned(),
+            path_prefix: path_prefix.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_is_route_allowed() {
+        assert!(is_route_allowed(&[], &Method::DELETE, "/admin"));
+
+        let routes = vec![route("GET", "/api/"), route("*", "/health")];
+        AllowedRoute::validate(&routes).unwrap();
+        assert!(is_route_allowed(&routes, &Method::GET, "/api"));
+        assert!(is_route_allowed(&routes, &Method::GET, "/api/users"));
+        assert!(!is_route_allowed(&routes, &Method::GET, "/apis"));
+        assert!(!is_route_allowed(&routes, &Method::POST, "/api/users"));
+        assert!(is_route_allowed(&routes, &Method::POST, "/health"));
+        assert!(!is_route_allowed(&routes, &Method::GET, "/"));
+
+        // the root prefix allows every path
+        assert!(is_route_allowed(&[route("GET", "/")], &Method::GET, "/a/b"));
+    }
+
+    #[test]
+    fn test_is_public_path() {
+        let prefixes = vec![
+            "/.well-known/acme-challenge/".to_owned(),
+            "/.well-known/security.txt".to_owned(),
+            "/admin".to_owned(),
+        ];
+        assert!(is_public_path(&prefixes, "/.well-known/acme-challenge/abc"));
+        assert!(is_public_path(&prefixes, "/.well-known/security.txt"));
+        assert!(!is_public_path(&prefixes, "/.well-known/security.txt.bak"));
+        assert!(!is_public_path(&prefixes, "/.well-known/other"));
+        // only .well-known prefixes are honored
+        assert!(!is_public_path(&prefixes, "/admin"));
+        assert!(!is_public_path(
+            &prefixes,
+            "/.well-known/acme-challenge/../../admin"
+        ));
+        assert!(!is_public_path(
+            &prefixes,
+            "/.well-known/acme-challenge/%2E%2E/admin"
+        ));
+        assert!(!is_public_path(
+            &prefixes,
+            "/.well-known/acme-challenge/..%2fadmin"
+        ));
+    }
+
+    #[test]
+    fn test_invalid_routes() {
+        assert!(AllowedRoute::validate(&[route("GE T", "/")]).is_err());
+        assert!(AllowedRoute::validate(&[route("GET", "api")]).is_err());
+    }
+}
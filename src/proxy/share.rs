@@ -0,0 +1,89 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// query param carrying the token in share links
+pub(crate) const SHARE_PARAM: &str = "prezel_share";
+/// the token is moved from the link to this cookie on the first request
+pub(crate) const SHARE_COOKIE: &str = "prezel_share";
+
+/// `<deployment>.<expiration in millis>.<hex HMAC-SHA256 of the first two parts>`,
+/// signed with the instance token so only this server can mint them
+pub(crate) fn sign_share_token(secret: &str, deployment: i64, expires: i64) -> String {
+    let payload = format!("{deployment}.{expires}");
+    let signature = hex::encode(mac(secret, &payload).finalize().into_bytes());
+    format!("{payload}.{signature}")
+}
+
+/// Returns the expiration of the token if it was signed for the deployment and hasn't expired
+pub(crate) fn verify_share_token(
+    secret: &str,
+    token: &str,
+    deployment: i64,
+    now: i64,
+) -> Option<i64> {
+    let (payload, signature) = token.rsplit_once('.')?;
+    let (token_deployment, expires) = payload.split_once('.')?;
+    let expires: i64 = expires.parse().ok()?;
+    if token_deployment.parse::<i64>().ok()? != deployment || expires <= now {
+        return None;
+    }
+    let signature = hex::decode(signature).ok()?;
+    // constant time comparison
+    mac(secret, payload).verify_slice(&signature).ok()?;
+    Some(expires)
+}
+
+/// Splits the share token out of the query, returning it together with the rest of the query
+pub(crate) fn take_share_param(query: &str) -> Option<(String, String)> {
+    let mut token = None;
+    let rest: Vec<_> = query
+        .split('&')
+        .filter(|pair| match pair.strip_prefix(SHARE_PARAM) {
+            Some(value) if value.starts_with('=') => {
+                token = Some(value[1..].to_owned());
+                false
+            }
+            _ => true,
+        })
+        .collect();
+    Some((token?, rest.join("&")))
+}
+
+fn mac(secret: &str, payload: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(payload.as_bytes());
+    mac
+}
+
+#[cfg(test)]
+mod share_tests {
+    use super::{sign_share_token, take_share_param, verify_share_token};
+
+    #[test]
+    fn test_share_token() {
+        let token = sign_share_token("secret", 7, 2000);
+        assert_eq!(verify_share_token("secret", &token, 7, 1000), Some(2000));
+        // expired, another deployment or another instance
+        assert_eq!(verify_share_token("secret", &token, 7, 2000), None);
+        assert_eq!(verify_share_token("secret", &token, 8, 1000), None);
+        assert_eq!(verify_share_token("other", &token, 7, 1000), None);
+
+        let tampered = token.replacen("2000", "9000", 1);
+        assert_eq!(verify_share_token("secret", &tampered, 7, 1000), None);
+        assert_eq!(verify_share_token("secret", "7.2000.zz", 7, 1000), None);
+    }
+
+    #[test]
+    fn test_take_share_param() {
+        assert_eq!(
+            take_share_param("page=2&prezel_share=abc&sort=asc"),
+            Some(("abc".to_owned(), "page=2&sort=asc".to_owned()))
+        );
+        assert_eq!(
+            take_share_param("prezel_share=abc"),
+            Some(("abc".to_owned(), "".to_owned()))
+        );
+        assert_eq!(take_share_param("prezel_shared=abc"), None);
+        assert_eq!(take_share_param("page=2"), None);
+    }
+}
@@ -0,0 +1,205 @@
+use std::{
+    io,
+    path::{Component, Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use anyhow::ensure;
+use http::{header, Method, StatusCode};
+use log::warn;
+use percent_encoding::percent_decode_str;
+use pingora::{http::ResponseHeader, prelude::Session, Result};
+use tokio::fs;
+
+const INDEX_FILE: &str = "index.html";
+/// pages are revalidated on every request so new deployments show up right away
+const PAGE_CACHE_CONTROL: &str = "no-cache";
+const ASSET_CACHE_CONTROL: &str = "public, max-age=3600";
+
+pub(crate) fn validate_static_dir(dir: &str) -> anyhow::Result<()> {
+    let path = Path::new(dir);
+    ensure!(path.is_absolute(), "{dir} needs to be an absolute path");
+    ensure!(
+        path.components()
+            .all(|component| component != Component::ParentDir),
+        "{dir} can not contain .."
+    );
+    Ok(())
+}
+
+/// Maps the request path to a file under root. Folders are served through their index.html
+/// and paths with no extension that don't match anything get the root index.html,
+/// so single page apps can route them on the client
+pub(crate) fn resolve_static_file(root: &Path, path: &str) -> Option<PathBuf> {
+    let path = percent_decode_str(path).decode_utf8().ok()?;
+    let relative = Path::new(path.trim_start_matches('/'));
+    let inside_root = relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if !inside_root {
+        return None;
+    }
+
+    let file = root.join(relative);
+    if file.is_file() {
+        return Some(file);
+    }
+    let index = file.join(INDEX_FILE);
+    if index.is_file() {
+        return Some(index);
+    }
+    let fallback = root.join(INDEX_FILE);
+    (relative.extension().is_none() && fallback.is_file()).then_some(fallback)
+}
+
+pub(crate) fn content_type(file: &Path) -> &'static str {
+    let extension = file
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_lowercase());
+    match extension.as_deref() {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js" | "mjs") => "text/javascript; charset=utf-8",
+        Some("json" | "map") => "application/json",
+        Some("webmanifest") => "application/manifest+json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("avif") => "image/avif",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        Some("otf") => "font/otf",
+        Some("wasm") => "application/wasm",
+        Some("pdf") => "application/pdf",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        Some("mp3") => "audio/mpeg",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Answers the request with the file under root it points to
+pub(crate) async fn serve_static(session: &mut Session, root: &Path) -> Result<()> {
+    let request = session.req_header();
+    let head = request.method == Method::HEAD;
+    if request.method != Method::GET && !head {
+        let mut resp: Box<_> = ResponseHeader::build(StatusCode::METHOD_NOT_ALLOWED, None)?.into();
+        resp.insert_header(header::ALLOW, "GET, HEAD")?;
+        resp.insert_header(header::CONTENT_LENGTH, 0)?;
+        return session.write_response_header(resp, true).await;
+    }
+    let Some(file) = resolve_static_file(root, request.uri.path()) else {
+        return session.respond_error(StatusCode::NOT_FOUND.as_u16()).await;
+    };
+    let (content, etag) = match read_file(&file).await {
+        Ok(result) => result,
+        Err(error) => {
+            warn!("failed to read static file {}: {error}", file.display());
+            return session
+                .respond_error(StatusCode::INTERNAL_SERVER_ERROR.as_u16())
+                .await;
+        }
+    };
+    let not_modified = session
+        .get_header(header::IF_NONE_MATCH)
+        .is_some_and(|value| value.as_bytes() == etag.as_bytes());
+
+    let content_type = content_type(&file);
+    let cache_control = if content_type.starts_with("text/html") {
+        PAGE_CACHE_CONTROL
+    } else {
+        ASSET_CACHE_CONTROL
+    };
+    let code = if not_modified {
+        StatusCode::NOT_MODIFIED
+    } else {
+        StatusCode::OK
+    };
+    let mut resp: Box<_> = ResponseHeader::build(code, None)?.into();
+    resp.insert_header(header::CONTENT_TYPE, content_type)?;
+    resp.insert_header(header::CACHE_CONTROL, cache_control)?;
+    resp.insert_header(header::ETAG, etag)?;
+    if not_modified {
+        session.write_response_header(resp, true).await
+    } else {
+        resp.insert_header(header::CONTENT_LENGTH, content.len())?;
+        session.write_response_header(resp, head).await?;
+        if head {
+            return Ok(());
+        }
+        session
+            .write_response_body(Some(content.into()), true)
+            .await
+    }
+}
+
+/// Content and ETag of the file, the ETag changes with the size and the modification time
+async fn read_file(file: &Path) -> io::Result<(Vec<u8>, String)> {
+    let metadata = fs::metadata(file).await?;
+    let modified = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let etag = format!("\"{:x}-{:x}\"", metadata.len(), modified.as_secs());
+    Ok((fs::read(file).await?, etag))
+}
+
+#[cfg(test)]
+mod static_files_tests {
+    use std::{fs, path::Path};
+
+    use tempfile::TempDir;
+
+    use super::{content_type, resolve_static_file, validate_static_dir};
+
+    #[test]
+    fn test_resolve_static_file() {
+        let root = TempDir::new().unwrap();
+        let root = root.path();
+        fs::create_dir_all(root.join("docs/assets")).unwrap();
+        fs::write(root.join("index.html"), "root").unwrap();
+        fs::write(root.join("docs/index.html"), "docs").unwrap();
+        fs::write(root.join("docs/assets/app 1.js"), "app").unwrap();
+
+        let resolve = |path| resolve_static_file(root, path);
+        assert_eq!(resolve("/"), Some(root.join("index.html")));
+        assert_eq!(resolve("/docs/"), Some(root.join("docs/index.html")));
+        assert_eq!(resolve("/docs"), Some(root.join("docs/index.html")));
+        assert_eq!(
+            resolve("/docs/assets/app%201.js"),
+            Some(root.join("docs/assets/app 1.js"))
+        );
+        // client side routes get the app, missing assets don't
+        assert_eq!(resolve("/settings/profile"), Some(root.join("index.html")));
+        assert_eq!(resolve("/docs/assets/missing.js"), None);
+        assert_eq!(resolve("/../etc/passwd"), None);
+        assert_eq!(resolve("/docs/%2e%2e/%2e%2e/etc"), None);
+    }
+
+    #[test]
+    fn test_content_type() {
+        assert_eq!(
+            content_type(Path::new("/a/index.HTML")),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(content_type(Path::new("/a/logo.svg")), "image/svg+xml");
+        assert_eq!(
+            content_type(Path::new("/a/data.bin")),
+            "application/octet-stream"
+        );
+    }
+
+    #[test]
+    fn test_validate_static_dir() {
+        assert!(validate_static_dir("/app/dist").is_ok());
+        assert!(validate_static_dir("dist").is_err());
+        assert!(validate_static_dir("/app/../etc").is_err());
+    }
+}
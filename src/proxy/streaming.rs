@@ -0,0 +1,75 @@
+use std::sync::atomic::Ordering;
+
+use http::{header, HeaderMap, HeaderName};
+use pingora::http::{RequestHeader, ResponseHeader};
+
+use crate::listener::OpenStreams;
+
+pub(crate) const EVENT_STREAM: &str = "text/event-stream";
+
+/// EventSource clients always ask for an event stream, so the connection
+/// can be told apart before the upstream answers
+pub(crate) fn accepts_event_stream(request: &RequestHeader) -> bool {
+    lists_event_stream(&request.headers, header::ACCEPT)
+}
+
+/// Events need to reach the client as soon as they are sent, so these responses
+/// are never buffered, rewritten or cached
+pub(crate) fn is_event_stream(response: &ResponseHeader) -> bool {
+    lists_event_stream(&response.headers, header::CONTENT_TYPE)
+}
+
+fn lists_event_stream(headers: &HeaderMap, name: HeaderName) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|mime| {
+            let mime = mime.split(';').next().unwrap_or_default().trim();
+            mime.eq_ignore_ascii_case(EVENT_STREAM)
+        })
+}
+
+/// Counts an event stream as open until the request is done
+pub(crate) struct StreamGuard(OpenStreams);
+
+impl StreamGuard {
+    pub(crate) fn new(streams: OpenStreams) -> Self {
+        streams.fetch_add(1, Ordering::Relaxed);
+        Self(streams)
+    }
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod streaming_tests {
+    use pingora::http::{RequestHeader, ResponseHeader};
+
+    use super::{accepts_event_stream, is_event_stream};
+
+    #[test]
+    fn test_event_stream_detection() {
+        let mut request = RequestHeader::build("GET", b"/events", None).unwrap();
+        assert!(!accepts_event_stream(&request));
+        request
+            .insert_header("Accept", "text/html, Text/Event-Stream;q=0.9")
+            .unwrap();
+        assert!(accepts_event_stream(&request));
+
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        response
+            .insert_header("Content-Type", "text/event-stream; charset=utf-8")
+            .unwrap();
+        assert!(is_event_stream(&response));
+        response
+            .insert_header("Content-Type", "text/plain")
+            .unwrap();
+        assert!(!is_event_stream(&response));
+    }
+}
@@ -0,0 +1,37 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// How the proxy connects to the app container of a project
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum UpstreamTls {
+    /// plain HTTP
+    #[default]
+    Off,
+    /// HTTPS, the certificate of the app needs to be valid for the requested host
+    On,
+    /// HTTPS accepting any certificate, like self-signed ones
+    SkipVerify,
+}
+
+impl UpstreamTls {
+    pub(crate) fn parse(value: &str) -> Self {
+        match value {
+            "on" => Self::On,
+            "skip_verify" => Self::SkipVerify,
+            _ => Self::Off,
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::On => "on",
+            Self::SkipVerify => "skip_verify",
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        *self != Self::Off
+    }
+}
@@ -0,0 +1,212 @@
+use std::{env, sync::OnceLock};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use bollard::{
+    container::{MemoryStatsStats, StartContainerOptions, Stats, StatsOptions},
+    Docker as BollardDocker, API_DEFAULT_VERSION,
+};
+use futures::StreamExt;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::conf::{RuntimeConf, RuntimeKind};
+
+/// same as the bollard default
+const CLIENT_TIMEOUT_SECS: u64 = 120;
+const PODMAN_ROOTFUL_SOCKET: &str = "/run/podman/podman.sock";
+
+static RUNTIME: OnceLock<Box<dyn ContainerRuntime>> = OnceLock::new();
+
+/// Connects to the runtime in the config, needs to be called before any container is managed
+pub(crate) fn init_runtime(conf: &RuntimeConf) -> anyhow::Result<()> {
+    let runtime: Box<dyn ContainerRuntime> = match conf.kind {
+        RuntimeKind::Docker => Box::new(DockerRuntime::connect(conf.socket.as_deref())?),
+        RuntimeKind::Podman => Box::new(PodmanRuntime::connect(conf.socket.as_deref())?),
+    };
+    RUNTIME
+        .set(runtime)
+        .map_err(|_| anyhow!("the container runtime is already initialized"))
+}
+
+pub(crate) fn runtime() -> &'static dyn ContainerRuntime {
+    RUNTIME
+        .get()
+        .expect("the container runtime is not initialized")
+        .as_ref()
+}
+
+/// Resource usage of a running container
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub(crate) struct ContainerStats {
+    /// Percentage of a single CPU, can go above 100 when using several of them
+    pub(crate) cpu_percent: f64,
+    /// Bytes in use, excluding the page cache
+    pub(crate) memory_usage: u64,
+    pub(crate) memory_limit: u64,
+    pub(crate) network_rx_bytes: u64,
+    pub(crate) network_tx_bytes: u64,
+}
+
+impl From<Stats> for ContainerStats {
+    /// Same computation as the docker cli, the CPU usage is relative to the previous sample
+    fn from(stats: Stats) -> Self {
+        let cpu_delta = stats.cpu_stats.cpu_usage.total_usage as f64
+            - stats.precpu_stats.cpu_usage.total_usage as f64;
+        let system_delta = stats.cpu_stats.system_cpu_usage.unwrap_or(0) as f64
+            - stats.precpu_stats.system_cpu_usage.unwrap_or(0) as f64;
+        let online_cpus = stats.cpu_stats.online_cpus.unwrap_or(1) as f64;
+        let cpu_percent = if cpu_delta > 0.0 && system_delta > 0.0 {
+            cpu_delta / system_delta * online_cpus * 100.0
+        } else {
+            0.0
+        };
+
+        let memory = &stats.memory_stats;
+        let cache = match memory.stats {
+            Some(MemoryStatsStats::V1(stats)) => stats.total_inactive_file,
+            Some(MemoryStatsStats::V2(stats)) => stats.inactive_file,
+            None => 0,
+        };
+        let memory_usage = memory.usage.unwrap_or(0).saturating_sub(cache);
+
+        let (network_rx_bytes, network_tx_bytes) = stats
+            .networks
+            .iter()
+            .flatten()
+            .fold((0, 0), |(rx, tx), (_, network)| {
+                (rx + network.rx_bytes, tx + network.tx_bytes)
+            });
+
+        Self {
+            cpu_percent,
+            memory_usage,
+            memory_limit: memory.limit.unwrap_or(0),
+            network_rx_bytes,
+            network_tx_bytes,
+        }
+    }
+}
+
+/// Engine running the containers. Both runtimes are driven through the Docker compatible API,
+/// implementations override the operations where they differ from Docker
+#[async_trait]
+pub(crate) trait ContainerRuntime: Send + Sync {
+    /// also used for the images, volumes, networks and builds
+    fn client(&self) -> &BollardDocker;
+
+    async fn ping(&self) -> anyhow::Result<()> {
+        self.client().ping().await?;
+        Ok(())
+    }
+
+    async fn start_container(&self, id: &str) -> anyhow::Result<()> {
+        self.client()
+            .start_container(id, None::<StartContainerOptions<String>>)
+            .await?;
+        Ok(())
+    }
+
+    async fn stop_container(&self, id: &str) -> anyhow::Result<()> {
+        self.client().stop_container(id, None).await?;
+        Ok(())
+    }
+
+    async fn delete_container(&self, id: &str) -> anyhow::Result<()> {
+        self.client().remove_container(id, None).await?;
+        Ok(())
+    }
+
+    /// Takes about a second, as docker needs two samples to compute the CPU usage
+    async fn get_container_stats(&self, id: &str) -> anyhow::Result<ContainerStats> {
+        let options = StatsOptions {
+            stream: false,
+            one_shot: false,
+        };
+        let stats = self
+            .client()
+            .stats(id, Some(options))
+            .next()
+            .await
+            .ok_or(anyhow!("the runtime returned no stats for {id}"))??;
+        Ok(stats.into())
+    }
+
+    /// None while the container is still running
+    async fn get_container_exit_code(&self, id: &str) -> anyhow::Result<Option<i64>> {
+        let state = self.client().inspect_container(id, None).await?.state;
+        let exit_code = state
+            .filter(|state| state.running == Some(false))
+            .map(|state| state.exit_code.unwrap_or_default());
+        Ok(exit_code)
+    }
+}
+
+pub(crate) struct DockerRuntime {
+    client: BollardDocker,
+}
+
+impl DockerRuntime {
+    /// `DOCKER_HOST` or `/var/run/docker.sock` if no socket is given
+    fn connect(socket: Option<&str>) -> anyhow::Result<Self> {
+        let client = match socket {
+            Some(socket) => {
+                BollardDocker::connect_with_unix(socket, CLIENT_TIMEOUT_SECS, API_DEFAULT_VERSION)?
+            }
+            None => BollardDocker::connect_with_unix_defaults()?,
+        };
+        Ok(Self { client })
+    }
+}
+
+#[async_trait]
+impl ContainerRuntime for DockerRuntime {
+    fn client(&self) -> &BollardDocker {
+        &self.client
+    }
+}
+
+/// Podman through the Docker compatible API of `podman system service`
+pub(crate) struct PodmanRuntime {
+    client: BollardDocker,
+}
+
+impl PodmanRuntime {
+    /// The rootless socket of the user if no socket is given, the rootful one otherwise
+    fn connect(socket: Option<&str>) -> anyhow::Result<Self> {
+        let socket = match socket {
+            Some(socket) => socket.to_owned(),
+            None => match env::var("XDG_RUNTIME_DIR") {
+                Ok(dir) if !dir.is_empty() => format!("{dir}/podman/podman.sock"),
+                _ => PODMAN_ROOTFUL_SOCKET.to_owned(),
+            },
+        };
+        let client =
+            BollardDocker::connect_with_unix(&socket, CLIENT_TIMEOUT_SECS, API_DEFAULT_VERSION)?;
+        Ok(Self { client })
+    }
+}
+
+#[async_trait]
+impl ContainerRuntime for PodmanRuntime {
+    fn client(&self) -> &BollardDocker {
+        &self.client
+    }
+
+    /// Podman leaves the previous CPU sample empty when not streaming, so the second sample
+    /// of the stream is used instead, which also takes about a second
+    async fn get_container_stats(&self, id: &str) -> anyhow::Result<ContainerStats> {
+        let options = StatsOptions {
+            stream: true,
+            one_shot: false,
+        };
+        let stats = self
+            .client()
+            .stats(id, Some(options))
+            .skip(1)
+            .next()
+            .await
+            .ok_or(anyhow!("podman returned no stats for {id}"))??;
+        Ok(stats.into())
+    }
+}
@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, ensure, Context};
+use log::error;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use crate::{conf::Conf, env::EnvVars};
+
+/// Secret env values are stored as `prezel-secret:v1:<key id>:<nonce>:<ciphertext>:<tag>`,
+/// all of them hex encoded
+const PREFIX: &str = "prezel-secret:v1:";
+const KEY_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+
+#[derive(Debug)]
+struct Key {
+    /// fingerprint of the key telling which one encrypted a value, without revealing it
+    id: String,
+    bytes: Vec<u8>,
+}
+
+impl Key {
+    fn parse(key: &str) -> anyhow::Result<Self> {
+        let bytes = hex::decode(key.trim()).context("the secrets key needs to be hex encoded")?;
+        ensure!(
+            bytes.len() == KEY_LENGTH,
+            "the secrets key needs to be {KEY_LENGTH} bytes long"
+        );
+        let id = hex::encode(&Sha256::digest(&bytes)[..4]);
+        Ok(Self { id, bytes })
+    }
+}
+
+/// Encrypts the secret env values at rest with AES-256-GCM. Values encrypted with
+/// one of the previous keys can still be decrypted until they are rotated
+#[derive(Debug, Default)]
+pub(crate) struct Secrets {
+    current: Option<Key>,
+    previous: Vec<Key>,
+}
+
+impl Secrets {
+    pub(crate) fn new(conf: &Conf) -> anyhow::Result<Self> {
+        let current = conf.secrets_key.as_deref().map(Key::parse).transpose()?;
+        let previous = conf
+            .previous_secrets_keys
+            .iter()
+            .map(|key| Key::parse(key))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { current, previous })
+    }
+
+    pub(crate) fn is_secret(value: &str) -> bool {
+        value.starts_with(PREFIX)
+    }
+
+    pub(crate) fn encrypt(&self, value: &str) -> anyhow::Result<String> {
+        let key = self
+            .current
+            .as_ref()
+            .ok_or_else(|| anyhow!("no secrets key configured"))?;
+        let mut nonce = [0; NONCE_LENGTH];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let mut tag = [0; TAG_LENGTH];
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &key.bytes,
+            Some(&nonce),
+            &[],
+            value.as_bytes(),
+            &mut tag,
+        )?;
+        Ok(format!(
+            "{PREFIX}{}:{}:{}:{}",
+            key.id,
+            hex::encode(nonce),
+            hex::encode(ciphertext),
+            hex::encode(tag)
+        ))
+    }
+
+    pub(crate) fn decrypt(&self, value: &str) -> anyhow::Result<String> {
+        let encrypted = value
+            .strip_prefix(PREFIX)
+            .ok_or_else(|| anyhow!("not a secret value"))?;
+        let [id, nonce, ciphertext, tag] = encrypted
+            .split(':')
+            .collect::<Vec<_>>()
+            .try_into()
+            .map_err(|_| anyhow!("malformed secret value"))?;
+        let key = self
+            .keys()
+            .find(|key| key.id == id)
+            .ok_or_else(|| anyhow!("the key {id} that encrypted the value is not configured"))?;
+        let plaintext = decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &key.bytes,
+            Some(&hex::decode(nonce)?),
+            &[],
+            &hex::decode(ciphertext)?,
+            &hex::decode(tag)?,
+        )
+        .context("failed to decrypt the secret value")?;
+        Ok(String::from_utf8(plaintext)?)
+    }
+
+    /// Plain values are kept as they are. Secrets that can't be decrypted stay encrypted,
+    /// so the container refuses to start instead of running without them
+    pub(crate) fn decrypt_env(&self, env: EnvVars) -> EnvVars {
+        let env: HashMap<String, String> = env.into();
+        env.into_iter()
+            .map(|(name, value)| {
+                if !Self::is_secret(&value) {
+                    return (name, value);
+                }
+                match self.decrypt(&value) {
+                    Ok(value) => (name, value),
+                    Err(error) => {
+                        error!("failed to decrypt secret {name}: {error}");
+                        (name, value)
+                    }
+                }
+            })
+            .collect::<HashMap<_, _>>()
+            .into()
+    }
+
+    /// Whether both env texts hold the same values, as every encryption of a secret gives
+    /// a different value. Secrets that can't be decrypted are compared as they are
+    pub(crate) fn same_env(&self, left: &str, right: &str) -> bool {
+        let plain = |env: &str| -> HashMap<String, String> {
+            HashMap::from(EnvVars::from(env))
+                .into_iter()
+                .map(|(name, value)| match Self::is_secret(&value) {
+                    true => (name, self.decrypt(&value).unwrap_or(value)),
+                    false => (name, value),
+                })
+                .collect()
+        };
+        plain(left) == plain(right)
+    }
+
+    /// The env text with the secrets of previous keys encrypted with the current one,
+    /// None if nothing changed
+    pub(crate) fn rotate_env(&self, env: &str) -> Option<String> {
+        let current = self.current.as_ref()?;
+        let current_prefix = format!("{PREFIX}{}:", current.id);
+        let values: HashMap<String, String> = EnvVars::from(env).into();
+        let mut rotated = env.to_owned();
+        for (name, value) in values {
+            if !Self::is_secret(&value) || value.starts_with(&current_prefix) {
+                continue;
+            }
+            match self.decrypt(&value).and_then(|plain| self.encrypt(&plain)) {
+                Ok(encrypted) => rotated = rotated.replace(&value, &encrypted),
+                Err(error) => error!("failed to rotate secret {name}: {error}"),
+            }
+        }
+        (rotated != env).then_some(rotated)
+    }
+
+    fn keys(&self) -> impl Iterator<Item = &Key> {
+        self.current.iter().chain(&self.previous)
+    }
+}
+
+#[cfg(test)]
+mod secrets_tests {
+    use std::collections::HashMap;
+
+    use super::{Key, Secrets};
+    use crate::env::EnvVars;
+
+    fn secrets(current: Option<&str>, previous: &[&str]) -> Secrets {
+        Secrets {
+            current: current.map(|key| Key::parse(&key.repeat(64)).unwrap()),
+            previous: previous
+                .iter()
+                .map(|key| Key::parse(&key.repeat(64)).unwrap())
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_encrypt_and_rotate() {
+        let old = secrets(Some("a"), &[]);
+        let encrypted = old.encrypt("hunter2").unwrap();
+        assert!(Secrets::is_secret(&encrypted));
+        assert!(!encrypted.contains("hunter2"));
+        assert_eq!(old.decrypt(&encrypted).unwrap(), "hunter2");
+
+        let env = format!("PLAIN=1\nTOKEN={encrypted}");
+        let new = secrets(Some("b"), &["a"]);
+        let rotated = new.rotate_env(&env).unwrap();
+        assert!(rotated.starts_with("PLAIN=1\nTOKEN="));
+        assert_eq!(new.rotate_env(&rotated), None);
+        assert_ne!(rotated, env);
+        assert!(new.same_env(&env, &rotated));
+        assert!(!new.same_env(&env, "PLAIN=1\nTOKEN=hunter3"));
+
+        // secrets the key can't decrypt stay encrypted
+        let with_old: HashMap<_, _> = old.decrypt_env(EnvVars::from(rotated.as_str())).into();
+        assert!(Secrets::is_secret(&with_old["TOKEN"]));
+
+        let decrypted: HashMap<_, _> = new.decrypt_env(EnvVars::from(rotated.as_str())).into();
+        assert_eq!(decrypted["TOKEN"], "hunter2");
+        assert_eq!(decrypted["PLAIN"], "1");
+    }
+
+    #[test]
+    fn test_missing_key() {
+        let encrypted = secrets(Some("a"), &[]).encrypt("hunter2").unwrap();
+        let missing = secrets(None, &[]);
+        assert!(missing.encrypt("hunter2").is_err());
+        assert!(missing.decrypt(&encrypted).is_err());
+        assert!(Key::parse("abc").is_err());
+    }
+}
@@ -280,6 +280,7 @@ async fn write_dns_challenge(handle: Arc<ChallengeTask>, conf: &Conf) {
         token,
         hostname,
         coordinator,
+        ..
     } = conf;
     let challenge_response = handle.get_dns_value();
 
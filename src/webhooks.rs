@@ -0,0 +1,108 @@
+use std::{sync::Arc, time::Duration};
+
+use hmac::{Hmac, Mac};
+use log::{error, info};
+use serde::Serialize;
+use sha2::Sha256;
+use tokio::time::sleep;
+
+use crate::{
+    api::Status,
+    conf::{Conf, WebhookConf},
+};
+
+const SIGNATURE_HEADER: &str = "X-Prezel-Signature";
+const DELIVERY_ATTEMPTS: u32 = 3;
+
+#[derive(Serialize, Debug, Clone)]
+pub(crate) struct WebhookPayload {
+    pub(crate) deployment: i64,
+    pub(crate) project_id: i64,
+    pub(crate) project: String,
+    pub(crate) sha: String,
+    pub(crate) status: Status,
+    pub(crate) url: String,
+    pub(crate) time: i64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct WebhookNotifier {
+    webhooks: Arc<Vec<WebhookConf>>,
+}
+
+impl WebhookNotifier {
+    pub(crate) fn new(conf: &Conf) -> Self {
+        Self {
+            webhooks: conf.webhooks.clone().into(),
+        }
+    }
+
+    /// Delivery happens in the background so the deployment pipeline never waits for it
+    pub(crate) fn notify(&self, payload: WebhookPayload) {
+        for webhook in self.webhooks.iter() {
+            if webhook.events.contains(&payload.status) {
+                let webhook = webhook.clone();
+                let payload = payload.clone();
+                tokio::spawn(async move { deliver(&webhook, &payload).await });
+            }
+        }
+    }
+}
+
+async fn deliver(webhook: &WebhookConf, payload: &WebhookPayload) {
+    let body = serde_json::to_vec(payload).unwrap();
+    let signature = sign(&webhook.secret, &body);
+    let client = reqwest::Client::new();
+
+    let mut delay = Duration::from_secs(1);
+    for attempt in 1..=DELIVERY_ATTEMPTS {
+        let response = client
+            .post(&webhook.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(SIGNATURE_HEADER, &signature)
+            .body(body.clone())
+            .timeout(Duration::from_secs(10))
+            .send()
+            .await;
+        match response {
+            Ok(response) if response.status().is_success() => {
+                info!(
+                    "webhook {} delivered for deployment {}",
+                    webhook.url, payload.deployment
+                );
+                return;
+            }
+            Ok(response) => error!(
+                "webhook {} attempt {attempt} got status {}",
+                webhook.url,
+                response.status()
+            ),
+            Err(err) => error!("webhook {} attempt {attempt} failed: {err}", webhook.url),
+        }
+        if attempt < DELIVERY_ATTEMPTS {
+            sleep(delay).await;
+            delay *= 2;
+        }
+    }
+}
+
+/// hex encoded HMAC-SHA256 of the body, prefixed with the algorithm
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod webhooks_tests {
+    use super::sign;
+
+    #[test]
+    fn test_sign() {
+        // test case 2 of RFC 4231
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}